@@ -0,0 +1,132 @@
+use super::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// The backends that search a loaded cache from several threads at once
+pub const BENCH_BACKENDS: [&str; 3] = ["rayon", "mmap", "roaring"];
+
+type BenchIndex = Box<dyn DocumentIndexer + Send + Sync>;
+
+// The queries of a replay file: a --query-log, whose lines are "MATCHES\tQUERY", or one query per line
+fn replay_queries(log: &str) -> Vec<&str> {
+    log.lines()
+        .map(|line| match line.split_once('\t') {
+            Some((matches, query)) if matches.parse::<usize>().is_ok() => query,
+            _ => line
+        })
+        .map(str::trim)
+        .filter(|query| !query.is_empty())
+        .collect()
+}
+
+// What the cache's BuildInfo says it was built with, so queries analyze as the documents were.
+// Caches from before it was recorded are taken as default text mode builds.
+fn built_options(build: Option<&BuildInfo>) -> IndexOptions {
+    let build = match build {
+        Some(build) => build,
+        None => return IndexOptions::default()
+    };
+    IndexOptions {
+        format: parse_format(&build.format).unwrap_or(Format::Xml),
+        mode: if build.mode == "code" { Mode::Code } else { Mode::Text },
+        query_language: build.query_language.clone(),
+        split_units: build.split_units,
+        keyword_headers: build.keyword_headers.clone(),
+        max_doc_bytes: build.max_doc_bytes,
+        boosts: build.boosts.clone(),
+        ..IndexOptions::default()
+    }
+}
+
+fn load_cached(index_filename: &str, backend: &str) -> Result<BenchIndex, CacheError> {
+    SerializedIndex::check_current(index_filename)?;
+    let metadata = SerializedIndex::metadata(index_filename)?;
+    let options = IndexOptions { schema: metadata.schema, ..built_options(metadata.build.as_ref()) };
+    let mut word_index: BenchIndex = match backend {
+        "mmap" => Box::new(MmapIndexer::new(options)),
+        "roaring" => Box::new(RoaringIndexer::new(options)),
+        _ => Box::new(RayonIndexer::new(options))
+    };
+    try_build_from_cache(word_index.as_mut(), index_filename, metadata.schema)?;
+    Ok(word_index)
+}
+
+// Latencies of one replay, in the order the searches finished
+struct Replay {
+    latencies: Vec<time::Duration>,
+    refused: usize,
+    elapsed: time::Duration
+}
+
+// Runs every query once, `concurrency` at a time, each for the first `limit` hits as a search page
+// would be
+fn replay(word_index: &(dyn DocumentIndexer + Sync), queries: &[&str], concurrency: usize, limit: usize) -> Replay {
+    let next = AtomicUsize::new(0);
+    let page = Page { offset: 0, limit: Some(limit) };
+    let started = time::Instant::now();
+    let runs: Vec<(Vec<time::Duration>, usize)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..concurrency.max(1)).map(|_| scope.spawn(|| {
+            let (mut latencies, mut refused) = (Vec::new(), 0);
+            while let Some(query) = queries.get(next.fetch_add(1, Ordering::Relaxed)) {
+                let before = time::Instant::now();
+                let result = word_index.search_page(split_query(query), page);
+                latencies.push(before.elapsed());
+                refused += result.is_err() as usize;
+            }
+            (latencies, refused)
+        })).collect();
+        workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+    });
+    let elapsed = started.elapsed();
+    let refused = runs.iter().map(|(_, refused)| refused).sum();
+    Replay { latencies: runs.into_iter().flat_map(|(latencies, _)| latencies).collect(), refused, elapsed }
+}
+
+impl Replay {
+    fn print(mut self, source: &str, concurrency: usize) {
+        if self.latencies.is_empty() {
+            println!("No queries in {}", source);
+            return;
+        }
+        self.latencies.sort_unstable();
+        let sorted = &self.latencies;
+        println!("Replayed {} queries from {} at concurrency {} in {} ms: {:.1} queries/s",
+            sorted.len(), source, concurrency, self.elapsed.as_millis(), sorted.len() as f64 / self.elapsed.as_secs_f64());
+        println!("Latency: p50 {} us, p90 {} us, p99 {} us, max {} us",
+            Latencies::percentile(sorted, 50).as_micros(),
+            Latencies::percentile(sorted, 90).as_micros(),
+            Latencies::percentile(sorted, 99).as_micros(),
+            sorted[sorted.len() - 1].as_micros());
+        if self.refused > 0 {
+            println!("Refused: {} (over the query limits)", self.refused);
+        }
+    }
+}
+
+// `fulltext bench`: loads the cache next to `index_filename` and replays `replay_path` against it,
+// for capacity planning. Needs a cache, which is loaded as it was built.
+pub fn run_bench(index_filename: &str, backend: &str, replay_path: &str, concurrency: usize, limit: usize) -> Result<(), io::Error> {
+    let log = fs::read_to_string(replay_path)?;
+    let queries = replay_queries(&log);
+    let before = time::Instant::now();
+    let word_index = load_cached(index_filename, backend)
+        .map_err(|e| io::Error::other(format!("can't load the cache of {}, which is {}", index_filename, e)))?;
+    println!("Loaded {} documents in {} ms", word_index.num_documents(), before.elapsed().as_millis());
+    replay(word_index.as_ref(), &queries, concurrency, limit).print(replay_path, concurrency);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_logs_and_plain_lists_replay_every_query() {
+        assert_eq!(replay_queries("3\tapple pie\n\n0\tpear\nplum jam\ta\n"), vec!["apple pie", "pear", "plum jam\ta"]);
+
+        let mut word_index = RayonIndexer::new(IndexOptions { format: Format::Lines, ..IndexOptions::default() });
+        word_index.build_from_file_contents("fruit.log", String::from("apple pie\npear tart\n")).unwrap();
+        let queries = vec!["apple"; 20];
+        let run = replay(&word_index, &queries, 3, 10);
+        assert_eq!((run.latencies.len(), run.refused), (20, 0));
+    }
+}
@@ -0,0 +1,42 @@
+// A source file for tests in the temp directory, named after the test and the process so that
+// concurrent runs don't meet. Dropping it removes the file and everything written next to it under
+// the same name, cache files, segments and locks included, whether or not the test passed.
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+pub struct TempSource(PathBuf);
+
+impl TempSource {
+    pub fn new(name: &str, extension: &str, contents: &str) -> TempSource {
+        let path = std::env::temp_dir().join(format!("fulltext-{}-{}.{}", name, std::process::id(), extension));
+        fs::write(&path, contents).unwrap();
+        TempSource(path)
+    }
+}
+
+impl Deref for TempSource {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempSource {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempSource {
+    fn drop(&mut self) {
+        let prefix = format!("{}.", self.0.file_stem().unwrap().to_string_lossy());
+        let directory = self.0.parent().unwrap();
+        for entry in fs::read_dir(directory).into_iter().flatten().flatten() {
+            if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+}
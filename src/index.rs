@@ -0,0 +1,142 @@
+use crate::indexers::*;
+use std::io;
+use std::path::PathBuf;
+
+// An index over one source, built with the rayon backend. Searches take the CLI's query syntax, see
+// indexers/query.rs, and return each matching document once as a Hit, best first.
+pub struct Index {
+    indexer: RayonIndexer
+}
+
+// Err rather than the indexer's panic on token filters it can't make
+fn new_indexer(options: IndexOptions) -> Result<RayonIndexer, io::Error> {
+    options.check_token_filters().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    Ok(RayonIndexer::new(options))
+}
+
+impl Index {
+    // Reads and indexes the file at `path` in `options.format`. Record sources use the default
+    // columns, and sql needs a query, so for those read the source with read_source and use
+    // from_contents.
+    pub fn build(path: &str, options: IndexOptions) -> Result<Index, io::Error> {
+        let contents = read_source(path, options.format, &SourceOptions::default())?;
+        Index::from_contents(path, contents, options)
+    }
+
+    // Like build, over several files as one index, with directories standing for the files in them,
+    // see SourceFiles::list. Documents are numbered across the files in order. The cache maps a
+    // single source file, so such an index can't be saved.
+    pub fn build_from_files(paths: &[PathBuf], options: IndexOptions) -> Result<Index, io::Error> {
+        let paths = SourceFiles::list(paths.iter())?;
+        let (sources, contents) = SourceFiles::read(&paths, options.format, false, |path| read_source(path, options.format, &SourceOptions::default()))?;
+        let mut indexer = new_indexer(options)?;
+        indexer.build_from_files(sources, contents)?;
+        Ok(Index { indexer })
+    }
+
+    // Indexes a buffer in `options.format`. `source` is what documents of the lines format are
+    // labeled with. Err when postings spilled past `options.memory_limit` can't be read back.
+    pub fn from_contents(source: &str, contents: String, options: IndexOptions) -> Result<Index, io::Error> {
+        let mut indexer = new_indexer(options)?;
+        indexer.build_from_file_contents(source, contents)?;
+        Ok(Index { indexer })
+    }
+
+    // Indexes the documents `source` gives as it hands them over, without writing or parsing them
+    // again: an XmlSource, a ParquetSource, or a source of your own, implementing DocumentSource or
+    // DocumentBatches for one that hands over whole documents. `options.format` is ignored. There's
+    // no source file for the cache to map, so such an index can't be saved.
+    pub fn from_source(label: &str, source: &mut dyn DocumentSource, options: IndexOptions) -> Result<Index, io::Error> {
+        let mut indexer = new_indexer(IndexOptions { format: Format::Records, ..options })?;
+        indexer.build_from_source(label, source)?;
+        Ok(Index { indexer })
+    }
+
+    // Indexes documents the caller already has, as parsed out of their own database or scraper,
+    // without writing them out as XML or JSONL first. Like from_source, `options.format` is ignored
+    // and the index can't be saved.
+    pub fn build_from_documents(label: &str, docs: impl IntoIterator<Item = InputDoc>, options: IndexOptions) -> Result<Index, io::Error> {
+        Index::from_source(label, &mut InputDocs(docs.into_iter()), options)
+    }
+
+    // Like from_contents, but gives up with an Interrupted error once `cancel` is cancelled from
+    // another thread, having dropped the partial index and `contents`
+    pub fn from_contents_cancellable(source: &str, contents: String, options: IndexOptions, cancel: &CancelToken) -> Result<Index, io::Error> {
+        let mut indexer = new_indexer(options)?;
+        indexer.set_cancel_token(cancel.clone());
+        indexer.build_from_file_contents(source, contents)?;
+        if cancel.is_cancelled() {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "build cancelled"));
+        }
+        Ok(Index { indexer })
+    }
+
+    // Loads the cache files the CLI or save wrote next to `path`. The cache isn't checked against
+    // `options`, which must match the build's format and analysis; its schema is taken from the cache.
+    // Refused when `path` isn't the file it was built from, see BuildInfo::check_cache.
+    pub fn open(path: &str, options: IndexOptions) -> Result<Index, io::Error> {
+        let serialized = SerializedIndex::load_from_path(path)?;
+        let mut indexer = new_indexer(options)?;
+        indexer.build_from_serialized(serialized)?;
+        Ok(Index { indexer })
+    }
+
+    // Indexes more documents without a rebuild, numbered after the existing ones. save keeps them
+    // in the cache's .add file, the source isn't touched.
+    pub fn add_documents(&mut self, docs: Vec<Document>) {
+        self.indexer.add_documents(docs);
+    }
+
+    // Leaves documents out of searches from then on, the others keep their ids. save records them in
+    // the cache's .del file. Returns how many weren't deleted already.
+    pub fn delete_documents(&mut self, ids: &[i32]) -> usize {
+        self.indexer.delete_documents(ids)
+    }
+
+    // Writes the cache files for `path`, which must be the file the index was built from
+    pub fn save(&self, path: &str) -> Result<(), io::Error> {
+        SerializedIndex::write_index_to_path(path, &self.indexer, SyncPolicy::default())
+    }
+
+    // Err when the query goes over `options.limits`
+    pub fn search(&self, query: &str) -> Result<SearchResults, QueryError> {
+        self.indexer.search(split_query(query))
+    }
+
+    // The hits of search on `page`, fetching only their documents
+    pub fn search_page(&self, query: &str, page: Page) -> Result<SearchResults, QueryError> {
+        self.indexer.search_page(split_query(query), page)
+    }
+
+    // search for each of `queries`, in order, run in parallel
+    pub fn search_batch(&self, queries: &[&str]) -> Vec<Result<SearchResults, QueryError>> {
+        let split: Vec<Vec<&str>> = queries.iter().map(|query| split_query(query)).collect();
+        self.indexer.search_batch(&split)
+    }
+
+    // Text around where `hit` matched its terms, see indexers/snippets.rs
+    pub fn snippet(&self, hit: &Hit, highlight: Highlight) -> String {
+        match_snippet(&self.indexer, &hit.label(), &hit.doc, highlight)
+    }
+
+    // Up to `limit` titles starting with `prefix`, with the id of a document that has each
+    pub fn suggest_titles(&self, prefix: &str, limit: usize) -> Vec<(String, i32)> {
+        self.indexer.suggest_titles(prefix, limit)
+    }
+
+    pub fn document(&self, id: i32) -> Option<Document> {
+        self.indexer.document(id)
+    }
+
+    pub fn num_documents(&self) -> usize {
+        self.indexer.num_documents()
+    }
+
+    pub fn num_terms(&self) -> usize {
+        self.indexer.num_tokens()
+    }
+
+    pub(crate) fn into_indexer(self) -> RayonIndexer {
+        self.indexer
+    }
+}
@@ -0,0 +1,115 @@
+use crate::indexers::*;
+
+// One word of the input and what the analyzer made of it. Code mode identifiers give several, one
+// per term, that share a position.
+struct TokenTrace<'a> {
+    original: &'a str,
+    // What was done to the word on the way, in order
+    filters: Vec<&'a str>,
+    // None when a filter dropped the word
+    term: Option<String>,
+    position: u32,
+    start: u32,
+    end: u32
+}
+
+fn span(letters: &str, word: &str) -> (u32, u32) {
+    let start = word.as_ptr() as usize - letters.as_ptr() as usize;
+    (start as u32, (start + word.len()) as u32)
+}
+
+// Mirrors Analyzer::analyze_into for text mode, keeping the words it drops and naming the token
+// filters that changed each. A word a filter made several terms of gets a trace per term.
+fn trace_text<'a>(analyzer: &'a Analyzer, letters: &'a str, stem: bool) -> Vec<TokenTrace<'a>> {
+    let mut traces: Vec<TokenTrace> = Vec::new();
+    let mut position: u32 = 0;
+    for word in text_words(letters) {
+        let pieces = analyzer.word_pieces(word);
+        for piece in pieces.iter().filter(|piece| !piece.is_empty()) {
+            let (start, end) = span(letters, piece);
+            let mut filters: Vec<&str> = Vec::new();
+            if !pieces[1].is_empty() {
+                filters.push("unit split");
+            }
+            let kept: String = piece.chars().filter(|c| c.is_alphanumeric()).collect();
+            if kept != *piece {
+                filters.push("number separators");
+            }
+            let mut tokens: Vec<String> = vec![kept];
+            for (name, filter) in analyzer.filters.iter().filter(|(name, _)| stem || name != "stemmer") {
+                let before = tokens.clone();
+                filter.filter(&mut tokens);
+                if tokens != before {
+                    filters.push(name);
+                }
+            }
+            tokens.retain(|token| !token.is_empty());
+            if tokens.is_empty() {
+                traces.push(TokenTrace { original: piece, filters: filters.clone(), term: None, position, start, end });
+            }
+            for term in tokens {
+                traces.push(TokenTrace { original: piece, filters: filters.clone(), term: Some(term), position, start, end });
+            }
+            position += 1;
+        }
+    }
+    traces
+}
+
+// Labels the terms analyze_code_into emits, which come per identifier as the whole word
+// lowercased, its parts when it has several, then the exact form
+fn trace_code(letters: &str) -> Vec<TokenTrace<'_>> {
+    let mut tokens: Vec<String> = Vec::new();
+    let mut positions: Vec<TokenPosition> = Vec::new();
+    analyze_code_into(letters, &mut tokens, Some(&mut positions));
+    let mut previous: Option<u32> = None;
+    tokens.into_iter().zip(positions).map(|(term, at)| {
+        let original = &letters[at.start as usize..at.end as usize];
+        let mut filters: Vec<&'static str> = Vec::new();
+        if term.strip_prefix(EXACT_FIELD).is_some_and(|rest| rest.starts_with(':')) {
+            filters.push("exact");
+        } else {
+            if previous == Some(at.position) {
+                filters.push("identifier part");
+            }
+            if term != original {
+                filters.push("lowercase");
+            }
+        }
+        previous = Some(at.position);
+        TokenTrace {
+            original,
+            filters,
+            term: Some(term),
+            position: at.position,
+            start: at.start,
+            end: at.end
+        }
+    }).collect()
+}
+
+// Prints what indexing `text` would produce, one line per token: the word as written, the filters
+// applied, the final term ("-" when dropped), its position and byte offsets. `language` picks the
+// stemmer and stopwords like --query-language, English when None, and `token_filters` replaces the
+// default filters as IndexOptions::token_filters does.
+pub fn print_analysis(text: &str, language: Option<&str>, mode: Mode, stem: bool, split_units: bool, token_filters: Option<&[String]>) -> Result<(), String> {
+    let mut analyzer = Analyzer::for_language(language.unwrap_or("en")).with_split_units(split_units);
+    if let Some(names) = token_filters {
+        analyzer = analyzer.with_token_filters(names)?;
+    }
+    let traces = match mode {
+        Mode::Code => trace_code(text),
+        Mode::Text => trace_text(&analyzer, text, stem)
+    };
+    let width = traces.iter().map(|t| t.original.chars().count()).max().unwrap_or(0).max("token".len());
+    let term_width = traces.iter().filter_map(|t| t.term.as_ref()).map(|t| t.chars().count()).max().unwrap_or(0).max("term".len());
+    println!("{:>8}  {:<11}  {:<width$}  {:<term_width$}  filters", "position", "offsets", "token", "term", width = width, term_width = term_width);
+    for t in traces.iter() {
+        let filters = if t.filters.is_empty() { String::from("-") } else { t.filters.join(", ") };
+        println!("{:>8}  {:<11}  {:<width$}  {:<term_width$}  {}", t.position, format!("{}..{}", t.start, t.end), t.original,
+            t.term.as_deref().unwrap_or("-"), filters, width = width, term_width = term_width);
+    }
+    let kept = traces.iter().filter(|t| t.term.is_some()).count();
+    println!("{} tokens, {} terms, {} dropped", traces.len(), kept, traces.len() - kept);
+    Ok(())
+}
@@ -0,0 +1,167 @@
+use crate::indexers::*;
+use crate::indexers::records::{DocumentSource, RecordWriter, read_documents};
+use std::io::BufReader;
+
+pub fn is_archive(path: &str) -> bool {
+    let path = path.to_lowercase();
+    [".tar", ".tar.gz", ".tgz", ".tar.zst", ".tzst", ".zip"].iter().any(|ext| path.ends_with(ext))
+}
+
+const BINARY_MAGIC: &[&[u8]] = &[
+    b"\x89PNG", b"\xff\xd8\xff", b"GIF8", b"%PDF", b"PK\x03\x04", b"\x1f\x8b", b"\x7fELF", b"\x28\xb5\x2f\xfd", b"MZ"
+];
+
+// Same heuristic as most grep implementations: a NUL byte near the start means binary, plus a few
+// well known file signatures that can start with printable bytes
+pub(super) fn looks_binary(contents: &[u8]) -> bool {
+    let head = &contents[..cmp::min(contents.len(), 8192)];
+    head.contains(&0) || BINARY_MAGIC.iter().any(|magic| head.starts_with(magic))
+}
+
+// Which files of an archive or directory become documents, and what happened to the rest
+pub(super) struct EntryFilter<'a> {
+    force_extensions: &'a [String],
+    skipped_binary: usize,
+    keep_going: bool,
+    // Entries that couldn't be read, with keep_going
+    failed: Vec<String>
+}
+
+impl<'a> EntryFilter<'a> {
+    pub(super) fn new(force_extensions: &'a [String], keep_going: bool) -> Self {
+        EntryFilter { force_extensions, skipped_binary: 0, keep_going, failed: Vec::new() }
+    }
+
+    // Fails the whole read unless keep_going, which logs the entry and carries on
+    pub(super) fn skip_failed(&mut self, name: &str, error: io::Error) -> Result<(), io::Error> {
+        if !self.keep_going {
+            return Err(error);
+        }
+        println!("Skipping {}: {}", name, error);
+        self.failed.push(String::from(name));
+        Ok(())
+    }
+
+    // The file's name is the title and `name`, its path, the url
+    pub(super) fn push_entry(&mut self, records: &mut RecordWriter, name: &str, contents: &[u8]) {
+        let title = name.rsplit(['/', std::path::MAIN_SEPARATOR]).next().unwrap_or(name);
+        let forced = match title.rsplit_once('.') {
+            Some((_, ext)) => self.force_extensions.iter().any(|f| f.eq_ignore_ascii_case(ext)),
+            None => false
+        };
+        if !forced && looks_binary(contents) {
+            self.skipped_binary += 1;
+            return;
+        }
+        records.push(title, name, &String::from_utf8_lossy(contents));
+    }
+
+    pub(super) fn report(&self, source: &str) {
+        if self.skipped_binary > 0 {
+            println!("Skipped {} binary entries in {}", self.skipped_binary, source);
+        }
+        if !self.failed.is_empty() {
+            println!("Skipped {} unreadable entries in {}: {}", self.failed.len(), source, self.failed.join(", "));
+        }
+    }
+}
+
+fn read_tar<R: Read>(reader: R, records: &mut RecordWriter, filter: &mut EntryFilter) -> Result<(), io::Error> {
+    let mut archive = tar::Archive::new(reader);
+    let mut contents: Vec<u8> = Vec::new();
+    for (i, entry) in archive.entries()?.enumerate() {
+        let mut entry = match entry {
+            Ok(entry) => entry,
+            // Nothing past a bad header can be found, the iterator ends after it
+            Err(e) => {
+                filter.skip_failed(&format!("entry {} and the rest of the archive", i + 1), e)?;
+                break;
+            }
+        };
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = match entry.path() {
+            Ok(path) => path.to_string_lossy().into_owned(),
+            Err(e) => {
+                filter.skip_failed(&format!("entry {}", i + 1), e)?;
+                continue;
+            }
+        };
+        contents.clear();
+        if let Err(e) = entry.read_to_end(&mut contents) {
+            filter.skip_failed(&name, e)?;
+            continue;
+        }
+        filter.push_entry(records, &name, &contents);
+    }
+    Ok(())
+}
+
+fn read_zip(file: File, records: &mut RecordWriter, filter: &mut EntryFilter) -> Result<(), io::Error> {
+    let mut archive = zip::ZipArchive::new(BufReader::new(file)).map_err(io::Error::other)?;
+    let mut contents: Vec<u8> = Vec::new();
+    for i in 0..archive.len() {
+        let mut entry = match archive.by_index(i) {
+            Ok(entry) => entry,
+            Err(e) => {
+                filter.skip_failed(&format!("entry {}", i + 1), io::Error::other(e))?;
+                continue;
+            }
+        };
+        if !entry.is_file() {
+            continue;
+        }
+        let name = match entry.name() {
+            Ok(name) => name.into_owned(),
+            Err(e) => {
+                drop(entry);
+                filter.skip_failed(&format!("entry {}", i + 1), io::Error::other(e))?;
+                continue;
+            }
+        };
+        contents.clear();
+        if let Err(e) = entry.read_to_end(&mut contents) {
+            filter.skip_failed(&name, e)?;
+            continue;
+        }
+        filter.push_entry(records, &name, &contents);
+    }
+    Ok(())
+}
+
+struct ArchiveSource<'a> {
+    path: &'a str,
+    filter: EntryFilter<'a>,
+    done: bool
+}
+
+impl DocumentSource for ArchiveSource<'_> {
+    // Every entry in one batch, the archive is read through once
+    fn next_batch(&mut self, contents: &mut String) -> Result<Option<Vec<DocumentRaw>>, io::Error> {
+        if std::mem::replace(&mut self.done, true) {
+            return Ok(None);
+        }
+        let mut records = RecordWriter::after(std::mem::take(contents));
+        let file = File::open(self.path)?;
+        let lower = self.path.to_lowercase();
+        if lower.ends_with(".zip") {
+            read_zip(file, &mut records, &mut self.filter)?;
+        } else if lower.ends_with(".tar.gz") || lower.ends_with(".tgz") {
+            read_tar(flate2::read::GzDecoder::new(BufReader::new(file)), &mut records, &mut self.filter)?;
+        } else if lower.ends_with(".tar.zst") || lower.ends_with(".tzst") {
+            read_tar(zstd::stream::read::Decoder::new(file)?, &mut records, &mut self.filter)?;
+        } else {
+            read_tar(BufReader::new(file), &mut records, &mut self.filter)?;
+        }
+        self.filter.report(self.path);
+        Ok(Some(records.finish(contents)))
+    }
+}
+
+// Every regular text file in the archive becomes one document, decompressed entry by entry in memory.
+// Binary entries are skipped unless their extension is in `force_extensions`. An entry that can't be
+// read fails the whole archive unless `keep_going`, which skips it and lists it at the end.
+pub fn read_archive_records(path: &str, force_extensions: &[String], keep_going: bool) -> Result<String, io::Error> {
+    read_documents(&mut ArchiveSource { path, filter: EntryFilter::new(force_extensions, keep_going), done: false })
+}
@@ -0,0 +1,177 @@
+use crate::indexers::*;
+
+// The extension of a segment file is this and the id of its first document, e.g. "seg1200"
+pub const SEGMENT_PREFIX: &str = "seg";
+
+// The documents of one add_documents call with their postings, saved next to the cache in a file
+// of their own so that later calls, and documents deleted since, never rewrite what's on disk. A
+// full cache write folds them into the .idx and .add files and removes them. Document ranges point
+// into the indexer's added text as it was, where `contents` started at `contents_start`.
+#[derive(Serialize, Deserialize)]
+pub(super) struct CacheSegment {
+    pub documents: Vec<DocumentRaw>,
+    pub contents_start: usize,
+    pub contents: String,
+    // As in the .idx file, see postings.rs
+    pub postings: Vec<u8>,
+    // As in the .pos file, see positions.rs, when the schema has entries
+    pub positions: Option<Vec<u8>>
+}
+
+pub fn segment_extension(first_id: i32) -> String {
+    format!("{}{}", SEGMENT_PREFIX, first_id)
+}
+
+// The first id of the segment file with `extension`, None when it isn't one
+pub(super) fn segment_number(extension: &str) -> Option<i32> {
+    let digits = extension.strip_prefix(SEGMENT_PREFIX)?;
+    match !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+        true => digits.parse().ok(),
+        false => None
+    }
+}
+
+// The extensions of the segment files next to `base_path`, and of any the manifest lists that
+// aren't there, which then fail to load, in document order
+pub(super) fn segment_extensions(base_path: &Path, manifest: Option<&disk::Manifest>) -> Result<Vec<String>, io::Error> {
+    let mut numbers: Vec<i32> = manifest.into_iter().flat_map(|manifest| manifest.keys()).filter_map(|extension| segment_number(extension)).collect();
+    let directory = match base_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new(".")
+    };
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e)
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.file_stem() == base_path.file_stem() {
+            numbers.extend(path.extension().and_then(|extension| extension.to_str()).and_then(segment_number));
+        }
+    }
+    numbers.sort_unstable();
+    numbers.dedup();
+    Ok(numbers.into_iter().map(segment_extension).collect())
+}
+
+// Whether documents were added to or deleted from the cache next to `base_path` after its build,
+// which only RayonIndexer can load
+pub(super) fn has_changes(base_path: &Path) -> bool {
+    base_path.with_extension("add").exists() || base_path.with_extension("del").exists()
+        || segment_extensions(base_path, None).map_or(true, |extensions| !extensions.is_empty())
+}
+
+pub(super) fn read_segment(data: &[u8]) -> Result<CacheSegment, io::Error> {
+    bincode::deserialize(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("segment: {}", e)))
+}
+
+// Moves `d`'s ranges from text that started at `from` to where it starts now, at `to`. Added
+// documents have no keywords.
+pub(super) fn rebase(d: &mut DocumentRaw, from: usize, to: usize) {
+    for range in [&mut d.title, &mut d.url, &mut d.text] {
+        *range = range.start - from + to..range.end - from + to;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::TempSource;
+
+    #[test]
+    fn segments_are_written_once_and_removed_by_a_full_write() {
+        let text = "apple pie\nplain toast\n";
+        let path = TempSource::new("segments", "log", text);
+        let path_str = path.to_str().unwrap();
+        let options = IndexOptions { format: Format::Lines, ..IndexOptions::default() };
+        let mut indexer = RayonIndexer::new(options.clone());
+        indexer.build_from_file_contents(path_str, String::from(text)).unwrap();
+        SerializedIndex::write_index_to_path(path_str, &indexer, SyncPolicy::Never).unwrap();
+        let add = |indexer: &mut RayonIndexer, text: &str| {
+            indexer.add_documents(vec![Document { title: String::from(text), text: String::from(text), ..Document::default() }]);
+            SerializedIndex::write_additions_to_path(path_str, indexer, SyncPolicy::Never).unwrap();
+        };
+        add(&mut indexer, "apple jam");
+        let first = fs::metadata(path.with_extension("seg2")).unwrap().modified().unwrap();
+        add(&mut indexer, "pear jam");
+        assert_eq!(fs::metadata(path.with_extension("seg2")).unwrap().modified().unwrap(), first);
+        assert_eq!(indexer.delete_documents(&[0, 3, 9]), 2);
+        SerializedIndex::write_additions_to_path(path_str, &indexer, SyncPolicy::Never).unwrap();
+        assert_eq!(segment_extensions(&path, None).unwrap(), vec!["seg2", "seg3"]);
+
+        let load = || {
+            let mut reloaded = RayonIndexer::new(options.clone());
+            reloaded.build_from_serialized(SerializedIndex::load_from_path(path_str).unwrap()).unwrap();
+            reloaded
+        };
+        let mut reloaded = load();
+        assert_eq!((reloaded.num_documents(), reloaded.pending_additions()), (4, (2, 2)));
+        assert_eq!(reloaded.search(vec!["apple"]).unwrap().ids().collect::<Vec<i32>>(), vec![2]);
+        assert_eq!(reloaded.document(2).unwrap().text, "apple jam");
+        assert!(reloaded.document(3).is_none());
+        assert_eq!(reloaded.document_frequency("appl"), 1);
+
+        let pending = reloaded.pending_additions();
+        SerializedIndex::write_index_to_path(path_str, &reloaded, SyncPolicy::Never).unwrap();
+        reloaded.mark_compacted(pending);
+        assert!(segment_extensions(&path, None).unwrap().is_empty());
+        let compacted = load();
+        assert_eq!((compacted.num_documents(), compacted.pending_additions(), compacted.num_deleted()), (4, (0, 0), 2));
+        assert_eq!(compacted.search(vec!["jam"]).unwrap().ids().collect::<Vec<i32>>(), vec![2]);
+        // Gone from the .idx, not just filtered out
+        let idx = postings::read_index(&fs::read(path.with_extension("idx")).unwrap()).unwrap();
+        assert!(idx.values().all(|ids| !ids.contains(&0) && !ids.contains(&3)));
+        assert!(!idx.contains_key("pear"));
+        assert_eq!(compacted.cooccurring_terms("jam", 10), vec![(String::from("appl"), 1)]);
+    }
+
+    #[test]
+    fn compaction_leaves_out_deleted_documents_postings_and_records() {
+        let text = "apple pie\nplain toast\n";
+        let path = TempSource::new("compaction", "log", text);
+        let path_str = path.to_str().unwrap();
+        let options = IndexOptions { format: Format::Lines, schema: Schema { positions: true, offsets: true, frequencies: true }, ..IndexOptions::default() };
+        let load = || {
+            let mut reloaded = RayonIndexer::new(options.clone());
+            reloaded.build_from_serialized(SerializedIndex::load_from_path(path_str).unwrap()).unwrap();
+            reloaded
+        };
+        let mut indexer = RayonIndexer::new(options.clone());
+        indexer.build_from_file_contents(path_str, String::from(text)).unwrap();
+        SerializedIndex::write_index_to_path(path_str, &indexer, SyncPolicy::Never).unwrap();
+        for added in ["apple jam", "pear jam"] {
+            indexer.add_documents(vec![Document { title: String::from(added), text: String::from(added), ..Document::default() }]);
+            SerializedIndex::write_additions_to_path(path_str, &indexer, SyncPolicy::Never).unwrap();
+        }
+        assert_eq!(indexer.delete_documents(&[0, 3]), 2);
+        SerializedIndex::write_additions_to_path(path_str, &indexer, SyncPolicy::Never).unwrap();
+
+        let mut reopened = load();
+        assert_eq!((reopened.num_documents(), reopened.num_deleted()), (4, 2));
+        let pending = reopened.pending_additions();
+        SerializedIndex::write_index_to_path(path_str, &reopened, SyncPolicy::Never).unwrap();
+        reopened.mark_compacted(pending);
+
+        let idx = postings::read_index(&fs::read(path.with_extension("idx")).unwrap()).unwrap();
+        assert!(idx.values().all(|ids| !ids.contains(&0) && !ids.contains(&3)));
+        assert!(!idx.contains_key("pie") && !idx.contains_key("pear"));
+        let (_, positions) = positions::read_positions(&fs::read(path.with_extension("pos")).unwrap()).unwrap();
+        assert!(positions.values().all(|docs| !docs.contains_key(&0) && !docs.contains_key(&3)));
+        // Blank records that keep their ids, so the documents after them keep theirs
+        let documents: Vec<DocumentRaw> = bincode::deserialize(&fs::read(path.with_extension("dcm")).unwrap()).unwrap();
+        assert_eq!((documents[0].id, documents[0].title.clone(), documents[0].text.clone()), (0, 0..0, 0..0));
+        assert!(!documents[1].text.is_empty());
+        let (added, _, covered, _): (Vec<DocumentRaw>, String, usize, usize) = bincode::deserialize(&fs::read(path.with_extension("add")).unwrap()).unwrap();
+        assert_eq!((added.len(), covered, added[1].id, added[1].text.clone()), (2, 2, 3, 0..0));
+        assert!(!added[0].text.is_empty());
+
+        let compacted = load();
+        assert_eq!(compacted.search(vec!["jam"]).unwrap().ids().collect::<Vec<i32>>(), vec![2]);
+        assert_eq!(compacted.document(1).unwrap().text, "plain toast");
+        assert!(compacted.document(0).is_none() && compacted.document(3).is_none());
+        for extension in CACHE_EXTENSIONS.iter().chain(["log"].iter()) {
+            let _ = fs::remove_file(path.with_extension(extension));
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use crate::indexers::*;
+
+// Only the first this many matched documents are clustered, the rest are left out of the groups
+pub const CLUSTER_TOP_N: usize = 200;
+const MAX_ROUNDS: usize = 20;
+const LABEL_TERMS: usize = 3;
+
+type TermVector = HashMap<String, f32>;
+
+pub struct Cluster {
+    // Highest weighted terms of the centroid
+    pub label: Vec<String>,
+    // Indexes into the documents passed to cluster_documents
+    pub members: Vec<usize>
+}
+
+fn normalize(vector: &mut TermVector) {
+    let norm = vector.values().map(|w| w * w).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        for weight in vector.values_mut() {
+            *weight /= norm;
+        }
+    }
+}
+
+fn similarity(a: &TermVector, b: &TermVector) -> f32 {
+    let (small, large) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    small.iter().map(|(term, w)| w * large.get(term).unwrap_or(&0.0)).sum()
+}
+
+fn tf_idf(indexer: &dyn DocumentIndexer, doc: &Document) -> TermVector {
+    let mut vector = TermVector::new();
+    for term in indexer.analyze_text(&doc.text) {
+        *vector.entry(term).or_insert(0.0) += 1.0;
+    }
+    let num_documents = indexer.num_documents() as f32;
+    for (term, weight) in vector.iter_mut() {
+        let df = cmp::max(indexer.document_frequency(term), 1) as f32;
+        *weight *= (num_documents / df).ln() + 1.0;
+    }
+    normalize(&mut vector);
+    vector
+}
+
+fn centroid(vectors: &[TermVector], members: &[usize]) -> TermVector {
+    let mut centroid = TermVector::new();
+    for &member in members {
+        for (term, weight) in &vectors[member] {
+            *centroid.entry(term.clone()).or_insert(0.0) += weight;
+        }
+    }
+    normalize(&mut centroid);
+    centroid
+}
+
+// Seeds with the first document, then repeatedly the document least similar to any seed so far, so
+// results don't change from run to run
+fn initial_centroids(vectors: &[TermVector], k: usize) -> Vec<TermVector> {
+    let mut centroids: Vec<TermVector> = vec![vectors[0].clone()];
+    while centroids.len() < k {
+        let farthest = (0..vectors.len())
+            .map(|i| (i, centroids.iter().map(|c| similarity(&vectors[i], c)).fold(f32::MIN, f32::max)))
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(cmp::Ordering::Equal))
+            .map(|(i, _)| i)
+            .unwrap();
+        centroids.push(vectors[farthest].clone());
+    }
+    centroids
+}
+
+fn closest(vector: &TermVector, centroids: &[TermVector]) -> usize {
+    (0..centroids.len())
+        .max_by(|a, b| similarity(vector, &centroids[*a]).partial_cmp(&similarity(vector, &centroids[*b])).unwrap_or(cmp::Ordering::Equal))
+        .unwrap()
+}
+
+// k-means over the TF-IDF vectors of the documents' text, using cosine similarity. Empty clusters are
+// dropped, so fewer than `k` may come back.
+pub fn cluster_documents(indexer: &dyn DocumentIndexer, docs: &[Document], k: usize) -> Vec<Cluster> {
+    let docs = &docs[..cmp::min(docs.len(), CLUSTER_TOP_N)];
+    if docs.is_empty() || k == 0 {
+        return Vec::new();
+    }
+    let vectors: Vec<TermVector> = docs.iter().map(|d| tf_idf(indexer, d)).collect();
+    let mut centroids = initial_centroids(&vectors, cmp::min(k, docs.len()));
+    let mut assignment: Vec<usize> = vectors.iter().map(|v| closest(v, &centroids)).collect();
+    for _ in 0..MAX_ROUNDS {
+        centroids = (0..centroids.len())
+            .map(|c| centroid(&vectors, &(0..vectors.len()).filter(|i| assignment[*i] == c).collect::<Vec<usize>>()))
+            .collect();
+        let next: Vec<usize> = vectors.iter().map(|v| closest(v, &centroids)).collect();
+        if next == assignment {
+            break;
+        }
+        assignment = next;
+    }
+
+    let mut clusters: Vec<Cluster> = Vec::new();
+    for (c, center) in centroids.iter().enumerate() {
+        let members: Vec<usize> = (0..vectors.len()).filter(|i| assignment[*i] == c).collect();
+        if members.is_empty() {
+            continue;
+        }
+        let mut terms: Vec<(&String, &f32)> = center.iter().collect();
+        terms.sort_by(|a, b| b.1.partial_cmp(a.1).unwrap_or(cmp::Ordering::Equal).then_with(|| a.0.cmp(b.0)));
+        clusters.push(Cluster {
+            label: terms.into_iter().take(LABEL_TERMS).map(|(t, _)| t.clone()).collect(),
+            members
+        });
+    }
+    clusters.sort_by_key(|c| cmp::Reverse(c.members.len()));
+    clusters
+}
@@ -0,0 +1,105 @@
+use crate::indexers::*;
+use crate::indexers::records::{ColumnMapping, DocumentSource};
+#[cfg(feature = "parquet")]
+use crate::indexers::records::{RecordWriter, to_io_error};
+#[cfg(feature = "parquet")]
+use ::parquet::arrow::arrow_reader::ParquetRecordBatchReader;
+
+// The rows of a parquet file as a DocumentSource, one record batch of the file at a time. Only the
+// three mapped columns are decoded; any column type arrow can cast to a string is accepted.
+#[cfg(feature = "parquet")]
+pub struct ParquetSource {
+    path: String,
+    names: [String; 3],
+    reader: ParquetRecordBatchReader
+}
+
+#[cfg(feature = "parquet")]
+impl ParquetSource {
+    pub fn open(path: &str, columns: &ColumnMapping) -> Result<Self, io::Error> {
+        use ::parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+        use ::parquet::arrow::ProjectionMask;
+
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?).map_err(to_io_error)?;
+        let names = [columns.title.clone(), columns.url.clone(), columns.text.clone()];
+        let projection = ProjectionMask::columns(builder.parquet_schema(), names.iter().map(String::as_str));
+        let reader = builder.with_projection(projection).build().map_err(to_io_error)?;
+        Ok(ParquetSource { path: String::from(path), names, reader })
+    }
+}
+
+#[cfg(feature = "parquet")]
+impl DocumentSource for ParquetSource {
+    fn next_batch(&mut self, contents: &mut String) -> Result<Option<Vec<DocumentRaw>>, io::Error> {
+        use arrow_array::{Array, StringArray};
+
+        let batch = match self.reader.next() {
+            Some(batch) => batch.map_err(to_io_error)?,
+            None => return Ok(None)
+        };
+        let mut fields: Vec<StringArray> = Vec::with_capacity(self.names.len());
+        for name in self.names.iter() {
+            let column = batch.column_by_name(name)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("no column '{}' in {}", name, self.path)))?;
+            let column = arrow_cast::cast(column, &arrow_schema::DataType::Utf8).map_err(to_io_error)?;
+            fields.push(column.as_any().downcast_ref::<StringArray>().unwrap().clone());
+        }
+        fn value(field: &StringArray, row: usize) -> &str {
+            if field.is_null(row) { "" } else { field.value(row) }
+        }
+        let mut records = RecordWriter::after(std::mem::take(contents));
+        for row in 0..batch.num_rows() {
+            records.push(value(&fields[0], row), value(&fields[1], row), value(&fields[2], row));
+        }
+        Ok(Some(records.finish(contents)))
+    }
+}
+
+#[cfg(not(feature = "parquet"))]
+pub struct ParquetSource;
+
+#[cfg(not(feature = "parquet"))]
+impl ParquetSource {
+    pub fn open(_path: &str, _columns: &ColumnMapping) -> Result<Self, io::Error> {
+        Err(io::Error::other("built without the 'parquet' feature"))
+    }
+}
+
+#[cfg(not(feature = "parquet"))]
+impl DocumentSource for ParquetSource {
+    fn next_batch(&mut self, _contents: &mut String) -> Result<Option<Vec<DocumentRaw>>, io::Error> {
+        Ok(None)
+    }
+}
+
+pub fn read_parquet_records(path: &str, columns: &ColumnMapping) -> Result<String, io::Error> {
+    read_documents(&mut ParquetSource::open(path, columns)?)
+}
+
+#[cfg(all(test, feature = "parquet"))]
+mod tests {
+    use super::*;
+    use crate::fixture::TempSource;
+    use std::sync::Arc;
+
+    #[test]
+    fn parquet_rows_index_as_read() {
+        use arrow_array::{ArrayRef, Int64Array, RecordBatch, StringArray};
+        let path = TempSource::new("rows", "parquet", "");
+        let batch = RecordBatch::try_from_iter(vec![
+            ("title", Arc::new(StringArray::from(vec![Some("Apple"), Some("Pear")])) as ArrayRef),
+            ("url", Arc::new(Int64Array::from(vec![1, 2])) as ArrayRef),
+            ("text", Arc::new(StringArray::from(vec![Some("a red fruit"), None])) as ArrayRef)
+        ]).unwrap();
+        let mut writer = ::parquet::arrow::ArrowWriter::try_new(File::create(&*path).unwrap(), batch.schema(), None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+
+        let mut indexer = RayonIndexer::new(IndexOptions { format: Format::Records, ..IndexOptions::default() });
+        indexer.build_from_source("rows", &mut ParquetSource::open(path.to_str().unwrap(), &ColumnMapping::default()).unwrap()).unwrap();
+        assert_eq!(indexer.num_documents(), 2);
+        let found: Vec<(String, String)> = indexer.search(vec!["red"]).unwrap().documents().map(|doc| (doc.title.clone(), doc.url.clone())).collect();
+        assert_eq!(found, vec![(String::from("Apple"), String::from("1"))]);
+        assert_eq!(indexer.document(1).unwrap().text, "");
+    }
+}
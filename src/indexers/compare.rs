@@ -0,0 +1,82 @@
+use crate::indexers::*;
+use crate::indexers::dump::Cursor;
+
+pub(super) struct IndexStats {
+    pub(super) num_documents: usize,
+    pub(super) document_frequencies: HashMap<String, usize>
+}
+
+impl IndexStats {
+    // Reads the term list out of the .idx next to `path` without keeping any postings, and the
+    // document count from the length prefix of the .dcm
+    pub(super) fn load(path: &Path) -> Result<Self, io::Error> {
+        let data = fs::read(path.with_extension("idx"))?;
+        let delta_encoded = postings::is_delta_encoded(&data);
+        let mut cursor = Cursor::new(&data);
+        if delta_encoded {
+            cursor.take(postings::IDX_MAGIC.len())?;
+        }
+        let num_terms = cursor.len(16)?;
+        let mut document_frequencies: HashMap<String, usize> = HashMap::with_capacity(num_terms);
+        for _ in 0..num_terms {
+            let term = cursor.string()?;
+            let df = match delta_encoded {
+                true => {
+                    let len = cursor.len(1)?;
+                    postings::count(cursor.take(len)?)
+                },
+                false => {
+                    let len = cursor.len(4)?;
+                    cursor.take(len * 4)?;
+                    len
+                }
+            };
+            document_frequencies.insert(term, df);
+        }
+        let mut prefix = [0; 8];
+        File::open(path.with_extension("dcm"))?.read_exact(&mut prefix)?;
+        Ok(IndexStats { num_documents: u64::from_le_bytes(prefix) as usize, document_frequencies })
+    }
+
+    fn share(&self, term: &str) -> f64 {
+        match (self.document_frequencies.get(term), self.num_documents) {
+            (Some(df), n) if n > 0 => *df as f64 / n as f64,
+            _ => 0.0
+        }
+    }
+}
+
+fn print_unique(name: &str, stats: &IndexStats, other: &IndexStats, top: usize) {
+    let mut unique: Vec<(&String, &usize)> = stats.document_frequencies.iter()
+        .filter(|(term, _)| !other.document_frequencies.contains_key(*term))
+        .collect();
+    println!("Terms only in {}: {}", name, unique.len());
+    unique.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+    for (term, df) in unique.into_iter().take(top) {
+        println!("  {}\t{}", df, term);
+    }
+}
+
+// Compares the cache files of two indexes, `a` and `b` being either the indexed files or their .idx
+// files. Document frequency shifts are measured as a share of each index's documents, so a term that
+// merely grew with the corpus doesn't count as shifted.
+pub fn compare_indexes(a: &str, b: &str, top: usize) -> Result<(), io::Error> {
+    let a_stats = IndexStats::load(Path::new(a))?;
+    let b_stats = IndexStats::load(Path::new(b))?;
+    for (name, stats) in [(a, &a_stats), (b, &b_stats)].iter() {
+        println!("{}: {} documents, {} terms", name, stats.num_documents, stats.document_frequencies.len());
+    }
+    println!("Document count change: {:+}", b_stats.num_documents as i64 - a_stats.num_documents as i64);
+    print_unique(a, &a_stats, &b_stats, top);
+    print_unique(b, &b_stats, &a_stats, top);
+
+    let terms: HashSet<&String> = a_stats.document_frequencies.keys().chain(b_stats.document_frequencies.keys()).collect();
+    let mut shifts: Vec<(f64, &String)> = terms.into_iter().map(|term| (b_stats.share(term) - a_stats.share(term), term)).collect();
+    shifts.sort_by(|x, y| y.0.abs().total_cmp(&x.0.abs()).then_with(|| x.1.cmp(y.1)));
+    println!("Largest document frequency shifts (percentage points of documents):");
+    for (shift, term) in shifts.into_iter().take(top) {
+        let df = |stats: &IndexStats| stats.document_frequencies.get(term).copied().unwrap_or(0);
+        println!("  {:+.2}\t{} -> {}\t{}", shift * 100.0, df(&a_stats), df(&b_stats), term);
+    }
+    Ok(())
+}
@@ -0,0 +1,194 @@
+// What every DocumentIndexer must do alike: one small corpus, a set of queries covering each kind of
+// term and operator, and the results they must give. A new backend is checked by adding a test that
+// hands a fresh one to conforms. Results are compared by title since backends may number documents
+// differently.
+use crate::indexers::*;
+
+const CORPUS: &str = r#"<feed>
+<doc>
+<title>Wikipedia: Anarchism</title>
+<url>https://en.wikipedia.org/wiki/Anarchism</url>
+<abstract>Anarchism is a political philosophy that rejects the state.</abstract>
+</doc>
+<doc>
+<title>Wikipedia: Apple</title>
+<url>https://en.wikipedia.org/wiki/Apple</url>
+<abstract>The apple is a fruit grown on apple trees in orchards.</abstract>
+</doc>
+<doc>
+<title>Wikipedia: Apple Inc.</title>
+<url>https://en.wikipedia.org/wiki/Apple_Inc.</url>
+<abstract>Apple Inc. is a technology company that designs phones and computers.</abstract>
+</doc>
+<doc>
+<title>Wikipedia: Orchard</title>
+<url>https://en.wikipedia.org/wiki/Orchard</url>
+<abstract>An orchard is a planting of fruit trees such as apple and pear.</abstract>
+</doc>
+<doc>
+<title>Wikipedia: State (polity)</title>
+<url>https://en.wikipedia.org/wiki/State_(polity)</url>
+<abstract>A state is a political entity with a government.</abstract>
+</doc>
+<doc>
+<title>Wikipedia: Pear</title>
+<url>https://en.wikipedia.org/wiki/Pear</url>
+<abstract>The pear is a fruit tree of the genus Pyrus, grown in orchards.</abstract>
+</doc>
+<doc>
+<title>Wikipedia: Computer</title>
+<url>https://en.wikipedia.org/wiki/Computer</url>
+<abstract>A computer is a machine that carries out computation.</abstract>
+</doc>
+<doc>
+<title>Wikipedia: Philosophy</title>
+<url>https://en.wikipedia.org/wiki/Philosophy</url>
+<abstract>Philosophy is the study of general questions about existence and knowledge.</abstract>
+</doc>
+</feed>
+"#;
+
+// Each query's SearchResults: its label and the titles of its matches, sorted
+type Expected = &'static [(&'static str, &'static [&'static str])];
+
+const QUERIES: &[(&str, Expected)] = &[
+    ("apple", &[("appl", &["Apple", "Apple Inc.", "Orchard"])]),
+    ("fruit trees", &[("fruit", &["Apple", "Orchard", "Pear"]), ("tree", &["Apple", "Orchard", "Pear"])]),
+    ("\"fruit trees\"", &[("\"fruit trees\"", &["Orchard", "Pear"])]),
+    ("\"trees fruit\"", &[]),
+    ("+fruit +apple", &[("fruit", &["Apple", "Orchard"]), ("appl", &["Apple", "Orchard"])]),
+    ("fruit -apple", &[("fruit", &["Pear"])]),
+    ("orchard*", &[("orchard*", &["Apple", "Orchard", "Pear"])]),
+    ("comput*", &[("comput*", &["Apple Inc.", "Computer"])]),
+    ("political AND (state OR philosophy)", &[("political AND (state OR philosophy)", &["Anarchism", "State (polity)"])]),
+    ("(computer OR phones) AND NOT machine", &[("(computer OR phones) AND NOT machine", &["Apple Inc."])]),
+    ("nonexistent", &[]),
+    // Stopwords only
+    ("the", &[]),
+    // Titles aren't indexed without IndexOptions::search_titles
+    ("Wikipedia", &[])
+];
+
+// Each listed term with the titles of the hits that matched it, as QUERIES has them
+fn by_term(results: &SearchResults) -> Vec<(&str, Vec<&str>)> {
+    results.terms.iter().map(|term| {
+        let mut titles: Vec<&str> = results.hits.iter()
+            .filter(|hit| hit.terms.contains(term))
+            .map(|hit| hit.doc.title.trim_start_matches("Wikipedia: "))
+            .collect();
+        titles.sort_unstable();
+        (term.as_str(), titles)
+    }).collect()
+}
+
+fn conforms(mut indexer: Box<dyn DocumentIndexer>) {
+    indexer.build_from_file_contents("conformance.xml", String::from(CORPUS)).unwrap();
+    assert_eq!(indexer.num_documents(), 8);
+    assert_eq!(indexer.document_frequency("fruit"), 3);
+    assert_eq!(indexer.terms_with_prefix("or", 5), vec![(String::from("orchard"), 3)]);
+    let suggested: Vec<String> = indexer.suggest_titles("wikipedia: a", 5).into_iter().map(|(title, _)| title).collect();
+    assert_eq!(suggested, vec!["Wikipedia: Anarchism", "Wikipedia: Apple", "Wikipedia: Apple Inc."]);
+    assert_eq!(indexer.cooccurring_terms("fruit", 3), vec![(String::from("is"), 3), (String::from("orchard"), 3), (String::from("tree"), 3)]);
+
+    // "The apple is a fruit grown on apple trees in orchards."
+    let apple = indexer.search(split_query("grown apple")).unwrap().hits[0].doc.id;
+    let expected = match indexer.schema().frequencies {
+        true => (Some(2), Some(1), None),
+        false => (None, None, None)
+    };
+    assert_eq!((indexer.term_frequency("appl", apple), indexer.term_frequency("tree", apple), indexer.term_frequency("pear", apple)), expected);
+
+    let queries: Vec<Vec<&str>> = QUERIES.iter().map(|(query, _)| split_query(query)).collect();
+    let batch = indexer.search_batch(&queries);
+    for ((query, expected), batched) in QUERIES.iter().zip(batch) {
+        let results = indexer.search(split_query(query)).unwrap();
+        let batched = batched.unwrap();
+        let actual = by_term(&results);
+        let expected: Vec<(&str, Vec<&str>)> = expected.iter().map(|(term, titles)| (*term, titles.to_vec())).collect();
+        assert_eq!(actual, expected, "{}", query);
+        assert_eq!(results.missing, 0, "{}", query);
+        assert_eq!(by_term(&batched), actual, "{} in a batch", query);
+        assert!(results.hits.windows(2).all(|pair| pair[0].score >= pair[1].score), "{} ranked", query);
+        let mut ids: Vec<i32> = results.ids().collect();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), results.len(), "{} lists a document twice", query);
+        for doc in results.documents() {
+            assert_eq!(indexer.document(doc.id).map(|found| found.title), Some(doc.title.clone()), "{}", query);
+        }
+    }
+}
+
+fn with_positions() -> IndexOptions {
+    IndexOptions { schema: Schema { positions: true, offsets: true, frequencies: true }, ..IndexOptions::default() }
+}
+
+#[test]
+fn rayon_conforms() {
+    conforms(Box::new(RayonIndexer::new(IndexOptions::default())));
+}
+
+#[test]
+fn rayon_with_positions_conforms() {
+    conforms(Box::new(RayonIndexer::new(with_positions())));
+}
+
+#[test]
+fn threadpool_conforms() {
+    conforms(Box::new(ThreadPoolIndexer::new_hashmap(IndexOptions::default(), 3, 2)));
+}
+
+#[test]
+fn threadpool_with_positions_conforms() {
+    conforms(Box::new(ThreadPoolIndexer::new_hashmap(with_positions(), 3, 2)));
+}
+
+#[test]
+fn threadpool_dashmap_conforms() {
+    conforms(Box::new(ThreadPoolIndexer::new_dashmap(IndexOptions::default(), 3, 2)));
+}
+
+#[test]
+fn mmap_conforms() {
+    conforms(Box::new(MmapIndexer::new(IndexOptions::default())));
+}
+
+#[test]
+fn roaring_conforms() {
+    conforms(Box::new(RoaringIndexer::new(IndexOptions::default())));
+}
+
+#[test]
+fn titles_are_searched_and_rank_first() {
+    let options = || IndexOptions { search_titles: true, ..IndexOptions::default() };
+    let backends: Vec<Box<dyn DocumentIndexer>> = vec![Box::new(RayonIndexer::new(options())), Box::new(ThreadPoolIndexer::new_hashmap(options(), 3, 2))];
+    for mut indexer in backends {
+        indexer.build_from_file_contents("conformance.xml", String::from(CORPUS)).unwrap();
+        let titles = |terms: Vec<&str>| -> Vec<String> {
+            indexer.search(terms).unwrap().documents().map(|doc| String::from(doc.title.trim_start_matches("Wikipedia: "))).collect()
+        };
+        assert_eq!(titles(split_query("Wikipedia")).len(), 8);
+        assert_eq!(titles(split_query("philosophy")), vec!["Philosophy", "Anarchism"]);
+        assert_eq!(titles(split_query("computer")), vec!["Computer", "Apple Inc."]);
+        assert_eq!(titles(split_query("title:orchard")), vec!["Orchard"]);
+        let apple = titles_only(&split_query("apple -inc"));
+        assert_eq!(titles(apple.iter().map(String::as_str).collect()), vec!["Apple"]);
+    }
+}
+
+#[test]
+fn title_phrases_need_every_word() {
+    let corpus = ["Web Server Setup", "Web Design", "Server Racks"].iter()
+        .map(|title| format!("<doc><title>{}</title><url>u</url><abstract>notes</abstract></doc>", title))
+        .collect::<String>();
+    let mut indexer = RayonIndexer::new(IndexOptions { search_titles: true, ..IndexOptions::default() });
+    indexer.build_from_file_contents("titles.xml", format!("<feed>{}</feed>", corpus)).unwrap();
+    let titles = |query: &str| -> Vec<String> {
+        let terms = titles_only(&split_query(query));
+        let mut titles: Vec<String> = indexer.search(terms.iter().map(String::as_str).collect()).unwrap().documents().map(|doc| doc.title.clone()).collect();
+        titles.sort();
+        titles
+    };
+    assert_eq!(titles("\"web server\""), vec!["Web Server Setup"]);
+    assert_eq!(titles("(\"web server\" OR racks)"), vec!["Server Racks", "Web Server Setup"]);
+}
@@ -0,0 +1,58 @@
+use crate::indexers::*;
+use crate::indexers::archive::EntryFilter;
+use crate::indexers::records::{DocumentSource, RecordWriter, read_documents};
+
+// The files under a directory, like the entries of an archive. Hidden files and directories are
+// left out, and so are symlinks, which could lead outside the tree or around in circles.
+struct DirectorySource<'a> {
+    root: &'a str,
+    filter: EntryFilter<'a>,
+    done: bool
+}
+
+impl DocumentSource for DirectorySource<'_> {
+    // Every file in one batch, depth first with each directory's entries sorted, so the same tree
+    // always gives the same ids
+    fn next_batch(&mut self, contents: &mut String) -> Result<Option<Vec<DocumentRaw>>, io::Error> {
+        if std::mem::replace(&mut self.done, true) {
+            return Ok(None);
+        }
+        let mut records = RecordWriter::after(std::mem::take(contents));
+        let mut pending: Vec<PathBuf> = vec![PathBuf::from(self.root)];
+        while let Some(dir) = pending.pop() {
+            let mut entries: Vec<PathBuf> = match fs::read_dir(&dir).and_then(|entries| entries.map(|entry| entry.map(|entry| entry.path())).collect()) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    self.filter.skip_failed(&dir.to_string_lossy(), e)?;
+                    continue;
+                }
+            };
+            entries.sort();
+            let mut subdirs: Vec<PathBuf> = Vec::new();
+            for path in entries {
+                if path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.')) {
+                    continue;
+                }
+                let name = path.to_string_lossy().into_owned();
+                match fs::symlink_metadata(&path) {
+                    Ok(metadata) if metadata.is_dir() => subdirs.push(path),
+                    Ok(metadata) if metadata.is_file() => match fs::read(&path) {
+                        Ok(file_contents) => self.filter.push_entry(&mut records, &name, &file_contents),
+                        Err(e) => self.filter.skip_failed(&name, e)?
+                    },
+                    Ok(_) => {},
+                    Err(e) => self.filter.skip_failed(&name, e)?
+                }
+            }
+            pending.extend(subdirs.into_iter().rev());
+        }
+        self.filter.report(self.root);
+        Ok(Some(records.finish(contents)))
+    }
+}
+
+// Every text file under `root`, at any depth, becomes one document titled with its file name, with
+// its path as the url. Binary files and unreadable ones are handled as in read_archive_records.
+pub fn read_directory_records(root: &str, force_extensions: &[String], keep_going: bool) -> Result<String, io::Error> {
+    read_documents(&mut DirectorySource { root, filter: EntryFilter::new(force_extensions, keep_going), done: false })
+}
@@ -0,0 +1,228 @@
+use crate::indexers::*;
+use std::collections::BTreeMap;
+use std::sync::atomic::AtomicBool;
+
+// Free space is looked at again after every this much written
+const CHUNK_BYTES: usize = 64 << 20;
+const BUFFER_BYTES: usize = 1 << 20;
+// Left free for everything else on the disk, a cache write fails rather than go below it
+pub(super) const RESERVE_BYTES: u64 = 256 << 20;
+
+// Whether cache writes wait for the OS to put files on disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    // Leave it to the OS. After a crash the checksums catch a torn cache and it's rebuilt.
+    #[default]
+    Never,
+    // fsync each file before it's renamed into place
+    Files,
+    // Also fsync the directory after the renames, so they survive a crash too
+    All
+}
+
+impl SyncPolicy {
+    pub fn parse(name: &str) -> Option<SyncPolicy> {
+        match name {
+            "never" => Some(SyncPolicy::Never),
+            "files" => Some(SyncPolicy::Files),
+            "all" => Some(SyncPolicy::All),
+            _ => None
+        }
+    }
+}
+
+static WRITES_FORBIDDEN: AtomicBool = AtomicBool::new(false);
+
+// From here on this process writes no cache or temp files: cache writes fail before touching the
+// disk and builds keep spilled segments in memory instead. For sources on read-only mounts or shares
+// that mustn't be written next to. There's no undoing it.
+pub fn forbid_writes() {
+    WRITES_FORBIDDEN.store(true, Ordering::SeqCst);
+}
+
+pub(super) fn writes_forbidden() -> bool {
+    WRITES_FORBIDDEN.load(Ordering::SeqCst)
+}
+
+pub(super) fn check_writable(path: &Path) -> Result<(), io::Error> {
+    match writes_forbidden() {
+        true => Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("read-only mode, not writing {:?}", path))),
+        false => Ok(())
+    }
+}
+
+// Space an unprivileged process can still use on the filesystem holding `path`, None where that
+// can't be found out
+#[cfg(unix)]
+pub(super) fn available_bytes(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let dir = std::ffi::CString::new(parent_dir(path).as_os_str().as_bytes()).ok()?;
+    unsafe {
+        let mut stat: libc::statvfs = std::mem::zeroed();
+        if libc::statvfs(dir.as_ptr(), &mut stat) != 0 {
+            return None;
+        }
+        Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+}
+
+#[cfg(not(unix))]
+pub(super) fn available_bytes(_path: &Path) -> Option<u64> {
+    None
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new(".")
+    }
+}
+
+pub(super) fn out_of_space(path: &Path, needed: u64, available: u64) -> io::Error {
+    io::Error::new(io::ErrorKind::StorageFull, format!("{:?} needs {} MB but only {} MB are free, keeping {} MB in reserve",
+                                                      path, needed >> 20, available >> 20, RESERVE_BYTES >> 20))
+}
+
+// One file of the cache, written straight from the index by `write` rather than from a copy of it.
+// `size` is exact, for the free space check before anything is written.
+pub(super) struct CacheFile<'a> {
+    pub(super) size: u64,
+    pub(super) write: WriteFn<'a>
+}
+
+pub(super) type WriteFn<'a> = Box<dyn Fn(&mut dyn Write) -> Result<bool, io::Error> + 'a>;
+
+// Counts what would be written, to size a CacheFile without holding it in memory
+struct Counter(u64);
+
+impl Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> CacheFile<'a> {
+    pub(super) fn bytes(data: Vec<u8>) -> Self {
+        CacheFile { size: data.len() as u64, write: Box::new(move |w| w.write_all(&data).map(|_| true)) }
+    }
+
+    // `write` is one of the DocumentIndexer::write_* hooks, run once here into nothing for the size.
+    // None when it writes nothing, for files a backend or schema doesn't have.
+    pub(super) fn stream(write: WriteFn<'a>) -> Result<Option<Self>, io::Error> {
+        let mut counter = Counter(0);
+        Ok(match write(&mut counter)? {
+            true => Some(CacheFile { size: counter.0, write }),
+            false => None
+        })
+    }
+}
+
+// bincode into a cache writer, keeping the writer's own errors, like running out of space, as they are
+pub(super) fn serialize_into<T: Serialize + ?Sized>(w: &mut dyn Write, value: &T) -> Result<(), io::Error> {
+    bincode::serialize_into(w, value).map_err(|e| match *e {
+        bincode::ErrorKind::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+    })
+}
+
+// Checksums the bytes going through to the file, and fails before writing on once the disk is down
+// to RESERVE_BYTES. Something else filling the disk mid-write is caught at the next check, or by the
+// write itself failing.
+struct CheckedWriter<'p> {
+    file: File,
+    path: &'p Path,
+    crc: flate2::Crc,
+    unchecked: usize
+}
+
+impl Write for CheckedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.unchecked >= CHUNK_BYTES {
+            if let Some(available) = available_bytes(self.path) {
+                if available < CHUNK_BYTES as u64 + RESERVE_BYTES {
+                    return Err(out_of_space(self.path, CHUNK_BYTES as u64, available));
+                }
+            }
+            self.unchecked = 0;
+        }
+        let written = self.file.write(buf)?;
+        self.crc.update(&buf[..written]);
+        self.unchecked += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// Writes `cache_file` to a new file at `path`, returning its CRC-32
+pub(super) fn write_checked(path: &Path, cache_file: &CacheFile, sync: SyncPolicy) -> Result<u32, io::Error> {
+    let checked = CheckedWriter { file: File::create(path)?, path, crc: flate2::Crc::new(), unchecked: 0 };
+    let mut buffered = io::BufWriter::with_capacity(BUFFER_BYTES, checked);
+    (cache_file.write)(&mut buffered)?;
+    let checked = buffered.into_inner().map_err(|e| e.into_error())?;
+    if sync != SyncPolicy::Never {
+        checked.file.sync_all()?;
+    }
+    Ok(checked.crc.sum())
+}
+
+#[cfg(unix)]
+pub(super) fn sync_dir(path: &Path) -> Result<(), io::Error> {
+    File::open(parent_dir(path))?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub(super) fn sync_dir(_path: &Path) -> Result<(), io::Error> {
+    Ok(())
+}
+
+pub(super) fn checksum(data: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+// The .sum file next to the cache, one "extension crc32" line per cache file. Caches from before it
+// existed have none and aren't checked.
+pub(super) type Manifest = BTreeMap<String, u32>;
+
+pub(super) fn read_manifest(base_path: &Path) -> Result<Option<Manifest>, io::Error> {
+    let text = match fs::read_to_string(base_path.with_extension("sum")) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e)
+    };
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("malformed checksums in {:?}", base_path.with_extension("sum")));
+    text.lines().map(|line| {
+        let (extension, crc) = line.split_once(' ').ok_or_else(invalid)?;
+        let crc = u32::from_str_radix(crc, 16).map_err(|_| invalid())?;
+        Ok((String::from(extension), crc))
+    }).collect::<Result<Manifest, io::Error>>().map(Some)
+}
+
+pub(super) fn manifest_file<'a>(manifest: &Manifest) -> CacheFile<'a> {
+    CacheFile::bytes(manifest.iter().map(|(extension, crc)| format!("{} {:08x}\n", extension, crc)).collect::<String>().into_bytes())
+}
+
+// Every file the manifest lists must be there with a matching checksum, and no file it doesn't list
+// may be, since that would be left from another write
+pub(super) fn verify(manifest: &Manifest, base_path: &Path, files: &[(&str, Option<&[u8]>)]) -> Result<(), io::Error> {
+    for (extension, data) in files {
+        let ok = match (manifest.get(*extension), data) {
+            (Some(crc), Some(data)) => checksum(data) == *crc,
+            (None, None) => true,
+            _ => false
+        };
+        if !ok {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?} doesn't match its checksum", base_path.with_extension(extension))));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,210 @@
+use crate::indexers::*;
+
+// The cache files are plain bincode (fixed width little endian integers, u64 lengths), walked here by
+// hand so the dump shows byte offsets and still works on files the current structs can't load
+const IDX_LAYOUT: &str = "\"FTDELTA1\", u64 term count, then per term: u64 length + utf-8 term, u64 length + document ids as \
+                          delta varints (no magic: u64 posting count + i32 ids, from before migrate)";
+const DCM_LAYOUT: &str = "u64 document count, then per document: title, url, text, keywords as u64 start/end pairs, i32 id, u8 oversized";
+const POS_LAYOUT: &str = "\"FTPOSTF1\", u8 positions + u8 offsets + u8 frequencies (the schema), u64 term count, then per term: \
+                          u64 length + utf-8 term, u64 document count, then per document: i32 id, u64 count + u32 positions, \
+                          u64 count + u32 start/end pairs, u32 frequency (no magic: no frequencies, from before migrate)";
+
+type DumpFn = fn(&[u8], bool) -> Result<(), io::Error>;
+
+pub(super) struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Cursor<'a> {
+    pub(super) fn new(data: &'a [u8]) -> Self {
+        Cursor { data, pos: 0 }
+    }
+
+    pub(super) fn take(&mut self, len: usize) -> Result<&'a [u8], io::Error> {
+        if self.data.len() - self.pos < len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("wanted {} bytes at offset {}, file has {}", len, self.pos, self.data.len())));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, io::Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, io::Error> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn i32(&mut self) -> Result<i32, io::Error> {
+        Ok(self.u32()? as i32)
+    }
+
+    pub(super) fn u64(&mut self) -> Result<u64, io::Error> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    // A u64 length checked against what is left, so a corrupt length fails instead of allocating
+    pub(super) fn len(&mut self, item_size: usize) -> Result<usize, io::Error> {
+        let at = self.pos;
+        let len = self.u64()?;
+        if len.saturating_mul(item_size as u64) > (self.data.len() - self.pos) as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("length {} at offset {} runs past the end of the file", len, at)));
+        }
+        Ok(len as usize)
+    }
+
+    pub(super) fn string(&mut self) -> Result<String, io::Error> {
+        let len = self.len(1)?;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+
+    fn finish(&self, name: &str) {
+        match self.data.len() - self.pos {
+            0 => println!("  {} ends at offset {}", name, self.pos),
+            trailing => println!("  {} has {} trailing bytes after offset {}", name, trailing, self.pos)
+        }
+    }
+}
+
+fn id_span(ids: &[i32]) -> String {
+    match (ids.iter().min(), ids.iter().max()) {
+        (Some(min), Some(max)) => format!("ids {}..={}", min, max),
+        _ => String::from("no ids")
+    }
+}
+
+fn dump_idx(data: &[u8], raw: bool) -> Result<(), io::Error> {
+    let delta_encoded = postings::is_delta_encoded(data);
+    let mut cursor = Cursor::new(data);
+    if delta_encoded {
+        cursor.take(postings::IDX_MAGIC.len())?;
+    } else {
+        println!("  raw i32 ids, migrate rewrites them as delta varints");
+    }
+    let num_terms = cursor.len(16)?;
+    let mut num_postings = 0;
+    for _ in 0..num_terms {
+        let at = cursor.pos;
+        let term = cursor.string()?;
+        let ids = match delta_encoded {
+            true => {
+                let len = cursor.len(1)?;
+                let mut ids: Vec<i32> = Vec::new();
+                postings::decode(cursor.take(len)?, &mut ids)?;
+                ids
+            },
+            false => {
+                let len = cursor.len(4)?;
+                (0..len).map(|_| cursor.i32()).collect::<Result<Vec<i32>, io::Error>>()?
+            }
+        };
+        let len = ids.len();
+        if raw {
+            println!("  @{} term {:?}: {} postings, {} bytes, {}", at, term, len, cursor.pos - at, id_span(&ids));
+        }
+        num_postings += len;
+    }
+    println!("  terms: {}, postings: {}", num_terms, num_postings);
+    cursor.finish("idx");
+    Ok(())
+}
+
+fn dump_dcm(data: &[u8], raw: bool) -> Result<(), io::Error> {
+    let mut cursor = Cursor::new(data);
+    let num_docs = cursor.len(69)?;
+    let mut num_oversized = 0;
+    for _ in 0..num_docs {
+        let at = cursor.pos;
+        let mut ranges = [(0, 0); 4];
+        for range in ranges.iter_mut() {
+            *range = (cursor.u64()?, cursor.u64()?);
+        }
+        let id = cursor.i32()?;
+        let oversized = cursor.u8()? != 0;
+        if raw {
+            let [title, url, text, keywords] = ranges;
+            println!("  @{} doc {}: title {:?}, url {:?}, text {:?}, keywords {:?}{}", at, id, title, url, text, keywords,
+                if oversized { ", oversized" } else { "" });
+        }
+        num_oversized += oversized as usize;
+    }
+    println!("  documents: {}, oversized: {}", num_docs, num_oversized);
+    cursor.finish("dcm");
+    Ok(())
+}
+
+fn dump_pos(data: &[u8], raw: bool) -> Result<(), io::Error> {
+    let mut cursor = Cursor::new(data);
+    let current = positions::is_current(data);
+    if current {
+        cursor.take(positions::POS_MAGIC.len())?;
+    }
+    let positions = cursor.u8()? != 0;
+    let offsets = cursor.u8()? != 0;
+    let frequencies = current && cursor.u8()? != 0;
+    println!("  schema: positions {}, offsets {}, frequencies {}", positions, offsets, frequencies);
+    let num_terms = cursor.len(16)?;
+    let mut num_entries = 0;
+    for _ in 0..num_terms {
+        let at = cursor.pos;
+        let term = cursor.string()?;
+        let num_docs = cursor.len(20)?;
+        let mut ids: Vec<i32> = Vec::with_capacity(num_docs);
+        let mut num_positions = 0;
+        let mut num_offsets = 0;
+        let mut occurrences = 0;
+        for _ in 0..num_docs {
+            ids.push(cursor.i32()?);
+            let len = cursor.len(4)?;
+            cursor.take(len * 4)?;
+            num_positions += len;
+            let len = cursor.len(8)?;
+            cursor.take(len * 8)?;
+            num_offsets += len;
+            if current {
+                occurrences += cursor.u32()? as usize;
+            }
+        }
+        if raw {
+            println!("  @{} term {:?}: {} documents, {} positions, {} offsets, {} occurrences, {} bytes, {}", at, term, num_docs, num_positions, num_offsets,
+                occurrences, cursor.pos - at, id_span(&ids));
+        }
+        num_entries += num_docs;
+    }
+    println!("  terms: {}, term/document pairs: {}", num_terms, num_entries);
+    cursor.finish("pos");
+    Ok(())
+}
+
+// Prints the structure of the cache files next to `file_to_index_path`, with `raw` one line per
+// term or document giving its byte offset
+pub fn dump_index(file_to_index_path: &str, raw: bool) -> Result<(), io::Error> {
+    let base_path = Path::new(file_to_index_path);
+    let files: [(&str, &str, DumpFn); 3] = [
+        ("idx", IDX_LAYOUT, dump_idx),
+        ("dcm", DCM_LAYOUT, dump_dcm),
+        ("pos", POS_LAYOUT, dump_pos)
+    ];
+    for (extension, layout, dump) in files.iter() {
+        let path = base_path.with_extension(extension);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if *extension == "pos" && e.kind() == io::ErrorKind::NotFound => {
+                println!("{:?}: not present, index has no positions or offsets", path);
+                continue;
+            },
+            Err(e) => return Err(e)
+        };
+        println!("{:?}: {} bytes", path, data.len());
+        println!("  layout: {}", layout);
+        dump(&data, raw)?;
+    }
+    Ok(())
+}
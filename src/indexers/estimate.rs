@@ -0,0 +1,226 @@
+use crate::indexers::*;
+use std::time;
+
+// Rough in-memory costs of the build's structures on 64-bit targets: a term's String, its slot in the
+// index map and its (empty) posting set, one id in a posting set including hash table slack, one
+// DocumentRaw, and one term/document entry in the position index
+const TERM_BYTES: usize = 24 + 16 + 48;
+const POSTING_BYTES: usize = 9;
+const DOCUMENT_BYTES: usize = 72;
+const POSITIONED_BYTES: usize = 64;
+
+// What a build over some source would produce, counted without building it, see RayonIndexer::dry_run
+pub struct BuildEstimate {
+    pub source_bytes: usize,
+    pub documents: usize,
+    pub oversized: usize,
+    // Analyzed tokens, stopwords excluded
+    pub tokens: usize,
+    // Distinct term/document pairs, one posting each
+    pub postings: usize,
+    pub terms: usize,
+    // Total length of the distinct terms
+    pub term_bytes: usize
+}
+
+fn mb(bytes: usize) -> String {
+    format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+impl BuildEstimate {
+    // The cache files are bincode with u64 lengths, see the layouts in dump.rs. The .idx ids are
+    // varints of the gaps between them, see postings.rs, counted as if every term had the average
+    // number of postings spread evenly over the documents.
+    pub fn idx_bytes(&self) -> usize {
+        let gap = (self.documents * self.terms).checked_div(self.postings).unwrap_or(0);
+        let posting_bytes = postings::varint_len(gap.min(u32::MAX as usize) as u32);
+        postings::IDX_MAGIC.len() + 8 + self.terms * 16 + self.term_bytes + self.postings * posting_bytes
+    }
+
+    pub fn dcm_bytes(&self) -> usize {
+        8 + self.documents * 69
+    }
+
+    // Positions and offsets are counted as if every token was in the text, keyword terms have none
+    pub fn pos_bytes(&self, schema: Schema) -> usize {
+        if !schema.has_entries() {
+            return 0;
+        }
+        let per_token = if schema.positions { 4 } else { 0 } + if schema.offsets { 8 } else { 0 };
+        8 + 3 + 8 + self.terms * 16 + self.term_bytes + self.postings * 24 + self.tokens * per_token
+    }
+
+    // The source stays in memory next to the index for building result documents
+    pub fn memory_bytes(&self, schema: Schema) -> usize {
+        let mut index = self.terms * TERM_BYTES + self.term_bytes + self.postings * POSTING_BYTES + self.documents * DOCUMENT_BYTES;
+        if schema.has_entries() {
+            index += self.postings * POSITIONED_BYTES + self.pos_bytes(schema);
+        }
+        self.source_bytes + index
+    }
+
+    pub fn print(&self, schema: Schema) {
+        println!("Documents: {} ({} over max doc bytes), tokens: {}, term/document pairs: {}, distinct terms: {}",
+            self.documents, self.oversized, self.tokens, self.postings, self.terms);
+        print!("Projected cache size: idx {}, dcm {}", mb(self.idx_bytes()), mb(self.dcm_bytes()));
+        if schema.has_entries() {
+            print!(", pos {}", mb(self.pos_bytes(schema)));
+        }
+        println!();
+        println!("Projected memory: about {} ({} of source text)", mb(self.memory_bytes(schema)), mb(self.source_bytes));
+    }
+}
+
+// Two-sided 95% confidence
+const Z: f64 = 1.96;
+
+// Estimates draw with the same seed every run so they're repeatable
+pub(super) const SAMPLE_SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+// Picks `size` distinct documents uniformly at random, in id order. The same seed picks the same ones.
+pub(super) fn draw_sample(documents: &[DocumentRaw], size: usize, seed: u64) -> Vec<DocumentRaw> {
+    // xorshift never leaves 0
+    let mut rng: u64 = seed | 1;
+    let mut picks: Vec<usize> = (0..documents.len()).collect();
+    let size = cmp::min(size, picks.len());
+    for i in 0..size {
+        rng ^= rng << 13;
+        rng ^= rng >> 7;
+        rng ^= rng << 17;
+        let j = i + (rng % (picks.len() - i) as u64) as usize;
+        picks.swap(i, j);
+    }
+    picks.truncate(size);
+    picks.sort_unstable();
+    picks.into_iter().map(|i| documents[i].clone()).collect()
+}
+
+// What RayonIndexer::estimate_from_sample measured: exact counts over the whole source, per document
+// counts over the sample, and real builds over parts of it
+pub struct Sample {
+    pub source_bytes: usize,
+    pub documents: usize,
+    pub oversized: usize,
+    // Per sampled document: tokens, and distinct terms (postings)
+    pub tokens: Vec<usize>,
+    pub postings: Vec<usize>,
+    pub terms: usize,
+    // Terms in exactly one sampled document
+    pub singletons: usize,
+    pub term_bytes: usize,
+    pub parse_time: time::Duration,
+    // (tokens, build time) of builds over growing shares of the sample
+    pub builds: Vec<(usize, time::Duration)>
+}
+
+// The projected build as (low, expected, high). Token and posting bounds are 95% confidence
+// intervals, term bounds are those of distinct_terms, build time bounds only follow the tokens.
+pub struct SampleEstimate {
+    pub sampled: usize,
+    pub low: BuildEstimate,
+    pub expected: BuildEstimate,
+    pub high: BuildEstimate,
+    pub build_time: (time::Duration, time::Duration, time::Duration)
+}
+
+// Expected total of `values` over a population of `population` values they were sampled from, with
+// the confidence interval of the sample mean (finite population corrected)
+fn total_interval(values: &[usize], population: usize) -> (f64, f64, f64) {
+    let n = values.len() as f64;
+    let population = population as f64;
+    if values.is_empty() {
+        return (0.0, 0.0, 0.0);
+    }
+    let mean = values.iter().sum::<usize>() as f64 / n;
+    let variance = if values.len() > 1 {
+        values.iter().map(|&v| (v as f64 - mean).powi(2)).sum::<f64>() / (n - 1.0)
+    } else {
+        0.0
+    };
+    let margin = Z * population * (variance / n * (1.0 - n / population)).sqrt();
+    let total = mean * population;
+    let sampled = values.iter().sum::<usize>() as f64;
+    ((total - margin).max(sampled), total, total + margin)
+}
+
+// The guaranteed-error estimator for the number of distinct values from a sample (Charikar et al.):
+// a term seen in several sampled documents is likely to be one of few, while one seen in a single
+// document may stand for up to 1 / fraction terms that were missed. Returns the terms seen, the
+// estimate, and the terms if every singleton stood for that many, which bound the estimate.
+fn distinct_terms(terms: usize, singletons: usize, fraction: f64) -> (f64, f64, f64) {
+    let repeated = (terms - singletons) as f64;
+    let singletons = singletons as f64;
+    (terms as f64, repeated + singletons / fraction.sqrt(), repeated + singletons / fraction)
+}
+
+// Fits build time = c * tokens^exponent in log space over builds of growing size. Builds get
+// cheaper per token as they grow, the tables having fewer new terms to insert per token, so
+// scaling the largest build by tokens alone overestimates. The exponent is kept within 0.5 to 1.
+fn fit_build_time(builds: &[(usize, time::Duration)]) -> Option<(f64, f64)> {
+    let points: Vec<(f64, f64)> = builds.iter()
+        .filter(|(tokens, time)| *tokens > 0 && time.as_secs_f64() > 0.0)
+        .map(|(tokens, time)| ((*tokens as f64).ln(), time.as_secs_f64().ln()))
+        .collect();
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let sxx: f64 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+    if points.len() < 2 || sxx <= 0.0 {
+        return None;
+    }
+    let exponent = (points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum::<f64>() / sxx).clamp(0.5, 1.0);
+    Some(((mean_y - exponent * mean_x).exp(), exponent))
+}
+
+impl Sample {
+    // Tokens and postings are sums over documents, so their totals are scaled up sample means. The
+    // vocabulary doesn't scale with the sample, see distinct_terms. Build time is parsing the whole
+    // source, which was timed as is, plus the fitted build time at the estimated tokens.
+    pub fn extrapolate(&self) -> SampleEstimate {
+        let (tokens_low, tokens, tokens_high) = total_interval(&self.tokens, self.documents);
+        let (postings_low, postings, postings_high) = total_interval(&self.postings, self.documents);
+        let (terms_low, terms, terms_high) = distinct_terms(self.terms, self.singletons, self.tokens.len() as f64 / self.documents as f64);
+        let mean_term_bytes = if self.terms > 0 { self.term_bytes as f64 / self.terms as f64 } else { 0.0 };
+        let estimate = |tokens: f64, postings: f64, terms: f64| {
+            // Never fewer terms than the sample had, nor more than there are postings
+            let terms = terms.max(self.terms as f64).min(postings.max(self.terms as f64));
+            BuildEstimate {
+                source_bytes: self.source_bytes,
+                documents: self.documents,
+                oversized: self.oversized,
+                tokens: tokens.round() as usize,
+                postings: postings.round() as usize,
+                terms: terms.round() as usize,
+                term_bytes: (terms * mean_term_bytes).round() as usize
+            }
+        };
+        let (scale, exponent) = fit_build_time(&self.builds).unwrap_or((0.0, 1.0));
+        let build_time = |tokens: f64| self.parse_time + time::Duration::from_secs_f64(scale * tokens.powf(exponent));
+        SampleEstimate {
+            sampled: self.tokens.len(),
+            low: estimate(tokens_low, postings_low, terms_low),
+            expected: estimate(tokens, postings, terms),
+            high: estimate(tokens_high, postings_high, terms_high),
+            build_time: (build_time(tokens_low), build_time(tokens), build_time(tokens_high))
+        }
+    }
+}
+
+impl SampleEstimate {
+    pub fn print(&self, schema: Schema) {
+        let (low, expected, high) = (&self.low, &self.expected, &self.high);
+        println!("Sampled {} of {} documents ({} over max doc bytes in all), bounds in brackets",
+            self.sampled, expected.documents, expected.oversized);
+        println!("Tokens: {} [{} - {}]", expected.tokens, low.tokens, high.tokens);
+        println!("Term/document pairs: {} [{} - {}]", expected.postings, low.postings, high.postings);
+        println!("Distinct terms: {} [{} - {}]", expected.terms, low.terms, high.terms);
+        println!("Index cache size: {} [{} - {}]", mb(expected.idx_bytes()), mb(low.idx_bytes()), mb(high.idx_bytes()));
+        println!("Documents cache size: {}", mb(expected.dcm_bytes()));
+        if schema.has_entries() {
+            println!("Positions cache size: {} [{} - {}]", mb(expected.pos_bytes(schema)), mb(low.pos_bytes(schema)), mb(high.pos_bytes(schema)));
+        }
+        println!("Memory: {} [{} - {}]", mb(expected.memory_bytes(schema)), mb(low.memory_bytes(schema)), mb(high.memory_bytes(schema)));
+        let (time_low, time, time_high) = self.build_time;
+        println!("Build time: {} ms [{} - {}]", time.as_millis(), time_low.as_millis(), time_high.as_millis());
+    }
+}
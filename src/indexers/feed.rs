@@ -0,0 +1,71 @@
+use crate::indexers::*;
+use std::sync::atomic;
+
+pub fn fetch_feed(url: &str) -> Result<String, io::Error> {
+    ureq::get(url).call()
+        .map_err(|e| io::Error::other(e.to_string()))?
+        .into_string()
+}
+
+// Handles both RSS (<item> with <link>text</link>) and Atom (<entry> with <link href=".."/>)
+pub(super) fn parse_feed(contents: &ContentsSplit, cur_id: &atomic::AtomicI32) -> Vec<DocumentRaw> {
+    let base_offset = contents.base_offset;
+    let mut cur_doc = DocumentRaw::default();
+    let mut cur_tag: &str = "";
+    let mut in_entry = false;
+    let mut link_rel_ok = true;
+    let mut docs: Vec<DocumentRaw> = Vec::new();
+    // Only the first chunk can carry the <?xml ?> declaration, which fragment mode rejects
+    let tokenizer = match base_offset {
+        0 => xmlparser::Tokenizer::from(contents.data),
+        _ => xmlparser::Tokenizer::from_fragment(contents.data, 0..contents.data.len())
+    };
+    for token in tokenizer {
+        match token {
+            Ok(xmlparser::Token::ElementStart{local, ..}) => {
+                cur_tag = local.as_str();
+                if cur_tag == "item" || cur_tag == "entry" {
+                    in_entry = true;
+                    cur_doc = DocumentRaw::default();
+                }
+                link_rel_ok = true;
+            },
+            Ok(xmlparser::Token::Attribute{local, value, ..}) if in_entry && cur_tag == "link" => {
+                match local.as_str() {
+                    "rel" => link_rel_ok = value.as_str() == "alternate",
+                    "href" if link_rel_ok && cur_doc.url.is_empty() => {
+                        cur_doc.url = Range{start: base_offset + value.range().start, end: base_offset + value.range().end};
+                    },
+                    _ => {}
+                }
+            },
+            Ok(xmlparser::Token::Text{text}) | Ok(xmlparser::Token::Cdata{text, ..}) if in_entry => {
+                if text.as_str().trim().is_empty() {
+                    continue;
+                }
+                let absolute_range = Range{start: base_offset + text.range().start, end: base_offset + text.range().end};
+                match cur_tag {
+                    "title" => cur_doc.title = absolute_range,
+                    "link" => cur_doc.url = absolute_range,
+                    "description" | "summary" => cur_doc.text = absolute_range,
+                    "content" if cur_doc.text.is_empty() => cur_doc.text = absolute_range,
+                    _ => {}
+                }
+            },
+            Ok(xmlparser::Token::ElementEnd{end, ..}) => {
+                if let xmlparser::ElementEnd::Close(_, n) = end {
+                    cur_tag = "";
+                    if in_entry && (n.as_str() == "item" || n.as_str() == "entry") {
+                        in_entry = false;
+                        cur_doc.id = cur_id.fetch_add(1, atomic::Ordering::SeqCst);
+                        docs.push(cur_doc);
+                        cur_doc = DocumentRaw::default();
+                    }
+                }
+            },
+            Ok(_) => {},
+            Err(e) => { println!("Error parsing feed: {}", e); }
+        }
+    }
+    docs
+}
@@ -0,0 +1,209 @@
+use crate::indexers::*;
+use std::borrow::Cow;
+use std::sync::RwLock;
+
+// One step of text mode analysis. Each word, once split off and with its number separators dropped,
+// goes through an analyzer's filters in order as the tokens it has become so far, starting as the
+// word alone. Code mode analyzes identifiers its own way and has no filters.
+pub trait TokenFilter: Send + Sync {
+    // Rewrites the tokens of one word: change them in place, remove them all to drop the word, or
+    // push more, which are indexed at the word's position
+    fn filter(&self, tokens: &mut Vec<String>);
+}
+
+// What a filter is made for: the analyzer's language, one of LANGUAGES, and the text after the ':'
+// when the filter was named "name:argument"
+pub struct FilterArgs<'a> {
+    pub language: &'a str,
+    pub argument: Option<&'a str>
+}
+
+// A filter with the name it was registered under
+pub type NamedFilter = (String, Arc<dyn TokenFilter>);
+
+type FilterFactory = Arc<dyn Fn(&FilterArgs) -> Result<Box<dyn TokenFilter>, String> + Send + Sync>;
+
+// An analyzer's filters when IndexOptions::token_filters is None
+pub const DEFAULT_TOKEN_FILTERS: [&str; 3] = ["lowercase", "stopword", "stemmer"];
+
+// Per character, so 'Σ' is 'σ' wherever it is, as the terms of existing caches have it
+struct Lowercase;
+
+impl TokenFilter for Lowercase {
+    fn filter(&self, tokens: &mut Vec<String>) {
+        for token in tokens.iter_mut() {
+            match token.is_ascii() {
+                true => token.make_ascii_lowercase(),
+                false => *token = token.chars().flat_map(char::to_lowercase).collect()
+            }
+        }
+    }
+}
+
+// Only English has a list, other languages keep every word
+struct Stopwords {
+    words: HashSet<&'static str>
+}
+
+impl Stopwords {
+    fn for_language(language: &str) -> Stopwords {
+        let words: &[&'static str] = match language {
+            "en" => &["a", "and", "be", "have", "i", "in", "of", "that", "the", "to"],
+            _ => &[]
+        };
+        Stopwords { words: words.iter().copied().collect() }
+    }
+}
+
+impl TokenFilter for Stopwords {
+    fn filter(&self, tokens: &mut Vec<String>) {
+        tokens.retain(|token| !self.words.contains(token.as_str()));
+    }
+}
+
+struct Stemmer {
+    stemmer: rust_stemmers::Stemmer,
+    // Another language's, whose stem of each token is pushed too where it differs, see
+    // stemmer_with_query_stems
+    query_stemmer: Option<rust_stemmers::Stemmer>
+}
+
+impl TokenFilter for Stemmer {
+    fn filter(&self, tokens: &mut Vec<String>) {
+        for i in 0..tokens.len() {
+            let query_stem = self.query_stemmer.as_ref().map(|stemmer| stemmer.stem(&tokens[i]).into_owned());
+            if let Cow::Owned(stemmed) = self.stemmer.stem(&tokens[i]) {
+                tokens[i] = stemmed;
+            }
+            if let Some(query_stem) = query_stem.filter(|stem| *stem != tokens[i]) {
+                tokens.push(query_stem);
+            }
+        }
+    }
+}
+
+// The stemmer of `language` that also pushes each token as `query_language`'s stemmer has it, so
+// documents analyzed with it are found by queries analyzed in `query_language`
+pub(super) fn stemmer_with_query_stems(language: &str, query_language: &str) -> Arc<dyn TokenFilter> {
+    Arc::new(Stemmer {
+        stemmer: rust_stemmers::Stemmer::create(language_algorithm(language)),
+        query_stemmer: Some(rust_stemmers::Stemmer::create(language_algorithm(query_language)))
+    })
+}
+
+// Replaces each token with its character n-grams of `min` to `max` characters, so a search finds
+// words by any part of them. Tokens shorter than `min` are kept whole.
+struct Ngrams {
+    min: usize,
+    max: usize
+}
+
+impl Ngrams {
+    // "3" for trigrams, "2-4" for 2 to 4 characters
+    fn parse(argument: Option<&str>) -> Result<Ngrams, String> {
+        let argument = argument.ok_or("ngram needs its lengths, as in ngram:3 or ngram:2-4")?;
+        let (min, max) = argument.split_once('-').unwrap_or((argument, argument));
+        match (min.trim().parse::<usize>(), max.trim().parse::<usize>()) {
+            (Ok(min), Ok(max)) if min > 0 && min <= max => Ok(Ngrams { min, max }),
+            _ => Err(format!("bad ngram lengths '{}', expected N or MIN-MAX with 0 < MIN <= MAX", argument))
+        }
+    }
+}
+
+impl TokenFilter for Ngrams {
+    fn filter(&self, tokens: &mut Vec<String>) {
+        let mut grams: Vec<String> = Vec::with_capacity(tokens.len());
+        for token in tokens.drain(..) {
+            let chars: Vec<char> = token.chars().collect();
+            if chars.len() < self.min {
+                grams.push(token);
+                continue;
+            }
+            for length in self.min..=self.max.min(chars.len()) {
+                grams.extend(chars.windows(length).map(|gram| gram.iter().collect::<String>()));
+            }
+        }
+        *tokens = grams;
+    }
+}
+
+fn no_argument(name: &str, args: &FilterArgs) -> Result<(), String> {
+    match args.argument {
+        Some(argument) => Err(format!("{} takes no argument, got '{}'", name, argument)),
+        None => Ok(())
+    }
+}
+
+fn registry() -> &'static RwLock<HashMap<String, FilterFactory>> {
+    static REGISTRY: OnceLock<RwLock<HashMap<String, FilterFactory>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let mut filters: HashMap<String, FilterFactory> = HashMap::new();
+        filters.insert(String::from("lowercase"), Arc::new(|args: &FilterArgs| {
+            no_argument("lowercase", args).map(|_| Box::new(Lowercase) as Box<dyn TokenFilter>)
+        }));
+        filters.insert(String::from("stopword"), Arc::new(|args: &FilterArgs| {
+            no_argument("stopword", args).map(|_| Box::new(Stopwords::for_language(args.language)) as Box<dyn TokenFilter>)
+        }));
+        filters.insert(String::from("stemmer"), Arc::new(|args: &FilterArgs| {
+            no_argument("stemmer", args)?;
+            Ok(Box::new(Stemmer { stemmer: rust_stemmers::Stemmer::create(language_algorithm(args.language)), query_stemmer: None }) as Box<dyn TokenFilter>)
+        }));
+        filters.insert(String::from("ngram"), Arc::new(|args: &FilterArgs| {
+            Ngrams::parse(args.argument).map(|ngrams| Box::new(ngrams) as Box<dyn TokenFilter>)
+        }));
+        RwLock::new(filters)
+    })
+}
+
+// Makes `name` usable in IndexOptions::token_filters from then on, so a library user can put their
+// own filter in the pipeline without changing the crate. Registering a name again replaces its
+// factory, built in ones included. Analyzers made before keep the filters they were made with.
+pub fn register_token_filter<F>(name: &str, factory: F)
+    where F: Fn(&FilterArgs) -> Result<Box<dyn TokenFilter>, String> + Send + Sync + 'static {
+    registry().write().unwrap().insert(String::from(name), Arc::new(factory));
+}
+
+// The filters `names` refer to in order, each "name" or "name:argument", for an analyzer of
+// `language`. Fails on the first name that isn't registered or whose factory refuses its argument.
+pub fn token_filters(names: &[String], language: &str) -> Result<Vec<NamedFilter>, String> {
+    let registry = registry().read().unwrap();
+    names.iter().map(|name| {
+        let (filter, argument) = match name.split_once(':') {
+            Some((filter, argument)) => (filter.trim(), Some(argument.trim())),
+            None => (name.trim(), None)
+        };
+        let factory = registry.get(filter).ok_or_else(|| format!("unknown token filter '{}'", filter))?;
+        let made = factory(&FilterArgs { language, argument })?;
+        Ok((String::from(filter), Arc::from(made)))
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Reverse;
+
+    impl TokenFilter for Reverse {
+        fn filter(&self, tokens: &mut Vec<String>) {
+            for token in tokens.iter_mut() {
+                *token = token.chars().rev().collect();
+            }
+        }
+    }
+
+    #[test]
+    fn registered_filters_run_in_the_order_named() {
+        register_token_filter("test-reverse", |_: &FilterArgs| Ok(Box::new(Reverse) as Box<dyn TokenFilter>));
+        let names = |names: &[&str]| names.iter().map(|name| String::from(*name)).collect::<Vec<String>>();
+        let analyzer = Analyzer::new_english().with_token_filters(&names(&["lowercase", "test-reverse", "stopword"])).unwrap();
+        assert_eq!(analyzer.analyze("The Apples eht"), vec!["eht", "selppa"]);
+        let ngrams = Analyzer::new_english().with_token_filters(&names(&["lowercase", "ngram:2-3"])).unwrap();
+        assert_eq!(ngrams.analyze("Cat a"), vec!["ca", "at", "cat", "a"]);
+        assert_eq!(Analyzer::new_english().analyze("The Apples"), Analyzer::new_english().with_token_filters(&names(&DEFAULT_TOKEN_FILTERS)).unwrap().analyze("The Apples"));
+
+        assert_eq!(token_filters(&names(&["nope"]), "en").err().unwrap(), "unknown token filter 'nope'");
+        assert!(token_filters(&names(&["ngram:3-2"]), "en").is_err());
+        assert!(token_filters(&names(&["stemmer:x"]), "en").is_err());
+    }
+}
@@ -0,0 +1,48 @@
+use crate::indexers::*;
+use crate::indexers::records::{ColumnMapping, RecordWriter};
+use serde_json::Value;
+
+// Strings are taken as is, other values as their JSON text, missing fields and nulls are empty
+fn field_text(object: &serde_json::Map<String, Value>, name: &str) -> String {
+    match object.get(name) {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(other) => other.to_string()
+    }
+}
+
+fn field_vector(object: &serde_json::Map<String, Value>, name: &str, line: usize) -> Result<Option<Vec<f32>>, io::Error> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("line {}: '{}' must be an array of numbers", line, name));
+    match object.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::Array(values)) => values.iter()
+            .map(|v| v.as_f64().map(|x| x as f32).ok_or_else(invalid))
+            .collect::<Result<Vec<f32>, io::Error>>()
+            .map(Some),
+        Some(_) => Err(invalid())
+    }
+}
+
+// One JSON object per line, blank lines are skipped. The mapped vector field, when present, is
+// carried along for VectorIndex::from_records.
+pub fn read_jsonl_records(path: &str, columns: &ColumnMapping) -> Result<String, io::Error> {
+    let reader = io::BufReader::new(File::open(path)?);
+    let mut records = RecordWriter::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let object = match serde_json::from_str::<Value>(&line) {
+            Ok(Value::Object(object)) => object,
+            Ok(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("line {}: expected a JSON object", i + 1))),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", i + 1, e)))
+        };
+        let (title, url, text) = (field_text(&object, &columns.title), field_text(&object, &columns.url), field_text(&object, &columns.text));
+        match field_vector(&object, &columns.vector, i + 1)? {
+            Some(vector) => records.push_with_vector(&title, &url, &text, &vector),
+            None => records.push(&title, &url, &text)
+        }
+    }
+    Ok(records.into_contents())
+}
@@ -0,0 +1,178 @@
+use crate::indexers::*;
+use std::fmt;
+use std::sync::atomic::AtomicUsize;
+
+// How much one search may expand and read before it's refused with a QueryError, so a query like
+// "a*" or a long run of ORs over common terms can't take a long running process down. None is no
+// limit, the default.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QueryLimits {
+    // Indexed terms any one prefix query may stand for
+    pub max_expanded_terms: Option<usize>,
+    // Document ids read from the postings of the query's terms, a document counted once per term
+    pub max_candidates: Option<usize>,
+    // Bytes of the ids read and the matching documents fetched
+    pub max_memory: Option<usize>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueryError {
+    TooManyExpansions { prefix: String, limit: usize },
+    TooManyCandidates { limit: usize },
+    TooMuchMemory { limit: usize },
+    // The indexer's CancelToken was cancelled while it ran, see DocumentIndexer::set_cancel_token
+    Cancelled
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryError::TooManyExpansions { prefix, limit } => write!(f, "'{}*' matches more than {} terms", prefix, limit),
+            QueryError::TooManyCandidates { limit } => write!(f, "query matches more than {} documents across its terms", limit),
+            QueryError::TooMuchMemory { limit } => write!(f, "query needs more than {} bytes", limit),
+            QueryError::Cancelled => write!(f, "search cancelled")
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+// What one run_query has used so far. Once over a limit, or cancelled, no more postings are read or
+// documents fetched, and the query ends with the first limit it went over.
+pub(super) struct QueryBudget<'a> {
+    limits: &'a QueryLimits,
+    cancel: &'a CancelToken,
+    candidates: AtomicUsize,
+    bytes: AtomicUsize,
+    exceeded: OnceLock<QueryError>
+}
+
+impl QueryBudget<'_> {
+    pub(super) fn new<'a>(limits: &'a QueryLimits, cancel: &'a CancelToken) -> QueryBudget<'a> {
+        QueryBudget { limits, cancel, candidates: AtomicUsize::new(0), bytes: AtomicUsize::new(0), exceeded: OnceLock::new() }
+    }
+
+    fn exceed(&self, error: QueryError) {
+        let _ = self.exceeded.set(error);
+    }
+
+    pub(super) fn is_exceeded(&self) -> bool {
+        if self.cancel.is_cancelled() {
+            self.exceed(QueryError::Cancelled);
+        }
+        self.exceeded.get().is_some()
+    }
+
+    fn count_bytes(&self, bytes: usize) {
+        let used = self.bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if let Some(limit) = self.limits.max_memory.filter(|limit| used > *limit) {
+            self.exceed(QueryError::TooMuchMemory { limit });
+        }
+    }
+
+    // Whether `prefix` may stand for `terms` indexed terms
+    pub(super) fn expand(&self, prefix: &str, terms: usize) -> bool {
+        match self.limits.max_expanded_terms.filter(|limit| terms > *limit) {
+            Some(limit) => {
+                self.exceed(QueryError::TooManyExpansions { prefix: String::from(prefix), limit });
+                false
+            },
+            None => true
+        }
+    }
+
+    // Reads one term's postings onto `ids` through `read`, which returns whether the term is indexed
+    pub(super) fn read_postings(&self, ids: &mut Vec<i32>, read: impl FnOnce(&mut Vec<i32>) -> bool) -> bool {
+        if self.is_exceeded() {
+            return false;
+        }
+        let before = ids.len();
+        let found = read(ids);
+        let read = ids.len() - before;
+        self.count_candidates(read);
+        self.count_bytes(read * std::mem::size_of::<i32>());
+        found
+    }
+
+    fn count_candidates(&self, read: usize) {
+        let candidates = self.candidates.fetch_add(read, Ordering::Relaxed) + read;
+        if let Some(limit) = self.limits.max_candidates.filter(|limit| candidates > *limit) {
+            self.exceed(QueryError::TooManyCandidates { limit });
+        }
+    }
+
+    // Counts a term's postings used where they are, as the roaring backend's bitmaps are, rather than
+    // read onto a buffer: `ids` of them in `bytes`. False, with nothing counted, once over a limit.
+    pub(super) fn use_postings(&self, ids: usize, bytes: usize) -> bool {
+        if self.is_exceeded() {
+            return false;
+        }
+        self.count_candidates(ids);
+        self.count_bytes(bytes);
+        true
+    }
+
+    pub(super) fn fetch<D>(&self, id: i32, document: &D) -> Option<Document> where D: Fn(i32) -> Option<Document> {
+        if self.is_exceeded() {
+            return None;
+        }
+        let doc = document(id);
+        if let Some(doc) = &doc {
+            self.count_bytes(doc.title.len() + doc.url.len() + doc.text.len());
+        }
+        doc
+    }
+
+    pub(super) fn finish<T>(self, value: T) -> Result<T, QueryError> {
+        match self.exceeded.into_inner() {
+            Some(error) => Err(error),
+            None => Ok(value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_first_limit_gone_over_is_reported() {
+        let limits = QueryLimits { max_candidates: Some(3), max_memory: Some(8), ..QueryLimits::default() };
+        let cancel = CancelToken::new();
+        let budget = QueryBudget::new(&limits, &cancel);
+        let mut ids: Vec<i32> = Vec::new();
+        assert!(budget.read_postings(&mut ids, |ids| { ids.extend([1, 2]); true }));
+        assert!(budget.finish(()).is_ok());
+
+        let budget = QueryBudget::new(&limits, &cancel);
+        budget.read_postings(&mut ids, |ids| { ids.extend([1, 2, 3]); true });
+        // Nothing more is read once over
+        assert!(!budget.read_postings(&mut ids, |_| panic!("read past the limit")));
+        assert_eq!(budget.finish(()), Err(QueryError::TooMuchMemory { limit: 8 }));
+
+        let limits = QueryLimits { max_expanded_terms: Some(2), ..limits };
+        let budget = QueryBudget::new(&limits, &cancel);
+        assert!(budget.expand("ap", 2));
+        assert!(!budget.expand("a", 3));
+        assert_eq!(budget.finish(()), Err(QueryError::TooManyExpansions { prefix: String::from("a"), limit: 2 }));
+    }
+
+    #[test]
+    fn searches_over_a_limit_are_refused() {
+        let limits = QueryLimits { max_expanded_terms: Some(2), max_candidates: Some(4), max_memory: None };
+        let mut indexer = RayonIndexer::new(IndexOptions { format: Format::Lines, limits, ..IndexOptions::default() });
+        indexer.build_from_file_contents("fruit.log", String::from("apple pie\napricot jam\napple tart\navocado toast\napple juice\n")).unwrap();
+        assert_eq!(indexer.search(vec!["apple"]).unwrap().len(), 3);
+        assert!(indexer.search(vec!["apr*"]).is_ok());
+        assert_eq!(indexer.search(vec!["a*"]).err(), Some(QueryError::TooManyExpansions { prefix: String::from("a"), limit: 2 }));
+        assert_eq!(indexer.search(vec!["apple", "OR", "avocado", "OR", "apricot"]).err(), Some(QueryError::TooManyCandidates { limit: 4 }));
+        assert_eq!(indexer.search(vec!["apple", "avocado", "apricot"]).err(), Some(QueryError::TooManyCandidates { limit: 4 }));
+
+        let cancel = CancelToken::new();
+        indexer.set_cancel_token(cancel.clone());
+        cancel.cancel();
+        assert_eq!(indexer.search(vec!["apple"]).err(), Some(QueryError::Cancelled));
+        cancel.reset();
+        assert!(indexer.search(vec!["apple"]).is_ok());
+    }
+}
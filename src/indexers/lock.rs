@@ -0,0 +1,106 @@
+use crate::indexers::*;
+
+// The .lock file next to a cache, locked (flock on unix) by the one process that may write the cache
+// and spill files for it, with that process's pid in it. It's advisory: readers never look at it,
+// and only writers that take it are kept apart. The lock goes away with its process, so a file left
+// behind by a crash is simply locked by the next writer.
+pub(super) const LOCK_EXTENSION: &str = "lock";
+
+pub struct CacheLock {
+    // None when this process held the lock already, which then stays until that holder lets go
+    held: Option<(PathBuf, File)>
+}
+
+fn lock_path(base_path: &Path) -> PathBuf {
+    base_path.with_extension(LOCK_EXTENSION)
+}
+
+// Whether `path` is still the file `file` has open, rather than removed or replaced since
+#[cfg(unix)]
+fn is_open_at(file: &File, path: &Path) -> Result<bool, io::Error> {
+    use std::os::unix::fs::MetadataExt;
+    let (open, named) = match (file.metadata(), fs::metadata(path)) {
+        (Ok(open), Ok(named)) => (open, named),
+        (_, Err(e)) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        (Err(e), _) | (_, Err(e)) => return Err(e)
+    };
+    Ok((open.dev(), open.ino()) == (named.dev(), named.ino()))
+}
+
+// An open file can't be removed
+#[cfg(not(unix))]
+fn is_open_at(_file: &File, _path: &Path) -> Result<bool, io::Error> {
+    Ok(true)
+}
+
+impl CacheLock {
+    // Fails with ResourceBusy straight away, rather than waiting, while another process holds the lock
+    pub fn acquire(base_path: &Path) -> Result<CacheLock, io::Error> {
+        disk::check_writable(base_path)?;
+        let path = lock_path(base_path);
+        loop {
+            let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+            match file.try_lock() {
+                Ok(()) => {},
+                Err(fs::TryLockError::WouldBlock) => {
+                    let mut text = String::new();
+                    file.read_to_string(&mut text)?;
+                    return match text.trim().parse::<u32>().ok() {
+                        Some(pid) if pid == std::process::id() => Ok(CacheLock { held: None }),
+                        Some(pid) => Err(io::Error::new(io::ErrorKind::ResourceBusy, format!("{:?} is being written by process {}", base_path, pid))),
+                        // Its holder is still filling it in
+                        None => Err(io::Error::new(io::ErrorKind::ResourceBusy, format!("{:?} is being written by another process", base_path)))
+                    };
+                },
+                Err(fs::TryLockError::Error(e)) => return Err(e)
+            }
+            // Its holder removed it on letting go after we opened it, and the next writer may have
+            // locked a new one at the path already
+            if !is_open_at(&file, &path)? {
+                continue;
+            }
+            file.set_len(0)?;
+            writeln!(file, "{}", std::process::id())?;
+            return Ok(CacheLock { held: Some((path, file)) });
+        }
+    }
+}
+
+impl Drop for CacheLock {
+    // Removed while still locked, and unlocked as the file closes
+    fn drop(&mut self) {
+        if let Some((path, _)) = &self.held {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::TempSource;
+
+    #[test]
+    fn a_second_writer_is_refused_until_the_first_is_done() {
+        let base = TempSource::new("lock", "log", "");
+        let lock = CacheLock::acquire(&base).unwrap();
+        // This process again, as when the cache is written during a locked build
+        let nested = CacheLock::acquire(&base).unwrap();
+        drop(nested);
+        assert!(lock_path(&base).exists());
+        drop(lock);
+        assert!(!lock_path(&base).exists());
+
+        // Another process, as far as the lock can tell: its own open file, locked, naming another pid
+        fs::write(lock_path(&base), "1\n").unwrap();
+        let other = File::open(lock_path(&base)).unwrap();
+        other.try_lock().unwrap();
+        assert_eq!(CacheLock::acquire(&base).err().map(|e| e.kind()), Some(io::ErrorKind::ResourceBusy));
+        // Gone, as after a crash, leaving the file
+        drop(other);
+        let taken_over = CacheLock::acquire(&base).unwrap();
+        assert_eq!(fs::read_to_string(lock_path(&base)).unwrap().trim(), std::process::id().to_string());
+        drop(taken_over);
+        assert!(!lock_path(&base).exists());
+    }
+}
@@ -0,0 +1,310 @@
+use crate::indexers::*;
+use serde::ser::{Serializer, SerializeSeq};
+use std::convert::{TryFrom, TryInto};
+
+// The .mix cache file MmapIndexer searches in place, mapped rather than read and deserialized. All
+// numbers are little-endian:
+//
+//   "FTMIX001", term count, document count                      u64s after the 8 byte magic
+//   term offsets, one more than there are terms                 u64 byte offsets into the term text
+//   postings offsets, one more than there are terms             u64 id offsets into the postings
+//   documents, DOCUMENT_WORDS u64s each, in id order            title, url, text, keywords ranges, flags
+//   term text                                                   the terms in sorted order, back to back
+//   postings                                                    i32 ids, each term's sorted
+//
+// Terms are found by binary search over the offsets, and those starting with a prefix are one range.
+const MAGIC: &[u8; 8] = b"FTMIX001";
+const HEADER_BYTES: usize = 24;
+const DOCUMENT_WORDS: usize = 9;
+const OVERSIZED: u64 = 1;
+
+fn put(w: &mut dyn Write, value: u64) -> Result<(), io::Error> {
+    w.write_all(&value.to_le_bytes())
+}
+
+// Lays out `index` and `documents`, whose ids must be their positions
+pub(super) fn write_layout(w: &mut dyn Write, index: &HashMapInvertedIndex, documents: &[DocumentRaw]) -> Result<(), io::Error> {
+    let mut terms: Vec<&String> = index.keys().collect();
+    terms.sort_unstable();
+    write_head(w, terms.iter().map(|term| (term.len(), index[*term].len())), documents)?;
+    for term in &terms {
+        w.write_all(term.as_bytes())?;
+    }
+    let mut ids: Vec<i32> = Vec::new();
+    for term in &terms {
+        ids.clear();
+        ids.extend(index[*term].iter().copied());
+        ids.sort_unstable();
+        write_ids(w, &ids)?;
+    }
+    Ok(())
+}
+
+// As write_layout, for postings left spilled by a build that ran up against its memory limit. They're
+// merged twice, for the terms and how many ids each has and then for the ids, so only the term text
+// is held at once.
+pub(super) fn write_spilled_layout(w: &mut dyn Write, spilled: &segments::SpilledPostings, documents: &[DocumentRaw]) -> Result<(), io::Error> {
+    let mut text = String::new();
+    let mut sizes: Vec<(usize, usize)> = Vec::new();
+    spilled.merge(|term, ids, _| {
+        text.push_str(&term);
+        sizes.push((term.len(), ids.len()));
+        Ok(())
+    })?;
+    write_head(w, sizes.into_iter(), documents)?;
+    w.write_all(text.as_bytes())?;
+    drop(text);
+    spilled.merge(|_, ids, _| write_ids(w, &ids))
+}
+
+// Everything before the term text, from each term's length and how many ids it has, in term order
+fn write_head(w: &mut dyn Write, sizes: impl Iterator<Item = (usize, usize)> + Clone, documents: &[DocumentRaw]) -> Result<(), io::Error> {
+    w.write_all(MAGIC)?;
+    put(w, sizes.clone().count() as u64)?;
+    put(w, documents.len() as u64)?;
+    let mut offset = 0;
+    put(w, 0)?;
+    for (term_bytes, _) in sizes.clone() {
+        offset += term_bytes as u64;
+        put(w, offset)?;
+    }
+    offset = 0;
+    put(w, 0)?;
+    for (_, num_ids) in sizes {
+        offset += num_ids as u64;
+        put(w, offset)?;
+    }
+    for d in documents {
+        for range in [&d.title, &d.url, &d.text, &d.keywords] {
+            put(w, range.start as u64)?;
+            put(w, range.end as u64)?;
+        }
+        put(w, if d.oversized { OVERSIZED } else { 0 })?;
+    }
+    Ok(())
+}
+
+fn write_ids(w: &mut dyn Write, ids: &[i32]) -> Result<(), io::Error> {
+    for id in ids {
+        w.write_all(&id.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+pub(super) struct MappedIndex {
+    bytes: BoxedBytes,
+    terms: usize,
+    documents: usize,
+    // Where each section starts
+    postings_offsets: usize,
+    document_records: usize,
+    term_text: usize,
+    postings: usize
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("mapped index: {}", message))
+}
+
+impl MappedIndex {
+    // Checks the header, that the sections fit in `bytes` and that the term and postings offsets
+    // run in order, so that no term or postings lookup reads out of bounds. The documents' ranges
+    // are for check_documents, against the source they point into.
+    pub(super) fn new(bytes: BoxedBytes) -> Result<MappedIndex, io::Error> {
+        let data = (*bytes).as_ref();
+        if data.len() < HEADER_BYTES || &data[..8] != MAGIC {
+            return Err(invalid("not a .mix file"));
+        }
+        let word = |at: usize| u64::from_le_bytes(data[at..at + 8].try_into().unwrap()) as usize;
+        let (terms, documents) = (word(8), word(16));
+        let truncated = || invalid("truncated");
+        let offsets_bytes = terms.checked_add(1).and_then(|offsets| offsets.checked_mul(8)).ok_or_else(truncated)?;
+        let postings_offsets = HEADER_BYTES.checked_add(offsets_bytes).ok_or_else(truncated)?;
+        let document_records = postings_offsets.checked_add(offsets_bytes).ok_or_else(truncated)?;
+        let term_text = documents.checked_mul(DOCUMENT_WORDS * 8).and_then(|records| records.checked_add(document_records))
+            .filter(|end| *end <= data.len())
+            .ok_or_else(truncated)?;
+        let ascending = |start: usize| word(start) == 0 && (0..terms).all(|i| word(start + i * 8) <= word(start + (i + 1) * 8));
+        if !ascending(HEADER_BYTES) || !ascending(postings_offsets) {
+            return Err(invalid("offsets out of order"));
+        }
+        let postings = term_text.checked_add(word(postings_offsets - 8)).ok_or_else(truncated)?;
+        let postings_bytes = word(document_records - 8).checked_mul(4).ok_or_else(truncated)?;
+        if postings.checked_add(postings_bytes) != Some(data.len()) {
+            return Err(invalid("sections don't add up to the file's length"));
+        }
+        Ok(MappedIndex { bytes, terms, documents, postings_offsets, document_records, term_text, postings })
+    }
+
+    // Checks that every document's ranges lie in `source` on UTF-8 character boundaries, which
+    // taking their text as a str relies on
+    pub(super) fn check_documents(&self, source: &[u8]) -> Result<(), io::Error> {
+        let boundary = |at: usize| at == source.len() || source.get(at).is_some_and(|byte| byte & 0xc0 != 0x80);
+        for id in 0..self.documents {
+            let d = self.document(id as i32).unwrap();
+            if [d.title, d.url, d.text, d.keywords].iter().any(|range| range.start > range.end || !boundary(range.start) || !boundary(range.end)) {
+                return Err(invalid(&format!("document {} isn't within the source", id)));
+            }
+        }
+        Ok(())
+    }
+
+    // An index of nothing, before a build or load
+    pub(super) fn empty() -> MappedIndex {
+        let mut bytes: Vec<u8> = Vec::new();
+        write_layout(&mut bytes, &HashMapInvertedIndex::default(), &[]).unwrap();
+        MappedIndex::new(Box::new(bytes)).unwrap()
+    }
+
+    pub(super) fn bytes(&self) -> &[u8] {
+        (*self.bytes).as_ref()
+    }
+
+    fn word(&self, at: usize) -> usize {
+        u64::from_le_bytes(self.bytes()[at..at + 8].try_into().unwrap()) as usize
+    }
+
+    pub(super) fn num_terms(&self) -> usize {
+        self.terms
+    }
+
+    pub(super) fn num_documents(&self) -> usize {
+        self.documents
+    }
+
+    // Empty for a term whose text isn't UTF-8, which only a corrupt file has
+    pub(super) fn term(&self, i: usize) -> &str {
+        let (start, end) = (self.word(HEADER_BYTES + i * 8), self.word(HEADER_BYTES + (i + 1) * 8));
+        self.bytes().get(self.term_text + start..self.term_text + end)
+            .and_then(|text| std::str::from_utf8(text).ok())
+            .unwrap_or("")
+    }
+
+    // Number of the first term not less than `term`
+    fn lower_bound(&self, term: &str) -> usize {
+        let (mut low, mut high) = (0, self.terms);
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.term(mid) < term { low = mid + 1 } else { high = mid }
+        }
+        low
+    }
+
+    pub(super) fn find(&self, term: &str) -> Option<usize> {
+        let i = self.lower_bound(term);
+        (i < self.terms && self.term(i) == term).then_some(i)
+    }
+
+    // Numbers of the terms starting with `prefix`
+    pub(super) fn with_prefix(&self, prefix: &str) -> Range<usize> {
+        let start = self.lower_bound(prefix);
+        let mut end = start;
+        while end < self.terms && self.term(end).starts_with(prefix) {
+            end += 1;
+        }
+        start..end
+    }
+
+    fn postings_range(&self, i: usize) -> Range<usize> {
+        let (start, end) = (self.word(self.postings_offsets + i * 8), self.word(self.postings_offsets + (i + 1) * 8));
+        self.postings + start * 4..self.postings + end * 4
+    }
+
+    pub(super) fn document_frequency(&self, i: usize) -> usize {
+        self.postings_range(i).len() / 4
+    }
+
+    // Appends the sorted ids of term `i`
+    pub(super) fn postings_into(&self, i: usize, ids: &mut Vec<i32>) {
+        let bytes = self.bytes().get(self.postings_range(i)).unwrap_or_default();
+        ids.extend(bytes.chunks_exact(4).map(|id| i32::from_le_bytes(id.try_into().unwrap())));
+    }
+
+    pub(super) fn document(&self, id: i32) -> Option<DocumentRaw> {
+        let id = usize::try_from(id).ok().filter(|id| *id < self.documents)?;
+        let at = self.document_records + id * DOCUMENT_WORDS * 8;
+        let range = |field: usize| self.word(at + field * 16)..self.word(at + field * 16 + 8);
+        Some(DocumentRaw {
+            title: range(0),
+            url: range(1),
+            text: range(2),
+            keywords: range(3),
+            id: id as i32,
+            oversized: self.word(at + 64) as u64 & OVERSIZED != 0
+        })
+    }
+}
+
+// The documents, serialized like a DocumentIndex
+pub(super) struct MappedDocuments<'a>(pub(super) &'a MappedIndex);
+
+impl Serialize for MappedDocuments<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.0.documents))?;
+        for id in 0..self.0.documents {
+            seq.serialize_element(&self.0.document(id as i32).unwrap())?;
+        }
+        seq.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn laid_out() -> (HashMapInvertedIndex, Vec<DocumentRaw>, Vec<u8>) {
+        let mut index = HashMapInvertedIndex::default();
+        for (term, ids) in [("pear", vec![2, 0]), ("peach", vec![1]), ("apple", vec![0, 1, 2])] {
+            index.insert(String::from(term), ids.into_iter().collect());
+        }
+        let documents: Vec<DocumentRaw> = (0..3).map(|id| DocumentRaw { text: id * 10..id * 10 + 5, id: id as i32, oversized: id == 1, ..DocumentRaw::default() }).collect();
+        let mut bytes: Vec<u8> = Vec::new();
+        write_layout(&mut bytes, &index, &documents).unwrap();
+        (index, documents, bytes)
+    }
+
+    #[test]
+    fn terms_postings_and_documents_read_back() {
+        let (_, documents, bytes) = laid_out();
+        let mapped = MappedIndex::new(Box::new(bytes)).unwrap();
+        assert_eq!((mapped.num_terms(), mapped.num_documents()), (3, 3));
+        let mut ids: Vec<i32> = Vec::new();
+        mapped.postings_into(mapped.find("pear").unwrap(), &mut ids);
+        assert_eq!(ids, vec![0, 2]);
+        assert_eq!(mapped.find("plum"), None);
+        let prefixed: Vec<&str> = mapped.with_prefix("pea").map(|i| mapped.term(i)).collect();
+        assert_eq!(prefixed, vec!["peach", "pear"]);
+        assert_eq!(mapped.with_prefix("z").len(), 0);
+        assert_eq!(mapped.document(1).map(|d| (d.text, d.oversized)), Some((10..15, true)));
+        assert_eq!(mapped.document(3).map(|d| d.id), None);
+        // Loads as the .dcm of any other backend
+        let reloaded: Vec<DocumentRaw> = bincode::deserialize(&bincode::serialize(&MappedDocuments(&mapped)).unwrap()).unwrap();
+        assert!(reloaded == documents);
+    }
+
+    #[test]
+    fn damaged_files_are_rejected() {
+        let (_, _, mut bytes) = laid_out();
+        bytes.pop();
+        assert!(MappedIndex::new(Box::new(bytes.clone())).is_err());
+        bytes[0] = b'X';
+        assert!(MappedIndex::new(Box::new(bytes)).is_err());
+        assert!(MappedIndex::new(Box::new(Vec::new())).is_err());
+
+        let (_, _, mut bytes) = laid_out();
+        // A term count that overflows the offsets' size
+        bytes[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(MappedIndex::new(Box::new(bytes)).is_err());
+        let (_, _, mut bytes) = laid_out();
+        // The first term's end offset past the second's
+        bytes[HEADER_BYTES + 8..HEADER_BYTES + 16].copy_from_slice(&99u64.to_le_bytes());
+        assert!(MappedIndex::new(Box::new(bytes)).is_err());
+
+        let (_, _, bytes) = laid_out();
+        let mapped = MappedIndex::new(Box::new(bytes)).unwrap();
+        let source = "ünïcode text ".repeat(3);
+        assert!(mapped.check_documents(&source.as_bytes()[..25]).is_ok());
+        assert!(mapped.check_documents(&source.as_bytes()[..24]).is_err());
+        assert!(mapped.check_documents("é".repeat(20).as_bytes()).is_err());
+    }
+}
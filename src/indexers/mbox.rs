@@ -0,0 +1,157 @@
+use crate::indexers::*;
+use std::sync::atomic;
+
+pub(super) struct Header {
+    name: Range<usize>,
+    value: Range<usize>
+}
+
+// Headers run until the first empty line; folded continuation lines (leading whitespace) extend the
+// previous header's value. Returned ranges are relative to `block`.
+pub(super) fn parse_header_block(block: &str) -> (Vec<Header>, usize) {
+    let mut headers: Vec<Header> = Vec::new();
+    let mut line_start = 0;
+    for raw_line in block.split_terminator('\n') {
+        let line = raw_line.strip_suffix('\r').unwrap_or(raw_line);
+        let line_end = line_start + raw_line.len() + 1;
+        if line.is_empty() {
+            return (headers, cmp::min(line_end, block.len()));
+        }
+        if line.starts_with([' ', '\t']) {
+            if let Some(header) = headers.last_mut() {
+                header.value.end = line_start + line.trim_end().len();
+            }
+        } else if let Some(colon) = line.find(':') {
+            let value = &line[colon + 1..];
+            let value_start = line_start + colon + 1 + (value.len() - value.trim_start().len());
+            headers.push(Header {
+                name: Range{start: line_start, end: line_start + colon},
+                value: Range{start: value_start, end: cmp::max(value_start, line_start + line.trim_end().len())}
+            });
+        }
+        line_start = line_end;
+    }
+    (headers, block.len())
+}
+
+pub(super) fn find_header<'a>(block: &'a str, headers: &[Header], name: &str) -> Option<(&'a str, Range<usize>)> {
+    headers.iter()
+        .find(|h| block[h.name.clone()].eq_ignore_ascii_case(name))
+        .map(|h| (&block[h.value.clone()], h.value.clone()))
+}
+
+fn boundary_of(content_type: &str) -> Option<&str> {
+    let start = content_type.to_ascii_lowercase().find("boundary=")? + "boundary=".len();
+    let boundary = &content_type[start..];
+    let boundary = match boundary.strip_prefix('"') {
+        Some(quoted) => &quoted[..quoted.find('"').unwrap_or(quoted.len())],
+        None => &boundary[..boundary.find(|c: char| c == ';' || c.is_whitespace()).unwrap_or(boundary.len())]
+    };
+    if boundary.is_empty() { None } else { Some(boundary) }
+}
+
+// Picks the first inline text/plain part of a (possibly nested) multipart body, skipping attachments.
+// Non-multipart bodies are returned whole. Ranges are relative to `entity`.
+fn find_text_body(entity: &str, headers: &[Header], body_start: usize) -> Option<Range<usize>> {
+    let content_type = find_header(entity, headers, "content-type").map(|(v, _)| v).unwrap_or("text/plain");
+    let disposition = find_header(entity, headers, "content-disposition").map(|(v, _)| v).unwrap_or("");
+    if disposition.to_ascii_lowercase().starts_with("attachment") {
+        return None;
+    }
+    let lower_type = content_type.to_ascii_lowercase();
+    if lower_type.starts_with("multipart/") {
+        let delimiter = format!("--{}", boundary_of(content_type)?);
+        let body = &entity[body_start..];
+        let mut parts = body.split(delimiter.as_str()).scan(body_start, |offset, part| {
+            let part_start = *offset;
+            *offset += part.len() + delimiter.len();
+            Some((part_start, part))
+        });
+        // Anything before the first delimiter is the preamble
+        parts.next();
+        for (part_start, part) in parts {
+            if part.starts_with("--") {
+                break;
+            }
+            let stripped = part.strip_prefix("\r\n").or_else(|| part.strip_prefix('\n')).unwrap_or(part);
+            let part_start = part_start + (part.len() - stripped.len());
+            let part = stripped;
+            let (part_headers, part_body_start) = parse_header_block(part);
+            if let Some(range) = find_text_body(part, &part_headers, part_body_start) {
+                return Some(Range{start: part_start + range.start, end: part_start + range.end});
+            }
+        }
+        None
+    } else if lower_type.starts_with("text/plain") {
+        let body = entity[body_start..].trim_end();
+        Some(Range{start: body_start, end: body_start + body.len()})
+    } else {
+        None
+    }
+}
+
+fn parse_message(message: &str, base_offset: usize) -> DocumentRaw {
+    // Skip the "From " envelope line
+    let headers_start = message.find('\n').map(|i| i + 1).unwrap_or(message.len());
+    let entity = &message[headers_start..];
+    let base_offset = base_offset + headers_start;
+    let (headers, body_start) = parse_header_block(entity);
+    let absolute = |r: Range<usize>| Range{start: base_offset + r.start, end: base_offset + r.end};
+
+    let mut doc = DocumentRaw::default();
+    if let Some((_, range)) = find_header(entity, &headers, "subject") {
+        doc.title = absolute(range);
+    }
+    if let Some((value, range)) = find_header(entity, &headers, "message-id") {
+        let trimmed = value.trim_start_matches('<').trim_end_matches('>');
+        let start = range.start + (value.len() - value.trim_start_matches('<').len());
+        doc.url = absolute(Range{start, end: start + trimmed.len()});
+    }
+    if let Some(range) = find_text_body(entity, &headers, body_start) {
+        doc.text = absolute(range);
+    }
+    doc.keywords = absolute(Range{start: 0, end: body_start});
+    doc
+}
+
+pub(super) fn parse_mbox(contents: &ContentsSplit, cur_id: &atomic::AtomicI32) -> Vec<DocumentRaw> {
+    let data = contents.data;
+    let mut message_starts: Vec<usize> = Vec::new();
+    if data.starts_with("From ") {
+        message_starts.push(0);
+    }
+    message_starts.extend(data.match_indices("\nFrom ").map(|(i, _)| i + 1));
+
+    let mut docs: Vec<DocumentRaw> = Vec::with_capacity(message_starts.len());
+    for (i, start) in message_starts.iter().enumerate() {
+        let end = message_starts.get(i + 1).copied().unwrap_or(data.len());
+        let mut doc = parse_message(&data[*start..end], contents.base_offset + start);
+        doc.id = cur_id.fetch_add(1, atomic::Ordering::SeqCst);
+        docs.push(doc);
+    }
+    docs
+}
+
+// Yields "name:value" keyword tokens for the requested headers. Address lists are split on commas and
+// reduced to the bare address when one is given in angle brackets.
+pub(super) fn header_keywords(block: &str, fields: &HashSet<String>) -> Vec<String> {
+    let (headers, _) = parse_header_block(block);
+    let mut keywords: Vec<String> = Vec::new();
+    for header in headers {
+        let name = block[header.name.clone()].trim().to_lowercase();
+        if !fields.contains(&name) {
+            continue;
+        }
+        for value in block[header.value.clone()].split(',') {
+            let value = match (value.find('<'), value.rfind('>')) {
+                (Some(start), Some(end)) if start < end => &value[start + 1..end],
+                _ => value
+            };
+            let value = value.split_whitespace().collect::<Vec<&str>>().join(" ").to_lowercase();
+            if !value.is_empty() {
+                keywords.push(format!("{}:{}", name, value));
+            }
+        }
+    }
+    keywords
+}
@@ -0,0 +1,229 @@
+use crate::indexers::*;
+use std::sync::atomic::AtomicU64;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Throttled writes check for a pause and the rate after every this much
+const SLICE_BYTES: usize = 64 << 10;
+// How often a paused merge looks again
+const PAUSE_POLL: Duration = Duration::from_millis(5);
+
+// Paces a background merge's writes and reports how far along it is. Clones share the state, so the
+// server keeps one to pause the merge around each request, change the rate and read the progress,
+// while the merge thread writes through another. A rate of 0 is unthrottled.
+#[derive(Clone, Default)]
+pub struct MergeThrottle {
+    state: Arc<ThrottleState>
+}
+
+#[derive(Default)]
+struct ThrottleState {
+    bytes_per_sec: AtomicU64,
+    paused: AtomicBool,
+    written: AtomicU64,
+    total: AtomicU64
+}
+
+impl MergeThrottle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let throttle = MergeThrottle::default();
+        throttle.set_rate(bytes_per_sec);
+        throttle
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.state.bytes_per_sec.load(Ordering::Relaxed)
+    }
+
+    // Takes effect from the merge's next slice on
+    pub fn set_rate(&self, bytes_per_sec: u64) {
+        self.state.bytes_per_sec.store(bytes_per_sec, Ordering::Relaxed);
+    }
+
+    // The merge stops writing after its current slice until resume
+    pub fn pause(&self) {
+        self.state.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.state.paused.store(false, Ordering::SeqCst);
+    }
+
+    // Bytes written so far and in all, of the merge running or last run
+    pub fn progress(&self) -> (u64, u64) {
+        (self.state.written.load(Ordering::Relaxed), self.state.total.load(Ordering::Relaxed))
+    }
+
+    fn start(&self, total: u64) {
+        self.state.written.store(0, Ordering::Relaxed);
+        self.state.total.store(total, Ordering::Relaxed);
+    }
+
+    fn writer<'a>(&'a self, w: &'a mut dyn Write) -> ThrottledWriter<'a> {
+        ThrottledWriter { throttle: self, w, started: Instant::now(), since_start: 0 }
+    }
+}
+
+// Writes through to `w` a slice at a time, waiting out pauses and sleeping off whatever puts it ahead
+// of the rate. The rate is kept over the whole file rather than per slice, so time spent paused isn't
+// made up for in a burst afterwards.
+struct ThrottledWriter<'a> {
+    throttle: &'a MergeThrottle,
+    w: &'a mut dyn Write,
+    started: Instant,
+    since_start: u64
+}
+
+impl Write for ThrottledWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let state = &self.throttle.state;
+        for slice in data.chunks(SLICE_BYTES) {
+            if state.paused.load(Ordering::SeqCst) {
+                while state.paused.load(Ordering::SeqCst) {
+                    thread::sleep(PAUSE_POLL);
+                }
+                self.started = Instant::now();
+                self.since_start = 0;
+            }
+            self.w.write_all(slice)?;
+            self.since_start += slice.len() as u64;
+            state.written.fetch_add(slice.len() as u64, Ordering::Relaxed);
+            let rate = self.throttle.rate();
+            if rate > 0 {
+                let due = Duration::from_secs_f64(self.since_start as f64 / rate as f64);
+                if let Some(ahead) = due.checked_sub(self.started.elapsed()) {
+                    thread::sleep(ahead);
+                }
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+// A full cache write running on its own thread, so a long running process goes on answering
+// searches meanwhile. It writes from the indexer's DocumentIndexer::snapshot, so the indexer is free
+// to change once start returns, and nothing is serialized ahead of the disk writes, which are paced
+// by the throttle. Like write_index_to_path, nothing is renamed into place until all of it is
+// written, and segment files saved with write_additions_to_path meanwhile are kept. Nothing else may
+// write a full cache to the same path until it's finished.
+pub struct BackgroundMerge {
+    path: String,
+    // pending_additions when the snapshot was taken, for mark_compacted
+    pending: (usize, usize),
+    throttle: MergeThrottle,
+    started: Instant,
+    handle: thread::JoinHandle<Result<(), io::Error>>
+}
+
+impl BackgroundMerge {
+    // Unsupported for backends without snapshots
+    pub fn start(file_to_index_path: &str, indexer: &dyn DocumentIndexer, sync: SyncPolicy, throttle: &MergeThrottle) -> Result<BackgroundMerge, io::Error> {
+        disk::check_writable(Path::new(file_to_index_path))?;
+        let snapshot = indexer.snapshot()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "this backend can't write its cache in the background"))?;
+        throttle.start(0);
+        let (path, thread_throttle) = (PathBuf::from(file_to_index_path), throttle.clone());
+        let handle = thread::Builder::new().name(String::from("merge")).spawn(move || {
+            let throttle = &thread_throttle;
+            let files: Vec<(&str, Option<disk::CacheFile>)> = SerializedIndex::index_files(snapshot.as_ref())?.into_iter()
+                .map(|(extension, file)| (extension, file.map(|file| disk::CacheFile {
+                    size: file.size,
+                    write: Box::new(move |w| (file.write)(&mut throttle.writer(w)))
+                })))
+                .collect();
+            throttle.start(files.iter().filter_map(|(_, file)| file.as_ref()).map(|file| file.size).sum());
+            SerializedIndex::write_files(&path, files, sync, Some(snapshot.num_documents()))
+        })?;
+        Ok(BackgroundMerge {
+            path: String::from(file_to_index_path),
+            pending: indexer.pending_additions(),
+            throttle: throttle.clone(),
+            started: Instant::now(),
+            handle
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // The add_documents calls and documents the merge folds in
+    pub fn pending(&self) -> (usize, usize) {
+        self.pending
+    }
+
+    pub fn progress(&self) -> (u64, u64) {
+        self.throttle.progress()
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    // Waits for the merge, which isn't paused meanwhile, and on success marks what it folded in as
+    // compacted in `indexer`. Additions made since are still pending, in segment files when they were
+    // saved; deletions made since are in neither, so write_additions_to_path again afterwards.
+    pub fn finish(self, indexer: &mut dyn DocumentIndexer) -> Result<(), io::Error> {
+        self.throttle.resume();
+        let result = self.handle.join().unwrap_or_else(|_| Err(io::Error::other("the merge thread panicked")));
+        if result.is_ok() {
+            indexer.mark_compacted(self.pending);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::TempSource;
+
+    #[test]
+    fn changes_during_a_merge_are_saved_meanwhile() {
+        let text = "apple pie\nplain toast\n";
+        let path = TempSource::new("merge", "log", text);
+        let path_str = path.to_str().unwrap();
+        let mut indexer = RayonIndexer::new(IndexOptions { format: Format::Lines, ..IndexOptions::default() });
+        indexer.build_from_file_contents(path_str, String::from(text)).unwrap();
+        let add = |indexer: &mut RayonIndexer, text: &str| {
+            indexer.add_documents(vec![Document { title: String::from(text), text: String::from(text), ..Document::default() }]);
+        };
+        add(&mut indexer, "apple jam");
+        add(&mut indexer, "pear jam");
+        let throttle = MergeThrottle::new(1 << 20);
+        throttle.pause();
+        let merge = BackgroundMerge::start(path_str, &indexer, SyncPolicy::Never, &throttle).unwrap();
+        assert_eq!(merge.pending(), (2, 2));
+        while throttle.progress().1 == 0 {
+            thread::sleep(PAUSE_POLL);
+        }
+        // Changing the indexer leaves the snapshot the merge writes from as it was
+        add(&mut indexer, "apple tart");
+        assert_eq!(indexer.delete_documents(&[0]), 1);
+        SerializedIndex::write_additions_to_path(path_str, &indexer, SyncPolicy::Never).unwrap();
+        assert!(path.with_extension(segment_extension(4)).exists());
+        let (written, total) = throttle.progress();
+        assert_eq!(written, 0);
+        merge.finish(&mut indexer).unwrap();
+        assert_eq!(throttle.progress(), (total, total));
+        assert_eq!(indexer.pending_additions(), (1, 1));
+        assert_eq!(cache_segments::segment_extensions(&path, None).unwrap(), vec![segment_extension(4)]);
+        SerializedIndex::write_additions_to_path(path_str, &indexer, SyncPolicy::Never).unwrap();
+
+        let mut reloaded = RayonIndexer::new(IndexOptions { format: Format::Lines, ..IndexOptions::default() });
+        reloaded.build_from_serialized(SerializedIndex::load_from_path(path_str).unwrap()).unwrap();
+        assert_eq!(reloaded.num_documents(), 5);
+        assert_eq!(reloaded.pending_additions(), (1, 1));
+        let mut ids: Vec<i32> = reloaded.search(vec!["apple"]).unwrap().ids().collect();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![2, 4]);
+    }
+}
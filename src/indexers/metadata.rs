@@ -0,0 +1,378 @@
+use crate::indexers::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::SeekFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Bytes read from each end of the source for its fingerprint
+const FINGERPRINT_BYTES: u64 = 1 << 20;
+
+// The version of the cache files' formats a .meta records. 1 had bincode .idx files, 2 delta encodes
+// them, 3 adds term frequencies to the .pos file, 4 leaves deleted documents out of the .idx and .pos
+// files and blanks their .dcm and .add records. Older caches still load, newer ones are refused:
+// this build can't know what changed.
+pub const CACHE_FORMAT_VERSION: u32 = 4;
+
+// Why a cache wasn't loaded, for saying so when falling back to a build. Checks deep in loading
+// return it inside an io::Error, From gets it back out and sorts other io::Errors.
+#[derive(Debug)]
+pub enum CacheError {
+    // No cache files next to the source
+    Missing,
+    // The source changed after the cache was written, or isn't the one it was built from
+    Stale(String),
+    // Written by a newer build, or in an old layout `fulltext migrate` brings up to date
+    VersionMismatch(String),
+    // Built with other positions, offsets or frequencies than asked for
+    SchemaMismatch { cached: Schema, requested: Schema },
+    // Files missing from it, truncated, garbled or disagreeing with their checksums or each other
+    Corrupt(String),
+    // The backend can't search this cache
+    Unsupported(String),
+    // Reading the files failed, as without permission
+    Io(io::Error)
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CacheError::Missing => write!(f, "missing, there are no cache files"),
+            CacheError::Stale(reason) => write!(f, "stale, {}", reason),
+            CacheError::VersionMismatch(reason) => write!(f, "version mismatch, {}", reason),
+            CacheError::SchemaMismatch { cached, requested } => write!(f, "schema mismatch, the cache was built with {:?} but {:?} was asked for", cached, requested),
+            CacheError::Corrupt(reason) => write!(f, "corrupt, {}", reason),
+            CacheError::Unsupported(reason) => write!(f, "unsupported, {}", reason),
+            CacheError::Io(e) => write!(f, "unreadable, {}", e)
+        }
+    }
+}
+
+impl std::error::Error for CacheError {}
+
+impl From<io::Error> for CacheError {
+    fn from(e: io::Error) -> Self {
+        if e.get_ref().is_some_and(|inner| inner.is::<CacheError>()) {
+            return *e.into_inner().unwrap().downcast::<CacheError>().unwrap();
+        }
+        match e.kind() {
+            // Only some of the files, see SerializedIndex::check_current
+            io::ErrorKind::NotFound => CacheError::Corrupt(e.to_string()),
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => CacheError::Corrupt(e.to_string()),
+            io::ErrorKind::Unsupported => CacheError::Unsupported(e.to_string()),
+            _ => CacheError::Io(e)
+        }
+    }
+}
+
+impl From<CacheError> for io::Error {
+    fn from(e: CacheError) -> Self {
+        match e {
+            CacheError::Io(e) => e,
+            CacheError::Missing => io::Error::new(io::ErrorKind::NotFound, e),
+            CacheError::Unsupported(_) => io::Error::new(io::ErrorKind::Unsupported, e),
+            _ => io::Error::new(io::ErrorKind::InvalidData, e)
+        }
+    }
+}
+
+// How an index was built, written as JSON to the .meta cache file. Caches from before it existed
+// have none.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BuildInfo {
+    // 0 for caches from before it was recorded
+    #[serde(default)]
+    pub format_version: u32,
+    pub backend: String,
+    pub format: String,
+    pub mode: String,
+    pub query_language: Option<String>,
+    pub split_units: bool,
+    pub keyword_headers: Vec<String>,
+    pub max_doc_bytes: Option<usize>,
+    // Seconds since the Unix epoch
+    pub built_at: u64,
+    // None when the source isn't one file, like a sql query or several files
+    pub source: Option<SourceFingerprint>,
+    // Estimated from sketches kept during the build, None for caches from before they were
+    pub distinct_terms: Option<DistinctTerms>,
+    // Documents in the .dcm, not counting any added since, None for caches from before it was recorded
+    #[serde(default)]
+    pub documents: Option<usize>,
+    // Empty when none were given, and for caches from before they were recorded
+    #[serde(default)]
+    pub boosts: FieldBoosts
+}
+
+// Enough to tell whether the source changed since the build without reading all of it: its length,
+// modification time and the CRC-32 of its first and last megabyte
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SourceFingerprint {
+    pub bytes: u64,
+    pub modified: Option<u64>,
+    pub crc32: u32
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl SourceFingerprint {
+    pub fn of(path: &Path) -> Result<SourceFingerprint, io::Error> {
+        let mut file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let bytes = metadata.len();
+        let mut crc = flate2::Crc::new();
+        let mut buffer: Vec<u8> = Vec::new();
+        (&mut file).take(FINGERPRINT_BYTES).read_to_end(&mut buffer)?;
+        crc.update(&buffer);
+        if bytes > FINGERPRINT_BYTES {
+            buffer.clear();
+            file.seek(SeekFrom::Start(cmp::max(bytes - FINGERPRINT_BYTES, FINGERPRINT_BYTES)))?;
+            file.read_to_end(&mut buffer)?;
+            crc.update(&buffer);
+        }
+        Ok(SourceFingerprint { bytes, modified: metadata.modified().ok().map(unix_seconds), crc32: crc.sum() })
+    }
+
+    // Whether both are of the same contents, however long ago either was modified
+    pub fn same_contents(&self, other: &SourceFingerprint) -> bool {
+        self.bytes == other.bytes && self.crc32 == other.crc32
+    }
+}
+
+impl BuildInfo {
+    // For a build of `source` that just finished, None when it was of several files
+    pub(super) fn new(backend: &str, options: &IndexOptions, source: Option<&str>) -> BuildInfo {
+        BuildInfo {
+            format_version: CACHE_FORMAT_VERSION,
+            backend: String::from(backend),
+            format: format!("{:?}", options.format).to_lowercase(),
+            mode: format!("{:?}", options.mode).to_lowercase(),
+            query_language: options.query_language.clone(),
+            split_units: options.split_units,
+            keyword_headers: options.keyword_headers.clone(),
+            max_doc_bytes: options.max_doc_bytes,
+            built_at: unix_seconds(SystemTime::now()),
+            source: source.and_then(|source| SourceFingerprint::of(Path::new(source)).ok()),
+            distinct_terms: None,
+            documents: None,
+            boosts: options.boosts.clone()
+        }
+    }
+
+    // Refuses a cache of a newer format than this build reads, built from another source than
+    // `source`, or whose .dcm has another number of documents than was built, rather than let it
+    // load as garbage. Caches from before a check's field was recorded pass it.
+    pub(super) fn check_cache(&self, source: &Path, documents: usize) -> Result<(), io::Error> {
+        if self.format_version > CACHE_FORMAT_VERSION {
+            return Err(CacheError::VersionMismatch(format!("the cache's format version is {}, this build reads up to {}", self.format_version, CACHE_FORMAT_VERSION)).into());
+        }
+        if let Some(built) = &self.source {
+            if !built.same_contents(&SourceFingerprint::of(source)?) {
+                return Err(CacheError::Stale(format!("the cache was built from a different {:?}", source)).into());
+            }
+        }
+        match self.documents {
+            Some(built) if built != documents => Err(CacheError::Corrupt(format!("the cache was built with {} documents, its .dcm has {}", built, documents)).into()),
+            _ => Ok(())
+        }
+    }
+}
+
+// What the cache files next to an index say about it, read from their headers and the .meta file
+// without loading any postings or documents
+#[derive(Debug)]
+pub struct IndexMetadata {
+    // Layout of the .dcm records, see migrate.rs
+    pub documents_layout: String,
+    pub documents: usize,
+    // Documents added after the build, included in `documents`
+    pub added_documents: usize,
+    // Segment files of documents added since the last full write, see cache_segments.rs
+    pub segments: usize,
+    // Documents deleted since the build, still included in `documents`
+    pub deleted_documents: usize,
+    pub terms: usize,
+    pub schema: Schema,
+    pub build: Option<BuildInfo>,
+    // Whether the source still matches the build's fingerprint, None when either is unknown
+    pub source_unchanged: Option<bool>
+}
+
+// The little-endian u64 a bincode sequence or map starts with
+fn length_prefix(path: &Path) -> Result<usize, io::Error> {
+    let mut prefix = [0; 8];
+    File::open(path)?.read_exact(&mut prefix)?;
+    Ok(u64::from_le_bytes(prefix) as usize)
+}
+
+fn optional<T>(result: Result<T, io::Error>) -> Result<Option<T>, io::Error> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e)
+    }
+}
+
+pub(super) fn read_build_info(base_path: &Path) -> Result<Option<BuildInfo>, io::Error> {
+    match optional(fs::read(base_path.with_extension("meta")))? {
+        Some(data) => serde_json::from_slice(&data).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        None => Ok(None)
+    }
+}
+
+// Why the cache next to `source` is out of date, None when it's current or there's no cache to tell:
+// the source was modified after the cache's .idx or .dcm was written, the .meta says it was written
+// in a newer format than this build reads, or the source's contents aren't what it was built from
+pub(super) fn stale_reason(source: &Path) -> Option<String> {
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    let written = ["idx", "dcm"].iter().filter_map(|extension| modified(&source.with_extension(extension))).min()?;
+    if modified(source)? > written {
+        return Some(format!("{:?} was modified after its cache was written", source));
+    }
+    let build = read_build_info(source).ok()??;
+    if build.format_version > CACHE_FORMAT_VERSION {
+        return Some(format!("the cache of {:?} is in format version {}, this build reads up to {}", source, build.format_version, CACHE_FORMAT_VERSION));
+    }
+    let built = build.source?;
+    match SourceFingerprint::of(source) {
+        Ok(now) if !now.same_contents(&built) => Some(format!("{:?} has changed since its cache was built", source)),
+        _ => None
+    }
+}
+
+impl IndexMetadata {
+    pub(super) fn read(file_to_index_path: &str) -> Result<IndexMetadata, io::Error> {
+        let base_path = Path::new(file_to_index_path);
+        let doc_index_path = base_path.with_extension("dcm");
+        let base_documents = length_prefix(&doc_index_path)?;
+        let documents_layout = migrate::documents_layout(base_documents, fs::metadata(&doc_index_path)?.len() as usize)?;
+        let terms = postings::term_count(&base_path.with_extension("idx"))?;
+        let segments = cache_segments::segment_extensions(base_path, None)?;
+        let mut added_documents = optional(length_prefix(&base_path.with_extension("add")))?.unwrap_or(0);
+        for extension in segments.iter() {
+            added_documents += length_prefix(&base_path.with_extension(extension))?;
+        }
+        let deleted_documents = optional(length_prefix(&base_path.with_extension("del")))?.unwrap_or(0);
+        let schema = match optional(File::open(base_path.with_extension("pos")))? {
+            Some(file) => {
+                let mut header: Vec<u8> = Vec::new();
+                file.take(positions::POS_MAGIC.len() as u64 + 3).read_to_end(&mut header)?;
+                positions::read_schema(&header)?
+            },
+            None => Schema::default()
+        };
+        let build = read_build_info(base_path)?;
+        let source_unchanged = build.as_ref()
+            .and_then(|build| build.source.as_ref())
+            .and_then(|built| SourceFingerprint::of(base_path).ok().map(|now| now == *built));
+        Ok(IndexMetadata {
+            documents_layout,
+            documents: base_documents + added_documents,
+            added_documents,
+            segments: segments.len(),
+            deleted_documents,
+            terms,
+            schema,
+            build,
+            source_unchanged
+        })
+    }
+
+    pub fn print(&self) {
+        println!("Documents layout: {}", self.documents_layout);
+        println!("Documents: {} ({} added after the build, {} deleted)", self.documents, self.added_documents, self.deleted_documents);
+        if self.segments > 0 {
+            println!("Segments: {} not yet merged", self.segments);
+        }
+        println!("Terms: {}", self.terms);
+        println!("Schema: positions {}, offsets {}, frequencies {}", self.schema.positions, self.schema.offsets, self.schema.frequencies);
+        let build = match &self.build {
+            Some(build) => build,
+            None => {
+                println!("Build info: none, the cache predates it");
+                return;
+            }
+        };
+        println!("Format version: {}", build.format_version);
+        println!("Backend: {}", build.backend);
+        println!("Format: {}, mode: {}", build.format, build.mode);
+        println!("Query language: {}", build.query_language.as_deref().unwrap_or("english"));
+        println!("Split units: {}", build.split_units);
+        if !build.boosts.is_empty() {
+            println!("Boosts: {}", build.boosts);
+        }
+        if !build.keyword_headers.is_empty() {
+            println!("Keyword headers: {}", build.keyword_headers.join(", "));
+        }
+        if let Some(max_doc_bytes) = build.max_doc_bytes {
+            println!("Max doc bytes: {}", max_doc_bytes);
+        }
+        println!("Built at: {} (Unix seconds)", build.built_at);
+        if let Some(distinct) = &build.distinct_terms {
+            let estimates = |counts: &BTreeMap<String, u64>| counts.iter().map(|(name, count)| format!("{} ~{}", name, count)).collect::<Vec<String>>().join(", ");
+            println!("Distinct terms by field: {}", estimates(&distinct.fields));
+            if !distinct.sources.is_empty() {
+                println!("Distinct terms by source: {}", estimates(&distinct.sources));
+            }
+        }
+        match (&build.source, self.source_unchanged) {
+            (Some(source), unchanged) => println!("Source: {} bytes, modified {}, crc32 {:08x}, {}",
+                source.bytes,
+                source.modified.map(|m| m.to_string()).unwrap_or_else(|| String::from("unknown")),
+                source.crc32,
+                match unchanged {
+                    Some(true) => "unchanged since the build",
+                    Some(false) => "changed since the build",
+                    None => "now unreadable"
+                }),
+            (None, _) => println!("Source: not a file")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::TempSource;
+
+    #[test]
+    fn caches_of_another_source_or_version_are_refused() {
+        let source = TempSource::new("meta", "log", "apple pie\n");
+        let mut build = BuildInfo::new("rayon", &IndexOptions::default(), source.to_str());
+        build.documents = Some(1);
+        assert!(build.check_cache(&source, 1).is_ok());
+        let refusal = |result: Result<(), io::Error>| CacheError::from(result.unwrap_err());
+        assert!(matches!(refusal(build.check_cache(&source, 2)), CacheError::Corrupt(_)));
+        assert!(matches!(refusal(BuildInfo { format_version: CACHE_FORMAT_VERSION + 1, ..build.clone() }.check_cache(&source, 1)), CacheError::VersionMismatch(_)));
+        // Caches from before versions and counts were recorded still load
+        assert!(BuildInfo { format_version: 0, documents: None, ..build.clone() }.check_cache(&source, 2).is_ok());
+        fs::write(&source, "pear tart\n").unwrap();
+        assert!(refusal(build.check_cache(&source, 1)).to_string().starts_with("stale, "));
+        assert!(matches!(CacheError::from(io::Error::from(io::ErrorKind::PermissionDenied)), CacheError::Io(_)));
+    }
+
+    #[test]
+    fn a_changed_source_or_newer_cache_format_is_stale() {
+        let source = TempSource::new("stale", "log", "apple pie\n");
+        let build = BuildInfo::new("rayon", &IndexOptions::default(), source.to_str());
+        let write_meta = |build: &BuildInfo| fs::write(source.with_extension("meta"), serde_json::to_vec(build).unwrap()).unwrap();
+        write_meta(&build);
+        for extension in ["idx", "dcm"] {
+            fs::write(source.with_extension(extension), []).unwrap();
+        }
+        assert_eq!(stale_reason(&source), None);
+
+        write_meta(&BuildInfo { format_version: CACHE_FORMAT_VERSION + 1, ..build.clone() });
+        assert!(stale_reason(&source).unwrap().contains("format version"));
+        write_meta(&build);
+
+        // Same size and older than the cache, so only the fingerprint tells
+        fs::write(&source, "pear tart\n").unwrap();
+        let before = SystemTime::now() - std::time::Duration::from_secs(3600);
+        File::options().write(true).open(&source).unwrap().set_modified(before).unwrap();
+        assert!(stale_reason(&source).unwrap().contains("has changed"));
+        File::options().write(true).open(&source).unwrap().set_modified(SystemTime::now() + std::time::Duration::from_secs(3600)).unwrap();
+        assert!(stale_reason(&source).unwrap().contains("modified after"));
+    }
+}
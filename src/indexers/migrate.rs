@@ -0,0 +1,155 @@
+use crate::indexers::*;
+
+// Every layout the .dcm document records have had. The files carry no version, but each layout has
+// a different fixed record size so the version follows from the file length and document count.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum DocumentsVersion {
+    // title, url, text, id
+    V0,
+    // adds the keywords range
+    V1,
+    // adds the oversized flag
+    V2
+}
+
+const CURRENT_VERSION: DocumentsVersion = DocumentsVersion::V2;
+
+impl DocumentsVersion {
+    fn record_size(&self) -> usize {
+        match self {
+            DocumentsVersion::V0 => 3 * 16 + 4,
+            DocumentsVersion::V1 => 4 * 16 + 4,
+            DocumentsVersion::V2 => 4 * 16 + 4 + 1
+        }
+    }
+
+    fn detect(data: &[u8]) -> Result<DocumentsVersion, io::Error> {
+        if data.len() < 8 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "documents file is shorter than its header"));
+        }
+        let mut count = [0; 8];
+        count.copy_from_slice(&data[..8]);
+        DocumentsVersion::from_header(u64::from_le_bytes(count) as usize, data.len())
+    }
+
+    // From the document count at the start of the file and the file's length alone
+    fn from_header(count: usize, file_len: usize) -> Result<DocumentsVersion, io::Error> {
+        // With no documents every layout is the same 8 bytes
+        if count == 0 {
+            return Ok(CURRENT_VERSION);
+        }
+        [DocumentsVersion::V0, DocumentsVersion::V1, DocumentsVersion::V2].iter()
+            .find(|v| count.checked_mul(v.record_size()) == file_len.checked_sub(8))
+            .copied()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{} bytes of documents don't match any known layout for {} documents", file_len, count)))
+    }
+}
+
+// Refuses a .dcm of an older layout, which only loads once migrated
+pub(super) fn check_documents_layout(data: &[u8]) -> Result<(), io::Error> {
+    match DocumentsVersion::detect(data)? {
+        CURRENT_VERSION => Ok(()),
+        version => Err(CacheError::VersionMismatch(format!("the documents file has the {:?} layout, run `fulltext migrate` to bring it up to {:?}", version, CURRENT_VERSION)).into())
+    }
+}
+
+// Name of the .dcm layout, for index metadata
+pub(super) fn documents_layout(count: usize, file_len: usize) -> Result<String, io::Error> {
+    DocumentsVersion::from_header(count, file_len).map(|version| format!("{:?}", version))
+}
+
+#[derive(Deserialize)]
+struct DocumentRawV0 {
+    title: Range<usize>,
+    url: Range<usize>,
+    text: Range<usize>,
+    id: i32
+}
+
+#[derive(Deserialize)]
+struct DocumentRawV1 {
+    title: Range<usize>,
+    url: Range<usize>,
+    text: Range<usize>,
+    keywords: Range<usize>,
+    id: i32
+}
+
+fn to_io_error(e: bincode::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn upgrade_documents(data: &[u8], version: DocumentsVersion) -> Result<Vec<DocumentRaw>, io::Error> {
+    Ok(match version {
+        DocumentsVersion::V0 => bincode::deserialize::<Vec<DocumentRawV0>>(data).map_err(to_io_error)?
+            .into_iter()
+            .map(|d| DocumentRaw { title: d.title, url: d.url, text: d.text, id: d.id, ..DocumentRaw::default() })
+            .collect(),
+        DocumentsVersion::V1 => bincode::deserialize::<Vec<DocumentRawV1>>(data).map_err(to_io_error)?
+            .into_iter()
+            .map(|d| DocumentRaw { title: d.title, url: d.url, text: d.text, keywords: d.keywords, id: d.id, oversized: false })
+            .collect(),
+        DocumentsVersion::V2 => bincode::deserialize(data).map_err(to_io_error)?
+    })
+}
+
+// A .dcm of any layout
+pub(super) fn read_documents(data: &[u8]) -> Result<Vec<DocumentRaw>, io::Error> {
+    upgrade_documents(data, DocumentsVersion::detect(data)?)
+}
+
+// Rewrites the cache files next to `file_to_index_path` in the current format: the documents file,
+// an inverted index with raw ids, see postings.rs, and positions without frequencies, see
+// positions.rs.
+pub fn migrate_index(file_to_index_path: &str) -> Result<(), io::Error> {
+    let base_path = Path::new(file_to_index_path);
+    let inverted_index_path = base_path.with_extension("idx");
+    let inverted_index = match fs::read(&inverted_index_path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no inverted index at {:?}", inverted_index_path)));
+        },
+        result => result?
+    };
+    let mut files: Vec<(&str, Option<disk::CacheFile>)> = Vec::new();
+
+    let doc_index_path = base_path.with_extension("dcm");
+    let data = fs::read(&doc_index_path)?;
+    let version = DocumentsVersion::detect(&data)?;
+    if version == CURRENT_VERSION {
+        println!("{:?} is already {:?}, the current layout", doc_index_path, version);
+    } else {
+        let documents = upgrade_documents(&data, version)?;
+        files.push(("dcm", Some(disk::CacheFile::bytes(bincode::serialize(&documents).map_err(to_io_error)?))));
+        println!("Migrating {} documents in {:?} from {:?} to {:?}", documents.len(), doc_index_path, version, CURRENT_VERSION);
+    }
+
+    if postings::is_delta_encoded(&inverted_index) {
+        println!("{:?} already has delta encoded postings", inverted_index_path);
+    } else {
+        let index = postings::read_index(&inverted_index)?;
+        let mut data: Vec<u8> = Vec::new();
+        postings::write_index(&mut data, index.len(), index.iter().map(|(term, ids)| (term.as_str(), ids.iter().copied().collect())))?;
+        println!("Migrating {} terms in {:?} to delta encoded postings, {} bytes to {}", index.len(), inverted_index_path, inverted_index.len(), data.len());
+        files.push(("idx", Some(disk::CacheFile::bytes(data))));
+    }
+
+    let positions_path = base_path.with_extension("pos");
+    match fs::read(&positions_path) {
+        Ok(data) if positions::is_current(&data) => println!("{:?} already has the current layout", positions_path),
+        Ok(data) => {
+            let (schema, index) = positions::read_positions(&data)?;
+            let mut migrated: Vec<u8> = Vec::new();
+            positions::write_positions(&mut migrated, schema, &index)?;
+            println!("Migrating positions of {} terms in {:?} to the current layout", index.len(), positions_path);
+            files.push(("pos", Some(disk::CacheFile::bytes(migrated))));
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => return Err(e)
+    }
+
+    if !files.is_empty() {
+        SerializedIndex::write_files(base_path, files, SyncPolicy::default(), None)?;
+        println!("Migrated {:?}", base_path);
+    }
+    Ok(())
+}
@@ -0,0 +1,245 @@
+use crate::indexers::*;
+use crate::indexers::mapped::{MappedDocuments, MappedIndex};
+
+// Searches the .mix cache file in place, see mapped.rs: opening a cache maps it and the source rather
+// than reading and deserializing the postings, so it takes about as long for a large index as a small
+// one, and processes searching the same index share its pages. Builds go through RayonIndexer and are
+// laid out once done. Positions aren't laid out, so phrases are checked against the documents' text,
+// and documents can't be added.
+pub struct MmapIndexer {
+    index: MappedIndex,
+    full_contents: BoxedBytes,
+    // Always empty, for phrase_ids to fall back from
+    positions: PositionIndex,
+    titles: OnceLock<titles::TitleIndex>,
+    analyzer: Analyzer,
+    query_analyzer: Option<Analyzer>,
+    options: IndexOptions,
+    sources: SourceFiles,
+    build: Option<BuildInfo>,
+    cancel: CancelToken
+}
+
+// How many ids two sorted postings share
+fn shared_ids(a: &[i32], b: &[i32]) -> usize {
+    let (mut i, mut j, mut shared) = (0, 0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            cmp::Ordering::Less => i += 1,
+            cmp::Ordering::Greater => j += 1,
+            cmp::Ordering::Equal => {
+                shared += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    shared
+}
+
+impl MmapIndexer {
+    pub fn new(options: IndexOptions) -> Self {
+        let (analyzer, query_analyzer) = Analyzer::from_options(&options);
+        MmapIndexer {
+            index: MappedIndex::empty(),
+            full_contents: Box::new(String::new()),
+            positions: new_position_index(),
+            titles: OnceLock::new(),
+            analyzer,
+            query_analyzer,
+            options: IndexOptions { schema: Schema::default(), ..options },
+            sources: SourceFiles::default(),
+            build: None,
+            cancel: CancelToken::new()
+        }
+    }
+
+    fn lay_out(&mut self, built: RayonIndexer) -> Result<(), io::Error> {
+        let (postings, documents, full_contents, sources, build) = built.into_parts();
+        self.index = match postings {
+            segments::BuiltPostings::Memory(index, _) => {
+                let mut bytes: Vec<u8> = Vec::new();
+                mapped::write_layout(&mut bytes, &index, &documents)?;
+                drop(index);
+                MappedIndex::new(Box::new(bytes))?
+            },
+            // Laid out into a temporary file and mapped, so the postings never all come back into memory
+            segments::BuiltPostings::Spilled(spilled) => {
+                let path = std::env::temp_dir().join(format!("fulltext-{}-layout.mix", std::process::id()));
+                let mapped = (|| {
+                    let mut w = io::BufWriter::new(File::create(&path)?);
+                    mapped::write_spilled_layout(&mut w, &spilled, &documents)?;
+                    w.flush()?;
+                    drop(w);
+                    open_mmap(&path)
+                })();
+                let _ = fs::remove_file(&path);
+                MappedIndex::new(Box::new(mapped?))?
+            }
+        };
+        self.full_contents = full_contents;
+        self.sources = sources;
+        self.build = build.map(|build| BuildInfo { backend: String::from("mmap"), ..build });
+        self.options.use_stored_boosts(self.build.as_ref());
+        self.titles = OnceLock::new();
+        Ok(())
+    }
+
+    fn rayon(&self) -> RayonIndexer {
+        let mut rayon = RayonIndexer::new(self.options.clone());
+        rayon.set_cancel_token(self.cancel.clone());
+        rayon.keep_spilled();
+        rayon
+    }
+
+    fn title_of(&self, d: &DocumentRaw) -> &str {
+        self.full_contents.from_utf8_unchecked(d.title.clone())
+    }
+}
+
+impl DocumentIndexer for MmapIndexer {
+    fn build_from_files(&mut self, files: SourceFiles, file_contents: String) -> Result<(), io::Error> {
+        let mut rayon = self.rayon();
+        rayon.build_from_files(files, file_contents)?;
+        self.lay_out(rayon)
+    }
+    fn build_streaming(&mut self, path: &str) -> Result<(), io::Error> {
+        let mut rayon = self.rayon();
+        rayon.build_streaming(path)?;
+        self.lay_out(rayon)
+    }
+    fn set_cancel_token(&mut self, token: CancelToken) -> bool {
+        self.cancel = token;
+        true
+    }
+    // A cache another backend wrote, without a .mix file, laid out as if just built
+    fn build_from_serialized(&mut self, serialized_data: SerializedIndex) -> Result<(), io::Error> {
+        let mut rayon = self.rayon();
+        rayon.build_from_serialized(serialized_data)?;
+        // Nothing is spilled when loading, so the layout is written to memory
+        self.lay_out(rayon)
+    }
+    // The .mix file isn't checked against the checksum manifest, which would mean reading all of it
+    fn open_cache(&mut self, path: &str) -> Result<bool, io::Error> {
+        let base_path = Path::new(path);
+        if cache_segments::has_changes(base_path) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "documents were added to or deleted from the cache, which the mmap backend can't search"));
+        }
+        let index = match open_mmap(&base_path.with_extension("mix")) {
+            Ok(index) => MappedIndex::new(Box::new(index))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e)
+        };
+        let build = metadata::read_build_info(base_path)?;
+        if let Some(build) = &build {
+            build.check_cache(base_path, index.num_documents())?;
+        }
+        let full_contents = open_mmap(base_path)?;
+        index.check_documents(&full_contents)?;
+        self.full_contents = Box::new(full_contents);
+        self.index = index;
+        self.sources = SourceFiles::single(path);
+        self.options.use_stored_boosts(build.as_ref());
+        self.build = build;
+        self.titles = OnceLock::new();
+        println!("mapped {:?}", base_path.with_extension("mix"));
+        Ok(true)
+    }
+    // Written like RayonIndexer's, so the cache loads with any backend
+    fn write_index(&self, w: &mut dyn Write) -> Result<(), io::Error> {
+        postings::write_index(w, self.index.num_terms(), (0..self.index.num_terms()).map(|i| {
+            let mut ids: Vec<i32> = Vec::new();
+            self.index.postings_into(i, &mut ids);
+            (self.index.term(i), ids)
+        }))
+    }
+    fn write_documents(&self, w: &mut dyn Write) -> Result<(), io::Error> {
+        disk::serialize_into(w, &MappedDocuments(&self.index))
+    }
+    fn write_mapped(&self, w: &mut dyn Write) -> Result<bool, io::Error> {
+        w.write_all(self.index.bytes()).map(|_| true)
+    }
+    fn write_raw_offsets(&self, w: &mut dyn Write) -> Result<bool, io::Error> {
+        let documents: Vec<DocumentRaw> = (0..self.index.num_documents()).filter_map(|id| self.index.document(id as i32)).collect();
+        raw::write_raw_offsets(w, &self.options, &documents, self.full_contents.as_ref(), &self.sources)
+    }
+    fn build_info(&self) -> Option<BuildInfo> {
+        self.build.clone()
+    }
+
+    fn search_page(&self, all_terms: Vec<&str>, page: Page) -> Result<SearchResults, QueryError> {
+        let analyzer = self.query_analyzer.as_ref().unwrap_or(&self.analyzer);
+        run_query(analyzer, all_terms, PhraseSource::new(&self.analyzer, &self.positions, self.options.schema),
+            |term, ids| self.index.find(term).map(|i| self.index.postings_into(i, ids)).is_some(),
+            |prefix| self.index.with_prefix(prefix).map(|i| self.index.term(i)).collect(),
+            |id| self.document(id),
+            |term, id| self.term_frequency(term, id),
+            &self.cancel,
+            &self.options, page)
+    }
+    fn search_batch(&self, queries: &[Vec<&str>]) -> Vec<Result<SearchResults, QueryError>> {
+        search_batch_with(queries, |all_terms| self.search(all_terms))
+    }
+    fn document(&self, id: i32) -> Option<Document> {
+        self.index.document(id).map(|d| d.to_document(self.full_contents.as_ref(), self.options.format, &self.sources))
+    }
+    fn term_offsets(&self, term: &str, doc: &Document) -> Vec<(u32, u32)> {
+        find_term_offsets(&self.analyzer, &self.positions, self.options.schema, term, doc)
+    }
+    fn num_tokens(&self) -> usize {
+        self.index.num_terms()
+    }
+    fn num_documents(&self) -> usize {
+        self.index.num_documents()
+    }
+    fn num_oversized(&self) -> usize {
+        (0..self.index.num_documents()).filter_map(|id| self.index.document(id as i32)).filter(|d| d.oversized).count()
+    }
+    fn schema(&self) -> Schema {
+        self.options.schema
+    }
+    fn num_positioned(&self) -> usize {
+        0
+    }
+    fn language(&self) -> &str {
+        self.options.query_language.as_deref().unwrap_or("en")
+    }
+    fn analyze_text(&self, text: &str) -> Vec<String> {
+        self.analyzer.analyze(text)
+    }
+    fn document_frequency(&self, term: &str) -> usize {
+        self.index.find(term).map(|i| self.index.document_frequency(i)).unwrap_or(0)
+    }
+    fn terms_with_prefix(&self, prefix: &str, limit: usize) -> Vec<(String, usize)> {
+        most_frequent(self.index.with_prefix(prefix).map(|i| (String::from(self.index.term(i)), self.index.document_frequency(i))), limit)
+    }
+    fn suggest_titles(&self, prefix: &str, limit: usize) -> Vec<(String, i32)> {
+        let titles = self.titles.get_or_init(|| {
+            let documents: Vec<DocumentRaw> = (0..self.index.num_documents()).filter_map(|id| self.index.document(id as i32)).collect();
+            titles::TitleIndex::new(documents.iter().map(|d| (self.title_of(d), d.id)))
+        });
+        titles.complete(prefix, limit).into_iter()
+            .filter_map(|id| self.index.document(id))
+            .map(|d| (String::from(self.title_of(&d)), d.id))
+            .collect()
+    }
+    // Postings are sorted, so each intersection is a merge
+    fn cooccurring_terms(&self, term: &str, limit: usize) -> Vec<(String, usize)> {
+        let found = match self.index.find(term) {
+            Some(found) => found,
+            None => return Vec::new()
+        };
+        let mut ids: Vec<i32> = Vec::new();
+        self.index.postings_into(found, &mut ids);
+        let mut other_ids: Vec<i32> = Vec::new();
+        let shared = (0..self.index.num_terms()).filter(|i| *i != found).filter_map(|i| {
+            other_ids.clear();
+            self.index.postings_into(i, &mut other_ids);
+            match shared_ids(&ids, &other_ids) {
+                0 => None,
+                count => Some((String::from(self.index.term(i)), count))
+            }
+        });
+        most_frequent(shared, limit)
+    }
+}
@@ -1,23 +1,110 @@
 mod rayon_indexer;
 mod threadpool_indexer;
+mod mbox;
+mod feed;
+mod xml;
+mod records;
+mod sql;
+mod columnar;
+mod archive;
+mod directory;
+mod warc;
+mod dump;
+mod compare;
+mod estimate;
+mod analysis;
+mod migrate;
+mod segments;
+mod query;
+mod rewrite;
+mod titles;
+mod sorted_terms;
+mod disk;
+mod metadata;
+mod snippets;
+mod sentences;
+mod sketches;
+mod sources;
+mod cluster;
+mod rerank;
+mod jsonl;
+mod vectors;
+mod mapped;
+mod limits;
+mod postings;
+mod positions;
+mod results;
+mod raw;
+mod merge;
+mod lock;
+mod cache_segments;
+mod stats;
+mod sample;
+mod filters;
+mod mmap_indexer;
+mod roaring_indexer;
+#[cfg(test)]
+mod conformance;
 use std::hash::BuildHasherDefault;
 use hashers::fx_hash::FxHasher;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::io::prelude::*;
 use std::fs::File;
 use std::io;
-use memmap;
 use std::ops::Range;
 use std::cmp;
 use serde::{Serialize, Deserialize};
 use std::fs;
+use rayon::prelude::*;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 pub use rayon_indexer::RayonIndexer;
 pub use threadpool_indexer::ThreadPoolIndexer;
+pub use mmap_indexer::MmapIndexer;
+pub use roaring_indexer::RoaringIndexer;
+pub use feed::fetch_feed;
+pub use sql::query_records;
+pub use columnar::{ParquetSource, read_parquet_records};
+pub use records::{ColumnMapping, DocumentBatches, DocumentSource, InputDoc, RecordWriter, read_documents};
+pub use xml::XmlSource;
+pub(crate) use records::InputDocs;
+pub use archive::{is_archive, read_archive_records};
+pub use directory::read_directory_records;
+pub use warc::read_gzipped;
+pub use dump::dump_index;
+pub use compare::compare_indexes;
+pub use estimate::{BuildEstimate, SampleEstimate};
+pub use analysis::print_analysis;
+pub use migrate::migrate_index;
+pub use query::{explain_query, split_query, titles_only, IdSet};
+pub use rewrite::Rewrites;
+pub use cluster::{cluster_documents, CLUSTER_TOP_N};
+pub use rerank::{CommandEmbedder, rerank};
+pub use jsonl::read_jsonl_records;
+pub use vectors::{VectorIndex, fuse_rankings, parse_knn_term};
+pub use disk::{SyncPolicy, forbid_writes};
+pub use metadata::{BuildInfo, CACHE_FORMAT_VERSION, CacheError, IndexMetadata, SourceFingerprint};
+pub use sketches::DistinctTerms;
+pub use snippets::{Highlight, SNIPPET_CHARS, SnippetOptions, match_snippet, match_snippets, snippet, snippets};
+pub use sources::SourceFiles;
+pub use limits::{QueryError, QueryLimits};
+pub use results::{FieldBoosts, Hit, Page, SearchResults, TEXT_FIELD};
+pub use raw::{RawOffset, RawOffsets};
+pub use merge::{BackgroundMerge, MergeThrottle};
+pub use lock::CacheLock;
+pub use cache_segments::{SEGMENT_PREFIX, segment_extension};
+pub use stats::CorpusStats;
+pub use sample::{SampledDocument, random_documents};
+pub use filters::{DEFAULT_TOKEN_FILTERS, FilterArgs, NamedFilter, TokenFilter, register_token_filter, token_filters};
+#[cfg(feature = "legacy-results")]
+pub use results::TermResults;
+use limits::QueryBudget;
 
-trait SomeBytes: AsRef<[u8]> + Sync {
+trait SomeBytes: AsRef<[u8]> + Sync + Send {
+    #[allow(clippy::wrong_self_convention)]
     fn from_utf8_unchecked(&self, range: Range<usize>) -> &str {
         unsafe { std::str::from_utf8_unchecked(&self.as_ref()[range]) }
     }
@@ -27,26 +114,819 @@ impl SomeBytes for memmap::Mmap {}
 impl SomeBytes for String {}
 impl SomeBytes for Vec<u8> {}
 
+// Bytes an indexer shares with its snapshots, boxed for a backend that takes them over
+struct SharedBytes(Arc<dyn SomeBytes>);
+
+impl AsRef<[u8]> for SharedBytes {
+    fn as_ref(&self) -> &[u8] {
+        (*self.0).as_ref()
+    }
+}
+
+impl SomeBytes for SharedBytes {}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Xml,
+    Lines,
+    Mbox,
+    Rss,
+    Sql,
+    Parquet,
+    // Each text file in a tar or zip archive, or under a directory, is a document
+    Archive,
+    Warc,
+    Jsonl,
+    // Whatever a program using the library handed over through a DocumentSource, see Index::from_source
+    Records
+}
+
+impl Format {
+    // Tag that separates documents, and how many bytes of it belong to the preceding document
+    fn split_on(&self) -> (&'static str, usize) {
+        match self {
+            Format::Xml => ("</doc>", 6),
+            Format::Lines => ("\n", 1),
+            Format::Mbox => ("\nFrom ", 1),
+            // Atom feeds have no </item>, so they always end up in a single chunk
+            Format::Rss => ("</item>", 7),
+            Format::Sql | Format::Parquet | Format::Archive | Format::Jsonl | Format::Records => ("\u{1e}", 1),
+            Format::Warc => ("\r\n\r\nWARC/", 4)
+        }
+    }
+}
+
+// What reading a source needs besides its path and format
+#[derive(Default)]
+pub struct SourceOptions {
+    // Fields of sql, parquet and jsonl records
+    pub columns: ColumnMapping,
+    // Required for Format::Sql
+    pub sql_query: Option<String>,
+    // Archive members indexed whatever their extension
+    pub force_extensions: Vec<String>,
+    // Skip archive members that can't be read instead of failing the whole source
+    pub keep_going: bool
+}
+
+// The source at `path` as a buffer the indexers parse: files are read as they are, feeds are
+// fetched, and record sources, DocumentSources among them, are packed into records, see records.rs
+pub fn read_source(path: &str, format: Format, source: &SourceOptions) -> Result<String, io::Error> {
+    match format {
+        Format::Rss => fetch_feed(path),
+        Format::Sql => {
+            let query = source.sql_query.as_ref()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "--format sql requires --sql-query"))?;
+            query_records(path, query, &source.columns)
+        },
+        Format::Parquet => read_parquet_records(path, &source.columns),
+        Format::Jsonl => read_jsonl_records(path, &source.columns),
+        Format::Archive if Path::new(path).is_dir() => read_directory_records(path, &source.force_extensions, source.keep_going),
+        Format::Archive => read_archive_records(path, &source.force_extensions, source.keep_going),
+        Format::Warc if path.ends_with(".gz") => read_gzipped(path),
+        Format::Records => Err(io::Error::new(io::ErrorKind::InvalidInput, "records have no path to read, see read_documents")),
+        _ => fs::read_to_string(path)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mode {
+    // English words, stemmed, with stopwords dropped
+    Text,
+    // Source code, see Analyzer::for_code
+    Code
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OversizePolicy {
+    // Index only the first max_doc_bytes of the text
+    Truncate,
+    // Keep the document but index none of its text
+    Skip,
+    // Index the whole text and print a warning
+    Warn
+}
+
+// What the index records beyond which documents each term appears in. All are off by default so
+// boolean matching only pays for the document sets.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct Schema {
+    // Token positions within the text, counting stopwords, for phrase and proximity matching
+    pub positions: bool,
+    // Byte offsets of each token relative to the start of the text, for highlighting
+    pub offsets: bool,
+    // How many times each term occurs in each document, title and keyword terms included, for
+    // results to report, see DocumentIndexer::term_frequency
+    pub frequencies: bool
+}
+
+impl Schema {
+    fn captures_tokens(&self) -> bool {
+        self.positions || self.offsets
+    }
+
+    // Whether the position index gets an entry per term and document, and the cache a .pos file
+    fn has_entries(&self) -> bool {
+        self.captures_tokens() || self.frequencies
+    }
+}
+
+#[derive(Clone)]
+pub struct IndexOptions {
+    pub format: Format,
+    pub schema: Schema,
+    // Mail headers (lowercase names) indexed verbatim as "name:value" keyword terms
+    pub keyword_headers: Vec<String>,
+    pub max_doc_bytes: Option<usize>,
+    pub oversize_policy: OversizePolicy,
+    // Soft limit on resident memory in bytes, past which indexing threads spill to temp files
+    pub memory_limit: Option<usize>,
+    // Code from LANGUAGES to analyze queries with instead of the index's English analyzer, whose
+    // documents get that language's stems too
+    pub query_language: Option<String>,
+    pub mode: Mode,
+    // Index "10km" as "10" followed by "km", see Analyzer::with_split_units
+    pub split_units: bool,
+    // Names of the text mode token filters, DEFAULT_TOKEN_FILTERS when None, see filters.rs
+    pub token_filters: Option<Vec<String>>,
+    // Index the words of titles too, see Analyzer::with_search_titles
+    pub search_titles: bool,
+    // Weights of matches by field in ranking, the build's when loading a cache and none are given
+    pub boosts: FieldBoosts,
+    pub limits: QueryLimits,
+    // Write where each document is in its source file to the cache's .raw file, see raw.rs
+    pub raw_offsets: bool
+}
+
+impl Default for IndexOptions {
+    fn default() -> Self {
+        IndexOptions {
+            format: Format::Xml,
+            schema: Schema::default(),
+            keyword_headers: Vec::new(),
+            max_doc_bytes: None,
+            oversize_policy: OversizePolicy::Truncate,
+            memory_limit: None,
+            query_language: None,
+            mode: Mode::Text,
+            split_units: false,
+            token_filters: None,
+            search_titles: false,
+            boosts: FieldBoosts::default(),
+            limits: QueryLimits::default(),
+            raw_offsets: false
+        }
+    }
+}
+
+impl IndexOptions {
+    // Err from token_filters for the first language the token filters don't work for, the index's or
+    // the query language. The indexers' constructors panic on those, see Analyzer::from_options.
+    pub fn check_token_filters(&self) -> Result<(), String> {
+        if let Some(names) = &self.token_filters {
+            for language in std::iter::once("en").chain(self.query_language.as_deref()) {
+                token_filters(names, language)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Ranks with the boosts a cache was built with, unless others were given
+    pub(super) fn use_stored_boosts(&mut self, build: Option<&BuildInfo>) {
+        if let Some(build) = build.filter(|_| self.boosts.is_empty()) {
+            self.boosts = build.boosts.clone();
+        }
+    }
+}
+
+// When a long running process that adds documents rewrites the whole cache rather than just saving
+// each add_documents call as a segment file, folding the pending segments in. The full write is as slow as the first one, so it
+// waits until more than `max_segments` add_documents calls are pending, or until the pending
+// documents reach 1/`merge_factor` of the rest; 0 turns either check off. Compacting by hand is
+// always possible, so both off means only then.
+#[derive(Clone, Copy, Debug)]
+pub struct MergePolicy {
+    pub max_segments: usize,
+    pub merge_factor: usize
+}
+
+impl MergePolicy {
+    pub fn should_compact(&self, indexer: &dyn DocumentIndexer) -> bool {
+        let (segments, documents) = indexer.pending_additions();
+        let compacted = indexer.num_documents() - documents;
+        documents > 0 && ((self.max_segments > 0 && segments > self.max_segments)
+            || (self.merge_factor > 0 && documents * self.merge_factor >= compacted))
+    }
+}
+
+impl Default for MergePolicy {
+    fn default() -> Self {
+        MergePolicy { max_segments: 10, merge_factor: 10 }
+    }
+}
+
+// Aborts a build from another thread. Clones share the flag, so hand one to the indexer with
+// DocumentIndexer::set_cancel_token and keep one to cancel with. A cancelled build stops at its next
+// document, leaves the indexer empty and drops the contents; call reset before building again.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    // Only stores to an atomic, so it's safe to call from a signal handler
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::Relaxed);
+    }
+}
+
+// `data` is the slice of the contents starting at `base_offset` that holds the document's text
+fn enforce_max_doc_bytes(doc: &mut DocumentRaw, data: &str, base_offset: usize, options: &IndexOptions) {
+    let max_doc_bytes = match options.max_doc_bytes {
+        Some(max) if doc.text.len() > max => max,
+        _ => return
+    };
+    doc.oversized = true;
+    match options.oversize_policy {
+        OversizePolicy::Truncate => {
+            let mut end = doc.text.start + max_doc_bytes;
+            while !data.is_char_boundary(end - base_offset) {
+                end -= 1;
+            }
+            doc.text.end = end;
+        },
+        OversizePolicy::Skip => doc.text.end = doc.text.start,
+        OversizePolicy::Warn => println!("Document {} has {} bytes of text, over the {} byte limit", doc.id, doc.text.len(), max_doc_bytes)
+    }
+}
+
 type HashMapInvertedIndex = HashMap<String, HashSet<i32, BuildHasherDefault<FxHasher>>, BuildHasherDefault<FxHasher>>;
 
-struct Analyzer {
-    stopwords: HashSet<&'static str>,
-    stemmer: rust_stemmers::Stemmer,
+// Thread-local postings during a build, keyed by TermIds instead of the term itself
+type TermIdIndex = HashMap<u32, HashSet<i32, BuildHasherDefault<FxHasher>>, BuildHasherDefault<FxHasher>>;
+
+fn new_term_id_index(capacity: usize) -> TermIdIndex {
+    TermIdIndex::with_capacity_and_hasher(capacity, BuildHasherDefault::<FxHasher>::default())
+}
+
+fn merge_postings<K: Eq + std::hash::Hash>(into: &mut HashMap<K, HashSet<i32, BuildHasherDefault<FxHasher>>, BuildHasherDefault<FxHasher>>,
+                                           from: HashMap<K, HashSet<i32, BuildHasherDefault<FxHasher>>, BuildHasherDefault<FxHasher>>) {
+    for (term, ids) in from {
+        match into.get_mut(&term) {
+            Some(joined) => joined.extend(ids),
+            None => {
+                into.insert(term, ids);
+            }
+        }
+    }
+}
+
+// Term -> id assignment shared by every indexing thread of one build, so each distinct term string
+// is owned once and the thread-local indexes merge on integer keys
+struct TermIds {
+    ids: dashmap::DashMap<String, u32>,
+    next_id: std::sync::atomic::AtomicU32
+}
+
+impl TermIds {
+    fn new() -> TermIds {
+        TermIds {
+            ids: dashmap::DashMap::with_capacity(500_000),
+            next_id: std::sync::atomic::AtomicU32::new(0)
+        }
+    }
+
+    fn id_of(&self, term: String) -> u32 {
+        if let Some(id) = self.ids.get(term.as_str()) {
+            return *id;
+        }
+        *self.ids.entry(term).or_insert_with(|| self.next_id.fetch_add(1, std::sync::atomic::Ordering::SeqCst))
+    }
+
+    // Swaps the ids in `index` back for the terms they were assigned to
+    fn resolve(self, index: TermIdIndex) -> HashMapInvertedIndex {
+        let mut terms: Vec<String> = vec![String::new(); self.next_id.into_inner() as usize];
+        for (term, id) in self.ids {
+            terms[id as usize] = term;
+        }
+        let mut resolved = HashMapInvertedIndex::with_capacity_and_hasher(index.len(), BuildHasherDefault::<FxHasher>::default());
+        for (id, ids) in index {
+            resolved.insert(std::mem::take(&mut terms[id as usize]), ids);
+        }
+        resolved
+    }
+
+    // The terms in `index` with their ids, sorted by term, leaving the ids assigned for the rest of
+    // the build
+    fn terms_of(&self, index: &TermIdIndex) -> Vec<(String, u32)> {
+        let mut terms: Vec<(String, u32)> = self.ids.iter()
+            .filter(|entry| index.contains_key(entry.value()))
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        terms.sort_unstable();
+        terms
+    }
+}
+
+// Where one term occurs in one document, only the fields enabled by the schema are filled
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct TermPositions {
+    pub positions: Vec<u32>,
+    pub offsets: Vec<(u32, u32)>,
+    pub frequency: u32
+}
+
+// Kept apart from the inverted index so indexes without positions keep the compact postings
+type PositionIndex = HashMap<String, HashMap<i32, TermPositions, BuildHasherDefault<FxHasher>>, BuildHasherDefault<FxHasher>>;
+
+fn new_position_index() -> PositionIndex {
+    PositionIndex::with_hasher(BuildHasherDefault::<FxHasher>::default())
+}
+
+// Documents are indexed by exactly one thread, so the per-document entries never collide
+fn merge_positions(into: &mut PositionIndex, from: PositionIndex) {
+    for (term, docs) in from {
+        match into.get_mut(&term) {
+            Some(joined) => joined.extend(docs),
+            None => {
+                into.insert(term, docs);
+            }
+        }
+    }
+}
+
+// What DocumentIndexer::term_frequency gives for backends that keep a position index
+fn find_term_frequency(positions: &PositionIndex, schema: Schema, term: &str, id: i32) -> Option<u32> {
+    match schema.frequencies {
+        true => positions.get(term).and_then(|docs| docs.get(&id)).map(|p| p.frequency),
+        false => None
+    }
+}
+
+// Byte ranges of `term` within a document's text: the recorded offsets when the schema has them,
+// otherwise found by analyzing the text again
+fn find_term_offsets(analyzer: &Analyzer, positions: &PositionIndex, schema: Schema, term: &str, doc: &Document) -> Vec<(u32, u32)> {
+    if schema.offsets {
+        return positions.get(term).and_then(|docs| docs.get(&doc.id)).map(|p| p.offsets.clone()).unwrap_or_default();
+    }
+    let mut tokens: Vec<String> = Vec::new();
+    let mut token_positions: Vec<TokenPosition> = Vec::new();
+    analyzer.analyze_into(&doc.text, &mut Vec::new(), &mut tokens, Some(&mut token_positions));
+    tokens.iter().zip(token_positions).filter(|(token, _)| *token == term).map(|(_, at)| (at.start, at.end)).collect()
+}
+
+#[derive(Clone, Copy)]
+struct TokenPosition {
+    position: u32,
+    start: u32,
+    end: u32
+}
+
+// ISO 639-1 codes of the languages Analyzer::for_language supports
+pub const LANGUAGES: [&str; 18] = ["ar", "da", "de", "el", "en", "es", "fi", "fr", "hu", "it", "nl", "no", "pt", "ro", "ru", "sv", "ta", "tr"];
+
+fn language_algorithm(code: &str) -> rust_stemmers::Algorithm {
+    use rust_stemmers::Algorithm;
+    match code {
+        "ar" => Algorithm::Arabic,
+        "da" => Algorithm::Danish,
+        "de" => Algorithm::German,
+        "el" => Algorithm::Greek,
+        "en" => Algorithm::English,
+        "es" => Algorithm::Spanish,
+        "fi" => Algorithm::Finnish,
+        "fr" => Algorithm::French,
+        "hu" => Algorithm::Hungarian,
+        "it" => Algorithm::Italian,
+        "nl" => Algorithm::Dutch,
+        "no" => Algorithm::Norwegian,
+        "pt" => Algorithm::Portuguese,
+        "ro" => Algorithm::Romanian,
+        "ru" => Algorithm::Russian,
+        "sv" => Algorithm::Swedish,
+        "ta" => Algorithm::Tamil,
+        "tr" => Algorithm::Turkish,
+        _ => panic!("unknown language '{}'", code)
+    }
+}
+
+// Keyword fields every code mode document gets from its url, which is the file path for archives
+const CODE_KEYWORD_FIELDS: [&str; 2] = ["path", "ext"];
+// Field holding identifiers with their case preserved in code mode
+const EXACT_FIELD: &str = "exact";
+// Field of the terms of documents' titles, see Analyzer::with_search_titles
+pub const TITLE_FIELD: &str = "title";
+
+// Turns text into index terms: words put through the token filters in text mode, by default
+// lowercased, without stopwords and stemmed, see for_code for code mode. Queries go through the
+// documents' analyzer unless a query language is set.
+#[derive(Clone)]
+pub struct Analyzer {
+    // In the order they're applied, with the names they were made from
+    filters: Vec<NamedFilter>,
+    language: String,
+    keyword_fields: HashSet<String>,
+    mode: Mode,
+    split_units: bool,
+    search_titles: bool
 }
 
 impl Analyzer {
-    fn new_english() -> Analyzer {
-        Analyzer { 
-            stopwords: vec!["a", "and", "be", "have", "i", "in", "of", "that", "the", "to"].into_iter().collect(),
-            stemmer: rust_stemmers::Stemmer::create(rust_stemmers::Algorithm::English)
+    pub fn new_english() -> Analyzer {
+        Analyzer::for_language("en")
+    }
+
+    // No stopwords or stemming. Identifiers (runs of alphanumerics and '_') are indexed lowercased
+    // whole, as their camelCase/snake_case/digit delimited parts, and verbatim as "exact:Ident".
+    // Documents with a url also get "path:" and "ext:" keyword terms.
+    pub fn for_code() -> Analyzer {
+        Analyzer {
+            filters: Vec::new(),
+            language: String::from("en"),
+            keyword_fields: CODE_KEYWORD_FIELDS.iter().map(|f| String::from(*f)).collect(),
+            mode: Mode::Code,
+            split_units: false,
+            search_titles: false
+        }
+    }
+
+    // Only English has a stopword list, other languages keep every word. `code` is one of LANGUAGES.
+    pub fn for_language(code: &str) -> Analyzer {
+        let defaults: Vec<String> = DEFAULT_TOKEN_FILTERS.iter().map(|name| String::from(*name)).collect();
+        Analyzer {
+            filters: token_filters(&defaults, code).unwrap(),
+            language: String::from(code),
+            keyword_fields: HashSet::new(),
+            mode: Mode::Text,
+            split_units: false,
+            search_titles: false
+        }
+    }
+
+    // The analyzer for documents, and the one for queries when a query language overrides it, in
+    // which case documents are also indexed with that language's stems, see with_query_stems.
+    // Panics on token filters token_filters refuses, callers check them first with
+    // IndexOptions::check_token_filters.
+    fn from_options(options: &IndexOptions) -> (Analyzer, Option<Analyzer>) {
+        if options.mode == Mode::Code {
+            return (Analyzer::for_code(), None);
+        }
+        let for_language = |code: &str| {
+            let analyzer = Analyzer::for_language(code).with_keyword_fields(&options.keyword_headers).with_split_units(options.split_units)
+                .with_search_titles(options.search_titles);
+            match &options.token_filters {
+                Some(names) => analyzer.with_token_filters(names).unwrap_or_else(|e| panic!("{}", e)),
+                None => analyzer
+            }
+        };
+        match options.query_language.as_deref() {
+            Some(code) => (for_language("en").with_query_stems(code), Some(for_language(code))),
+            None => (for_language("en"), None)
+        }
+    }
+
+    // Words are also indexed as `code`'s stemmer has them, at their own position, where that isn't
+    // how they're stemmed already, so a query analyzed in `code` finds "Häuser" by "Hauses". Only
+    // replaces a "stemmer" filter, without one the query language stems nothing either.
+    fn with_query_stems(mut self, code: &str) -> Analyzer {
+        if code != self.language {
+            for (_, filter) in self.filters.iter_mut().filter(|(name, _)| name == "stemmer") {
+                *filter = filters::stemmer_with_query_stems(&self.language, code);
+            }
+        }
+        self
+    }
+
+    // Replaces the filters with the ones `names` refer to, see token_filters. Code mode has none.
+    pub fn with_token_filters(mut self, names: &[String]) -> Result<Analyzer, String> {
+        if self.mode == Mode::Text {
+            self.filters = token_filters(names, &self.language)?;
+        }
+        Ok(self)
+    }
+
+    pub fn with_keyword_fields(mut self, fields: &[String]) -> Analyzer {
+        self.keyword_fields = fields.iter().map(|f| f.to_lowercase()).collect();
+        self
+    }
+
+    // A number directly followed by letters, "10km" or "1,000mg", becomes two terms at consecutive
+    // positions so it's found by "10 km" and the other way around. Off by default, it also splits
+    // words like "2nd" and "4k".
+    pub fn with_split_units(mut self, split_units: bool) -> Analyzer {
+        self.split_units = split_units;
+        self
+    }
+
+    // Each document's title, when it isn't its whole text as in lines format, is analyzed like the
+    // text and its terms indexed both as they are and as "title:term", which is what a title: query
+    // term looks up. A plain query term also lists the "title:" one, so a match in the title counts
+    // as two terms matched and ranks above one only in the text. Text mode only.
+    pub fn with_search_titles(mut self, search_titles: bool) -> Analyzer {
+        self.search_titles = search_titles && self.mode == Mode::Text;
+        self
+    }
+
+    // Same configuration for handing to a worker thread, which shares the filters
+    fn fork(&self) -> Analyzer {
+        self.clone()
+    }
+
+    // Query terms of the form "field:value" for a keyword field are matched verbatim, see query.rs
+    // for escaping
+    pub fn analyze_query(&self, term: &str) -> Vec<String> {
+        let term = query::parse_term(term.trim());
+        match term.field {
+            Some(field) if self.search_titles && field.eq_ignore_ascii_case(TITLE_FIELD) => {
+                self.analyze(&term.text).into_iter().map(|term| format!("{}:{}", TITLE_FIELD, term)).collect()
+            },
+            Some(field) if self.keyword_fields.contains(&field.to_lowercase()) => {
+                let value = term.text.split_whitespace().collect::<Vec<&str>>().join(" ");
+                vec![format!("{}:{}", field, value).to_lowercase()]
+            },
+            Some(field) if self.mode == Mode::Code && field == EXACT_FIELD => vec![format!("{}:{}", EXACT_FIELD, term.text.trim())],
+            // Identifier parts are only indexed, so a query for "HashMap" doesn't match every "map"
+            Some(field) if self.mode == Mode::Code => code_identifiers(&format!("{}:{}", field, term.text)).map(str::to_lowercase).collect(),
+            None if self.mode == Mode::Code => code_identifiers(&term.text).map(str::to_lowercase).collect(),
+            Some(field) => self.analyze(&format!("{}:{}", field, term.text)),
+            None => self.analyze(&term.text)
+        }
+    }
+
+    // What a prefix query's terms must start with: lowercased, but not stemmed since "comput" isn't a
+    // word, see query.rs
+    fn analyze_prefix(&self, prefix: &str) -> String {
+        let term = query::parse_term(prefix.trim());
+        match term.field {
+            Some(field) => format!("{}:{}", field, term.text).to_lowercase(),
+            None => term.text.to_lowercase()
+        }
+    }
+
+    // The terms of a phrase with their word positions, which count dropped stopwords. In code mode
+    // only whole identifiers, like analyze_query.
+    fn analyze_phrase(&self, phrase: &str) -> Vec<(String, u32)> {
+        if self.mode == Mode::Code {
+            return code_identifiers(phrase).enumerate().map(|(position, word)| (word.to_lowercase(), position as u32)).collect();
+        }
+        let mut tokens: Vec<String> = Vec::new();
+        let mut positions: Vec<TokenPosition> = Vec::new();
+        self.analyze_into(phrase, &mut Vec::new(), &mut tokens, Some(&mut positions));
+        tokens.into_iter().zip(positions).map(|(token, at)| (token, at.position)).collect()
+    }
+
+    pub fn analyze(&self, letters: &str) -> Vec<String> {
+        let mut tokens: Vec<String> = Vec::new();
+        self.analyze_into(letters, &mut Vec::new(), &mut tokens, None);
+        tokens
+    }
+
+    // Appends the terms of `letters` to `tokens`, putting each word through the filters in
+    // `word_tokens` first so callers that keep both buffers around only allocate for the terms.
+    // When `positions` is given it gets one entry per pushed token.
+    fn analyze_into(&self, letters: &str, word_tokens: &mut Vec<String>, tokens: &mut Vec<String>, mut positions: Option<&mut Vec<TokenPosition>>) {
+        if self.mode == Mode::Code {
+            return analyze_code_into(letters, tokens, positions);
+        }
+        let mut position: u32 = 0;
+        for word in text_words(letters) {
+            for piece in self.word_pieces(word).iter().filter(|piece| !piece.is_empty()) {
+                // Drops the separators of numbers
+                word_tokens.clear();
+                word_tokens.push(piece.chars().filter(|c| c.is_alphanumeric()).collect());
+                for (_, filter) in self.filters.iter() {
+                    filter.filter(word_tokens);
+                }
+                let start = piece.as_ptr() as usize - letters.as_ptr() as usize;
+                for token in word_tokens.drain(..).filter(|token| !token.is_empty()) {
+                    tokens.push(token);
+                    if let Some(positions) = positions.as_mut() {
+                        positions.push(TokenPosition {
+                            position,
+                            start: start as u32,
+                            end: (start + piece.len()) as u32
+                        });
+                    }
+                }
+                position += 1;
+            }
+        }
+    }
+
+    // A word from text_words as the one or two pieces it's indexed as, see with_split_units
+    fn word_pieces<'a>(&self, word: &'a str) -> [&'a str; 2] {
+        let unit_start = match self.split_units && word.starts_with(|c: char| c.is_ascii_digit()) {
+            true => word.find(char::is_alphabetic).filter(|&at| word[at..].chars().all(char::is_alphabetic)),
+            false => None
+        };
+        match unit_start {
+            Some(at) => [&word[..at], &word[at..]],
+            None => [word, ""]
+        }
+    }
+}
+
+const DIGIT_GROUP_SEPARATORS: [char; 4] = [',', '_', '\'', '\u{202f}'];
+
+// The words of text mode: runs of alphanumerics, except that numbers written with thousands
+// separators, "1,000,000" or "10_000", stay whole so they're indexed like "1000000". A separator
+// only joins groups of exactly three digits after a first group of one to three, so lists like
+// "1,2,3" or "2020,2021" are still split.
+fn text_words(letters: &str) -> impl Iterator<Item = &str> {
+    let mut rest = letters;
+    std::iter::from_fn(move || {
+        let start = rest.find(char::is_alphanumeric)?;
+        rest = &rest[start..];
+        let run_end = |from: usize| rest[from..].find(|c: char| !c.is_alphanumeric()).map_or(rest.len(), |i| from + i);
+        let mut end = run_end(0);
+        if end <= 3 && rest[..end].bytes().all(|b| b.is_ascii_digit()) {
+            while let Some(separator) = rest[end..].chars().next().filter(|c| DIGIT_GROUP_SEPARATORS.contains(c)) {
+                let group = &rest[end + separator.len_utf8()..];
+                if group.bytes().take_while(u8::is_ascii_digit).count() != 3 {
+                    break;
+                }
+                // Letters right after a group are a unit and end the number, "1,000km"
+                let group_end = end + separator.len_utf8() + 3;
+                end = run_end(group_end);
+                if end > group_end {
+                    break;
+                }
+            }
+        }
+        let word = &rest[..end];
+        rest = &rest[end..];
+        Some(word)
+    })
+}
+
+fn code_identifiers(letters: &str) -> impl Iterator<Item = &str> {
+    letters.split(|c: char| !(c.is_alphanumeric() || c == '_')).filter(|x| !x.is_empty())
+}
+
+// Splits an identifier on underscores, lower to upper case changes ("parseJson"), the last capital
+// of an acronym ("HTTPServer") and letter/digit changes ("utf8")
+fn identifier_parts(word: &str) -> Vec<&str> {
+    let chars: Vec<(usize, char)> = word.char_indices().collect();
+    let mut parts: Vec<&str> = Vec::new();
+    let mut start: Option<usize> = None;
+    for (i, &(at, c)) in chars.iter().enumerate() {
+        if c == '_' {
+            if let Some(start) = start.take() {
+                parts.push(&word[start..at]);
+            }
+            continue;
+        }
+        let part_start = match start {
+            Some(part_start) => part_start,
+            None => {
+                start = Some(at);
+                continue;
+            }
+        };
+        let prev = chars[i - 1].1;
+        let next_lower = chars.get(i + 1).is_some_and(|(_, n)| n.is_lowercase());
+        if (prev.is_lowercase() && c.is_uppercase())
+            || (prev.is_uppercase() && c.is_uppercase() && next_lower)
+            || prev.is_numeric() != c.is_numeric() {
+            parts.push(&word[part_start..at]);
+            start = Some(at);
+        }
+    }
+    if let Some(start) = start {
+        parts.push(&word[start..]);
+    }
+    parts
+}
+
+// Analyzer::analyze_into for Mode::Code. Parts of an identifier share its position.
+fn analyze_code_into(letters: &str, tokens: &mut Vec<String>, mut positions: Option<&mut Vec<TokenPosition>>) {
+    for (position, word) in code_identifiers(letters).enumerate() {
+        let start = word.as_ptr() as usize - letters.as_ptr() as usize;
+        let mut push = |token: String, span: &str| {
+            tokens.push(token);
+            if let Some(positions) = positions.as_mut() {
+                let offset = start + (span.as_ptr() as usize - word.as_ptr() as usize);
+                positions.push(TokenPosition { position: position as u32, start: offset as u32, end: (offset + span.len()) as u32 });
+            }
+        };
+        push(word.to_lowercase(), word);
+        let parts = identifier_parts(word);
+        if parts.len() > 1 {
+            for part in parts {
+                push(part.to_lowercase(), part);
+            }
+        }
+        push(format!("{}:{}", EXACT_FIELD, word), word);
+    }
+}
+
+// "path:" and "ext:" keyword terms for a code mode document's url
+fn path_keywords(path: &str) -> Vec<String> {
+    let path = path.trim();
+    if path.is_empty() {
+        return Vec::new();
+    }
+    let mut keywords = vec![format!("path:{}", path.to_lowercase())];
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    if let Some((_, ext)) = file_name.rsplit_once('.') {
+        keywords.push(format!("ext:{}", ext.to_lowercase()));
+    }
+    keywords
+}
+
+// Owned by a single indexing thread: a forked analyzer plus scratch buffers reused across documents.
+// When the schema asks for them, text token positions are collected into the thread's own
+// PositionIndex as documents are analyzed, see take_positions.
+struct ThreadAnalyzer {
+    analyzer: Analyzer,
+    schema: Schema,
+    word_tokens: Vec<String>,
+    tokens: Vec<String>,
+    token_positions: Vec<TokenPosition>,
+    positions: PositionIndex
+}
+
+impl ThreadAnalyzer {
+    fn new(analyzer: &Analyzer, schema: Schema) -> ThreadAnalyzer {
+        ThreadAnalyzer {
+            analyzer: analyzer.fork(),
+            schema,
+            word_tokens: Vec::new(),
+            tokens: Vec::new(),
+            token_positions: Vec::new(),
+            positions: new_position_index()
+        }
+    }
+
+    fn analyze_document(&mut self, full_contents: &str, doc: &DocumentRaw) -> std::vec::Drain<'_, String> {
+        self.tokens.clear();
+        if self.schema.captures_tokens() {
+            self.token_positions.clear();
+            self.analyzer.analyze_into(&full_contents[doc.text.clone()], &mut self.word_tokens, &mut self.tokens, Some(&mut self.token_positions));
+            self.record_positions(doc.id);
+        } else {
+            self.analyzer.analyze_into(&full_contents[doc.text.clone()], &mut self.word_tokens, &mut self.tokens, None);
+        }
+        if !self.analyzer.keyword_fields.is_empty() && !doc.keywords.is_empty() {
+            self.tokens.extend(mbox::header_keywords(&full_contents[doc.keywords.clone()], &self.analyzer.keyword_fields));
+        }
+        if self.analyzer.mode == Mode::Code {
+            self.tokens.extend(path_keywords(&full_contents[doc.url.clone()]));
+        }
+        if self.analyzer.search_titles && !doc.title.is_empty() && doc.title != doc.text {
+            let start = self.tokens.len();
+            self.analyzer.analyze_into(&full_contents[doc.title.clone()], &mut self.word_tokens, &mut self.tokens, None);
+            for i in start..self.tokens.len() {
+                let title_term = format!("{}:{}", TITLE_FIELD, self.tokens[i]);
+                self.tokens.push(title_term);
+            }
         }
+        if self.schema.frequencies {
+            self.record_frequencies(doc.id);
+        }
+        self.tokens.drain(..)
     }
 
-    fn analyze(&self, letters: &str) -> Vec<String> {
-        letters.split(|c: char| !c.is_alphanumeric())
-            .map(|x| x.to_lowercase())
-            .filter(|x| !self.stopwords.contains(x.as_str()) && !x.is_empty())
-            .map(|x| self.stemmer.stem(&x).into_owned()).collect()
+    // Counts every term of the document, keyword and title terms too, which have no positions
+    fn record_frequencies(&mut self, doc_id: i32) {
+        for token in self.tokens.iter() {
+            if !self.positions.contains_key(token) {
+                self.positions.insert(token.clone(), HashMap::with_hasher(BuildHasherDefault::<FxHasher>::default()));
+            }
+            self.positions.get_mut(token).unwrap().entry(doc_id).or_default().frequency += 1;
+        }
+    }
+
+    fn record_positions(&mut self, doc_id: i32) {
+        for (token, at) in self.tokens.iter().zip(&self.token_positions) {
+            if !self.positions.contains_key(token) {
+                self.positions.insert(token.clone(), HashMap::with_hasher(BuildHasherDefault::<FxHasher>::default()));
+            }
+            let entry = self.positions.get_mut(token).unwrap().entry(doc_id).or_default();
+            if self.schema.positions {
+                entry.positions.push(at.position);
+            }
+            if self.schema.offsets {
+                entry.offsets.push((at.start, at.end));
+            }
+        }
+    }
+
+    // Hands over the positions gathered so far and starts a fresh map
+    fn take_positions(&mut self) -> PositionIndex {
+        std::mem::replace(&mut self.positions, new_position_index())
     }
 }
 
@@ -58,7 +938,7 @@ pub struct Document {
     pub id: i32
 }
 
-impl<'a> PartialEq for Document {
+impl PartialEq for Document {
     fn eq(&self, other: &Self) -> bool {
         self.id == other.id
     }
@@ -83,14 +963,29 @@ pub struct DocumentRaw {
     pub title: Range<usize>,
     pub url: Range<usize>,
     pub text: Range<usize>,
-    pub id: i32
+    // Format-specific region holding keyword fields (the header block for mbox), empty otherwise
+    pub keywords: Range<usize>,
+    pub id: i32,
+    // Text was over IndexOptions::max_doc_bytes when parsed, the policy decides what was indexed
+    pub oversized: bool
 }
 
 impl DocumentRaw {
-    fn to_document(&self, full_document: &dyn SomeBytes) -> Document {
+    // For a document parsed out of a chunk that starts `by` bytes into the source
+    fn shift(&mut self, by: usize) {
+        for range in [&mut self.title, &mut self.url, &mut self.text, &mut self.keywords] {
+            *range = range.start + by..range.end + by;
+        }
+    }
+
+    fn to_document(&self, full_document: &dyn SomeBytes, format: Format, sources: &SourceFiles) -> Document {
+        let url = match format {
+            Format::Xml | Format::Mbox | Format::Rss | Format::Sql | Format::Parquet | Format::Archive | Format::Warc | Format::Jsonl | Format::Records => String::from(full_document.from_utf8_unchecked(self.url.clone())),
+            Format::Lines => sources.line_url(self.text.start, self.id)
+        };
         Document {
             title: String::from(full_document.from_utf8_unchecked(self.title.clone())),
-            url: String::from(full_document.from_utf8_unchecked(self.url.clone())),
+            url,
             text: String::from(full_document.from_utf8_unchecked(self.text.clone())),
             id: self.id
         }
@@ -123,22 +1018,41 @@ impl Default for DocumentRaw {
             title: Range{start: 0, end: 0},
             url: Range{start: 0, end: 0},
             text: Range{start: 0, end: 0},
-            id: 0
+            keywords: Range{start: 0, end: 0},
+            id: 0,
+            oversized: false
         }
     }
 }
 
 type BoxedBytes = Box<dyn SomeBytes>;
 
-pub struct SearchResults {
-    pub term: String,
-    pub matches: Vec<Document>
+// Bytes of the source a streaming build reads at a time, see DocumentIndexer::build_streaming
+pub const STREAM_CHUNK_BYTES: usize = 64 << 20;
+
+// What the cache files next to a source are named with, see SerializedIndex::write_index_to_path,
+// and its writer's CacheLock
+pub const CACHE_EXTENSIONS: [&str; 11] = ["idx", "dcm", "pos", "add", "del", "sum", "meta", "mix", "raw", vectors::VECTORS_EXTENSION, lock::LOCK_EXTENSION];
+
+// One of CACHE_EXTENSIONS, or a segment file's, see cache_segments.rs
+pub fn is_cache_extension(extension: &str) -> bool {
+    CACHE_EXTENSIONS.contains(&extension) || cache_segments::segment_number(extension).is_some()
 }
 
 pub struct SerializedIndex {
+    source: String,
     inverted_index: BoxedBytes,
     documents: BoxedBytes,
-    file_contents: BoxedBytes
+    file_contents: BoxedBytes,
+    // The .pos file, only written for indexes whose schema has entries, see positions.rs
+    positions: Option<BoxedBytes>,
+    // Documents added after the build and the text they point into, see DocumentIndexer::add_documents
+    additions: Option<BoxedBytes>,
+    // The segment files of add_documents calls since the last full write, in document order
+    segments: Vec<BoxedBytes>,
+    // The ids delete_documents was given
+    deletions: Option<BoxedBytes>,
+    build: Option<BuildInfo>
 }
 
 // Adapted from
@@ -159,6 +1073,8 @@ impl SerializedIndex {
         let base_path = Path::new(file_to_index_path);
         let inverted_index_path = base_path.with_extension("idx");
         let doc_index_path = base_path.with_extension("dcm");
+        let positions_path = base_path.with_extension("pos");
+        let additions_path = base_path.with_extension("add");
 
         println!("trying {:?}", &base_path);
         let file_content = open_mmap(base_path)?;
@@ -170,59 +1086,739 @@ impl SerializedIndex {
 
         let doc_index = fs::read(doc_index_path.as_path())?;
         println!("read doc index {:?}", doc_index_path);
+        migrate::check_documents_layout(&doc_index)?;
+
+        let positions = match fs::read(&positions_path) {
+            Ok(positions) => {
+                println!("read positions {:?}", positions_path);
+                Some(positions)
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e)
+        };
+
+        let additions = match fs::read(&additions_path) {
+            Ok(additions) => {
+                println!("read additions {:?}", additions_path);
+                Some(additions)
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e)
+        };
+
+        let manifest = disk::read_manifest(base_path)?;
+        let mut segments: Vec<(String, Vec<u8>)> = Vec::new();
+        for extension in cache_segments::segment_extensions(base_path, manifest.as_ref())? {
+            let segment = fs::read(base_path.with_extension(&extension))?;
+            println!("read segment {:?}", base_path.with_extension(&extension));
+            segments.push((extension, segment));
+        }
+
+        let deletions = match fs::read(base_path.with_extension("del")) {
+            Ok(deletions) => Some(deletions),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e)
+        };
+
+        let build = match fs::read(base_path.with_extension("meta")) {
+            Ok(build) => Some(build),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e)
+        };
+
+        if let Some(manifest) = manifest {
+            let mut files: Vec<(&str, Option<&[u8]>)> = vec![
+                ("idx", Some(&inverted_index)),
+                ("dcm", Some(&doc_index)),
+                ("pos", positions.as_deref()),
+                ("add", additions.as_deref()),
+                ("del", deletions.as_deref()),
+                ("meta", build.as_deref())
+            ];
+            files.extend(segments.iter().map(|(extension, segment)| (extension.as_str(), Some(segment.as_slice()))));
+            disk::verify(&manifest, base_path, &files)?;
+            println!("verified checksums");
+        }
+
+        let build: Option<BuildInfo> = match build {
+            Some(build) => Some(serde_json::from_slice(&build).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("meta: {}", e)))?),
+            None => None
+        };
+        if let Some(build) = &build {
+            // The length prefix of the bincode sequence
+            let documents: u64 = bincode::deserialize(&doc_index).unwrap_or(0);
+            build.check_cache(base_path, documents as usize)?;
+        }
 
         Ok(SerializedIndex {
+            source: String::from(file_to_index_path),
             inverted_index: Box::new(inverted_index),
             documents: Box::new(doc_index),
-            file_contents: Box::new(file_content)
+            file_contents: Box::new(file_content),
+            positions: positions.map(|positions| Box::new(positions) as BoxedBytes),
+            additions: additions.map(|additions| Box::new(additions) as BoxedBytes),
+            segments: segments.into_iter().map(|(_, segment)| Box::new(segment) as BoxedBytes).collect(),
+            deletions: deletions.map(|deletions| Box::new(deletions) as BoxedBytes),
+            build
         })
     }
 
-    pub fn write_index_to_path(file_to_index_path: &str, indexer: &dyn DocumentIndexer) -> Result<(), io::Error> {
+    // What the cache next to `file_to_index_path` holds and how it was built, from the file headers
+    // and the .meta file, without loading postings or documents
+    pub fn metadata(file_to_index_path: &str) -> Result<IndexMetadata, io::Error> {
+        IndexMetadata::read(file_to_index_path)
+    }
+
+    // Why the cache next to `file_to_index_path` shouldn't be loaded, None when it's current or there
+    // is none, see metadata::stale_reason
+    pub fn stale_reason(file_to_index_path: &str) -> Option<String> {
+        metadata::stale_reason(Path::new(file_to_index_path))
+    }
+
+    // Ok when there's a cache next to `file_to_index_path` that isn't stale, which may still fail to
+    // load. Missing when neither its .idx nor its .dcm is there.
+    pub fn check_current(file_to_index_path: &str) -> Result<(), CacheError> {
         let base_path = Path::new(file_to_index_path);
-        let inverted_index_path = base_path.with_extension("idx.tmp");
-        let doc_index_path = base_path.with_extension("dcm.tmp");
-        {
-            let mut inverted_index = File::create(&inverted_index_path)?;
-            let mut doc_index = File::create(&doc_index_path)?;
-            inverted_index.write_all(&indexer.get_serialized_inverted_index())?;
-            doc_index.write_all(&indexer.get_serialized_documents())?;
-        }
-        fs::rename(&inverted_index_path, inverted_index_path.with_extension("").with_extension("idx"))?;
-        fs::rename(&doc_index_path, doc_index_path.with_extension("").with_extension("dcm"))?;
+        if !base_path.with_extension("idx").exists() && !base_path.with_extension("dcm").exists() {
+            return Err(CacheError::Missing);
+        }
+        match SerializedIndex::stale_reason(file_to_index_path) {
+            Some(reason) => Err(CacheError::Stale(reason)),
+            None => Ok(())
+        }
+    }
+
+    // Corrupt when the .pos file can't be read, so the index is rebuilt rather than loaded
+    pub fn schema(&self) -> Result<Schema, CacheError> {
+        match &self.positions {
+            Some(positions) => positions::read_schema((**positions).as_ref()).map_err(|e| CacheError::Corrupt(e.to_string())),
+            None => Ok(Schema::default())
+        }
+    }
+
+    fn deserialize_positions(&self) -> Result<PositionIndex, CacheError> {
+        match &self.positions {
+            Some(positions) => positions::read_positions((**positions).as_ref()).map(|(_, positions)| positions).map_err(|e| CacheError::Corrupt(e.to_string())),
+            None => Ok(new_position_index())
+        }
+    }
+
+    // The added documents, their text, how many of them the .idx and .pos files cover, and how many
+    // add_documents calls added the rest
+    fn deserialize_additions(&self) -> (Vec<DocumentRaw>, String, usize, usize) {
+        match &self.additions {
+            Some(additions) => bincode::deserialize((**additions).as_ref()).unwrap(),
+            None => (Vec::new(), String::new(), 0, 0)
+        }
+    }
+
+    fn deserialize_segments(&self) -> Vec<cache_segments::CacheSegment> {
+        self.segments.iter().map(|segment| cache_segments::read_segment((**segment).as_ref()).unwrap()).collect()
+    }
+
+    fn deserialize_deletions(&self) -> Vec<i32> {
+        match &self.deletions {
+            Some(deletions) => bincode::deserialize((**deletions).as_ref()).unwrap(),
+            None => Vec::new()
+        }
+    }
+
+    // Writes each file to the file with its extension next to `base_path`, or removes that file when
+    // there's nothing to write, so a stale one from an earlier build isn't loaded with this index.
+    // Everything goes to temp files first, which are only renamed into place once all of them are
+    // written, and removed if any fails. Nothing is written unless the disk has room for all of it,
+    // see disk.rs, and nothing at all after disk::forbid_writes. The checksum manifest is renamed
+    // last; a full write, given the number of documents it `covers`, starts one, others only update
+    // an existing one, and removes the segment files of the documents it covers. Fails while another
+    // process holds the CacheLock. Writes in this process may overlap, as a BackgroundMerge and the
+    // segments saved meanwhile do: only reading the manifest through to the renames is one at a time.
+    fn write_files(base_path: &Path, files: Vec<(&str, Option<disk::CacheFile>)>, sync: SyncPolicy, covers: Option<usize>) -> Result<(), io::Error> {
+        static COMMIT: Mutex<()> = Mutex::new(());
+        static WRITES: AtomicUsize = AtomicUsize::new(0);
+        let _lock = CacheLock::acquire(base_path)?;
+        let needed: u64 = files.iter().filter_map(|(_, file)| file.as_ref()).map(|file| file.size).sum();
+        if let Some(available) = disk::available_bytes(base_path) {
+            if available < needed + disk::RESERVE_BYTES {
+                return Err(disk::out_of_space(base_path, needed, available));
+            }
+        }
+        let write_number = WRITES.fetch_add(1, Ordering::Relaxed);
+        let mut written: Vec<(PathBuf, PathBuf)> = Vec::new();
+        let mut write = |extension: &str, file: &disk::CacheFile| {
+            let tmp_path = base_path.with_extension(format!("{}.{}.tmp", extension, write_number));
+            let result = disk::write_checked(&tmp_path, file, sync);
+            written.push((tmp_path, base_path.with_extension(extension)));
+            result
+        };
+        let mut crcs: Vec<(&str, Option<u32>)> = Vec::new();
+        let mut result: Result<(), io::Error> = Ok(());
+        for (extension, file) in files.iter() {
+            match file.as_ref().map(|file| write(extension, file)).transpose() {
+                Ok(crc) => crcs.push((extension, crc)),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        let _commit = COMMIT.lock().unwrap_or_else(|e| e.into_inner());
+        let mut stale_segments: Vec<String> = Vec::new();
+        if result.is_ok() {
+            result = (|| {
+                let mut manifest = match disk::read_manifest(base_path) {
+                    Ok(Some(manifest)) => Some(manifest),
+                    _ if covers.is_some() => Some(disk::Manifest::new()),
+                    _ => None
+                };
+                if let Some(covers) = covers {
+                    for segment in cache_segments::segment_extensions(base_path, manifest.as_ref())? {
+                        match cache_segments::segment_number(&segment).is_some_and(|first_id| (first_id as usize) < covers) {
+                            true => stale_segments.push(segment),
+                            // Saved since the write started, and listed even in a manifest it starts
+                            false => if let Some(manifest) = manifest.as_mut().filter(|manifest| !manifest.contains_key(&segment)) {
+                                let crc = disk::checksum(&fs::read(base_path.with_extension(&segment))?);
+                                manifest.insert(segment, crc);
+                            }
+                        }
+                    }
+                }
+                if let Some(manifest) = manifest.as_mut() {
+                    for segment in stale_segments.iter() {
+                        manifest.remove(segment);
+                    }
+                    for (extension, crc) in crcs.iter() {
+                        match crc {
+                            Some(crc) => manifest.insert(String::from(*extension), *crc),
+                            None => manifest.remove(*extension)
+                        };
+                    }
+                }
+                if let Some(manifest) = &manifest {
+                    write("sum", &disk::manifest_file(manifest))?;
+                }
+                Ok(())
+            })();
+        }
+        if let Err(e) = result {
+            for (tmp_path, _) in written {
+                let _ = fs::remove_file(tmp_path);
+            }
+            return Err(e);
+        }
+        for extension in files.iter().filter(|(_, file)| file.is_none()).map(|(extension, _)| String::from(*extension)).chain(stale_segments) {
+            match fs::remove_file(base_path.with_extension(extension)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        for (tmp_path, path) in written {
+            fs::rename(&tmp_path, &path)?;
+        }
+        if sync == SyncPolicy::All {
+            disk::sync_dir(base_path)?;
+        }
         Ok(())
     }
+
+    pub fn write_index_to_path(file_to_index_path: &str, indexer: &dyn DocumentIndexer, sync: SyncPolicy) -> Result<(), io::Error> {
+        SerializedIndex::write_files(Path::new(file_to_index_path), SerializedIndex::index_files(indexer)?, sync, Some(indexer.num_documents()))
+    }
+
+    // Every file of a full cache write
+    fn index_files(indexer: &dyn DocumentIndexer) -> Result<Vec<(&'static str, Option<disk::CacheFile<'_>>)>, io::Error> {
+        Ok(vec![
+            ("idx", disk::CacheFile::stream(Box::new(move |w| indexer.write_index(w).map(|_| true)))?),
+            ("dcm", disk::CacheFile::stream(Box::new(move |w| indexer.write_documents(w).map(|_| true)))?),
+            ("pos", disk::CacheFile::stream(Box::new(move |w| indexer.write_positions(w)))?),
+            ("add", disk::CacheFile::stream(Box::new(move |w| indexer.write_additions(w)))?),
+            ("del", disk::CacheFile::stream(Box::new(move |w| indexer.write_deletions(w)))?),
+            ("mix", disk::CacheFile::stream(Box::new(move |w| indexer.write_mapped(w)))?),
+            ("raw", disk::CacheFile::stream(Box::new(move |w| indexer.write_raw_offsets(w)))?),
+            ("meta", indexer.build_info().map(|build| disk::CacheFile::bytes(serde_json::to_vec_pretty(&BuildInfo { format_version: CACHE_FORMAT_VERSION, ..build }).unwrap())))
+        ])
+    }
+
+    // Saves what was added and deleted since the last full write without touching the rest of the
+    // cache: each pending add_documents call not yet on disk goes to a segment file of its own, see
+    // cache_segments.rs, and the deleted ids to the .del file. Segments are loaded as they are, until
+    // the next write_index_to_path folds them in; call DocumentIndexer::mark_compacted, with
+    // pending_additions from before, once that succeeds.
+    pub fn write_additions_to_path(file_to_index_path: &str, indexer: &dyn DocumentIndexer, sync: SyncPolicy) -> Result<(), io::Error> {
+        let base_path = Path::new(file_to_index_path);
+        let mut extensions: Vec<String> = Vec::new();
+        let mut segments: Vec<Option<disk::CacheFile>> = Vec::new();
+        for first_id in indexer.pending_segments() {
+            let extension = cache_segments::segment_extension(first_id);
+            if base_path.with_extension(&extension).exists() {
+                continue;
+            }
+            // Serialized once here rather than for sizing and again for writing, since it means
+            // analyzing the segment's documents again
+            let mut data: Vec<u8> = Vec::new();
+            if indexer.write_segment(&mut data, first_id)? {
+                extensions.push(extension);
+                segments.push(Some(disk::CacheFile::bytes(data)));
+            }
+        }
+        let mut files: Vec<(&str, Option<disk::CacheFile>)> = extensions.iter().map(String::as_str).zip(segments).collect();
+        files.push(("del", disk::CacheFile::stream(Box::new(|w| indexer.write_deletions(w)))?));
+        SerializedIndex::write_files(base_path, files, sync, None)
+    }
 }
 
 pub trait DocumentIndexer {
-    fn build_from_file_contents(&mut self, file_contents: String);
+    fn build_from_file_contents(&mut self, source: &str, file_contents: String) -> Result<(), io::Error> {
+        self.build_from_files(SourceFiles::single(source), file_contents)
+    }
+    // Builds one index over several files, `file_contents` being what SourceFiles::read joined them
+    // into. Documents are numbered across the files in order. Err when the postings spilled past
+    // IndexOptions::memory_limit can't be read back, see segments.rs, leaving the indexer empty.
+    fn build_from_files(&mut self, files: SourceFiles, file_contents: String) -> Result<(), io::Error>;
+    // Builds from the file at `path` read STREAM_CHUNK_BYTES at a time, each cut after its last whole
+    // document, so the source needn't fit in memory. Unsupported for backends that can't, and for
+    // formats whose source isn't the file as it is.
+    #[allow(unused_variables)]
+    fn build_streaming(&mut self, path: &str) -> Result<(), io::Error> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "this backend can't stream its source"))
+    }
+    // Builds after this check `token` and stop early once it's cancelled, and searches end with
+    // QueryError::Cancelled. False for backends that can't stop mid-build, whose searches still check it.
     #[allow(unused_variables)]
-    fn build_from_serialized(&mut self, serialized_data: SerializedIndex) {
+    fn set_cancel_token(&mut self, token: CancelToken) -> bool {
+        false
+    }
+    // Resizes the thread pool the backend builds, merges and searches on for `parse_threads` and
+    // `index_threads`, from the next build on. False for backends that run on rayon's global pool.
+    #[allow(unused_variables)]
+    fn set_threads(&mut self, parse_threads: usize, index_threads: usize) -> bool {
+        false
+    }
+    #[allow(unused_variables)]
+    fn build_from_serialized(&mut self, serialized_data: SerializedIndex) -> Result<(), io::Error> {
         panic!("Not implemented");
     }
-    fn get_serialized_inverted_index(&self) -> Vec<u8> {
+    // Opens the cache next to `path` the backend's own way, before SerializedIndex::load_from_path
+    // and build_from_serialized are tried. False, having done nothing, for backends that only load
+    // that way, or when their own files aren't there.
+    #[allow(unused_variables)]
+    fn open_cache(&mut self, path: &str) -> Result<bool, io::Error> {
+        Ok(false)
+    }
+    // The cache files' contents, streamed into `w` so the caller decides on buffering, checksums and
+    // where it all goes, see SerializedIndex::write_index_to_path. Each is called twice per write,
+    // the first time only to size the file, and must write the same both times.
+    #[allow(unused_variables)]
+    fn write_index(&self, w: &mut dyn Write) -> Result<(), io::Error> {
         panic!("Not implemented");
     }
-    fn get_serialized_documents(&self) -> Vec<u8> {
+    #[allow(unused_variables)]
+    fn write_documents(&self, w: &mut dyn Write) -> Result<(), io::Error> {
+        panic!("Not implemented");
+    }
+    // False, having written nothing, when the schema records neither positions, offsets nor frequencies
+    #[allow(unused_variables)]
+    fn write_positions(&self, w: &mut dyn Write) -> Result<bool, io::Error> {
+        Ok(false)
+    }
+    // The .mix file of the mmap backend, see mapped.rs. False, having written nothing, for the others.
+    #[allow(unused_variables)]
+    fn write_mapped(&self, w: &mut dyn Write) -> Result<bool, io::Error> {
+        Ok(false)
+    }
+    // The .raw file, see raw.rs. False, having written nothing, unless IndexOptions::raw_offsets is
+    // set and the documents were parsed from their source as it is.
+    #[allow(unused_variables)]
+    fn write_raw_offsets(&self, w: &mut dyn Write) -> Result<bool, io::Error> {
+        Ok(false)
+    }
+    // Indexes `docs` into the built or loaded index under ids after the existing ones, without a
+    // rebuild. Their own ids are ignored.
+    #[allow(unused_variables)]
+    fn add_documents(&mut self, docs: Vec<Document>) {
+        panic!("Not implemented");
+    }
+    // Every added document and its text, written along with the rest of the cache, which then covers
+    // them all. False, having written nothing, when nothing was added.
+    #[allow(unused_variables)]
+    fn write_additions(&self, w: &mut dyn Write) -> Result<bool, io::Error> {
+        Ok(false)
+    }
+    // The id of the first document of each add_documents call since the last full cache write
+    fn pending_segments(&self) -> Vec<i32> {
+        Vec::new()
+    }
+    // The segment file of the pending add_documents call whose first document has `first_id`, see
+    // cache_segments.rs. False, having written nothing, when there's none.
+    #[allow(unused_variables)]
+    fn write_segment(&self, w: &mut dyn Write, first_id: i32) -> Result<bool, io::Error> {
+        Ok(false)
+    }
+    // Add_documents calls, and the documents they added, since the last full cache write
+    fn pending_additions(&self) -> (usize, usize) {
+        (0, 0)
+    }
+    // Leaves the documents with `ids` out of searches and document lookups from then on, keeping
+    // everyone else's id. Returns how many weren't deleted already; ids past the last are ignored.
+    #[allow(unused_variables)]
+    fn delete_documents(&mut self, ids: &[i32]) -> usize {
         panic!("Not implemented");
     }
-    fn search(&self, all_terms: Vec<&str>) -> Vec<SearchResults>;
+    fn num_deleted(&self) -> usize {
+        0
+    }
+    // The .del file. False, having written nothing, when nothing was deleted.
+    #[allow(unused_variables)]
+    fn write_deletions(&self, w: &mut dyn Write) -> Result<bool, io::Error> {
+        Ok(false)
+    }
+    // After a full cache write succeeds, with what pending_additions was when it started: additions
+    // made while it was written, as during a BackgroundMerge, stay pending
+    #[allow(unused_variables)]
+    fn mark_compacted(&mut self, written: (usize, usize)) {}
+    // The index as it is now, for a BackgroundMerge to write the cache from while this one goes on
+    // changing. None for backends that can't share what they've built.
+    fn snapshot(&self) -> Option<Box<dyn DocumentIndexer + Send + Sync>> {
+        None
+    }
+    // How the index was built, None for backends that don't record it
+    fn build_info(&self) -> Option<BuildInfo> {
+        None
+    }
+    // Err when the query goes over the index's QueryLimits
+    fn search(&self, all_terms: Vec<&str>) -> Result<SearchResults, QueryError> {
+        self.search_page(all_terms, Page::default())
+    }
+    // The hits on `page` of what search finds, ranked the same, fetching only their documents
+    fn search_page(&self, all_terms: Vec<&str>, page: Page) -> Result<SearchResults, QueryError>;
+    // What search gives for each of `queries`, in order. Distinct queries run in parallel and one
+    // repeated in the batch runs once, for evaluation and batch scoring runs.
+    fn search_batch(&self, queries: &[Vec<&str>]) -> Vec<Result<SearchResults, QueryError>>;
+    fn document(&self, id: i32) -> Option<Document>;
+    // Byte ranges in `doc.text` where the analyzed `term` occurs, empty for keyword terms
+    fn term_offsets(&self, term: &str, doc: &Document) -> Vec<(u32, u32)>;
+    // How many times the indexed `term` occurs in document `id`. None when the schema doesn't record
+    // frequencies, or the document has no such term, as for a hit's phrase, prefix or boolean label.
+    #[allow(unused_variables)]
+    fn term_frequency(&self, term: &str, id: i32) -> Option<u32> {
+        None
+    }
     fn num_tokens(&self) -> usize;
     fn num_documents(&self) -> usize;
+    fn num_oversized(&self) -> usize;
+    fn schema(&self) -> Schema;
+    // Term/document pairs that have positions, offsets or frequencies recorded
+    fn num_positioned(&self) -> usize;
+    // Code from LANGUAGES of the documents' text as far as the index knows, the query language when
+    // there is one, for snippets to find sentences by
+    fn language(&self) -> &str;
+    // Terms of `text` as the documents were analyzed
+    fn analyze_text(&self, text: &str) -> Vec<String>;
+    fn document_frequency(&self, term: &str) -> usize;
+    // Up to `limit` terms starting with `prefix` and their document frequencies, most frequent first
+    fn terms_with_prefix(&self, prefix: &str, limit: usize) -> Vec<(String, usize)>;
+    // Up to `limit` distinct document titles starting with `prefix`, ignoring case and spacing, as
+    // written with the id of their first document, see titles.rs
+    fn suggest_titles(&self, prefix: &str, limit: usize) -> Vec<(String, i32)>;
+    // Up to `limit` other terms sharing the most documents with the analyzed `term`, and how many
+    // documents they share, from the sizes of the postings intersections
+    fn cooccurring_terms(&self, term: &str, limit: usize) -> Vec<(String, usize)>;
 }
 
-fn get_next_codepoint_idx(string: &str, try_index: usize) -> usize {
-    let raw_bytes = string.as_bytes();
-    let mut try_index = try_index;
-    while try_index < raw_bytes.len() { 
-        match std::str::from_utf8(&raw_bytes[try_index..try_index + std::mem::size_of::<char>()]) {
-            Ok(_) => {
-                return try_index;
-            },
-            Err(_) => {
-                try_index += 1;
+// DocumentIndexer::search_batch over `search`, on the current rayon pool. RayonIndexer's prefix term
+// list and title index are OnceLocks, so queries running at once share whichever one builds first.
+fn search_batch_with<'q, S>(queries: &[Vec<&'q str>], search: S) -> Vec<Result<SearchResults, QueryError>>
+    where S: Fn(Vec<&'q str>) -> Result<SearchResults, QueryError> + Sync {
+    let mut distinct: HashMap<&[&str], usize> = HashMap::new();
+    let mut unique: Vec<&Vec<&str>> = Vec::new();
+    let slots: Vec<usize> = queries.iter().map(|query| *distinct.entry(query.as_slice()).or_insert_with(|| {
+        unique.push(query);
+        unique.len() - 1
+    })).collect();
+    let mut remaining: Vec<usize> = vec![0; unique.len()];
+    for slot in &slots {
+        remaining[*slot] += 1;
+    }
+    let mut results: Vec<Option<Result<SearchResults, QueryError>>> = unique.par_iter().map(|query| Some(search((*query).clone()))).collect();
+    slots.into_iter().map(|slot| {
+        remaining[slot] -= 1;
+        match remaining[slot] {
+            0 => results[slot].take().unwrap(),
+            _ => results[slot].clone().unwrap()
+        }
+    }).collect()
+}
+
+fn cooccurring_in(index: &HashMapInvertedIndex, term: &str, limit: usize) -> Vec<(String, usize)> {
+    let ids = match index.get(term) {
+        Some(ids) => ids,
+        None => return Vec::new()
+    };
+    let shared = index.iter()
+        .filter(|(other, _)| other.as_str() != term)
+        .map(|(other, other_ids)| {
+            let (small, large) = if ids.len() <= other_ids.len() { (ids, other_ids) } else { (other_ids, ids) };
+            (other.clone(), small.iter().filter(|id| large.contains(id)).count())
+        })
+        .filter(|(_, count)| *count > 0);
+    most_frequent(shared, limit)
+}
+
+// Buffers run_query keeps from one query to the next on the same thread, so a long running REPL or
+// --stdio server isn't allocating posting copies and id sets for every query
+#[derive(Default)]
+struct QueryScratch {
+    // Emptied id buffers, at most MAX_POOLED_IDS of them
+    free_ids: Vec<Vec<i32>>,
+    required_ids: HashSet<i32>,
+    next_required_ids: HashSet<i32>,
+    excluded: HashSet<i32>
+}
+
+const MAX_POOLED_IDS: usize = 32;
+// Matches of one term from which documents are built in parallel
+const PARALLEL_MATCHES: usize = 4096;
+
+impl QueryScratch {
+    fn recycle(&mut self, mut ids: Vec<i32>) {
+        if self.free_ids.len() < MAX_POOLED_IDS {
+            ids.clear();
+            self.free_ids.push(ids);
+        }
+    }
+}
+
+// What phrase matches are checked against: the index's positions when it has them, otherwise each
+// candidate's text analyzed again with the index's analyzer
+enum PhraseSource<'a> {
+    Positions(&'a PositionIndex),
+    Text(&'a Analyzer)
+}
+
+impl<'a> PhraseSource<'a> {
+    fn new(analyzer: &'a Analyzer, positions: &'a PositionIndex, schema: Schema) -> Self {
+        if schema.positions {
+            PhraseSource::Positions(positions)
+        } else {
+            PhraseSource::Text(analyzer)
+        }
+    }
+}
+
+// True when some occurrence of the first term has every other term at its distance in the phrase.
+// `doc_positions` are each term's sorted positions in the document.
+fn phrase_at(phrase: &[(String, u32)], doc_positions: &[&[u32]]) -> bool {
+    let first = phrase[0].1;
+    doc_positions[0].iter().any(|&start| {
+        phrase.iter().zip(doc_positions).all(|((_, at), positions)| positions.binary_search(&(start + at - first)).is_ok())
+    })
+}
+
+// Sorted ids of the documents containing `phrase`, None when it analyzes to nothing. Stopwords keep
+// their place, so "state of the art" also matches "state of an art" but not "state art".
+fn phrase_ids<P, D>(analyzer: &Analyzer, phrase: &str, source: &PhraseSource, postings: &P, document: &D) -> Option<Vec<i32>>
+    where P: Fn(&str, &mut Vec<i32>) -> bool, D: Fn(i32) -> Option<Document> {
+    let terms = analyzer.analyze_phrase(phrase);
+    let candidates = query::intersect_postings(terms.iter().map(|(term, _)| term.as_str()), postings)?;
+    Some(candidates.into_iter().filter(|&id| match source {
+        PhraseSource::Positions(index) => {
+            let doc_positions: Option<Vec<&[u32]>> = terms.iter()
+                .map(|(term, _)| index.get(term).and_then(|docs| docs.get(&id)).map(|p| p.positions.as_slice()))
+                .collect();
+            doc_positions.is_some_and(|doc_positions| phrase_at(&terms, &doc_positions))
+        },
+        PhraseSource::Text(index_analyzer) => {
+            // A missing document is counted when it comes up as a match, see run_query
+            let text = match document(id) {
+                Some(doc) => doc.text,
+                None => return true
+            };
+            let mut tokens: Vec<String> = Vec::new();
+            let mut token_positions: Vec<TokenPosition> = Vec::new();
+            index_analyzer.analyze_into(&text, &mut Vec::new(), &mut tokens, Some(&mut token_positions));
+            let term_positions: Vec<Vec<u32>> = terms.iter().map(|(term, _)| {
+                tokens.iter().zip(&token_positions).filter(|(token, _)| *token == term).map(|(_, at)| at.position).collect()
+            }).collect();
+            let doc_positions: Vec<&[u32]> = term_positions.iter().map(Vec::as_slice).collect();
+            phrase_at(&terms, &doc_positions)
+        }
+    }).collect())
+}
+
+thread_local! {
+    static QUERY_SCRATCH: std::cell::RefCell<QueryScratch> = std::cell::RefCell::new(QueryScratch::default());
+}
+
+// The analyzed prefix of a prefix query and the sorted ids of the documents with any term starting
+// with it, see query::parse_prefix. `prefix_terms` gives the indexed terms with an analyzed prefix.
+// None when the prefix analyzes to nothing, which would match everything, or stands for more terms
+// than `budget` allows.
+fn prefix_ids<'t, P, X>(analyzer: &Analyzer, prefix: &str, prefix_terms: &X, postings: &P, budget: &QueryBudget) -> Option<(String, Vec<i32>)>
+    where P: Fn(&str, &mut Vec<i32>) -> bool, X: Fn(&str) -> Vec<&'t str> {
+    let prefix = analyzer.analyze_prefix(prefix);
+    if prefix.is_empty() {
+        return None;
+    }
+    let terms = prefix_terms(&prefix);
+    if !budget.expand(&prefix, terms.len()) {
+        return None;
+    }
+    let mut ids: Vec<i32> = Vec::new();
+    for term in terms {
+        postings(term, &mut ids);
+    }
+    ids.sort_unstable();
+    ids.dedup();
+    Some((prefix, ids))
+}
+
+// The documents of `ids`, and how many of them `document` has none for
+fn fetch_matches<D>(ids: &[i32], keep: impl Fn(&i32) -> bool + Sync, document: &D, budget: &QueryBudget) -> (Vec<Document>, usize)
+    where D: Fn(i32) -> Option<Document> + Sync {
+    // On the caller's pool when run inside one, see ThreadPoolIndexer::search
+    let found: Vec<Option<Document>> = if ids.len() >= PARALLEL_MATCHES {
+        ids.par_iter().copied().filter(&keep).map(|id| budget.fetch(id, document)).collect()
+    } else {
+        ids.iter().copied().filter(&keep).map(|id| budget.fetch(id, document)).collect()
+    };
+    let missing = found.iter().filter(|doc| doc.is_none()).count();
+    (found.into_iter().flatten().collect(), missing)
+}
+
+// The search shared by every backend. `postings` appends the document ids of one analyzed term to the
+// buffer it's given, returning false when the term isn't indexed, and `document` turns an id into a
+// Document, None when there's no such document. `frequency` is Index::term_frequency, and `cancel` the
+// indexer's CancelToken, which ends it with QueryError::Cancelled. Each analyzed
+// term or phrase is listed, its matches becoming hits, except that a query with only +required terms
+// lists those. Refused once it goes over any of `limits`. Only the documents of the hits on `page`
+// are fetched.
+#[allow(clippy::too_many_arguments)]
+fn run_query<'t, P, X, D, F>(analyzer: &Analyzer, all_terms: Vec<&str>, phrases: PhraseSource, postings: P, prefix_terms: X, document: D, frequency: F, cancel: &CancelToken, options: &IndexOptions, page: Page) -> Result<SearchResults, QueryError>
+    where P: Fn(&str, &mut Vec<i32>) -> bool, X: Fn(&str) -> Vec<&'t str>, D: Fn(i32) -> Option<Document> + Sync, F: Fn(&str, i32) -> Option<u32> {
+    let budget = QueryBudget::new(&options.limits, cancel);
+    let postings = |term: &str, ids: &mut Vec<i32>| budget.read_postings(ids, |ids| postings(term, ids));
+    if query::is_boolean(&all_terms) {
+        let results = run_boolean_query(analyzer, all_terms, phrases, postings, prefix_terms, document, &budget, page);
+        return budget.finish(results);
+    }
+    // Taken out of the thread's slot rather than borrowed: fetch_matches can go parallel, and rayon may
+    // run another query of a batch on this thread while it waits, which then starts with empty buffers
+    let mut scratch = QUERY_SCRATCH.with(|slot| slot.take());
+    let results = {
+        let scratch = &mut scratch;
+        scratch.required_ids.clear();
+        scratch.excluded.clear();
+        let mut listed: Vec<(String, Vec<i32>)> = Vec::new();
+        let mut required: Vec<(String, Vec<i32>)> = Vec::new();
+        let mut any_required = false;
+        for search_term in all_terms {
+            let (occur, search_term) = query::split_occur(search_term);
+            // A phrase is one term, labeled with its quotes
+            let mut terms: Vec<(String, Vec<i32>, bool)> = Vec::new();
+            match query::parse_phrase(search_term) {
+                Some(phrase) => {
+                    let ids = phrase_ids(analyzer, &phrase, &phrases, &postings, &document).unwrap_or_default();
+                    let found = !ids.is_empty();
+                    terms.push((format!("\"{}\"", phrase), ids, found));
+                },
+                // So is a prefix, labeled with its '*'
+                None => match query::parse_prefix(search_term) {
+                    Some(prefix) => if let Some((prefix, ids)) = prefix_ids(analyzer, prefix, &prefix_terms, &postings, &budget) {
+                        let found = !ids.is_empty();
+                        terms.push((format!("{}*", prefix), ids, found));
+                    },
+                    None => for term in analyzer.analyze_query(search_term) {
+                        let mut ids = scratch.free_ids.pop().unwrap_or_default();
+                        let found = postings(&term, &mut ids);
+                        // Weighs matches in the title, see Analyzer::with_search_titles
+                        let in_title = analyzer.search_titles && occur == query::Occur::Should && query::parse_term(search_term).field.is_none();
+                        let title_term = in_title.then(|| format!("{}:{}", TITLE_FIELD, term));
+                        terms.push((term, ids, found));
+                        if let Some(title_term) = title_term {
+                            let mut ids = scratch.free_ids.pop().unwrap_or_default();
+                            let found = postings(&title_term, &mut ids);
+                            terms.push((title_term, ids, found));
+                        }
+                    }
+                }
+            }
+            for (term, ids, found) in terms {
+                match occur {
+                    query::Occur::Should if found => {
+                        listed.push((term, ids));
+                        continue;
+                    },
+                    query::Occur::Should => {},
+                    query::Occur::Must => {
+                        // Documents containing every required term so far
+                        if any_required {
+                            let required_ids = &scratch.required_ids;
+                            scratch.next_required_ids.clear();
+                            scratch.next_required_ids.extend(ids.iter().copied().filter(|id| required_ids.contains(id)));
+                            std::mem::swap(&mut scratch.required_ids, &mut scratch.next_required_ids);
+                        } else {
+                            scratch.required_ids.extend(ids.iter().copied());
+                            any_required = true;
+                        }
+                        required.push((term, ids));
+                        continue;
+                    },
+                    query::Occur::MustNot => scratch.excluded.extend(ids.iter().copied())
+                }
+                scratch.recycle(ids);
             }
         }
+        if listed.is_empty() {
+            std::mem::swap(&mut listed, &mut required);
+        }
+        let mut hits = results::HitsBuilder::new();
+        let keep = |id: &i32| !scratch.excluded.contains(id) && (!any_required || scratch.required_ids.contains(id));
+        for (term, ids) in &listed {
+            hits.add_counted(term.clone(), options.boosts.of_label(term), ids.iter().copied().filter(keep), |id| frequency(term, id));
+        }
+        let results = hits.finish(page, |ids| fetch_matches(ids, |_| true, &document, &budget));
+        for (_, ids) in listed.into_iter().chain(required) {
+            scratch.recycle(ids);
+        }
+        results
+    };
+    QUERY_SCRATCH.with(|slot| slot.replace(scratch));
+    budget.finish(results)
+}
+
+// A query with AND, OR, NOT or parentheses, see query::BoolQuery. Its matches come as hits of one
+// term, the whole query. One that only excludes, like "NOT python", matches nothing, as "-python" does.
+#[allow(clippy::too_many_arguments)]
+fn run_boolean_query<'t, P, X, D>(analyzer: &Analyzer, all_terms: Vec<&str>, phrases: PhraseSource, postings: P, prefix_terms: X, document: D, budget: &QueryBudget, page: Page) -> SearchResults
+    where P: Fn(&str, &mut Vec<i32>) -> bool, X: Fn(&str) -> Vec<&'t str>, D: Fn(i32) -> Option<Document> + Sync {
+    let term_ids = |term: &str| match (query::parse_phrase(term), query::parse_prefix(term)) {
+        (Some(phrase), _) => phrase_ids(analyzer, &phrase, &phrases, &postings, &document),
+        (None, Some(prefix)) => prefix_ids(analyzer, prefix, &prefix_terms, &postings, budget).map(|(_, ids)| ids),
+        (None, None) => query::all_terms_ids(analyzer, term, &postings)
+    };
+    boolean_results(&all_terms, &term_ids, &document, budget, page)
+}
+
+// The SearchResults of a boolean query, evaluated over whichever IdSet `term_ids` gives
+fn boolean_results<S, T, D>(all_terms: &[&str], term_ids: &T, document: &D, budget: &QueryBudget, page: Page) -> SearchResults
+    where S: query::IdSet, T: Fn(&str) -> Option<S>, D: Fn(i32) -> Option<Document> + Sync {
+    let mut hits = results::HitsBuilder::new();
+    if let Some(query::BoolMatches::Ids(ids)) = query::parse_boolean(all_terms).and_then(|query| query.evaluate(term_ids)) {
+        hits.add(all_terms.join(" "), 1.0, ids.into_ids().into_iter());
+    }
+    hits.finish(page, |ids| fetch_matches(ids, |_| true, document, budget))
+}
+
+fn most_frequent(terms: impl Iterator<Item = (String, usize)>, limit: usize) -> Vec<(String, usize)> {
+    let mut terms: Vec<(String, usize)> = terms.collect();
+    terms.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    terms.truncate(limit);
+    terms
+}
+
+fn get_next_codepoint_idx(string: &str, try_index: usize) -> usize {
+    let mut try_index = try_index;
+    while try_index < string.len() && !string.is_char_boundary(try_index) {
+        try_index += 1;
     }
     try_index
 }
@@ -232,7 +1828,7 @@ struct ContentsSplit<'a> {
     data: &'a str
 }
 
-fn split_contents<'a>(contents: &'a str, split_on_tag: &str, num_chunks: usize) -> Vec<ContentsSplit<'a>> {
+fn split_contents<'a>(contents: &'a str, split_on: (&str, usize), num_chunks: usize) -> Vec<ContentsSplit<'a>> {
     assert!(num_chunks > 0);
     if num_chunks <= 1 {
         return vec![ ContentsSplit{ base_offset: 0, data: contents } ];
@@ -245,20 +1841,176 @@ fn split_contents<'a>(contents: &'a str, split_on_tag: &str, num_chunks: usize)
         if try_index >= contents.len() {
             splits.push(ContentsSplit{ base_offset: prev_index, data: &contents[prev_index..]});
             //println!("sliced from prev_index: {}, to ending_index: {}", prev_index, contents.len());
+            prev_index = contents.len();
             break;
         }
-        let ending_index;
-        match &contents[try_index..].find(split_on_tag) {
-            Some(index) => {
-                ending_index = try_index + index + 6;
-            }
-            None => {
-                ending_index = contents.len() - 1;
-            }
-        }
+        let (split_on_tag, split_after) = split_on;
+        let ending_index = match &contents[try_index..].find(split_on_tag) {
+            Some(index) => try_index + index + split_after,
+            None => contents.len()
+        };
         //println!("sliced from prev_index: {}, to ending_index: {}", prev_index, ending_index);
         splits.push(ContentsSplit{ base_offset: prev_index, data: &contents[prev_index..ending_index] });
         prev_index = ending_index;
+        if prev_index >= contents.len() {
+            break;
+        }
+    }
+    if prev_index < contents.len() {
+        splits.push(ContentsSplit{ base_offset: prev_index, data: &contents[prev_index..] });
     }
     splits
 }
+
+// The longest run of whole documents that is at least `min_len` bytes long, or all of `contents`
+pub fn document_prefix(contents: &str, format: Format, min_len: usize) -> &str {
+    let try_index = get_next_codepoint_idx(contents, min_len);
+    if try_index >= contents.len() {
+        return contents;
+    }
+    let (split_on_tag, split_after) = format.split_on();
+    match contents[try_index..].find(split_on_tag) {
+        Some(index) => &contents[..try_index + index + split_after],
+        None => contents
+    }
+}
+
+// Id of the first document in each split for formats whose ids are their position in the source (a
+// line, or a record), so splits parsed in parallel agree on them without a shared counter
+fn first_ids(contents_split: &[ContentsSplit], format: Format) -> Vec<i32> {
+    let separator = match format {
+        Format::Lines => b'\n',
+        _ => records::RECORD_SEPARATOR as u8
+    };
+    let mut first_ids = Vec::with_capacity(contents_split.len());
+    let mut cur_id = 0;
+    for contents in contents_split {
+        first_ids.push(cur_id);
+        cur_id += contents.data.bytes().filter(|b| *b == separator).count() as i32;
+    }
+    first_ids
+}
+
+// Every line (including empty ones) becomes a document whose id is its zero-based line number,
+// so ids stay dense and the line can be recovered from the id alone
+fn parse_lines(contents: &ContentsSplit, first_line: i32) -> Vec<DocumentRaw> {
+    let base_offset = contents.base_offset;
+    let mut docs: Vec<DocumentRaw> = Vec::new();
+    let mut line_start = 0;
+    for (i, raw_line) in contents.data.split_terminator('\n').enumerate() {
+        let line = raw_line.strip_suffix('\r').unwrap_or(raw_line);
+        let line_range = Range{start: base_offset + line_start, end: base_offset + line_start + line.len()};
+        docs.push(DocumentRaw {
+            title: line_range.clone(),
+            url: Range{start: 0, end: 0},
+            text: line_range,
+            keywords: Range{start: 0, end: 0},
+            id: first_line + i as i32,
+            oversized: false
+        });
+        line_start += raw_line.len() + 1;
+    }
+    docs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXTS: [&str; 3] = ["red apple", "green apple", "red pepper"];
+
+    // Searches TEXTS as if the document with id 1 had gone while its postings stayed
+    fn search_without_second(query: &str) -> SearchResults {
+        let analyzer = Analyzer::new_english();
+        let mut index: HashMap<String, Vec<i32>> = HashMap::new();
+        for (id, text) in TEXTS.iter().enumerate() {
+            for term in analyzer.analyze_query(text) {
+                index.entry(term).or_default().push(id as i32);
+            }
+        }
+        let positions = new_position_index();
+        run_query(&analyzer, split_query(query), PhraseSource::new(&analyzer, &positions, Schema::default()),
+            |term, ids: &mut Vec<i32>| index.get(term).map(|postings| ids.extend(postings)).is_some(),
+            |_| Vec::new(),
+            |id| match id {
+                1 => None,
+                _ => Some(Document { title: String::new(), url: String::new(), text: String::from(TEXTS[id as usize]), id })
+            },
+            |_, _| None,
+            &CancelToken::new(),
+            &IndexOptions::default(), Page::default()).unwrap()
+    }
+
+    fn ids(results: &SearchResults) -> (Vec<i32>, usize) {
+        (results.ids().collect(), results.missing)
+    }
+
+    #[test]
+    fn missing_documents_are_skipped_and_counted() {
+        assert_eq!(ids(&search_without_second("apple")), (vec![0], 1));
+        assert_eq!(ids(&search_without_second("apple OR pepper")), (vec![0, 2], 1));
+        // Only matched the missing document
+        assert_eq!(ids(&search_without_second("\"green apple\"")), (vec![], 1));
+        assert_eq!(ids(&search_without_second("pepper")), (vec![2], 0));
+        // Counted once however many terms matched it
+        assert_eq!(ids(&search_without_second("apple green")), (vec![0], 1));
+    }
+
+    #[test]
+    fn inflected_queries_in_the_query_language_match() {
+        let search = |query_language: Option<&str>, query: &str| {
+            let options = IndexOptions { format: Format::Lines, query_language: query_language.map(String::from), ..IndexOptions::default() };
+            let mut indexer = rayon_indexer::RayonIndexer::new(options);
+            indexer.build_from_file_contents("lines", String::from("Die alten Häuser am Fluss\nhouses by the river\n")).unwrap();
+            indexer.search(split_query(query)).unwrap().ids().collect::<Vec<i32>>()
+        };
+        assert_eq!(search(Some("de"), "Hauses"), vec![0]);
+        assert_eq!(search(Some("de"), "\"altes Haus\""), vec![0]);
+        assert_eq!(search(None, "Hauses"), Vec::<i32>::new());
+        // English stems are still indexed
+        assert_eq!(search(Some("de"), "river"), vec![1]);
+    }
+
+    #[test]
+    fn batched_queries_with_parallel_matches_share_a_thread() {
+        let contents: String = (0..PARALLEL_MATCHES * 2).map(|i| format!("common alpha{}\n", i)).collect();
+        let mut indexer = rayon_indexer::RayonIndexer::new(IndexOptions { format: Format::Lines, ..IndexOptions::default() });
+        indexer.build_from_file_contents("lines", contents).unwrap();
+        let texts: Vec<String> = (0..300).map(|i| format!("common alpha{}", i)).collect();
+        let queries: Vec<Vec<&str>> = texts.iter().map(|text| split_query(text)).collect();
+        // Threads waiting on one query's matches steal the others
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(8).build().unwrap();
+        let results = pool.install(|| indexer.search_batch(&queries));
+        for (i, result) in results.iter().enumerate() {
+            let result = result.as_ref().unwrap();
+            assert_eq!(result.total, PARALLEL_MATCHES * 2);
+            assert_eq!(result.hits[0].doc.id, i as i32);
+        }
+    }
+
+    #[test]
+    fn unreadable_positions_are_corrupt_rather_than_a_panic() {
+        let options = IndexOptions { format: Format::Lines, schema: Schema { positions: true, offsets: false, frequencies: false }, ..IndexOptions::default() };
+        let mut built = RayonIndexer::new(options.clone());
+        built.build_from_files(SourceFiles::single("fruit.log"), String::from("red apple\ngreen apple\n")).unwrap();
+        let (mut index, mut documents, mut positions) = (Vec::new(), Vec::new(), Vec::new());
+        built.write_index(&mut index).unwrap();
+        built.write_documents(&mut documents).unwrap();
+        built.write_positions(&mut positions).unwrap();
+        positions.truncate(positions.len() - 1);
+        let serialized = SerializedIndex {
+            source: String::from("fruit.log"),
+            inverted_index: Box::new(index),
+            documents: Box::new(documents),
+            file_contents: Box::new(Vec::new()),
+            positions: Some(Box::new(positions)),
+            additions: None,
+            segments: Vec::new(),
+            deletions: None,
+            build: None
+        };
+        assert!(matches!(serialized.deserialize_positions(), Err(CacheError::Corrupt(_))));
+        let e = RayonIndexer::new(options).build_from_serialized(serialized).unwrap_err();
+        assert!(matches!(CacheError::from(e), CacheError::Corrupt(_)));
+    }
+}
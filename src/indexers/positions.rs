@@ -0,0 +1,88 @@
+use crate::indexers::*;
+
+// The .pos file: POS_MAGIC, then the Schema and the PositionIndex in bincode. Files from before term
+// frequencies were recorded have no magic, a schema of two bools and entries without a frequency;
+// they still load, and migrate rewrites them.
+pub(super) const POS_MAGIC: &[u8; 8] = b"FTPOSTF1";
+
+#[derive(Deserialize)]
+struct LegacySchema {
+    positions: bool,
+    offsets: bool
+}
+
+#[derive(Deserialize)]
+struct LegacyTermPositions {
+    positions: Vec<u32>,
+    offsets: Vec<(u32, u32)>
+}
+
+type LegacyPositionIndex = HashMap<String, HashMap<i32, LegacyTermPositions, BuildHasherDefault<FxHasher>>, BuildHasherDefault<FxHasher>>;
+
+fn invalid(e: bincode::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("positions: {}", e))
+}
+
+impl From<LegacySchema> for Schema {
+    fn from(legacy: LegacySchema) -> Self {
+        Schema { positions: legacy.positions, offsets: legacy.offsets, frequencies: false }
+    }
+}
+
+pub(super) fn is_current(data: &[u8]) -> bool {
+    data.starts_with(POS_MAGIC)
+}
+
+pub(super) fn write_positions(w: &mut dyn Write, schema: Schema, positions: &PositionIndex) -> Result<(), io::Error> {
+    w.write_all(POS_MAGIC)?;
+    disk::serialize_into(w, &(schema, positions))
+}
+
+// The schema at the start of a .pos of either layout, which is all `data` needs to hold
+pub(super) fn read_schema(data: &[u8]) -> Result<Schema, io::Error> {
+    match data.strip_prefix(POS_MAGIC) {
+        Some(rest) => bincode::deserialize(rest).map_err(invalid),
+        None => bincode::deserialize::<LegacySchema>(data).map(Schema::from).map_err(invalid)
+    }
+}
+
+// A .pos of either layout
+pub(super) fn read_positions(data: &[u8]) -> Result<(Schema, PositionIndex), io::Error> {
+    if let Some(rest) = data.strip_prefix(POS_MAGIC) {
+        return bincode::deserialize(rest).map_err(invalid);
+    }
+    let (schema, legacy): (LegacySchema, LegacyPositionIndex) = bincode::deserialize(data).map_err(invalid)?;
+    let mut index = PositionIndex::with_capacity_and_hasher(legacy.len(), BuildHasherDefault::<FxHasher>::default());
+    for (term, docs) in legacy {
+        let docs = docs.into_iter().map(|(id, p)| (id, TermPositions { positions: p.positions, offsets: p.offsets, frequency: 0 })).collect();
+        index.insert(term, docs);
+    }
+    Ok((schema.into(), index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_layouts_load() {
+        let mut positions = new_position_index();
+        let entry = TermPositions { positions: vec![0, 4], offsets: vec![(0, 5), (20, 25)], frequency: 2 };
+        positions.entry(String::from("apple")).or_default().insert(3, entry);
+        let schema = Schema { positions: true, offsets: true, frequencies: true };
+        let mut data: Vec<u8> = Vec::new();
+        write_positions(&mut data, schema, &positions).unwrap();
+        assert!(is_current(&data));
+        assert_eq!(read_schema(&data).unwrap(), schema);
+        let (read, index) = read_positions(&data).unwrap();
+        assert_eq!((read, index["apple"][&3].frequency), (schema, 2));
+
+        // Two bools of schema, then the entries without frequencies
+        let legacy = bincode::serialize(&((true, false), vec![(String::from("apple"), vec![(3i32, (vec![0u32, 4], Vec::<(u32, u32)>::new()))])])).unwrap();
+        assert!(!is_current(&legacy));
+        let (read, index) = read_positions(&legacy).unwrap();
+        assert_eq!(read, Schema { positions: true, offsets: false, frequencies: false });
+        assert_eq!(index["apple"][&3].positions, vec![0, 4]);
+        assert!(read_positions(&data[..data.len() - 1]).is_err());
+    }
+}
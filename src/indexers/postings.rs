@@ -0,0 +1,146 @@
+use crate::indexers::*;
+use crate::indexers::dump::Cursor;
+
+// The .idx file: IDX_MAGIC, a u64 term count, then per term a u64 length + utf-8 term and a u64
+// length + its ids, sorted and delta encoded as LEB128 varints, the first from 0. Ids are dense and
+// common terms' gaps small, so most take a byte instead of four. Files from before have no magic
+// and are a bincode HashMapInvertedIndex, with raw i32 ids; they still load, and migrate rewrites
+// them.
+pub(super) const IDX_MAGIC: &[u8; 8] = b"FTDELTA1";
+
+pub(super) fn is_delta_encoded(data: &[u8]) -> bool {
+    data.starts_with(IDX_MAGIC)
+}
+
+// Appends `ids`, which must be sorted and not negative
+pub(super) fn encode(ids: &[i32], out: &mut Vec<u8>) {
+    let mut previous = 0;
+    for id in ids {
+        let mut delta = *id as u32 - previous;
+        previous = *id as u32;
+        while delta >= 0x80 {
+            out.push(delta as u8 | 0x80);
+            delta >>= 7;
+        }
+        out.push(delta as u8);
+    }
+}
+
+// How many bytes encode writes for a gap of `delta`
+pub(super) fn varint_len(delta: u32) -> usize {
+    (32 - (delta | 1).leading_zeros() as usize).div_ceil(7)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("postings: {}", message))
+}
+
+pub(super) fn decode(bytes: &[u8], ids: &mut Vec<i32>) -> Result<(), io::Error> {
+    let (mut id, mut delta, mut shift) = (0u32, 0u32, 0);
+    for byte in bytes {
+        if shift > 28 {
+            return Err(invalid("varint longer than 5 bytes"));
+        }
+        delta |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 != 0 {
+            shift += 7;
+            continue;
+        }
+        id = id.checked_add(delta).filter(|id| *id <= i32::MAX as u32).ok_or_else(|| invalid("id out of range"))?;
+        ids.push(id as i32);
+        delta = 0;
+        shift = 0;
+    }
+    match shift {
+        0 => Ok(()),
+        _ => Err(invalid("last varint is cut short"))
+    }
+}
+
+// How many ids `bytes` encode, without decoding them: one per byte without the high bit
+pub(super) fn count(bytes: &[u8]) -> usize {
+    bytes.iter().filter(|byte| **byte < 0x80).count()
+}
+
+// Writes an .idx of `num_terms` terms and their ids, in any order
+pub(super) fn write_index<'a>(w: &mut dyn Write, num_terms: usize, terms: impl Iterator<Item = (&'a str, Vec<i32>)>) -> Result<(), io::Error> {
+    w.write_all(IDX_MAGIC)?;
+    w.write_all(&(num_terms as u64).to_le_bytes())?;
+    let mut encoded: Vec<u8> = Vec::new();
+    for (term, mut ids) in terms {
+        ids.sort_unstable();
+        encoded.clear();
+        encode(&ids, &mut encoded);
+        w.write_all(&(term.len() as u64).to_le_bytes())?;
+        w.write_all(term.as_bytes())?;
+        w.write_all(&(encoded.len() as u64).to_le_bytes())?;
+        w.write_all(&encoded)?;
+    }
+    Ok(())
+}
+
+// An .idx of either layout
+pub(super) fn read_index(data: &[u8]) -> Result<HashMapInvertedIndex, io::Error> {
+    if !is_delta_encoded(data) {
+        return bincode::deserialize(data).map_err(|e| invalid(&e.to_string()));
+    }
+    let mut cursor = Cursor::new(data);
+    cursor.take(IDX_MAGIC.len())?;
+    let num_terms = cursor.len(16)?;
+    let mut index = HashMapInvertedIndex::with_capacity_and_hasher(num_terms, BuildHasherDefault::<FxHasher>::default());
+    let mut ids: Vec<i32> = Vec::new();
+    for _ in 0..num_terms {
+        let term = cursor.string()?;
+        let len = cursor.len(1)?;
+        ids.clear();
+        decode(cursor.take(len)?, &mut ids)?;
+        index.insert(term, ids.iter().copied().collect());
+    }
+    Ok(index)
+}
+
+// The term count at the start of an .idx of either layout
+pub(super) fn term_count(path: &Path) -> Result<usize, io::Error> {
+    let mut file = File::open(path)?;
+    let mut header = [0; 8];
+    file.read_exact(&mut header)?;
+    if header == *IDX_MAGIC {
+        file.read_exact(&mut header)?;
+    }
+    Ok(u64::from_le_bytes(header) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_round_trip_in_about_a_byte_each() {
+        let ids: Vec<i32> = vec![0, 1, 2, 130, 131, 20_000, i32::MAX];
+        let mut encoded: Vec<u8> = Vec::new();
+        encode(&ids, &mut encoded);
+        assert_eq!(encoded.len(), 3 + 2 + 1 + 3 + 5);
+        assert_eq!([0, 127, 128, 20_000 - 131, i32::MAX as u32 - 20_000].map(varint_len), [1, 1, 2, 3, 5]);
+        assert_eq!(count(&encoded), ids.len());
+        let mut decoded: Vec<i32> = Vec::new();
+        decode(&encoded, &mut decoded).unwrap();
+        assert_eq!(decoded, ids);
+        assert!(decode(&encoded[..encoded.len() - 1], &mut decoded).is_err());
+        assert!(decode(&[0xff; 6], &mut decoded).is_err());
+    }
+
+    #[test]
+    fn both_layouts_load() {
+        let mut index = HashMapInvertedIndex::default();
+        index.insert(String::from("apple"), vec![5, 1, 3].into_iter().collect());
+        index.insert(String::from("pear"), HashSet::default());
+        let mut data: Vec<u8> = Vec::new();
+        write_index(&mut data, index.len(), index.iter().map(|(term, ids)| (term.as_str(), ids.iter().copied().collect()))).unwrap();
+        assert!(is_delta_encoded(&data));
+        assert_eq!(read_index(&data).unwrap(), index);
+        let legacy = bincode::serialize(&index).unwrap();
+        assert!(!is_delta_encoded(&legacy));
+        assert_eq!(read_index(&legacy).unwrap(), index);
+        assert!(read_index(&data[..data.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,663 @@
+// Backslash, backtick, double quote, colon, parentheses, '*', '~' and whitespace are reserved in
+// query terms, as are '+' and '-' at the start of a term. Any of them can be searched for literally
+// by escaping it with a backslash, or by putting it inside a `backtick` literal where nothing is
+// special.
+//
+// A term in double quotes is a phrase, `"operating system"`, whose words must appear in that order
+// with nothing between them. Whitespace inside the quotes doesn't split the term.
+//
+// A term ending in '*' matches every indexed term it is a prefix of, so `comput*` finds "compute",
+// "computer" and "computing". The prefix is lowercased but not stemmed, and is compared against the
+// indexed terms, which in text mode are stemmed.
+
+use crate::indexers::{Analyzer, DocumentIndexer};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Occur {
+    // Matches are listed, the default
+    Should,
+    // "+term": every match must contain it
+    Must,
+    // "-term": no match may contain it
+    MustNot
+}
+
+// Strips a leading '+' or '-' off a term, as in web search boxes. A lone sign is an ordinary term.
+pub(super) fn split_occur(term: &str) -> (Occur, &str) {
+    let term = term.trim_start();
+    match term.as_bytes() {
+        [b'+', _, ..] => (Occur::Must, &term[1..]),
+        [b'-', _, ..] => (Occur::MustNot, &term[1..]),
+        _ => (Occur::Should, term)
+    }
+}
+
+// A query term with escapes and literals resolved. `field` is set when the term had an unescaped
+// colon outside any literal, everything after that colon is `text`.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct QueryTerm {
+    pub field: Option<String>,
+    pub text: String
+}
+
+pub(super) fn parse_term(term: &str) -> QueryTerm {
+    let mut field: Option<String> = None;
+    let mut text = String::with_capacity(term.len());
+    let mut chars = term.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            // A trailing backslash has nothing to escape and stands for itself
+            '\\' => text.push(chars.next().unwrap_or('\\')),
+            // An unterminated literal runs to the end of the term
+            '`' => text.extend(chars.by_ref().take_while(|c| *c != '`')),
+            ':' if field.is_none() => field = Some(std::mem::take(&mut text)),
+            _ => text.push(c)
+        }
+    }
+    QueryTerm { field, text }
+}
+
+// The text of a phrase term with its escapes resolved, None when `term` isn't one. Like a literal, an
+// unterminated phrase runs to the end of the term.
+pub(super) fn parse_phrase(term: &str) -> Option<String> {
+    let mut chars = term.strip_prefix('"')?.chars();
+    let mut text = String::with_capacity(term.len());
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => text.push(chars.next().unwrap_or('\\')),
+            '"' => break,
+            _ => text.push(c)
+        }
+    }
+    Some(text)
+}
+
+// The term of a prefix query without its '*', escapes and all, None when `term` isn't one. A lone '*'
+// isn't a prefix query.
+pub(super) fn parse_prefix(term: &str) -> Option<&str> {
+    let prefix = term.strip_suffix('*').filter(|prefix| !prefix.is_empty() && !prefix.starts_with('"'))?;
+    let mut escaped = false;
+    let mut in_literal = false;
+    for c in prefix.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if !in_literal => escaped = true,
+            '`' => in_literal = !in_literal,
+            _ => {}
+        }
+    }
+    match escaped || in_literal {
+        true => None,
+        false => Some(prefix)
+    }
+}
+
+// Splits a line of input into terms on whitespace that isn't escaped or inside a literal or phrase.
+// Terms keep their escapes, backticks and quotes for parse_term and parse_phrase.
+pub fn split_query(input: &str) -> Vec<&str> {
+    let mut terms: Vec<&str> = Vec::new();
+    let mut term_start: Option<usize> = None;
+    let mut escaped = false;
+    let mut in_literal = false;
+    let mut in_phrase = false;
+    for (i, c) in input.char_indices() {
+        if c.is_whitespace() && !escaped && !in_literal && !in_phrase {
+            if let Some(start) = term_start.take() {
+                terms.push(&input[start..i]);
+            }
+            continue;
+        }
+        if term_start.is_none() {
+            term_start = Some(i);
+        }
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if !in_literal => escaped = true,
+            '`' if !in_phrase => in_literal = !in_literal,
+            '"' if !in_literal => in_phrase = !in_phrase,
+            _ => {}
+        }
+    }
+    if let Some(start) = term_start {
+        terms.push(&input[start..]);
+    }
+    terms
+}
+
+// Characters a term escapes to have them taken literally
+const RESERVED: [char; 11] = ['\\', '`', '"', ':', '(', ')', '*', '~', ' ', '+', '-'];
+
+fn escape(term: &str) -> String {
+    let mut escaped = String::with_capacity(term.len());
+    for c in term.chars() {
+        if RESERVED.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// The terms of a --titles-only query, each word, prefix or phrase looked up as a "title:" term of an
+// index built with Analyzer::with_search_titles. Signs, operators, parentheses and terms that name
+// their own field stay as they are. Titles have no positions, so a phrase becomes its words, each
+// +required, which a boolean query ANDs too. An excluded phrase stays one term, excluding titles with
+// any of its words.
+pub fn titles_only(terms: &[&str]) -> Vec<String> {
+    terms.iter().flat_map(|term| {
+        let (occur, unsigned) = split_occur(term);
+        let opens = unsigned.len() - unsigned.trim_start_matches('(').len();
+        let mut core = &unsigned[opens..];
+        while ends_with_close(core) {
+            core = &core[..core.len() - 1];
+        }
+        if matches!(unsigned, "AND" | "OR" | "NOT") || core.is_empty() || parse_term(core).field.is_some() {
+            return vec![String::from(*term)];
+        }
+        let at = term.len() - unsigned.len() + opens;
+        let words = parse_phrase(core).filter(|_| occur != Occur::MustNot)
+            .map(|phrase| phrase.split_whitespace().map(escape).collect::<Vec<String>>())
+            .filter(|words| !words.is_empty());
+        match words {
+            Some(words) => {
+                let (opens, closes) = (&unsigned[..opens], &unsigned[opens + core.len()..]);
+                let last = words.len() - 1;
+                words.iter().enumerate().map(|(i, word)| {
+                    let (before, after) = (if i == 0 { opens } else { "" }, if i == last { closes } else { "" });
+                    format!("+{}{}:{}{}", before, crate::indexers::TITLE_FIELD, word, after)
+                }).collect()
+            },
+            None => vec![format!("{}{}:{}", &term[..at], crate::indexers::TITLE_FIELD, &term[at..])]
+        }
+    }).collect()
+}
+
+// A query that combines terms with AND, OR, NOT and parentheses, detected by is_boolean. NOT binds
+// tightest, then AND, then OR, and terms next to each other are ANDed, so
+// `rust AND (tokio OR async) NOT python` is `(rust AND (tokio OR async)) AND NOT python`. A sign
+// works as in other queries: "-term" is NOT term and "+term" is just term. Operators must be
+// uppercase, lowercase "and" is a term.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum BoolQuery<'a> {
+    Term(&'a str),
+    And(Box<BoolQuery<'a>>, Box<BoolQuery<'a>>),
+    Or(Box<BoolQuery<'a>>, Box<BoolQuery<'a>>),
+    Not(Box<BoolQuery<'a>>)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BoolToken<'a> {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    Term(&'a str)
+}
+
+// True when `term` ends in a ')' that isn't escaped
+fn ends_with_close(term: &str) -> bool {
+    match term.strip_suffix(')') {
+        Some(rest) => (rest.len() - rest.trim_end_matches('\\').len()).is_multiple_of(2),
+        None => false
+    }
+}
+
+pub(super) fn is_boolean(terms: &[&str]) -> bool {
+    terms.iter().any(|term| {
+        let (_, term) = split_occur(term);
+        matches!(term, "AND" | "OR" | "NOT") || term.starts_with('(') || ends_with_close(term)
+    })
+}
+
+// Parentheses come off the ends of terms, so "(tokio" and "async)" group like "( tokio" and "async )"
+fn bool_tokens<'a>(terms: &[&'a str]) -> Vec<BoolToken<'a>> {
+    let mut tokens: Vec<BoolToken> = Vec::new();
+    for term in terms {
+        let (occur, mut term) = split_occur(term);
+        match term {
+            "AND" => tokens.push(BoolToken::And),
+            "OR" => tokens.push(BoolToken::Or),
+            "NOT" => tokens.push(BoolToken::Not),
+            _ => {
+                if occur == Occur::MustNot {
+                    tokens.push(BoolToken::Not);
+                }
+                while let Some(rest) = term.strip_prefix('(') {
+                    tokens.push(BoolToken::Open);
+                    term = rest;
+                }
+                let mut closes = 0;
+                while ends_with_close(term) {
+                    closes += 1;
+                    term = &term[..term.len() - 1];
+                }
+                if !term.is_empty() {
+                    tokens.push(BoolToken::Term(term));
+                }
+                tokens.extend(std::iter::repeat_n(BoolToken::Close, closes));
+            }
+        }
+    }
+    tokens
+}
+
+fn combine<'a>(left: Option<BoolQuery<'a>>, right: Option<BoolQuery<'a>>, op: fn(Box<BoolQuery<'a>>, Box<BoolQuery<'a>>) -> BoolQuery<'a>) -> Option<BoolQuery<'a>> {
+    match (left, right) {
+        (Some(left), Some(right)) => Some(op(Box::new(left), Box::new(right))),
+        (left, right) => left.or(right)
+    }
+}
+
+// Recursive descent over the tokens. Like literals that run to the end of the term, mistakes are
+// forgiven rather than rejected: an unclosed group ends with the query, and a stray ')' or an
+// operator missing an operand is dropped.
+struct BoolParser<'a> {
+    tokens: Vec<BoolToken<'a>>,
+    next: usize
+}
+
+impl<'a> BoolParser<'a> {
+    fn peek(&self) -> Option<BoolToken<'a>> {
+        self.tokens.get(self.next).copied()
+    }
+
+    fn or(&mut self) -> Option<BoolQuery<'a>> {
+        let mut query = self.and();
+        while self.peek() == Some(BoolToken::Or) {
+            self.next += 1;
+            let right = self.and();
+            query = combine(query, right, BoolQuery::Or);
+        }
+        query
+    }
+
+    fn and(&mut self) -> Option<BoolQuery<'a>> {
+        let mut query = self.unary();
+        loop {
+            match self.peek() {
+                Some(BoolToken::And) => self.next += 1,
+                Some(BoolToken::Not) | Some(BoolToken::Open) | Some(BoolToken::Term(_)) => {},
+                _ => return query
+            }
+            let right = self.unary();
+            query = combine(query, right, BoolQuery::And);
+        }
+    }
+
+    fn unary(&mut self) -> Option<BoolQuery<'a>> {
+        let token = self.peek()?;
+        if token == BoolToken::Close {
+            return None;
+        }
+        self.next += 1;
+        match token {
+            BoolToken::Not => self.unary().map(|query| BoolQuery::Not(Box::new(query))),
+            BoolToken::Open => {
+                let query = self.or();
+                if self.peek() == Some(BoolToken::Close) {
+                    self.next += 1;
+                }
+                query
+            },
+            BoolToken::Term(term) => Some(BoolQuery::Term(term)),
+            _ => self.unary()
+        }
+    }
+}
+
+// None when no term is left, as in "()" or "AND"
+pub(super) fn parse_boolean<'a>(terms: &[&'a str]) -> Option<BoolQuery<'a>> {
+    let mut parser = BoolParser { tokens: bool_tokens(terms), next: 0 };
+    let mut query = parser.or();
+    while parser.peek().is_some() {
+        parser.next += 1;
+        let right = parser.or();
+        query = combine(query, right, BoolQuery::And);
+    }
+    query
+}
+
+// The documents a BoolQuery matches, as sorted ids or another IdSet. Without the set of all documents
+// a NOT can't be listed, so it's kept as everything but its ids until something it's ANDed with
+// narrows it.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum BoolMatches<S = Vec<i32>> {
+    Ids(S),
+    AllBut(S)
+}
+
+// Document ids a BoolQuery is evaluated over: sorted Vecs, or the roaring backend's bitmaps
+pub trait IdSet: Sized {
+    fn and(&self, other: &Self) -> Self;
+    fn or(&self, other: &Self) -> Self;
+    fn and_not(&self, other: &Self) -> Self;
+    fn into_ids(self) -> Vec<i32>;
+}
+
+impl IdSet for Vec<i32> {
+    fn and(&self, other: &Self) -> Self {
+        intersection(self, other)
+    }
+    fn or(&self, other: &Self) -> Self {
+        union(self, other)
+    }
+    fn and_not(&self, other: &Self) -> Self {
+        difference(self, other)
+    }
+    fn into_ids(self) -> Vec<i32> {
+        self
+    }
+}
+
+fn intersection(a: &[i32], b: &[i32]) -> Vec<i32> {
+    a.iter().filter(|id| b.binary_search(id).is_ok()).copied().collect()
+}
+
+fn union(a: &[i32], b: &[i32]) -> Vec<i32> {
+    let mut ids: Vec<i32> = a.iter().chain(b).copied().collect();
+    ids.sort_unstable();
+    ids.dedup();
+    ids
+}
+
+fn difference(a: &[i32], b: &[i32]) -> Vec<i32> {
+    a.iter().filter(|id| b.binary_search(id).is_err()).copied().collect()
+}
+
+// Sorted ids of the documents with every term `query` analyzes to, None when it analyzes to nothing.
+// `postings` appends the ids of an analyzed term's documents, as in run_query.
+pub(super) fn all_terms_ids<P>(analyzer: &Analyzer, query: &str, postings: &P) -> Option<Vec<i32>>
+    where P: Fn(&str, &mut Vec<i32>) -> bool {
+    intersect_postings(analyzer.analyze_query(query).iter().map(String::as_str), postings)
+}
+
+// Sorted ids of the documents with all of the analyzed `terms`, None when there are none
+pub(super) fn intersect_postings<'t, I, P>(terms: I, postings: &P) -> Option<Vec<i32>>
+    where I: Iterator<Item = &'t str>, P: Fn(&str, &mut Vec<i32>) -> bool {
+    terms.map(|term| {
+        let mut ids: Vec<i32> = Vec::new();
+        postings(term, &mut ids);
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }).reduce(|a, b| intersection(&a, &b))
+}
+
+impl<'a> BoolQuery<'a> {
+    // `term_ids` gives the ids a query term matches, see all_terms_ids. A term that analyzes to
+    // nothing, like a stopword, is left out of the query. None when nothing is left.
+    pub(super) fn evaluate<S, T>(&self, term_ids: &T) -> Option<BoolMatches<S>>
+        where S: IdSet, T: Fn(&str) -> Option<S> {
+        use BoolMatches::{Ids, AllBut};
+        match self {
+            BoolQuery::Term(term) => term_ids(term).map(Ids),
+            BoolQuery::Not(query) => query.evaluate(term_ids).map(|matches| match matches {
+                Ids(ids) => AllBut(ids),
+                AllBut(ids) => Ids(ids)
+            }),
+            BoolQuery::And(left, right) => match (left.evaluate(term_ids), right.evaluate(term_ids)) {
+                (Some(Ids(a)), Some(Ids(b))) => Some(Ids(a.and(&b))),
+                (Some(Ids(a)), Some(AllBut(b))) | (Some(AllBut(b)), Some(Ids(a))) => Some(Ids(a.and_not(&b))),
+                (Some(AllBut(a)), Some(AllBut(b))) => Some(AllBut(a.or(&b))),
+                (left, right) => left.or(right)
+            },
+            BoolQuery::Or(left, right) => match (left.evaluate(term_ids), right.evaluate(term_ids)) {
+                (Some(Ids(a)), Some(Ids(b))) => Some(Ids(a.or(&b))),
+                (Some(Ids(a)), Some(AllBut(b))) | (Some(AllBut(b)), Some(Ids(a))) => Some(AllBut(b.and_not(&a))),
+                (Some(AllBut(a)), Some(AllBut(b))) => Some(AllBut(a.and(&b))),
+                (left, right) => left.or(right)
+            }
+        }
+    }
+}
+
+impl BoolQuery<'_> {
+    // The query with every operator parenthesized, as the parser grouped it
+    fn grouped(&self) -> String {
+        match self {
+            BoolQuery::Term(term) => String::from(*term),
+            BoolQuery::Not(query) => format!("NOT {}", query.grouped()),
+            BoolQuery::And(left, right) => format!("({} AND {})", left.grouped(), right.grouped()),
+            BoolQuery::Or(left, right) => format!("({} OR {})", left.grouped(), right.grouped())
+        }
+    }
+
+    fn terms<'q>(&'q self, terms: &mut Vec<&'q str>) {
+        match self {
+            BoolQuery::Term(term) => terms.push(term),
+            BoolQuery::Not(query) => query.terms(terms),
+            BoolQuery::And(left, right) | BoolQuery::Or(left, right) => {
+                left.terms(terms);
+                right.terms(terms);
+            }
+        }
+    }
+}
+
+// Prefix expansions listed by explain_query
+const EXPLAIN_PREFIX_TERMS: usize = 5;
+
+// What a phrase, prefix or plain term is looked up as, with the number of documents of each indexed
+// term it comes to
+fn explain_term(indexer: &dyn DocumentIndexer, term: &str) -> String {
+    let frequencies = |terms: Vec<String>| -> String {
+        match terms.is_empty() {
+            true => String::from("nothing, it's left out"),
+            false => terms.iter().map(|term| format!("{} ({})", term, indexer.document_frequency(term))).collect::<Vec<String>>().join(", ")
+        }
+    };
+    if let Some(phrase) = parse_phrase(term) {
+        return format!("phrase \"{}\" => {}, in that order", phrase, frequencies(indexer.analyze_text(&phrase)));
+    }
+    if let Some(prefix) = parse_prefix(term) {
+        let prefix = parse_term(prefix).text.to_lowercase();
+        let expanded = indexer.terms_with_prefix(&prefix, usize::MAX);
+        let listed: Vec<String> = expanded.iter().take(EXPLAIN_PREFIX_TERMS).map(|(term, documents)| format!("{} ({})", term, documents)).collect();
+        return match expanded.len() > EXPLAIN_PREFIX_TERMS {
+            true => format!("prefix {}* => {} terms: {}, ...", prefix, expanded.len(), listed.join(", ")),
+            false => format!("prefix {}* => {} terms: {}", prefix, expanded.len(), listed.join(", "))
+        };
+    }
+    format!("term {} => {}", term, frequencies(indexer.analyze_text(&parse_term(term).text)))
+}
+
+// Lines on how `all_terms` are searched: how a boolean query is grouped, or whether each term is
+// required, excluded or listed, then what each term is looked up as with the document frequencies
+// of what it's analyzed into. Terms are analyzed as the documents were, so a --query-language or
+// keyword field search may look them up differently.
+pub fn explain_query(indexer: &dyn DocumentIndexer, all_terms: &[&str]) -> Vec<String> {
+    if is_boolean(all_terms) {
+        let query = match parse_boolean(all_terms) {
+            Some(query) => query,
+            None => return vec![String::from("Boolean query with no terms, matches nothing")]
+        };
+        let mut terms: Vec<&str> = Vec::new();
+        query.terms(&mut terms);
+        let mut lines = vec![format!("Boolean query {}", query.grouped())];
+        lines.extend(terms.into_iter().map(|term| format!("  {}", explain_term(indexer, term))));
+        return lines;
+    }
+    all_terms.iter().map(|term| {
+        let (occur, term) = split_occur(term);
+        let occur = match occur {
+            Occur::Should => "listed",
+            Occur::Must => "required",
+            Occur::MustNot => "excluded"
+        };
+        format!("{} {}", occur, explain_term(indexer, term))
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyword_analyzer() -> Analyzer {
+        Analyzer::new_english().with_keyword_fields(&[String::from("from"), String::from("subject")])
+    }
+
+    #[test]
+    fn escaped_terms_round_trip() {
+        for term in &["plain", "from:alice", "a\\b", "`tick`", "\"quoted\"", "(group)", "wild*", "fuzzy~2", "two words", "trailing\\"] {
+            assert_eq!(parse_term(&escape(term)), QueryTerm { field: None, text: String::from(*term) });
+        }
+    }
+
+    #[test]
+    fn literals_are_verbatim() {
+        assert_eq!(parse_term("`from:a\\b*`"), QueryTerm { field: None, text: String::from("from:a\\b*") });
+        assert_eq!(parse_term("from:`a:b`"), QueryTerm { field: Some(String::from("from")), text: String::from("a:b") });
+        assert_eq!(parse_term("`unterminated"), QueryTerm { field: None, text: String::from("unterminated") });
+    }
+
+    #[test]
+    fn unescaped_colon_splits_field() {
+        assert_eq!(parse_term("from:a:b"), QueryTerm { field: Some(String::from("from")), text: String::from("a:b") });
+        assert_eq!(parse_term("from\\:a"), QueryTerm { field: None, text: String::from("from:a") });
+        assert_eq!(parse_term("\\"), QueryTerm { field: None, text: String::from("\\") });
+    }
+
+    #[test]
+    fn split_respects_escapes_and_literals() {
+        assert_eq!(split_query("  one two\\ words `a literal` three\n"), vec!["one", "two\\ words", "`a literal`", "three"]);
+        assert_eq!(split_query("`a \\` b"), vec!["`a \\`", "b"]);
+        assert!(split_query(" \t ").is_empty());
+        assert_eq!(split_query("a \"operating  system\" -\"x `y\" z"), vec!["a", "\"operating  system\"", "-\"x `y\"", "z"]);
+    }
+
+    #[test]
+    fn phrases_resolve_escapes() {
+        assert_eq!(parse_phrase("\"operating system\""), Some(String::from("operating system")));
+        assert_eq!(parse_phrase("\"say \\\"hi\\\" now"), Some(String::from("say \"hi\" now")));
+        assert_eq!(parse_phrase("plain"), None);
+    }
+
+    #[test]
+    fn only_unescaped_trailing_stars_make_prefixes() {
+        assert_eq!(parse_prefix("comput*"), Some("comput"));
+        assert_eq!(parse_prefix("title:comp*"), Some("title:comp"));
+        assert_eq!(parse_prefix("comput\\*"), None);
+        assert_eq!(parse_prefix("`comput*`"), None);
+        assert_eq!(parse_prefix("`comput*"), None);
+        assert_eq!(parse_prefix("*"), None);
+        assert_eq!(parse_prefix("\\\\*"), Some("\\\\"));
+    }
+
+    #[test]
+    fn signs_set_occur() {
+        assert_eq!(split_occur("+must"), (Occur::Must, "must"));
+        assert_eq!(split_occur("-not"), (Occur::MustNot, "not"));
+        assert_eq!(split_occur("-from:alice"), (Occur::MustNot, "from:alice"));
+        assert_eq!(split_occur("\\-literal"), (Occur::Should, "\\-literal"));
+        assert_eq!(split_occur("a-b+c"), (Occur::Should, "a-b+c"));
+        assert_eq!(split_occur("-"), (Occur::Should, "-"));
+    }
+
+    #[test]
+    fn keyword_values_keep_reserved_characters() {
+        let analyzer = keyword_analyzer();
+        assert_eq!(analyzer.analyze_query("from:alice\\(work\\)@example.com"), vec!["from:alice(work)@example.com"]);
+        assert_eq!(analyzer.analyze_query("Subject:`Re: (urgent) *read*`"), vec!["subject:re: (urgent) *read*"]);
+        assert_eq!(analyzer.analyze_query("subject:two\\ \\ spaces"), vec!["subject:two spaces"]);
+    }
+
+    #[test]
+    fn escaped_colon_is_analyzed_as_text() {
+        let analyzer = keyword_analyzer();
+        assert_eq!(analyzer.analyze_query("from\\:alice"), analyzer.analyze("from alice"));
+        assert_eq!(analyzer.analyze_query("`from:alice`"), analyzer.analyze("from alice"));
+        assert_eq!(analyzer.analyze_query("title:running"), analyzer.analyze("title running"));
+    }
+
+    #[test]
+    fn escaped_text_matches_unescaped_analysis() {
+        let analyzer = keyword_analyzer();
+        for term in &["running*", "(jumping)", "\"quoted words\"", "fuzzy~"] {
+            assert_eq!(analyzer.analyze_query(&escape(term)), analyzer.analyze(term));
+        }
+    }
+
+    #[test]
+    fn grouped_numbers_match_plain_ones() {
+        let analyzer = Analyzer::new_english();
+        assert_eq!(analyzer.analyze_query("1,000,000"), vec!["1000000"]);
+        assert_eq!(analyzer.analyze("1_000 10,000km"), vec!["1000", "10000km"]);
+        assert_eq!(analyzer.analyze("1,2 2020,2021 1,0000"), vec!["1", "2", "2020", "2021", "1", "0000"]);
+        assert_eq!(analyzer.with_split_units(true).analyze("10km 1,000mg 2x4"), vec!["10", "km", "1000", "mg", "2x4"]);
+    }
+
+    fn term(t: &str) -> Box<BoolQuery<'_>> {
+        Box::new(BoolQuery::Term(t))
+    }
+
+    #[test]
+    fn boolean_precedence_and_groups() {
+        let terms = split_query("rust AND (tokio OR async) NOT python");
+        assert!(is_boolean(&terms));
+        assert_eq!(parse_boolean(&terms), Some(BoolQuery::And(
+            Box::new(BoolQuery::And(term("rust"), Box::new(BoolQuery::Or(term("tokio"), term("async"))))),
+            Box::new(BoolQuery::Not(term("python")))
+        )));
+        assert_eq!(parse_boolean(&split_query("a b OR -c")), Some(BoolQuery::Or(
+            Box::new(BoolQuery::And(term("a"), term("b"))),
+            Box::new(BoolQuery::Not(term("c")))
+        )));
+        assert!(!is_boolean(&split_query("rust and +tokio -python \\(x\\)")));
+    }
+
+    #[test]
+    fn boolean_mistakes_are_forgiven() {
+        assert_eq!(parse_boolean(&split_query("((a OR b")), Some(BoolQuery::Or(term("a"), term("b"))));
+        assert_eq!(parse_boolean(&split_query("a) AND OR b")), Some(BoolQuery::And(term("a"), term("b"))));
+        assert_eq!(parse_boolean(&split_query("x\\)")), Some(BoolQuery::Term("x\\)")));
+        assert_eq!(parse_boolean(&split_query("( ) AND")), None);
+    }
+
+    #[test]
+    fn boolean_evaluation() {
+        let analyzer = Analyzer::new_english();
+        let postings = |term: &str, ids: &mut Vec<i32>| {
+            ids.extend_from_slice(match term {
+                "rust" => &[1, 2, 3, 4],
+                "tokio" => &[2, 5],
+                "async" => &[3, 4],
+                "python" => &[4],
+                _ => &[]
+            });
+            !ids.is_empty()
+        };
+        let term_ids = |term: &str| all_terms_ids(&analyzer, term, &postings);
+        let evaluate = |query: &str| parse_boolean(&split_query(query)).and_then(|q| q.evaluate(&term_ids));
+        assert_eq!(evaluate("rust AND (tokio OR async) NOT python"), Some(BoolMatches::Ids(vec![2, 3])));
+        assert_eq!(evaluate("NOT python OR tokio"), Some(BoolMatches::AllBut(vec![4])));
+        assert_eq!(evaluate("the AND tokio"), Some(BoolMatches::Ids(vec![2, 5])));
+        assert_eq!(evaluate("NOT (rust OR tokio) AND async"), Some(BoolMatches::Ids(vec![])));
+    }
+
+    #[test]
+    fn titles_only_keeps_signs_operators_and_fields() {
+        let terms = titles_only(&split_query(r#"+rust (tokio OR async) -"web server" comp* from:alice AND"#));
+        assert_eq!(terms, vec!["+title:rust", "(title:tokio", "OR", "title:async)", r#"-title:"web server""#, "title:comp*", "from:alice", "AND"]);
+        let terms = titles_only(&split_query(r#""web server" ("c++ api" OR rest)"#));
+        assert_eq!(terms, vec!["+title:web", "+title:server", "+(title:c\\+\\+", "+title:api", "OR", "title:rest)"]);
+        let analyzer = Analyzer::new_english().with_search_titles(true);
+        assert_eq!(analyzer.analyze_query(r#"title:"Web Servers""#), vec!["title:web", "title:server"]);
+        assert_eq!(analyzer.analyze_prefix("title:Comp"), "title:comp");
+    }
+
+    #[test]
+    fn explanations_show_occur_kind_and_frequencies() {
+        use crate::indexers::{Format, IndexOptions, RayonIndexer};
+        let mut indexer = RayonIndexer::new(IndexOptions { format: Format::Lines, ..IndexOptions::default() });
+        indexer.build_from_file_contents("fruit.log", String::from("apple pie\napricot jam\napple tart\n")).unwrap();
+        let explain = |query: &str| explain_query(&indexer, &split_query(query));
+        assert_eq!(explain("+apples -\"apple pie\" ap* the"), vec![
+            "required term apples => appl (2)",
+            "excluded phrase \"apple pie\" => appl (2), pie (1), in that order",
+            "listed prefix ap* => 2 terms: appl (2), apricot (1)",
+            "listed term the => nothing, it's left out"
+        ]);
+        assert_eq!(explain("jam OR NOT tart"), vec!["Boolean query (jam OR NOT tart)", "  term jam => jam (1)", "  term tart => tart (1)"]);
+    }
+}
@@ -0,0 +1,127 @@
+use crate::indexers::*;
+use std::convert::TryFrom;
+use std::io::SeekFrom;
+
+// The .raw cache file, written with IndexOptions::raw_offsets: the file and byte range each document
+// was parsed from, so tooling can re-extract the original fragment of a hit, as `fulltext show --raw`
+// does. Only sources indexed as the file is have them, so xml, lines, mbox and uncompressed warc; the
+// documents of the rest were parsed out of what reading the source turned it into.
+#[derive(Default, Serialize, Deserialize)]
+pub struct RawOffsets {
+    // As the build was given them
+    pub files: Vec<String>,
+    // By document id. Documents added after the build have none.
+    pub documents: Vec<RawOffset>
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RawOffset {
+    // Into RawOffsets::files
+    pub file: u32,
+    // Bytes into that file
+    pub range: Range<u64>
+}
+
+fn has_raw_source(format: Format, sources: &SourceFiles) -> bool {
+    match format {
+        Format::Xml | Format::Lines | Format::Mbox => true,
+        Format::Warc => !sources.paths().any(|path| path.ends_with(".gz")),
+        _ => false
+    }
+}
+
+// The unit of `contents` that `doc` was parsed from: from the separator before its fields to the end
+// of the one after them, less the whitespace between documents. An xml document starts at its <doc.
+fn fragment(contents: &str, format: Format, doc: &DocumentRaw) -> Range<usize> {
+    let (split_on, split_after) = format.split_on();
+    let fields = [&doc.title, &doc.url, &doc.text, &doc.keywords];
+    let first = fields.iter().filter(|range| !range.is_empty()).map(|range| range.start).min().unwrap_or(doc.text.start);
+    let last = fields.iter().map(|range| range.end).max().unwrap_or(first).max(first);
+    let mut start = contents[..first].rfind(split_on).map_or(0, |at| at + split_after);
+    let end = contents[last..].find(split_on).map_or(contents.len(), |at| last + at + split_after);
+    if format == Format::Xml {
+        start += contents[start..first].rfind("<doc").unwrap_or(0);
+    }
+    let unit = &contents[start..end];
+    let trimmed = unit.trim_start();
+    let start = start + unit.len() - trimmed.len();
+    start..start + trimmed.trim_end().len()
+}
+
+// None when the source isn't indexed as it is, see RawOffsets. `documents` must be in id order.
+pub(super) fn raw_offsets(documents: &[DocumentRaw], contents: &str, format: Format, sources: &SourceFiles) -> Option<RawOffsets> {
+    if !has_raw_source(format, sources) {
+        return None;
+    }
+    let documents = documents.par_iter().map(|doc| {
+        let range = fragment(contents, format, doc);
+        let (file, file_start) = sources.locate(range.start).unwrap_or((0, 0));
+        RawOffset { file: file as u32, range: (range.start - file_start) as u64..(range.end - file_start) as u64 }
+    }).collect();
+    Some(RawOffsets { files: sources.paths().map(String::from).collect(), documents })
+}
+
+// DocumentIndexer::write_raw_offsets for a backend with these documents and contents
+pub(super) fn write_raw_offsets(w: &mut dyn Write, options: &IndexOptions, documents: &[DocumentRaw], contents: &dyn SomeBytes, sources: &SourceFiles) -> Result<bool, io::Error> {
+    if !options.raw_offsets {
+        return Ok(false);
+    }
+    let contents = contents.from_utf8_unchecked(0..contents.as_ref().len());
+    match raw_offsets(documents, contents, options.format, sources) {
+        Some(offsets) => disk::serialize_into(w, &offsets).map(|_| true),
+        None => Ok(false)
+    }
+}
+
+impl RawOffsets {
+    // The .raw file next to `file_to_index_path`, checked against the cache's checksums. NotFound
+    // when the cache was written without one.
+    pub fn read(file_to_index_path: &str) -> Result<RawOffsets, io::Error> {
+        let base_path = Path::new(file_to_index_path);
+        let data = fs::read(base_path.with_extension("raw"))?;
+        if let Some(manifest) = disk::read_manifest(base_path)? {
+            disk::verify(&manifest, base_path, &[("raw", Some(&data))])?;
+        }
+        bincode::deserialize(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("raw offsets: {}", e)))
+    }
+
+    pub fn get(&self, id: i32) -> Option<(&str, Range<u64>)> {
+        let offset = self.documents.get(usize::try_from(id).ok()?)?;
+        Some((self.files.get(offset.file as usize)?.as_str(), offset.range.clone()))
+    }
+
+    // The bytes document `id` was parsed from, read back from its file
+    pub fn fragment(&self, id: i32) -> Result<Vec<u8>, io::Error> {
+        let (path, range) = self.get(id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no raw offsets for document {}", id)))?;
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(range.start))?;
+        let mut bytes: Vec<u8> = Vec::new();
+        file.take(range.end - range.start).read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragments(contents: &str, format: Format) -> Vec<&str> {
+        let mut indexer = RayonIndexer::new(IndexOptions { format, ..IndexOptions::default() });
+        indexer.build_from_file_contents("source", String::from(contents)).unwrap();
+        let offsets = raw_offsets(&indexer.into_parts().1, contents, format, &SourceFiles::single("source")).unwrap();
+        offsets.documents.iter().map(|offset| &contents[offset.range.start as usize..offset.range.end as usize]).collect()
+    }
+
+    #[test]
+    fn fragments_are_whole_source_units() {
+        let xml = "<feed>\n<doc>\n<title>Wikipedia: Apple</title>\n<url>a</url>\n<abstract>A fruit.</abstract>\n</doc>\n\
+                   <doc>\n<title>Wikipedia: Pear</title>\n<url>p</url>\n<abstract>Another.</abstract>\n</doc>\n</feed>\n";
+        assert_eq!(fragments(xml, Format::Xml), vec![
+            "<doc>\n<title>Wikipedia: Apple</title>\n<url>a</url>\n<abstract>A fruit.</abstract>\n</doc>",
+            "<doc>\n<title>Wikipedia: Pear</title>\n<url>p</url>\n<abstract>Another.</abstract>\n</doc>"
+        ]);
+        assert_eq!(fragments("apple pie\npear tart\n", Format::Lines), vec!["apple pie", "pear tart"]);
+        assert!(raw_offsets(&[], "", Format::Jsonl, &SourceFiles::single("source")).is_none());
+    }
+}
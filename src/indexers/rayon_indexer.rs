@@ -2,188 +2,847 @@ use crate::indexers::*;
 use std::hash::BuildHasherDefault;
 use hashers::fx_hash::FxHasher;
 use std::sync::atomic;
-use core::ops::Range;
 use rayon::prelude::*;
 //use flexbuffers;
-use bincode;
 //use rmp_serde;
 use std::time::{self};
+use std::collections::BTreeSet;
 
 pub type InvertedIndex = HashMapInvertedIndex;
 pub type DocumentIndex = Vec<DocumentRaw>;
 
-fn index_docs_index_only(full_contents: &str, documents: &[DocumentRaw], analyzer: &Analyzer) -> InvertedIndex {
-    let mut inverted_index: InvertedIndex = InvertedIndex::with_capacity_and_hasher(500_000, BuildHasherDefault::<FxHasher>::default());
+fn index_docs_index_only(full_contents: &str, documents: &[DocumentRaw], analyzer: &mut ThreadAnalyzer, terms: &TermIds, spill: &segments::SegmentSpill, cancel: &CancelToken, mut sketches: Option<&mut sketches::TermSketches>) -> (TermIdIndex, PositionIndex) {
+    let mut inverted_index = new_term_id_index(500_000);
     
-    for d in documents {
+    for (i, d) in documents.iter().enumerate() {
+        if cancel.is_cancelled() {
+            break;
+        }
+        spill.maybe_spill(i, &mut inverted_index, analyzer, terms);
         //println!("text: {:?}, {}", d.text, &full_contents[d.text.clone()]);
-        let text = &full_contents[d.text.clone()];
-        //println!("analyzing {}", text);
-        for token in analyzer.analyze(text) {
-            match inverted_index.get_mut(&token) {
+        //println!("analyzing {}", &full_contents[d.text.clone()]);
+        if let Some(sketches) = sketches.as_mut() {
+            sketches.start_document(d);
+        }
+        for token in analyzer.analyze_document(full_contents, d) {
+            if let Some(sketches) = sketches.as_mut() {
+                sketches.insert(&token);
+            }
+            let term_id = terms.id_of(token);
+            match inverted_index.get_mut(&term_id) {
                 Some(set) => {
-                    set.insert(d.id as i32);
+                    set.insert(d.id);
                 }, 
                 None => {
                     let mut set = HashSet::with_capacity_and_hasher(5, BuildHasherDefault::<FxHasher>::default());
-                    set.insert(d.id as i32);
-                    inverted_index.insert(token, set);
+                    set.insert(d.id);
+                    inverted_index.insert(term_id, set);
                 }
             }
         }
     }
 
-    inverted_index
+    (inverted_index, analyzer.take_positions())
 }
 
+// What the cache files are written from is shared with snapshots and copied on the first change
+// after one is taken, see DocumentIndexer::snapshot
 pub struct RayonIndexer { 
-    index: InvertedIndex, 
-    documents: DocumentIndex,
-    full_contents: BoxedBytes,
+    index: Arc<InvertedIndex>, 
+    positions: Arc<PositionIndex>,
+    documents: Arc<DocumentIndex>,
+    full_contents: Arc<dyn SomeBytes>,
+    // Documents from add_documents, numbered after `documents`, and the text their ranges point into
+    added_documents: Arc<DocumentIndex>,
+    added_contents: Arc<String>,
+    // How many of added_documents the last full cache write covered, and where in added_documents
+    // each add_documents call since starts
+    compacted_additions: usize,
+    pending_segments: Vec<usize>,
+    deleted: Arc<BTreeSet<i32>>,
+    titles: OnceLock<titles::TitleIndex>,
+    sorted_terms: OnceLock<sorted_terms::SortedTerms>,
     analyzer: Analyzer,
-    cur_id: atomic::AtomicI32
+    query_analyzer: Option<Analyzer>,
+    cur_id: atomic::AtomicI32,
+    options: IndexOptions,
+    sources: SourceFiles,
+    // None until built, or when loaded from a cache written before build info was
+    build: Option<metadata::BuildInfo>,
+    // Set by MmapIndexer, which lays out a build that spilled straight from its segments, kept in
+    // `spilled` instead of being read back into `index`
+    keep_spilled: bool,
+    spilled: Option<segments::SpilledPostings>,
+    cancel: CancelToken
 }
 
 impl RayonIndexer {
-    pub fn new() -> Self {
+    pub fn new(options: IndexOptions) -> Self {
+        let (analyzer, query_analyzer) = Analyzer::from_options(&options);
         RayonIndexer { 
-            index: InvertedIndex::with_capacity_and_hasher(2_000_000, BuildHasherDefault::<FxHasher>::default()), 
-            documents: DocumentIndex::new(), 
-            analyzer: Analyzer::new_english(),
-            full_contents: Box::new(String::new()),
-            cur_id: atomic::AtomicI32::new(0)
-        }
-    }
-    fn parse_documents_vec(&self, file_contents: &ContentsSplit) -> DocumentIndex {
-        let base_offset = file_contents.base_offset;
-        let mut cur_doc = DocumentRaw::default();
-        let mut cur_tag: &str = "";
-        let mut docs: Vec<DocumentRaw> = Vec::with_capacity(500_000);
-        //println!("len contents: {}", file_contents.len());
-        for token in xmlparser::Tokenizer::from_fragment(file_contents.data, 0..file_contents.data.len()) {
-            //println!("token: {:?}", token);
-            match token {
-                Ok(xmlparser::Token::ElementStart{local, ..}) => {
-                    cur_tag = local.as_str();
-                },
-                Ok(xmlparser::Token::Text{text}) => {
-                    let range_start = text.range().start;
-                    let range_end = text.range().end;
-                    let absolute_range = Range{start: base_offset+range_start, end: base_offset+range_end};
-                    match cur_tag {
-                        "title" => cur_doc.title = absolute_range,
-                        "abstract" => {
-                            //println!("RANGE: {}\n{:?}\n{}\n{}\n", base_offset, absolute_range.clone(), 
-                            //    &file_contents.data[text.range()], text.as_str());
-                            cur_doc.text = absolute_range
-                        },
-                        "url" => cur_doc.url = absolute_range,
-                        _ => {}
-                    }
-                },
-                Ok(xmlparser::Token::ElementEnd{end, ..}) => {
-                    if let xmlparser::ElementEnd::Close(_, n) = end {
-                        cur_tag = "";
-                        if n.as_str() == "doc" {
-                            cur_doc.id = self.cur_id.fetch_add(1, atomic::Ordering::SeqCst);
-                            docs.push(cur_doc);
-                            cur_doc = DocumentRaw::default();                        
-                        }
-                    }
-                },
-    
-                Ok(_) => {},
-                Err(e) => {println!("Error! {}, contents_start: {}", e, &file_contents.data[0..1024])}
-            }
+            index: Arc::new(InvertedIndex::with_capacity_and_hasher(2_000_000, BuildHasherDefault::<FxHasher>::default())), 
+            positions: Arc::new(new_position_index()),
+            documents: Arc::new(DocumentIndex::new()), 
+            analyzer,
+            query_analyzer,
+            full_contents: Arc::new(String::new()),
+            added_documents: Arc::new(DocumentIndex::new()),
+            added_contents: Arc::new(String::new()),
+            compacted_additions: 0,
+            pending_segments: Vec::new(),
+            deleted: Arc::new(BTreeSet::new()),
+            titles: OnceLock::new(),
+            sorted_terms: OnceLock::new(),
+            cur_id: atomic::AtomicI32::new(0),
+            options,
+            sources: SourceFiles::default(),
+            build: None,
+            keep_spilled: false,
+            spilled: None,
+            cancel: CancelToken::new()
         }
-        docs
     }
-}
 
-impl DocumentIndexer for RayonIndexer {
-    fn build_from_file_contents(&mut self, file_contents: String) {
+    // Every document in `file_contents` with max_doc_bytes applied, sorted by id
+    fn parse_all(&self, file_contents: &str) -> DocumentIndex {
+        self.cur_id.store(0, atomic::Ordering::SeqCst);
+        self.parse_chunk(file_contents, 0)
+    }
+
+    // parse_all for a run of whole documents whose ids start at `first_id`, which cur_id must be at.
+    // Ranges point into `file_contents`.
+    fn parse_chunk(&self, file_contents: &str, first_id: i32) -> DocumentIndex {
         let mut contents_split: Vec<ContentsSplit> = Vec::new();
-        let num_threads = num_cpus::get();
-        for contents in split_contents(&file_contents, "</doc>", num_threads) {
+        for contents in split_contents(file_contents, self.options.format.split_on(), num_cpus::get()) {
             contents_split.push(contents);
         }
-        self.documents = contents_split.par_iter().map(|x| self.parse_documents_vec(x)).flatten().collect();
-        self.documents.sort();
-        self.index = self.documents.as_slice()
-            .par_chunks(std::cmp::max(self.documents.len() / num_threads, 1))
-            .map(|d| index_docs_index_only(&file_contents, d, &self.analyzer))
+        let mut documents: DocumentIndex = match self.options.format {
+            Format::Xml => contents_split.par_iter().map(|x| xml::parse_xml(x, &self.cur_id)).flatten().collect(),
+            Format::Mbox => contents_split.par_iter().map(|x| mbox::parse_mbox(x, &self.cur_id)).flatten().collect(),
+            Format::Rss => contents_split.par_iter().map(|x| feed::parse_feed(x, &self.cur_id)).flatten().collect(),
+            Format::Sql | Format::Parquet | Format::Archive | Format::Jsonl | Format::Records => {
+                let first_records = first_ids(&contents_split, self.options.format);
+                contents_split.par_iter().zip(first_records).map(|(x, first_record)| records::parse_records(x, first_id + first_record)).flatten().collect()
+            },
+            Format::Warc => contents_split.par_iter().map(|x| warc::parse_warc(x, &self.cur_id)).flatten().collect(),
+            Format::Lines => {
+                let first_lines = first_ids(&contents_split, self.options.format);
+                contents_split.par_iter().zip(first_lines).map(|(x, first_line)| parse_lines(x, first_id + first_line)).flatten().collect()
+            }
+        };
+        for d in documents.iter_mut() {
+            enforce_max_doc_bytes(d, file_contents, 0, &self.options);
+        }
+        documents.sort();
+        documents
+    }
+
+    // The whole documents in `file_contents[range]`, the first with id `first_id`, with the ids and
+    // urls a build of all of `file_contents` from `files` gives them, for add_documents to index a
+    // source a part at a time
+    pub fn parse_documents(&self, file_contents: &str, range: Range<usize>, first_id: i32, files: &SourceFiles) -> Vec<Document> {
+        self.cur_id.store(first_id, atomic::Ordering::SeqCst);
+        let start = range.start;
+        self.parse_chunk(&file_contents[range], first_id).into_iter().map(|mut d| {
+            d.shift(start);
+            let url = match self.options.format {
+                Format::Lines => files.line_url(d.text.start, d.id),
+                _ => String::from(&file_contents[d.url.clone()])
+            };
+            Document { title: String::from(&file_contents[d.title.clone()]), url, text: String::from(&file_contents[d.text.clone()]), id: d.id }
+        }).collect()
+    }
+
+    // Builds the index over self.documents, whose ranges point into `file_contents`, where source
+    // files start at `starts`. Returns the sketches of the distinct terms it indexed.
+    fn index_documents(&mut self, file_contents: &str, starts: &[usize]) -> Result<sketches::TermSketches, io::Error> {
+        let terms = TermIds::new();
+        let spill = segments::SegmentSpill::new(self.options.memory_limit);
+        let (index, positions, sketches) = self.index_slice(&self.documents, file_contents, &terms, &spill, starts);
+        // Dropping the spill removes its segments
+        if self.cancel.is_cancelled() {
+            return Ok(sketches);
+        }
+        self.set_postings(spill.finish(index, positions, terms)?)?;
+        Ok(sketches)
+    }
+
+    // A build's postings, read back from the segments it spilled unless keep_spilled is set
+    fn set_postings(&mut self, built: segments::BuiltPostings) -> Result<(), io::Error> {
+        let (index, positions) = match built {
+            segments::BuiltPostings::Spilled(spilled) if self.keep_spilled => {
+                self.spilled = Some(spilled);
+                (InvertedIndex::default(), new_position_index())
+            },
+            built => built.into_memory()?
+        };
+        self.index = Arc::new(index);
+        self.positions = Arc::new(positions);
+        Ok(())
+    }
+
+    pub(super) fn keep_spilled(&mut self) {
+        self.keep_spilled = true;
+    }
+
+    // Builds from the documents `source` gives, numbered in order, without parsing their text again.
+    // Err from the source leaves the indexer as it was.
+    pub fn build_from_source(&mut self, label: &str, source: &mut dyn DocumentSource) -> Result<(), io::Error> {
+        let mut contents = String::new();
+        let mut documents = DocumentIndex::new();
+        while let Some(batch) = source.next_batch(&mut contents)? {
+            for mut d in batch {
+                d.id = documents.len() as i32;
+                documents.push(d);
+            }
+        }
+        for d in documents.iter_mut() {
+            enforce_max_doc_bytes(d, &contents, 0, &self.options);
+        }
+        self.cur_id.store(documents.len() as i32, atomic::Ordering::SeqCst);
+        self.build_parsed(SourceFiles::single(label), contents, documents)
+    }
+
+    // Indexes `documents`, parsed out of `file_contents`, and keeps both
+    fn build_parsed(&mut self, files: SourceFiles, file_contents: String, documents: DocumentIndex) -> Result<(), io::Error> {
+        self.documents = Arc::new(documents);
+        let sketches = match self.cancel.is_cancelled() {
+            false => match self.index_documents(&file_contents, &files.starts()) {
+                Ok(sketches) => Some(sketches),
+                Err(e) => {
+                    self.reset_additions();
+                    self.reset_cancelled();
+                    return Err(e);
+                }
+            },
+            true => None
+        };
+        self.reset_additions();
+        if self.cancel.is_cancelled() {
+            self.reset_cancelled();
+            return Ok(());
+        }
+        self.full_contents = Arc::new(file_contents);
+        let mut build = metadata::BuildInfo::new("rayon", &self.options, files.single_path());
+        build.distinct_terms = sketches.map(|sketches| sketches.estimates(&files));
+        build.documents = Some(self.documents.len());
+        self.build = Some(build);
+        self.sources = files;
+        Ok(())
+    }
+
+    // The postings, positions and term sketches of `documents`, split across threads, with anything
+    // spilled left in `spill`
+    fn index_slice(&self, documents: &[DocumentRaw], file_contents: &str, terms: &TermIds, spill: &segments::SegmentSpill, starts: &[usize]) -> (TermIdIndex, PositionIndex, sketches::TermSketches) {
+        let num_threads = num_cpus::get();
+        documents
+            .par_chunks(std::cmp::max(documents.len() / num_threads, 1))
+            .map_init(|| ThreadAnalyzer::new(&self.analyzer, self.options.schema), |analyzer, d| {
+                let mut sketches = sketches::TermSketches::new(starts);
+                let (index, positions) = index_docs_index_only(file_contents, d, analyzer, terms, spill, &self.cancel, Some(&mut sketches));
+                (index, positions, sketches)
+            })
             .reduce(
-                || InvertedIndex::with_hasher(BuildHasherDefault::<FxHasher>::default()),
-                |mut a, b| {
-                    for (thread_k, thread_set) in b {
-                        match a.get_mut(&thread_k) {
-                            Some(joined_set) => {
-                                joined_set.extend(thread_set);
-                            },
-                            None => {
-                                a.insert(thread_k, thread_set);
-                            }                 
+                || (new_term_id_index(0), new_position_index(), sketches::TermSketches::new(starts)),
+                |(mut a, mut a_positions, a_sketches), (b, b_positions, b_sketches)| {
+                    merge_positions(&mut a_positions, b_positions);
+                    merge_postings(&mut a, b);
+                    (a, a_positions, a_sketches.merge(b_sketches))
+                }
+            )
+    }
+
+    // Drops what a previous build or add_documents left that the new documents don't cover
+    fn reset_additions(&mut self) {
+        self.added_documents = Arc::new(DocumentIndex::new());
+        self.added_contents = Arc::new(String::new());
+        self.compacted_additions = 0;
+        self.pending_segments = Vec::new();
+        self.deleted = Arc::new(BTreeSet::new());
+        self.titles = OnceLock::new();
+        self.sorted_terms = OnceLock::new();
+    }
+
+    // What a build or load left, for MmapIndexer to lay out. Added documents point into text of their
+    // own and aren't included.
+    pub(super) fn into_parts(self) -> (segments::BuiltPostings, DocumentIndex, BoxedBytes, SourceFiles, Option<metadata::BuildInfo>) {
+        let postings = match self.spilled {
+            Some(spilled) => segments::BuiltPostings::Spilled(spilled),
+            None => segments::BuiltPostings::Memory(Arc::unwrap_or_clone(self.index), Arc::unwrap_or_clone(self.positions))
+        };
+        (postings, Arc::unwrap_or_clone(self.documents), Box::new(SharedBytes(self.full_contents)), self.sources, self.build)
+    }
+
+    // The index of a build that was cancelled: empty
+    fn reset_cancelled(&mut self) {
+        self.index = Arc::new(InvertedIndex::default());
+        self.positions = Arc::new(new_position_index());
+        self.spilled = None;
+        self.documents = Arc::new(DocumentIndex::new());
+        self.full_contents = Arc::new(String::new());
+        self.sources = SourceFiles::default();
+        self.build = None;
+    }
+
+    // Postings and positions of `added`, whose ranges point into added_contents
+    fn index_additions(&self, added: &[DocumentRaw]) -> (HashMapInvertedIndex, PositionIndex) {
+        let terms = TermIds::new();
+        let spill = segments::SegmentSpill::new(None);
+        let mut analyzer = ThreadAnalyzer::new(&self.analyzer, self.options.schema);
+        let (index, positions) = index_docs_index_only(&self.added_contents, added, &mut analyzer, &terms, &spill, &CancelToken::new(), None);
+        (terms.resolve(index), positions)
+    }
+
+    // Added ids come after every indexed one, so their postings go on the end
+    fn merge_additions(&mut self, index: HashMapInvertedIndex, positions: PositionIndex) {
+        let merged = Arc::make_mut(&mut self.index);
+        for (term, ids) in index {
+            match merged.get_mut(&term) {
+                Some(postings) => postings.extend(ids),
+                None => {
+                    merged.insert(term, ids);
+                }
+            }
+        }
+        if !positions.is_empty() {
+            merge_positions(Arc::make_mut(&mut self.positions), positions);
+        }
+    }
+
+    // Appends a segment file's documents, text and postings, unless they're there already: segments
+    // are only saved between full writes, and one that doesn't start right after the documents so
+    // far is in the .add file
+    fn load_segment(&mut self, segment: cache_segments::CacheSegment) {
+        if segment.documents.first().is_none_or(|d| d.id as usize != self.num_documents()) {
+            return;
+        }
+        self.pending_segments.push(self.added_documents.len());
+        let (base, contents_start) = (self.added_contents.len(), segment.contents_start);
+        Arc::make_mut(&mut self.added_contents).push_str(&segment.contents);
+        Arc::make_mut(&mut self.added_documents).extend(segment.documents.into_iter().map(|mut d| {
+            cache_segments::rebase(&mut d, contents_start, base);
+            d
+        }));
+        let positions = match segment.positions {
+            Some(positions) => positions::read_positions(&positions).unwrap().1,
+            None => new_position_index()
+        };
+        self.merge_additions(postings::read_index(&segment.postings).unwrap(), positions);
+    }
+
+    fn sorted_terms(&self) -> &sorted_terms::SortedTerms {
+        self.sorted_terms.get_or_init(|| sorted_terms::SortedTerms::new(self.index.keys().cloned()))
+    }
+
+    // Takes `ids` out of the postings and positions, dropping terms only they had, so that searches,
+    // counts and the next full write leave them out
+    fn remove_postings(&mut self, ids: &BTreeSet<i32>) {
+        Arc::make_mut(&mut self.index).retain(|_, postings| {
+            postings.retain(|id| !ids.contains(id));
+            !postings.is_empty()
+        });
+        Arc::make_mut(&mut self.positions).retain(|_, docs| {
+            docs.retain(|id, _| !ids.contains(id));
+            !docs.is_empty()
+        });
+        self.sorted_terms = OnceLock::new();
+    }
+
+    // `documents` as a full write saves them: a deleted one keeps its id, so that the ones after it
+    // keep theirs, but none of its text
+    fn without_deleted(&self, documents: &[DocumentRaw]) -> Vec<DocumentRaw> {
+        documents.iter().map(|d| match self.deleted.contains(&d.id) {
+            true => DocumentRaw { id: d.id, ..DocumentRaw::default() },
+            false => d.clone()
+        }).collect()
+    }
+
+    fn raw_document(&self, id: i32) -> &DocumentRaw {
+        let id = id as usize;
+        self.documents.get(id).unwrap_or_else(|| &self.added_documents[id - self.documents.len()])
+    }
+
+    fn title_of(&self, d: &DocumentRaw) -> &str {
+        match (d.id as usize) < self.documents.len() {
+            true => self.full_contents.from_utf8_unchecked(d.title.clone()),
+            false => &self.added_contents[d.title.clone()]
+        }
+    }
+
+    fn document_at(&self, id: i32) -> Option<Document> {
+        if self.deleted.contains(&id) {
+            return None;
+        }
+        let id = id as usize;
+        match self.documents.get(id) {
+            Some(d) => Some(d.to_document(self.full_contents.as_ref(), self.options.format, &self.sources)),
+            // Added documents keep their own url whatever the format
+            None => self.added_documents.get(id - self.documents.len()).map(|d| Document {
+                title: String::from(&self.added_contents[d.title.clone()]),
+                url: String::from(&self.added_contents[d.url.clone()]),
+                text: String::from(&self.added_contents[d.text.clone()]),
+                id: d.id
+            })
+        }
+    }
+
+    // Parses and analyzes `file_contents` like a build would, but only counts what the build would
+    // store, see estimate.rs
+    pub fn dry_run(&self, file_contents: &str) -> BuildEstimate {
+        let documents = self.parse_all(file_contents);
+        let (tokens, postings, terms) = documents
+            .par_chunks(std::cmp::max(documents.len() / num_cpus::get(), 1))
+            .map_init(|| ThreadAnalyzer::new(&self.analyzer, Schema::default()), |analyzer, docs| {
+                let mut tokens = 0;
+                let mut postings = 0;
+                let mut terms: HashSet<String> = HashSet::new();
+                let mut doc_terms: HashSet<String> = HashSet::new();
+                for d in docs {
+                    doc_terms.clear();
+                    for token in analyzer.analyze_document(file_contents, d) {
+                        tokens += 1;
+                        if !doc_terms.contains(&token) {
+                            postings += 1;
+                            if !terms.contains(&token) {
+                                terms.insert(token.clone());
+                            }
+                            doc_terms.insert(token);
                         }
                     }
-                    a
+                }
+                (tokens, postings, terms)
+            })
+            .reduce(
+                || (0, 0, HashSet::new()),
+                |(a_tokens, a_postings, a_terms), (b_tokens, b_postings, b_terms)| {
+                    let (mut large, small) = if a_terms.len() >= b_terms.len() { (a_terms, b_terms) } else { (b_terms, a_terms) };
+                    large.extend(small);
+                    (a_tokens + b_tokens, a_postings + b_postings, large)
                 }
             );
-        self.full_contents = Box::new(file_contents);
+        BuildEstimate {
+            source_bytes: file_contents.len(),
+            documents: documents.len(),
+            oversized: documents.iter().filter(|d| d.oversized).count(),
+            tokens,
+            postings,
+            terms: terms.len(),
+            term_bytes: terms.iter().map(|t| t.len()).sum()
+        }
     }
-    fn build_from_serialized(&mut self, serialized_data: SerializedIndex) {
+
+    // Parses all of `file_contents`, then counts and builds an index over a random `fraction` of its
+    // documents for estimate.rs to extrapolate from
+    pub fn estimate_from_sample(&self, file_contents: &str, fraction: f64) -> SampleEstimate {
+        let before_parse = time::Instant::now();
+        let documents = self.parse_all(file_contents);
+        let parse_time = time::Instant::now() - before_parse;
+        let size = (documents.len() as f64 * fraction).ceil() as usize;
+        let sample = estimate::draw_sample(&documents, size, estimate::SAMPLE_SEED);
+
+        let mut analyzer = ThreadAnalyzer::new(&self.analyzer, Schema::default());
+        let mut tokens: Vec<usize> = Vec::with_capacity(sample.len());
+        let mut postings: Vec<usize> = Vec::with_capacity(sample.len());
+        // Sampled documents each term is in
+        let mut terms: HashMap<String, usize> = HashMap::new();
+        let mut doc_terms: HashSet<String> = HashSet::new();
+        for d in sample.iter() {
+            doc_terms.clear();
+            let mut doc_tokens = 0;
+            for token in analyzer.analyze_document(file_contents, d) {
+                doc_tokens += 1;
+                doc_terms.insert(token);
+            }
+            for term in doc_terms.iter() {
+                match terms.get_mut(term) {
+                    Some(count) => *count += 1,
+                    None => {
+                        terms.insert(term.clone(), 1);
+                    }
+                }
+            }
+            tokens.push(doc_tokens);
+            postings.push(doc_terms.len());
+        }
+
+        // Timed over growing shares of the sample, see fit_build_time
+        let time_build = |documents: &[DocumentRaw]| {
+            let mut sample_index = RayonIndexer::new(self.options.clone());
+            sample_index.documents = Arc::new(documents.to_vec());
+            let before_build = time::Instant::now();
+            // Without a memory_limit nothing spills, so nothing is read back that could fail
+            sample_index.options.memory_limit = None;
+            sample_index.index_documents(file_contents, &[]).unwrap();
+            (tokens[..documents.len()].iter().sum(), time::Instant::now() - before_build)
+        };
+        let builds = [8, 4, 2, 1].iter().map(|share| time_build(&sample[..sample.len() / share])).collect();
+
+        estimate::Sample {
+            source_bytes: file_contents.len(),
+            documents: documents.len(),
+            oversized: documents.iter().filter(|d| d.oversized).count(),
+            tokens,
+            postings,
+            terms: terms.len(),
+            singletons: terms.values().filter(|&&count| count == 1).count(),
+            term_bytes: terms.keys().map(|t| t.len()).sum(),
+            parse_time,
+            builds
+        }.extrapolate()
+    }
+}
+
+impl DocumentIndexer for RayonIndexer {
+    // Parsing runs to the end once started, cancellation is checked after it and between documents
+    // while indexing
+    fn build_from_files(&mut self, files: SourceFiles, file_contents: String) -> Result<(), io::Error> {
+        let documents = self.parse_all(&file_contents);
+        self.build_parsed(files, file_contents, documents)
+    }
+    // Each chunk is parsed, indexed and its documents' ranges moved to where the chunk is in the file
+    // before the next is read. The text is mapped from the file at the end, as when loading the cache.
+    fn build_streaming(&mut self, path: &str) -> Result<(), io::Error> {
+        let (split_on, split_after) = match self.options.format {
+            Format::Xml | Format::Lines | Format::Mbox => self.options.format.split_on(),
+            Format::Warc if !path.ends_with(".gz") => self.options.format.split_on(),
+            format => return Err(io::Error::new(io::ErrorKind::Unsupported, format!("{:?} sources can't be streamed", format)))
+        };
+        let mut file = File::open(path)?;
+        let terms = TermIds::new();
+        let spill = segments::SegmentSpill::new(self.options.memory_limit);
+        let mut index = new_term_id_index(0);
+        let mut positions = new_position_index();
+        let mut sketches = sketches::TermSketches::new(&[]);
+        let mut documents = DocumentIndex::new();
+        // What's been read past the last chunk, starting at `offset` in the file
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut offset = 0;
+        loop {
+            let read = (&mut file).take(STREAM_CHUNK_BYTES as u64).read_to_end(&mut buffer)?;
+            let at_end = read < STREAM_CHUNK_BYTES;
+            let end = match buffer.windows(split_on.len()).rposition(|window| window == split_on.as_bytes()) {
+                _ if at_end => buffer.len(),
+                Some(at) => at + split_after,
+                // A document longer than a chunk, read on until it ends
+                None => continue
+            };
+            let chunk = std::str::from_utf8(&buffer[..end])
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{} at byte {}", e, offset + e.valid_up_to())))?;
+            self.cur_id.store(documents.len() as i32, atomic::Ordering::SeqCst);
+            let mut chunk_documents = self.parse_chunk(chunk, documents.len() as i32);
+            let (chunk_index, chunk_positions, chunk_sketches) = self.index_slice(&chunk_documents, chunk, &terms, &spill, &[]);
+            merge_postings(&mut index, chunk_index);
+            merge_positions(&mut positions, chunk_positions);
+            sketches = sketches.merge(chunk_sketches);
+            for d in chunk_documents.iter_mut() {
+                d.shift(offset);
+            }
+            documents.extend(chunk_documents);
+            buffer.drain(..end);
+            offset += end;
+            if at_end || self.cancel.is_cancelled() {
+                break;
+            }
+        }
+        self.reset_additions();
+        if self.cancel.is_cancelled() {
+            self.reset_cancelled();
+            return Ok(());
+        }
+        self.set_postings(spill.finish(index, positions, terms)?)?;
+        self.documents = Arc::new(documents);
+        self.full_contents = match offset {
+            0 => Arc::new(String::new()),
+            _ => Arc::new(open_mmap(Path::new(path))?)
+        };
+        let sources = SourceFiles::single(path);
+        let mut build = metadata::BuildInfo::new("rayon", &self.options, Some(path));
+        build.distinct_terms = Some(sketches.estimates(&sources));
+        build.documents = Some(self.documents.len());
+        self.build = Some(build);
+        self.sources = sources;
+        Ok(())
+    }
+    fn set_cancel_token(&mut self, token: CancelToken) -> bool {
+        self.cancel = token;
+        true
+    }
+    fn build_from_serialized(&mut self, serialized_data: SerializedIndex) -> Result<(), io::Error> {
         let before = time::Instant::now();
         //let r = flexbuffers::Reader::get_root((*serialized_data.inverted_index).as_ref()).unwrap();
         //self.index = HashMapInvertedIndex::deserialize(r).unwrap();
-        self.index = bincode::deserialize((*serialized_data.inverted_index).as_ref()).unwrap();
+        self.index = Arc::new(postings::read_index((*serialized_data.inverted_index).as_ref())?);
         //self.index = rmp_serde::from_read_ref((*serialized_data.inverted_index).as_ref()).unwrap();
         let after = time::Instant::now(); let total = after - before;
         println!("Index deserialize elapsed: {}", total.as_millis());
         let before = time::Instant::now();
         //let r = flexbuffers::Reader::get_root((*serialized_data.documents).as_ref()).unwrap();
         //self.documents = DocumentIndex::deserialize(r).unwrap();
-        self.documents = bincode::deserialize((*serialized_data.documents).as_ref()).unwrap();
+        self.documents = Arc::new(bincode::deserialize((*serialized_data.documents).as_ref()).unwrap());
         //self.documents = rmp_serde::from_read_ref((*serialized_data.documents).as_ref()).unwrap();
         let after = time::Instant::now(); let total = after - before;
         println!("Documents deserialize elapsed: {}", total.as_millis());
 
-        self.full_contents = serialized_data.file_contents;
-    }
-    fn get_serialized_inverted_index(&self) -> Vec<u8> {
-        //let mut s = flexbuffers::FlexbufferSerializer::new();
-        //self.index.serialize(&mut s).unwrap();
-        //s.take_buffer()
-        bincode::serialize(&self.index).unwrap()
-        //rmp_serde::to_vec(&self.index).unwrap()
-    }
-    fn get_serialized_documents(&self) -> Vec<u8> {
-        //let mut s = flexbuffers::FlexbufferSerializer::new();
-        //self.documents.serialize(&mut s).unwrap();
-        //s.take_buffer()
-        bincode::serialize(&self.documents).unwrap()
-        //rmp_serde::to_vec(&self.documents).unwrap()
-    }
-
-    fn search(&self, all_terms: Vec<&str>) -> Vec<SearchResults> {
-        let mut results: Vec<SearchResults> = Vec::new();
-        for search_term in all_terms {
-            for term in self.analyzer.analyze(search_term) {
-                if let Some(ids) = self.index.get(&term) {
-                    let mut matched_docs: Vec<Document> = Vec::new();
-                    for id in ids {
-                        matched_docs.push(self.documents[*id as usize].to_document(self.full_contents.as_ref()));
-                    }
-                    results.push(SearchResults{term: term, matches: matched_docs});
-                }
-            }
+        self.options.schema = serialized_data.schema()?;
+        self.positions = Arc::new(serialized_data.deserialize_positions()?);
+        let (added_documents, added_contents, compacted, _) = serialized_data.deserialize_additions();
+        self.added_documents = Arc::new(added_documents);
+        self.added_contents = Arc::new(added_contents);
+        self.compacted_additions = compacted;
+        // The .idx and .pos files cover the whole .add file, except in caches from before segment
+        // files, whose .add kept what was added since the last full write too, indexed again here as
+        // one segment
+        self.pending_segments = Vec::new();
+        if compacted < self.added_documents.len() {
+            self.pending_segments.push(compacted);
+            let (index, positions) = self.index_additions(&self.added_documents[compacted..]);
+            self.merge_additions(index, positions);
         }
-    
-        results
+        for segment in serialized_data.deserialize_segments() {
+            self.load_segment(segment);
+        }
+        self.deleted = Arc::new(serialized_data.deserialize_deletions().into_iter().collect());
+        // A full write left them out already, but not of documents deleted since
+        if !self.deleted.is_empty() {
+            let deleted = self.deleted.clone();
+            self.remove_postings(&deleted);
+        }
+        self.titles = OnceLock::new();
+        self.sorted_terms = OnceLock::new();
+        self.full_contents = Arc::from(serialized_data.file_contents);
+        self.sources = SourceFiles::single(&serialized_data.source);
+        self.build = serialized_data.build;
+        self.options.use_stored_boosts(self.build.as_ref());
+        Ok(())
+    }
+    fn write_index(&self, w: &mut dyn Write) -> Result<(), io::Error> {
+        //rmp_serde::encode::write(w, &self.index)
+        postings::write_index(w, self.index.len(), self.index.iter().map(|(term, ids)| (term.as_str(), ids.iter().copied().collect())))
+    }
+    fn write_documents(&self, w: &mut dyn Write) -> Result<(), io::Error> {
+        //rmp_serde::encode::write(w, &self.documents)
+        match self.deleted.is_empty() {
+            true => disk::serialize_into(w, &*self.documents),
+            false => disk::serialize_into(w, &self.without_deleted(&self.documents))
+        }
+    }
+    fn write_raw_offsets(&self, w: &mut dyn Write) -> Result<bool, io::Error> {
+        raw::write_raw_offsets(w, &self.options, &self.documents, self.full_contents.as_ref(), &self.sources)
+    }
+    fn build_info(&self) -> Option<metadata::BuildInfo> {
+        self.build.clone()
+    }
+    fn write_positions(&self, w: &mut dyn Write) -> Result<bool, io::Error> {
+        if !self.options.schema.has_entries() {
+            return Ok(false);
+        }
+        positions::write_positions(w, self.options.schema, &self.positions).map(|_| true)
+    }
+    // Added text is stored with the documents rather than appended to the source, which the cache
+    // maps as is
+    fn add_documents(&mut self, docs: Vec<Document>) {
+        if docs.is_empty() {
+            return;
+        }
+        let first_id = self.num_documents();
+        let mut added: DocumentIndex = Vec::with_capacity(docs.len());
+        for (i, doc) in docs.iter().enumerate() {
+            let contents = Arc::make_mut(&mut self.added_contents);
+            let mut push = |field: &str| {
+                let start = contents.len();
+                contents.push_str(field);
+                contents.push('\n');
+                start..start + field.len()
+            };
+            let (title, url, text) = (push(&doc.title), push(&doc.url), push(&doc.text));
+            let mut d = DocumentRaw { title, url, text, id: (first_id + i) as i32, ..DocumentRaw::default() };
+            enforce_max_doc_bytes(&mut d, &self.added_contents, 0, &self.options);
+            added.push(d);
+        }
+        let (index, positions) = self.index_additions(&added);
+        self.merge_additions(index, positions);
+        self.pending_segments.push(self.added_documents.len());
+        Arc::make_mut(&mut self.added_documents).extend(added);
+        self.titles = OnceLock::new();
+        self.sorted_terms = OnceLock::new();
+    }
+    // The documents, their text, how many of them the full cache covers, which is all of them, and
+    // the add_documents calls it doesn't, which caches from before segment files counted
+    fn write_additions(&self, w: &mut dyn Write) -> Result<bool, io::Error> {
+        if self.added_documents.is_empty() {
+            return Ok(false);
+        }
+        let documents = self.without_deleted(&self.added_documents);
+        disk::serialize_into(w, &(&documents, &*self.added_contents, self.added_documents.len(), 0_usize)).map(|_| true)
+    }
+    fn pending_segments(&self) -> Vec<i32> {
+        self.pending_segments.iter().map(|&start| self.added_documents[start].id).collect()
+    }
+    // The postings are found by analyzing the segment's documents again, the merged index doesn't
+    // tell them apart
+    fn write_segment(&self, w: &mut dyn Write, first_id: i32) -> Result<bool, io::Error> {
+        let i = match self.pending_segments.iter().position(|&start| self.added_documents[start].id == first_id) {
+            Some(i) => i,
+            None => return Ok(false)
+        };
+        let end = self.pending_segments.get(i + 1).copied().unwrap_or(self.added_documents.len());
+        let documents = &self.added_documents[self.pending_segments[i]..end];
+        let contents_start = documents[0].title.start;
+        let contents_end = self.added_documents.get(end).map_or(self.added_contents.len(), |d| d.title.start);
+        let (index, positions) = self.index_additions(documents);
+        let mut postings: Vec<u8> = Vec::new();
+        postings::write_index(&mut postings, index.len(), index.iter().map(|(term, ids)| (term.as_str(), ids.iter().copied().collect())))?;
+        let positions = match self.options.schema.has_entries() {
+            true => {
+                let mut data: Vec<u8> = Vec::new();
+                positions::write_positions(&mut data, self.options.schema, &positions)?;
+                Some(data)
+            },
+            false => None
+        };
+        let segment = cache_segments::CacheSegment {
+            documents: documents.to_vec(),
+            contents_start,
+            contents: String::from(&self.added_contents[contents_start..contents_end]),
+            postings,
+            positions
+        };
+        disk::serialize_into(w, &segment).map(|_| true)
+    }
+    fn pending_additions(&self) -> (usize, usize) {
+        (self.pending_segments.len(), self.added_documents.len() - self.compacted_additions)
+    }
+    fn mark_compacted(&mut self, (segments, documents): (usize, usize)) {
+        self.compacted_additions += documents;
+        self.pending_segments.drain(..segments);
+    }
+    // Shares everything the cache files are written from, which this indexer copies before it next
+    // changes
+    fn snapshot(&self) -> Option<Box<dyn DocumentIndexer + Send + Sync>> {
+        Some(Box::new(RayonIndexer {
+            index: Arc::clone(&self.index),
+            positions: Arc::clone(&self.positions),
+            documents: Arc::clone(&self.documents),
+            full_contents: Arc::clone(&self.full_contents),
+            added_documents: Arc::clone(&self.added_documents),
+            added_contents: Arc::clone(&self.added_contents),
+            compacted_additions: self.compacted_additions,
+            pending_segments: self.pending_segments.clone(),
+            deleted: Arc::clone(&self.deleted),
+            titles: OnceLock::new(),
+            sorted_terms: OnceLock::new(),
+            analyzer: self.analyzer.clone(),
+            query_analyzer: self.query_analyzer.clone(),
+            cur_id: atomic::AtomicI32::new(self.num_documents() as i32),
+            options: self.options.clone(),
+            sources: self.sources.clone(),
+            build: self.build.clone(),
+            keep_spilled: false,
+            spilled: None,
+            cancel: CancelToken::new()
+        }))
+    }
+    // The documents' postings go at once. They stay in the .dcm and .add files, as blank records
+    // after a full write.
+    fn delete_documents(&mut self, ids: &[i32]) -> usize {
+        let num_documents = self.num_documents() as i32;
+        let deleted: BTreeSet<i32> = ids.iter().copied().filter(|&id| (0..num_documents).contains(&id) && !self.deleted.contains(&id)).collect();
+        if !deleted.is_empty() {
+            Arc::make_mut(&mut self.deleted).extend(deleted.iter().copied());
+            self.remove_postings(&deleted);
+            self.titles = OnceLock::new();
+        }
+        deleted.len()
+    }
+    fn num_deleted(&self) -> usize {
+        self.deleted.len()
+    }
+    fn write_deletions(&self, w: &mut dyn Write) -> Result<bool, io::Error> {
+        if self.deleted.is_empty() {
+            return Ok(false);
+        }
+        disk::serialize_into(w, &*self.deleted).map(|_| true)
+    }
+
+    fn search_page(&self, all_terms: Vec<&str>, page: Page) -> Result<SearchResults, QueryError> {
+        let analyzer = self.query_analyzer.as_ref().unwrap_or(&self.analyzer);
+        run_query(analyzer, all_terms, PhraseSource::new(&self.analyzer, &self.positions, self.options.schema),
+            |term, ids| self.index.get(term).map(|postings| ids.extend(postings.iter().copied())).is_some(),
+            |prefix| self.sorted_terms().with_prefix(prefix).iter().map(String::as_str).collect(),
+            |id| self.document_at(id),
+            |term, id| self.term_frequency(term, id),
+            &self.cancel,
+            &self.options, page)
+    }
+    fn search_batch(&self, queries: &[Vec<&str>]) -> Vec<Result<SearchResults, QueryError>> {
+        search_batch_with(queries, |all_terms| self.search(all_terms))
+    }
+    fn document(&self, id: i32) -> Option<Document> {
+        self.document_at(id)
+    }
+    fn term_offsets(&self, term: &str, doc: &Document) -> Vec<(u32, u32)> {
+        find_term_offsets(&self.analyzer, &self.positions, self.options.schema, term, doc)
+    }
+    fn term_frequency(&self, term: &str, id: i32) -> Option<u32> {
+        find_term_frequency(&self.positions, self.options.schema, term, id)
     }
     fn num_tokens(&self) -> usize {
         self.index.len()
     }
     fn num_documents(&self) -> usize {
-        self.documents.len()
+        self.documents.len() + self.added_documents.len()
     }
-    
-}
\ No newline at end of file
+    fn num_oversized(&self) -> usize {
+        self.documents.iter().chain(self.added_documents.iter()).filter(|d| d.oversized).count()
+    }
+    fn schema(&self) -> Schema {
+        self.options.schema
+    }
+    fn num_positioned(&self) -> usize {
+        self.positions.values().map(|docs| docs.len()).sum()
+    }
+    fn language(&self) -> &str {
+        self.options.query_language.as_deref().unwrap_or("en")
+    }
+    fn analyze_text(&self, text: &str) -> Vec<String> {
+        self.analyzer.analyze(text)
+    }
+    fn document_frequency(&self, term: &str) -> usize {
+        self.index.get(term).map(|ids| ids.len()).unwrap_or(0)
+    }
+    fn terms_with_prefix(&self, prefix: &str, limit: usize) -> Vec<(String, usize)> {
+        most_frequent(self.sorted_terms().with_prefix(prefix).iter().map(|term| (term.clone(), self.index[term].len())), limit)
+    }
+    fn suggest_titles(&self, prefix: &str, limit: usize) -> Vec<(String, i32)> {
+        let titles = self.titles.get_or_init(|| titles::TitleIndex::new(self.documents.iter().chain(self.added_documents.iter())
+            .filter(|d| !self.deleted.contains(&d.id))
+            .map(|d| (self.title_of(d), d.id))));
+        titles.complete(prefix, limit).into_iter().map(|id| (String::from(self.title_of(self.raw_document(id))), id)).collect()
+    }
+    fn cooccurring_terms(&self, term: &str, limit: usize) -> Vec<(String, usize)> {
+        cooccurring_in(&self.index, term, limit)
+    }
+
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn documents_added_a_part_at_a_time_match_a_full_build() {
+        let contents = String::from("apple pie\npear tart\napple jam\nplum cake\n");
+        let options = IndexOptions { format: Format::Lines, ..IndexOptions::default() };
+        let files = SourceFiles::single("fruit.log");
+        let mut full = RayonIndexer::new(options.clone());
+        full.build_from_files(files.clone(), contents.clone()).unwrap();
+
+        let prefix = document_prefix(&contents, Format::Lines, 12).len();
+        let mut staged = RayonIndexer::new(options);
+        staged.build_from_files(files.clone(), String::from(&contents[..prefix])).unwrap();
+        staged.add_documents(staged.parse_documents(&contents, prefix..contents.len(), staged.num_documents() as i32, &files));
+        for id in 0..4 {
+            assert_eq!(staged.document(id).map(|doc| (doc.title, doc.url)), full.document(id).map(|doc| (doc.title, doc.url)));
+        }
+        assert_eq!(staged.search(vec!["apple"]).unwrap().ids().collect::<Vec<i32>>(), vec![0, 2]);
+    }
+}
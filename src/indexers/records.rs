@@ -0,0 +1,203 @@
+use crate::indexers::*;
+
+// Sources that hand us already separated fields (database rows, columnar files) are packed into one
+// buffer of records. For the CLI's formats read_source returns it and it goes through the same
+// split/parse/index pipeline as files on disk; read as a DocumentSource, the documents come with their
+// ranges and aren't parsed again. Each record is "title US url US text RS" using the ASCII unit (0x1F)
+// and record (0x1E) separators. Sources with vectors add a fourth field of whitespace separated
+// floats, which the text index ignores.
+pub const RECORD_SEPARATOR: char = '\u{1e}';
+pub const UNIT_SEPARATOR: char = '\u{1f}';
+
+// Which source columns hold each document field
+pub struct ColumnMapping {
+    pub title: String,
+    pub url: String,
+    pub text: String,
+    // Array of numbers to load into the vector index (jsonl only)
+    pub vector: String
+}
+
+impl Default for ColumnMapping {
+    fn default() -> Self {
+        ColumnMapping {
+            title: String::from("title"),
+            url: String::from("url"),
+            text: String::from("text"),
+            vector: String::from("vector")
+        }
+    }
+}
+
+// Where documents come from, a batch at a time until next_batch gives None: the XML parser over a
+// file (see XmlSource), archive entries, the files under a directory, or a program's own. Each batch
+// is appended to `contents` and its documents' ranges point into it, so indexers take them as they
+// are. Ids are given out in order by whatever reads the source, the batch's own are ignored.
+pub trait DocumentSource {
+    fn next_batch(&mut self, contents: &mut String) -> Result<Option<Vec<DocumentRaw>>, io::Error>;
+}
+
+// A DocumentSource for sources that page through rows or read a stream, like a database cursor or
+// a CSV reader, that hand over whole documents a batch at a time until next_batch gives None.
+pub trait DocumentBatches {
+    fn next_batch(&mut self) -> Option<Vec<Document>>;
+}
+
+impl<B: DocumentBatches> DocumentSource for B {
+    fn next_batch(&mut self, contents: &mut String) -> Result<Option<Vec<DocumentRaw>>, io::Error> {
+        let batch = match DocumentBatches::next_batch(self) {
+            Some(batch) => batch,
+            None => return Ok(None)
+        };
+        let mut records = RecordWriter::after(std::mem::take(contents));
+        for doc in batch {
+            records.push(&doc.title, &doc.url, &doc.text);
+        }
+        Ok(Some(records.finish(contents)))
+    }
+}
+
+// A document of the caller's own, for Index::build_from_documents. Like DocumentBatches' documents it
+// has no id, ids are given out in order.
+#[derive(Default, Clone)]
+pub struct InputDoc {
+    pub title: String,
+    pub url: String,
+    pub text: String
+}
+
+// `docs` as a DocumentSource, INPUT_BATCH of them at a time
+pub(crate) struct InputDocs<I>(pub(crate) I);
+
+const INPUT_BATCH: usize = 10_000;
+
+impl<I: Iterator<Item = InputDoc>> DocumentSource for InputDocs<I> {
+    fn next_batch(&mut self, contents: &mut String) -> Result<Option<Vec<DocumentRaw>>, io::Error> {
+        let mut records = RecordWriter::after(std::mem::take(contents));
+        for doc in self.0.by_ref().take(INPUT_BATCH) {
+            records.push(&doc.title, &doc.url, &doc.text);
+        }
+        let batch = records.finish(contents);
+        Ok(Some(batch).filter(|batch| !batch.is_empty()))
+    }
+}
+
+// The text of every document `source` gives, as read_source returns it: a record buffer for sources
+// that hand over separate fields
+pub fn read_documents(source: &mut dyn DocumentSource) -> Result<String, io::Error> {
+    let mut contents = String::new();
+    while source.next_batch(&mut contents)?.is_some() {}
+    Ok(contents)
+}
+
+#[cfg(any(feature = "sqlite", feature = "postgres", feature = "parquet"))]
+pub fn to_io_error<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+// Packs documents into records, keeping where each one's fields ended up so a DocumentSource can
+// hand them over without the buffer being parsed again
+#[derive(Default)]
+pub struct RecordWriter {
+    contents: String,
+    documents: Vec<DocumentRaw>
+}
+
+impl RecordWriter {
+    pub fn new() -> Self {
+        RecordWriter::default()
+    }
+
+    // Packs records onto the end of `contents`
+    pub fn after(contents: String) -> Self {
+        RecordWriter { contents, documents: Vec::new() }
+    }
+
+    pub fn push(&mut self, title: &str, url: &str, text: &str) {
+        self.push_fields(&[title, url, text]);
+    }
+
+    pub fn push_with_vector(&mut self, title: &str, url: &str, text: &str, vector: &[f32]) {
+        let vector: Vec<String> = vector.iter().map(|x| x.to_string()).collect();
+        self.push_fields(&[title, url, text, &vector.join(" ")]);
+    }
+
+    fn push_fields(&mut self, fields: &[&str]) {
+        let mut ranges: [Range<usize>; 3] = Default::default();
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                self.contents.push(UNIT_SEPARATOR);
+            }
+            let start = self.contents.len();
+            self.contents.extend(field.chars().filter(|c| *c != RECORD_SEPARATOR && *c != UNIT_SEPARATOR));
+            if let Some(range) = ranges.get_mut(i) {
+                *range = Range{start, end: self.contents.len()};
+            }
+        }
+        self.contents.push(RECORD_SEPARATOR);
+        let [title, url, text] = ranges;
+        self.documents.push(DocumentRaw {
+            title,
+            url,
+            text,
+            keywords: Range{start: 0, end: 0},
+            id: self.documents.len() as i32,
+            oversized: false
+        });
+    }
+
+    pub fn into_contents(self) -> String {
+        self.contents
+    }
+
+    // The documents pushed, with the records handed back to `contents`
+    pub(super) fn finish(self, contents: &mut String) -> Vec<DocumentRaw> {
+        *contents = self.contents;
+        self.documents
+    }
+}
+
+// Ids are record numbers, like line numbers for Format::Lines, so they line up with the vector index
+pub(super) fn parse_records(contents: &ContentsSplit, first_id: i32) -> Vec<DocumentRaw> {
+    let base_offset = contents.base_offset;
+    let mut docs: Vec<DocumentRaw> = Vec::new();
+    let mut record_start = 0;
+    for (i, record) in contents.data.split_terminator(RECORD_SEPARATOR).enumerate() {
+        let mut fields: [Range<usize>; 4] = Default::default();
+        let mut field_start = record_start;
+        for (f, field) in record.splitn(4, UNIT_SEPARATOR).enumerate() {
+            fields[f] = Range{start: base_offset + field_start, end: base_offset + field_start + field.len()};
+            field_start += field.len() + UNIT_SEPARATOR.len_utf8();
+        }
+        let [title, url, text, _vector] = fields;
+        docs.push(DocumentRaw {
+            title,
+            url,
+            text,
+            keywords: Range{start: 0, end: 0},
+            id: first_id + i as i32,
+            oversized: false
+        });
+        record_start += record.len() + RECORD_SEPARATOR.len_utf8();
+    }
+    docs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_docs_are_indexed_in_order() {
+        let docs = ["apple pie", "pear tart", "apple juice"].iter().enumerate().map(|(i, text)| InputDoc {
+            title: format!("Recipe {}", i),
+            url: format!("db://recipes/{}", i),
+            text: String::from(*text)
+        });
+        let mut indexer = RayonIndexer::new(IndexOptions { format: Format::Records, ..IndexOptions::default() });
+        indexer.build_from_source("recipes", &mut InputDocs(docs)).unwrap();
+        assert_eq!(indexer.num_documents(), 3);
+        let found: Vec<(i32, String)> = indexer.search(vec!["apple"]).unwrap().documents().map(|doc| (doc.id, doc.url.clone())).collect();
+        assert_eq!(found, vec![(0, String::from("db://recipes/0")), (2, String::from("db://recipes/2"))]);
+    }
+}
@@ -0,0 +1,94 @@
+use crate::indexers::*;
+use std::process::{Command, Stdio};
+
+// Turns texts into vectors for semantic re-ranking, one vector per text in order
+pub trait Embedder {
+    fn embed(&mut self, texts: &[String]) -> Result<Vec<Vec<f32>>, io::Error>;
+}
+
+// Runs an external command per batch: texts are written to its stdin one per line (newlines folded
+// into spaces) and it must print one line of whitespace separated floats per text
+pub struct CommandEmbedder {
+    program: String,
+    args: Vec<String>
+}
+
+impl CommandEmbedder {
+    pub fn new(command: &str) -> Result<Self, io::Error> {
+        let mut parts = command.split_whitespace().map(String::from);
+        let program = parts.next().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty embedding command"))?;
+        Ok(CommandEmbedder { program, args: parts.collect() })
+    }
+}
+
+impl Embedder for CommandEmbedder {
+    fn embed(&mut self, texts: &[String]) -> Result<Vec<Vec<f32>>, io::Error> {
+        let mut child = Command::new(&self.program).args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        // Written on another thread while the output is read, so a command that prints as it reads
+        // doesn't wait on a full stdout pipe while we wait on its full stdin
+        let (written, output) = std::thread::scope(|scope| {
+            let writer = scope.spawn(move || {
+                let mut stdin = io::BufWriter::new(stdin);
+                for text in texts {
+                    writeln!(stdin, "{}", text.replace(['\r', '\n'], " "))?;
+                }
+                stdin.flush()
+            });
+            let output = child.wait_with_output();
+            (writer.join().unwrap(), output)
+        });
+        let output = output?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!("embedding command exited with {}", output.status)));
+        }
+        written?;
+        let vectors = String::from_utf8_lossy(&output.stdout).lines()
+            .map(|line| line.split_whitespace().map(|x| x.parse::<f32>()).collect::<Result<Vec<f32>, _>>())
+            .collect::<Result<Vec<Vec<f32>>, _>>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("bad embedding output: {}", e)))?;
+        if vectors.len() != texts.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("embedding command returned {} vectors for {} texts", vectors.len(), texts.len())));
+        }
+        Ok(vectors)
+    }
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norms = a.iter().map(|x| x * x).sum::<f32>().sqrt() * b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norms > 0.0 { dot / norms } else { 0.0 }
+}
+
+// Orders the first `top_k` of `docs` by similarity to `query`, returning (similarity, index into docs)
+// pairs. The query is embedded in the same batch as the documents.
+pub fn rerank(embedder: &mut dyn Embedder, query: &str, docs: &[Document], top_k: usize) -> Result<Vec<(f32, usize)>, io::Error> {
+    let candidates = &docs[..cmp::min(top_k, docs.len())];
+    if candidates.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut texts: Vec<String> = vec![String::from(query)];
+    texts.extend(candidates.iter().map(|d| format!("{} {}", d.title, d.text)));
+    let vectors = embedder.embed(&texts)?;
+    let mut ranked: Vec<(f32, usize)> = vectors[1..].iter().enumerate().map(|(i, v)| (cosine(&vectors[0], v), i)).collect();
+    ranked.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(cmp::Ordering::Equal).then_with(|| a.1.cmp(&b.1)));
+    Ok(ranked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_printing_as_they_read_fill_no_pipe() {
+        // Far more of either than a pipe holds
+        let texts: Vec<String> = (0..50_000).map(|i| format!("text {} {}", i, "x".repeat(100))).collect();
+        let mut embedder = CommandEmbedder::new("awk {print(1,0)}").unwrap();
+        let vectors = embedder.embed(&texts).unwrap();
+        assert_eq!(vectors.len(), texts.len());
+        assert_eq!(vectors[0], vec![1.0, 0.0]);
+    }
+}
@@ -0,0 +1,323 @@
+use crate::indexers::*;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::fmt;
+
+// One document a search matched
+#[derive(Clone)]
+pub struct Hit {
+    pub doc: Document,
+    // The FieldBoosts weights of the query's listed terms the document matched added up, so documents
+    // matching more of them, or in weightier fields, rank first. With Schema::frequencies a term
+    // occurring n times in the document weighs 1 + ln(n) times as much. Every match of a boolean
+    // query scores 1.
+    pub score: f32,
+    // Labels of the terms it matched, in query order: an analyzed term, a "quoted phrase", a
+    // "prefix*", or for a boolean query the whole query
+    pub terms: Vec<String>
+}
+
+impl Hit {
+    // The terms as one label, which snippets and term_offsets analyze back into the terms
+    pub fn label(&self) -> String {
+        self.terms.join(" ")
+    }
+}
+
+// Which of a search's ranked matches to return: `limit` of them after skipping `offset`, or all of
+// them from `offset` on without a limit. The default is every match.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Page {
+    pub offset: usize,
+    pub limit: Option<usize>
+}
+
+impl Page {
+    pub fn is_all(&self) -> bool {
+        self.offset == 0 && self.limit.is_none()
+    }
+}
+
+// A search's matches, each document once, best first: by score, then in the order of the first term
+// each matched, then as that term's postings listed them
+#[derive(Clone, Default)]
+pub struct SearchResults {
+    // The requested Page of the matches
+    pub hits: Vec<Hit>,
+    // Documents matched in all, on any page
+    pub total: usize,
+    // Labels of the listed terms that matched any id, in query order
+    pub terms: Vec<String>,
+    // Matching ids with no document, left out of `hits`. Postings can run ahead of the documents, as
+    // when a cache's .idx and .dcm are from different builds, and searching shouldn't panic on it.
+    pub missing: usize
+}
+
+impl SearchResults {
+    pub fn len(&self) -> usize {
+        self.hits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hits.is_empty()
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = i32> + '_ {
+        self.hits.iter().map(|hit| hit.doc.id)
+    }
+
+    pub fn documents(&self) -> impl Iterator<Item = &Document> {
+        self.hits.iter().map(|hit| &hit.doc)
+    }
+
+    pub fn into_documents(self) -> impl Iterator<Item = Document> {
+        self.hits.into_iter().map(|hit| hit.doc)
+    }
+
+    // Keeps the hits on `page` of these, for results that were searched for in full
+    pub fn into_page(mut self, page: Page) -> SearchResults {
+        self.hits = self.hits.into_iter().skip(page.offset).take(page.limit.unwrap_or(usize::MAX)).collect();
+        self
+    }
+}
+
+// Field of terms without a "field:" prefix
+pub const TEXT_FIELD: &str = "text";
+
+// What a match in each field adds to a hit's score, from "title=3.0,text=1.0". A listed term is in
+// the field of its label: "field:value" terms in theirs, every other one, phrases and prefixes
+// included, in TEXT_FIELD. Fields not named weigh 1, so no boosts score by terms matched.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct FieldBoosts(BTreeMap<String, f32>);
+
+impl FieldBoosts {
+    pub fn parse(boosts: &str) -> Result<FieldBoosts, String> {
+        let mut weights: BTreeMap<String, f32> = BTreeMap::new();
+        for boost in boosts.split(',').map(str::trim).filter(|boost| !boost.is_empty()) {
+            let weight = boost.split_once('=')
+                .map(|(field, weight)| (field.trim().to_lowercase(), weight.trim().parse::<f32>()));
+            match weight {
+                Some((field, Ok(weight))) if !field.is_empty() && weight.is_finite() && weight >= 0.0 => {
+                    // -0 passes the check but would rank first, as scores are ranked by their bits
+                    weights.insert(field, weight + 0.0);
+                },
+                _ => return Err(format!("bad boost '{}', expected FIELD=WEIGHT with a weight of 0 or more", boost))
+            }
+        }
+        Ok(FieldBoosts(weights))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn of_label(&self, label: &str) -> f32 {
+        let field = match label.split_once(':') {
+            Some((field, _)) if !field.is_empty() && !label.starts_with('"') => field,
+            _ => TEXT_FIELD
+        };
+        self.0.get(field).copied().unwrap_or(1.0)
+    }
+}
+
+impl fmt::Display for FieldBoosts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let boosts: Vec<String> = self.0.iter().map(|(field, weight)| format!("{}={}", field, weight)).collect();
+        write!(f, "{}", boosts.join(","))
+    }
+}
+
+// The ids each listed term matched, in query order, merged into hits
+pub(super) struct HitsBuilder {
+    terms: Vec<String>,
+    // What each of `terms` adds to the score of the ids it matched
+    weights: Vec<f32>,
+    // Each id once, in the order first matched, with the terms (numbers into `terms`) it matched and
+    // how much each counts in it
+    order: Vec<i32>,
+    matched: HashMap<i32, Vec<(usize, f32)>>
+}
+
+impl HitsBuilder {
+    pub(super) fn new() -> HitsBuilder {
+        HitsBuilder { terms: Vec::new(), weights: Vec::new(), order: Vec::new(), matched: HashMap::new() }
+    }
+
+    fn score(&self, matched: &[(usize, f32)]) -> f32 {
+        matched.iter().map(|(t, tf)| self.weights[*t] * tf).sum()
+    }
+
+    // A term with no ids kept isn't listed
+    pub(super) fn add(&mut self, term: String, weight: f32, ids: impl Iterator<Item = i32>) {
+        self.add_counted(term, weight, ids, |_| None);
+    }
+
+    // As add, with `frequency` giving how many times the term occurs in each document when the index
+    // recorded it. A term counts 1 + ln(frequency) times, so repeats raise a score less and less.
+    pub(super) fn add_counted(&mut self, term: String, weight: f32, ids: impl Iterator<Item = i32>, frequency: impl Fn(i32) -> Option<u32>) {
+        let t = self.terms.len();
+        let mut any = false;
+        for id in ids {
+            any = true;
+            let order = &mut self.order;
+            let terms = self.matched.entry(id).or_insert_with(|| {
+                order.push(id);
+                Vec::new()
+            });
+            if terms.last().map(|(last, _)| *last) != Some(t) {
+                let tf = frequency(id).filter(|count| *count > 1).map_or(1.0, |count| 1.0 + (count as f32).ln());
+                terms.push((t, tf));
+            }
+        }
+        if any {
+            self.terms.push(term);
+            self.weights.push(weight);
+        }
+    }
+
+    // Ranks the matched ids and fetches the documents of those on `page` once each through `fetch`,
+    // which returns the documents found and how many ids had none. With a limit only the best
+    // offset + limit ids are kept while ranking, in a heap whose top is the worst of them. Ids with no
+    // document still take their place, so a page can come out short.
+    pub(super) fn finish(mut self, page: Page, fetch: impl FnOnce(&[i32]) -> (Vec<Document>, usize)) -> SearchResults {
+        let total = self.order.len();
+        // Ranks by higher score, then matched earlier. Weights are never negative, and the bits of
+        // floats that aren't order as the floats do.
+        let ranked = self.order.iter().enumerate().map(|(i, id)| (Reverse(self.score(&self.matched[id]).to_bits()), i));
+        let mut best: Vec<(Reverse<u32>, usize)> = match page.limit {
+            Some(limit) => {
+                let keep = page.offset.saturating_add(limit);
+                let mut heap: BinaryHeap<(Reverse<u32>, usize)> = BinaryHeap::with_capacity(keep.min(total) + 1);
+                for rank in ranked {
+                    if heap.len() < keep {
+                        heap.push(rank);
+                    } else if heap.peek().is_some_and(|worst| rank < *worst) {
+                        heap.pop();
+                        heap.push(rank);
+                    }
+                }
+                heap.into_vec()
+            },
+            None => ranked.collect()
+        };
+        best.sort_unstable();
+        let ids: Vec<i32> = best.into_iter().skip(page.offset).map(|(_, i)| self.order[i]).collect();
+        let (docs, missing) = fetch(&ids);
+        let hits: Vec<Hit> = docs.into_iter().map(|doc| {
+            let matched = self.matched.remove(&doc.id).unwrap_or_default();
+            let score = self.score(&matched);
+            Hit { doc, score, terms: matched.into_iter().map(|(t, _)| self.terms[t].clone()).collect() }
+        }).collect();
+        SearchResults { hits, total, terms: self.terms, missing }
+    }
+}
+
+// The shape search had before hits: one entry per listed term with every document that matched it, a
+// document matching several terms listed under each. Kept while callers move to hits.
+#[cfg(feature = "legacy-results")]
+#[derive(Clone)]
+pub struct TermResults {
+    pub term: String,
+    pub matches: Vec<Document>,
+    // The whole search's, on the first term's results
+    pub missing: usize
+}
+
+#[cfg(feature = "legacy-results")]
+impl SearchResults {
+    #[deprecated(note = "use SearchResults::hits, where each document is listed once with the terms it matched")]
+    pub fn by_term(&self) -> Vec<TermResults> {
+        self.terms.iter().enumerate().map(|(i, term)| TermResults {
+            term: term.clone(),
+            matches: self.hits.iter().filter(|hit| hit.terms.contains(term)).map(|hit| hit.doc.clone()).collect(),
+            missing: if i == 0 { self.missing } else { 0 }
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(id: i32) -> Document {
+        Document { id, ..Document::default() }
+    }
+
+    #[test]
+    fn documents_matching_more_terms_rank_first() {
+        let mut builder = HitsBuilder::new();
+        builder.add(String::from("red"), 1.0, vec![3, 1].into_iter());
+        builder.add(String::from("none"), 1.0, std::iter::empty());
+        builder.add(String::from("appl"), 1.0, vec![1, 2, 4].into_iter());
+        let results = builder.finish(Page::default(), |ids| (ids.iter().copied().filter(|id| *id != 4).map(document).collect(), 1));
+        let hits: Vec<(i32, f32, Vec<&str>)> = results.hits.iter().map(|hit| (hit.doc.id, hit.score, hit.terms.iter().map(String::as_str).collect())).collect();
+        assert_eq!(hits, vec![(1, 2.0, vec!["red", "appl"]), (3, 1.0, vec!["red"]), (2, 1.0, vec!["appl"])]);
+        assert_eq!(results.terms, vec!["red", "appl"]);
+        assert_eq!(results.missing, 1);
+    }
+
+    #[test]
+    fn only_the_page_is_fetched() {
+        let pages: Vec<Vec<i32>> = [(0, Some(2)), (1, Some(2)), (3, None), (4, Some(1)), (0, Some(0))].iter().map(|(offset, limit)| {
+            let mut builder = HitsBuilder::new();
+            builder.add(String::from("red"), 1.0, vec![5, 3, 1].into_iter());
+            builder.add(String::from("appl"), 1.0, vec![1, 2, 3].into_iter());
+            let fetched = std::cell::Cell::new(0);
+            let results = builder.finish(Page { offset: *offset, limit: *limit }, |ids| {
+                fetched.set(ids.len());
+                (ids.iter().copied().map(document).collect(), 0)
+            });
+            assert_eq!(results.total, 4);
+            assert_eq!(fetched.get(), results.len());
+            results.ids().collect()
+        }).collect();
+        // 3 and 1 match both terms and rank first, in the order first matched
+        assert_eq!(pages, vec![vec![3, 1], vec![1, 5], vec![2], vec![], vec![]]);
+    }
+
+    #[test]
+    fn boosted_fields_outrank_more_terms() {
+        let boosts = FieldBoosts::parse("title=3, Text=0.5").unwrap();
+        assert_eq!(boosts.to_string(), "text=0.5,title=3");
+        assert_eq!((boosts.of_label("title:appl"), boosts.of_label("appl"), boosts.of_label("\"red appl\""), boosts.of_label("from:bob")), (3.0, 0.5, 0.5, 1.0));
+        assert!(FieldBoosts::parse("title=-1").is_err());
+        assert!(FieldBoosts::parse("title").is_err());
+        assert_eq!(FieldBoosts::parse("title=-0").unwrap().of_label("title:appl").to_bits(), 0.0f32.to_bits());
+
+        let mut builder = HitsBuilder::new();
+        for term in ["red", "appl", "title:appl"] {
+            builder.add(String::from(term), boosts.of_label(term), match term { "title:appl" => vec![2], _ => vec![1] }.into_iter());
+        }
+        let results = builder.finish(Page::default(), |ids| (ids.iter().copied().map(document).collect(), 0));
+        let hits: Vec<(i32, f32)> = results.hits.iter().map(|hit| (hit.doc.id, hit.score)).collect();
+        assert_eq!(hits, vec![(2, 3.0), (1, 1.0)]);
+    }
+
+    #[test]
+    fn repeated_terms_weigh_more() {
+        let mut builder = HitsBuilder::new();
+        builder.add_counted(String::from("red"), 1.0, vec![1, 2, 3].into_iter(), |id| match id {
+            2 => Some(3),
+            3 => Some(1),
+            _ => None
+        });
+        builder.add(String::from("appl"), 1.0, vec![1].into_iter());
+        let results = builder.finish(Page::default(), |ids| (ids.iter().copied().map(document).collect(), 0));
+        let hits: Vec<(i32, f32)> = results.hits.iter().map(|hit| (hit.doc.id, hit.score)).collect();
+        assert_eq!(hits, vec![(2, 1.0 + 3.0f32.ln()), (1, 2.0), (3, 1.0)]);
+    }
+
+    #[cfg(feature = "legacy-results")]
+    #[test]
+    #[allow(deprecated)]
+    fn by_term_lists_each_terms_documents() {
+        let mut builder = HitsBuilder::new();
+        builder.add(String::from("red"), 1.0, vec![3, 1].into_iter());
+        builder.add(String::from("appl"), 1.0, vec![1, 2].into_iter());
+        let by_term: Vec<(String, Vec<i32>)> = builder.finish(Page::default(), |ids| (ids.iter().copied().map(document).collect(), 0)).by_term().into_iter()
+            .map(|results| (results.term, results.matches.iter().map(|doc| doc.id).collect()))
+            .collect();
+        assert_eq!(by_term, vec![(String::from("red"), vec![1, 3]), (String::from("appl"), vec![1, 2])]);
+    }
+}
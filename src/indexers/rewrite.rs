@@ -0,0 +1,109 @@
+use crate::indexers::*;
+use crate::indexers::query::{split_occur, Occur};
+
+// Query rewrite rules, one per line as `pattern => replacement`. The pattern is one or more words
+// matched case-insensitively against whole consecutive query terms, and the replacement is query
+// text put in their place, so an expansion repeats the pattern: `car => car automobile`. Blank lines
+// and lines starting with '#' are skipped. Rules are applied once, longest pattern first, before the
+// terms reach the analyzer.
+pub struct Rewrites {
+    rules: Vec<Rule>
+}
+
+struct Rule {
+    pattern: Vec<String>,
+    replacement: Vec<String>
+}
+
+// One rule that fired, for --show-rewrites
+pub struct Rewrite {
+    pub from: String,
+    pub to: String
+}
+
+impl Rewrites {
+    pub fn load(path: &str) -> Result<Self, io::Error> {
+        Rewrites::parse(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, io::Error> {
+        let mut rules: Vec<Rule> = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", i + 1, message));
+            let (pattern, replacement) = line.split_once("=>").ok_or_else(|| invalid("expected 'pattern => replacement'"))?;
+            let pattern: Vec<String> = pattern.split_whitespace().map(str::to_lowercase).collect();
+            if pattern.is_empty() {
+                return Err(invalid("empty pattern"));
+            }
+            let replacement: Vec<String> = query::split_query(replacement).into_iter().map(String::from).collect();
+            rules.push(Rule { pattern, replacement });
+        }
+        // Stable, so among patterns of the same length the first in the file wins
+        rules.sort_by_key(|rule| cmp::Reverse(rule.pattern.len()));
+        Ok(Rewrites { rules })
+    }
+
+    // A sign on the first matched term carries over to every replacement term, so "-nyc" excludes
+    // each of "new york city"
+    pub fn apply(&self, terms: &[&str]) -> (Vec<String>, Vec<Rewrite>) {
+        let lowercase: Vec<String> = terms.iter().map(|t| t.to_lowercase()).collect();
+        let mut rewritten: Vec<String> = Vec::with_capacity(terms.len());
+        let mut applied: Vec<Rewrite> = Vec::new();
+        let mut i = 0;
+        while i < terms.len() {
+            let (occur, first) = split_occur(&lowercase[i]);
+            let rule = self.rules.iter().find(|rule| {
+                i + rule.pattern.len() <= terms.len()
+                    && rule.pattern[0] == first
+                    && rule.pattern[1..].iter().zip(&lowercase[i + 1..]).all(|(p, t)| p == t)
+            });
+            match rule {
+                Some(rule) => {
+                    let sign = match occur {
+                        Occur::Should => "",
+                        Occur::Must => "+",
+                        Occur::MustNot => "-"
+                    };
+                    let replacement: Vec<String> = rule.replacement.iter().map(|t| format!("{}{}", sign, t)).collect();
+                    applied.push(Rewrite { from: terms[i..i + rule.pattern.len()].join(" "), to: replacement.join(" ") });
+                    rewritten.extend(replacement);
+                    i += rule.pattern.len();
+                },
+                None => {
+                    rewritten.push(String::from(terms[i]));
+                    i += 1;
+                }
+            }
+        }
+        (rewritten, applied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(rules: &str, query: &str) -> Vec<String> {
+        Rewrites::parse(rules).unwrap().apply(&query::split_query(query)).0
+    }
+
+    #[test]
+    fn longest_pattern_wins_and_signs_carry_over() {
+        let rules = "# places\nnyc => new york city\nnew york => `new york`\ncar => car automobile\n";
+        assert_eq!(rewrite(rules, "NYC cabs"), vec!["new", "york", "city", "cabs"]);
+        assert_eq!(rewrite(rules, "new York car"), vec!["`new york`", "car", "automobile"]);
+        assert_eq!(rewrite(rules, "-nyc +car"), vec!["-new", "-york", "-city", "+car", "+automobile"]);
+        assert_eq!(rewrite(rules, "new -york"), vec!["new", "-york"]);
+    }
+
+    #[test]
+    fn malformed_rules_name_their_line() {
+        assert!(Rewrites::parse("\nnyc new york").err().unwrap().to_string().starts_with("line 2:"));
+        assert!(Rewrites::parse(" => x").is_err());
+    }
+}
@@ -0,0 +1,244 @@
+use crate::indexers::*;
+use roaring::RoaringBitmap;
+
+type BitmapIndex = HashMap<String, RoaringBitmap, BuildHasherDefault<FxHasher>>;
+
+// Keeps each term's postings as a roaring bitmap: runs of ids are stored as runs and dense stretches as
+// bitsets, so a term in most documents takes a bit or so per document rather than a hash set entry,
+// and boolean queries intersect and union whole bitmaps instead of looking ids up one at a time. Builds
+// go through RayonIndexer and are converted once done, and the cache is the usual one. Positions
+// aren't kept, so phrases are checked against the documents' text, and documents can't be added.
+pub struct RoaringIndexer {
+    index: BitmapIndex,
+    sorted_terms: sorted_terms::SortedTerms,
+    documents: Vec<DocumentRaw>,
+    full_contents: BoxedBytes,
+    // Always empty, for phrase_ids to fall back from
+    positions: PositionIndex,
+    titles: OnceLock<titles::TitleIndex>,
+    analyzer: Analyzer,
+    query_analyzer: Option<Analyzer>,
+    options: IndexOptions,
+    sources: SourceFiles,
+    build: Option<BuildInfo>,
+    cancel: CancelToken
+}
+
+impl query::IdSet for RoaringBitmap {
+    fn and(&self, other: &Self) -> Self {
+        self & other
+    }
+    fn or(&self, other: &Self) -> Self {
+        self | other
+    }
+    fn and_not(&self, other: &Self) -> Self {
+        self - other
+    }
+    fn into_ids(self) -> Vec<i32> {
+        self.iter().map(|id| id as i32).collect()
+    }
+}
+
+impl RoaringIndexer {
+    pub fn new(options: IndexOptions) -> Self {
+        let (analyzer, query_analyzer) = Analyzer::from_options(&options);
+        RoaringIndexer {
+            index: BitmapIndex::default(),
+            sorted_terms: sorted_terms::SortedTerms::new(std::iter::empty()),
+            documents: Vec::new(),
+            full_contents: Box::new(String::new()),
+            positions: new_position_index(),
+            titles: OnceLock::new(),
+            analyzer,
+            query_analyzer,
+            options: IndexOptions { schema: Schema::default(), ..options },
+            sources: SourceFiles::default(),
+            build: None,
+            cancel: CancelToken::new()
+        }
+    }
+
+    fn convert(&mut self, built: RayonIndexer) -> Result<(), io::Error> {
+        let (postings, documents, full_contents, sources, build) = built.into_parts();
+        let (index, _) = postings.into_memory()?;
+        self.index = index.into_iter().map(|(term, ids)| (term, ids.into_iter().map(|id| id as u32).collect())).collect();
+        self.sorted_terms = sorted_terms::SortedTerms::new(self.index.keys().cloned());
+        self.documents = documents;
+        self.full_contents = full_contents;
+        self.sources = sources;
+        self.build = build.map(|build| BuildInfo { backend: String::from("roaring"), ..build });
+        self.options.use_stored_boosts(self.build.as_ref());
+        self.titles = OnceLock::new();
+        Ok(())
+    }
+
+    fn rayon(&self) -> RayonIndexer {
+        let mut rayon = RayonIndexer::new(self.options.clone());
+        rayon.set_cancel_token(self.cancel.clone());
+        rayon
+    }
+
+    fn title_of(&self, d: &DocumentRaw) -> &str {
+        self.full_contents.from_utf8_unchecked(d.title.clone())
+    }
+
+    // `term`'s bitmap counted against `budget`, None when it isn't indexed or the budget is spent
+    fn bitmap(&self, term: &str, budget: &QueryBudget) -> Option<&RoaringBitmap> {
+        self.index.get(term).filter(|ids| budget.use_postings(ids.len() as usize, ids.serialized_size()))
+    }
+
+    // A boolean query term's documents as a bitmap, as run_boolean_query finds them as ids
+    fn term_bitmap(&self, analyzer: &Analyzer, term: &str, budget: &QueryBudget) -> Option<RoaringBitmap> {
+        if let Some(phrase) = query::parse_phrase(term) {
+            let ids = phrase_ids(analyzer, &phrase, &PhraseSource::Text(&self.analyzer), &|term, ids| self.postings_into(term, ids, budget), &|id| self.document(id))?;
+            return Some(ids.into_iter().map(|id| id as u32).collect());
+        }
+        if let Some(prefix) = query::parse_prefix(term) {
+            let prefix = analyzer.analyze_prefix(prefix);
+            let terms = self.sorted_terms.with_prefix(&prefix);
+            if prefix.is_empty() || !budget.expand(&prefix, terms.len()) {
+                return None;
+            }
+            return Some(terms.iter().filter_map(|term| self.bitmap(term, budget)).fold(RoaringBitmap::new(), |ids, term_ids| ids | term_ids));
+        }
+        analyzer.analyze_query(term).iter()
+            .map(|term| self.bitmap(term, budget).cloned().unwrap_or_default())
+            .reduce(|ids, term_ids| ids & term_ids)
+    }
+
+    fn postings_into(&self, term: &str, ids: &mut Vec<i32>, budget: &QueryBudget) -> bool {
+        budget.read_postings(ids, |ids| self.index.get(term).map(|postings| ids.extend(postings.iter().map(|id| id as i32))).is_some())
+    }
+}
+
+impl DocumentIndexer for RoaringIndexer {
+    fn build_from_files(&mut self, files: SourceFiles, file_contents: String) -> Result<(), io::Error> {
+        let mut rayon = self.rayon();
+        rayon.build_from_files(files, file_contents)?;
+        self.convert(rayon)
+    }
+    fn build_streaming(&mut self, path: &str) -> Result<(), io::Error> {
+        let mut rayon = self.rayon();
+        rayon.build_streaming(path)?;
+        self.convert(rayon)
+    }
+    fn set_cancel_token(&mut self, token: CancelToken) -> bool {
+        self.cancel = token;
+        true
+    }
+    // Refuses a cache with added or deleted documents, which RayonIndexer would keep apart from the rest
+    fn open_cache(&mut self, path: &str) -> Result<bool, io::Error> {
+        match cache_segments::has_changes(Path::new(path)) {
+            true => Err(io::Error::new(io::ErrorKind::Unsupported, "documents were added to or deleted from the cache, which the roaring backend can't search")),
+            false => Ok(false)
+        }
+    }
+    fn build_from_serialized(&mut self, serialized_data: SerializedIndex) -> Result<(), io::Error> {
+        let mut rayon = self.rayon();
+        rayon.build_from_serialized(serialized_data)?;
+        // Nothing is spilled when loading, so there's nothing to read back
+        self.convert(rayon)
+    }
+    fn write_index(&self, w: &mut dyn Write) -> Result<(), io::Error> {
+        postings::write_index(w, self.index.len(), self.index.iter().map(|(term, ids)| (term.as_str(), ids.iter().map(|id| id as i32).collect())))
+    }
+    fn write_documents(&self, w: &mut dyn Write) -> Result<(), io::Error> {
+        disk::serialize_into(w, &self.documents)
+    }
+    fn write_raw_offsets(&self, w: &mut dyn Write) -> Result<bool, io::Error> {
+        raw::write_raw_offsets(w, &self.options, &self.documents, self.full_contents.as_ref(), &self.sources)
+    }
+    fn build_info(&self) -> Option<BuildInfo> {
+        self.build.clone()
+    }
+
+    // Boolean queries are evaluated over the bitmaps, the rest as by every other backend
+    fn search_page(&self, all_terms: Vec<&str>, page: Page) -> Result<SearchResults, QueryError> {
+        let analyzer = self.query_analyzer.as_ref().unwrap_or(&self.analyzer);
+        if !query::is_boolean(&all_terms) {
+            return run_query(analyzer, all_terms, PhraseSource::new(&self.analyzer, &self.positions, self.options.schema),
+                |term, ids| self.index.get(term).map(|postings| ids.extend(postings.iter().map(|id| id as i32))).is_some(),
+                |prefix| self.sorted_terms.with_prefix(prefix).iter().map(String::as_str).collect(),
+                |id| self.document(id),
+                |term, id| self.term_frequency(term, id),
+                &self.cancel,
+                &self.options, page);
+        }
+        let budget = QueryBudget::new(&self.options.limits, &self.cancel);
+        let results = boolean_results(&all_terms, &|term: &str| self.term_bitmap(analyzer, term, &budget), &|id| self.document(id), &budget, page);
+        budget.finish(results)
+    }
+    fn search_batch(&self, queries: &[Vec<&str>]) -> Vec<Result<SearchResults, QueryError>> {
+        search_batch_with(queries, |all_terms| self.search(all_terms))
+    }
+    fn document(&self, id: i32) -> Option<Document> {
+        self.documents.get(id as usize).map(|d| d.to_document(self.full_contents.as_ref(), self.options.format, &self.sources))
+    }
+    fn term_offsets(&self, term: &str, doc: &Document) -> Vec<(u32, u32)> {
+        find_term_offsets(&self.analyzer, &self.positions, self.options.schema, term, doc)
+    }
+    fn num_tokens(&self) -> usize {
+        self.index.len()
+    }
+    fn num_documents(&self) -> usize {
+        self.documents.len()
+    }
+    fn num_oversized(&self) -> usize {
+        self.documents.iter().filter(|d| d.oversized).count()
+    }
+    fn schema(&self) -> Schema {
+        self.options.schema
+    }
+    fn num_positioned(&self) -> usize {
+        0
+    }
+    fn language(&self) -> &str {
+        self.options.query_language.as_deref().unwrap_or("en")
+    }
+    fn analyze_text(&self, text: &str) -> Vec<String> {
+        self.analyzer.analyze(text)
+    }
+    fn document_frequency(&self, term: &str) -> usize {
+        self.index.get(term).map(|ids| ids.len() as usize).unwrap_or(0)
+    }
+    fn terms_with_prefix(&self, prefix: &str, limit: usize) -> Vec<(String, usize)> {
+        most_frequent(self.sorted_terms.with_prefix(prefix).iter().map(|term| (term.clone(), self.index[term].len() as usize)), limit)
+    }
+    fn suggest_titles(&self, prefix: &str, limit: usize) -> Vec<(String, i32)> {
+        let titles = self.titles.get_or_init(|| titles::TitleIndex::new(self.documents.iter().map(|d| (self.title_of(d), d.id))));
+        titles.complete(prefix, limit).into_iter().map(|id| (String::from(self.title_of(&self.documents[id as usize])), id)).collect()
+    }
+    fn cooccurring_terms(&self, term: &str, limit: usize) -> Vec<(String, usize)> {
+        let ids = match self.index.get(term) {
+            Some(ids) => ids,
+            None => return Vec::new()
+        };
+        let shared = self.index.iter()
+            .filter(|(other, _)| other.as_str() != term)
+            .map(|(other, other_ids)| (other.clone(), ids.intersection_len(other_ids) as usize))
+            .filter(|(_, count)| *count > 0);
+        most_frequent(shared, limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boolean_queries_match_the_rayon_backend() {
+        let text = "apple pie\napricot jam\napple tart with jam\navocado toast\napple juice\nplain toast\n";
+        let mut roaring = RoaringIndexer::new(IndexOptions { format: Format::Lines, ..IndexOptions::default() });
+        roaring.build_from_file_contents("fruit.log", String::from(text)).unwrap();
+        let mut rayon = RayonIndexer::new(IndexOptions { format: Format::Lines, ..IndexOptions::default() });
+        rayon.build_from_file_contents("fruit.log", String::from(text)).unwrap();
+        for query in ["apple AND jam", "apple OR toast", "toast AND NOT avocado", "ap* AND NOT juice", "\"apple tart\" OR plain", "NOT apple"] {
+            let ids = |indexer: &dyn DocumentIndexer| -> Vec<String> {
+                indexer.search(split_query(query)).unwrap().documents().map(|doc| doc.text.clone()).collect()
+            };
+            assert_eq!(ids(&roaring), ids(&rayon), "{}", query);
+        }
+        assert_eq!(roaring.search(split_query("apple AND jam")).unwrap().len(), 1);
+        assert_eq!(roaring.cooccurring_terms("appl", 1), rayon.cooccurring_terms("appl", 1));
+    }
+}
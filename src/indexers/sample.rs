@@ -0,0 +1,64 @@
+use crate::indexers::*;
+
+// A document of a cache drawn at random, for checking a corpus by eye or picking documents to label
+// for an evaluation set
+#[derive(Serialize)]
+pub struct SampledDocument {
+    pub id: i32,
+    pub title: String,
+    pub url: String,
+    // The start of its text, as a snippet of a match of a keyword term shows
+    pub snippet: String
+}
+
+// `count` distinct documents of the cache next to `file_to_index_path`, or all of them when it has
+// fewer, read from its .dcm and source without loading the index. Drawn uniformly and listed in id
+// order; the same seed draws the same ones from the same cache. Documents added since the build
+// aren't drawn.
+pub fn random_documents(file_to_index_path: &str, count: usize, seed: u64) -> Result<Vec<SampledDocument>, io::Error> {
+    if let Some(reason) = SerializedIndex::stale_reason(file_to_index_path) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("the cache is stale: {}", reason)));
+    }
+    let base_path = Path::new(file_to_index_path);
+    let documents = migrate::read_documents(&fs::read(base_path.with_extension("dcm"))?)?;
+    let contents = open_mmap(base_path)?;
+    let build = metadata::read_build_info(base_path)?;
+    // Only lines documents have urls made from where they are rather than stored, the other
+    // formats all read them the same way
+    let format = match build.as_ref().map(|build| build.format.as_str()) {
+        Some("lines") => Format::Lines,
+        _ => Format::Xml
+    };
+    let language = build.as_ref().and_then(|build| build.query_language.as_deref()).unwrap_or("en");
+    let sources = SourceFiles::single(file_to_index_path);
+    Ok(estimate::draw_sample(&documents, count, seed).into_iter().map(|d| {
+        let doc = d.to_document(&contents, format, &sources);
+        SampledDocument { id: doc.id, snippet: snippet(&doc.text, &[], Highlight::Markdown, language), title: doc.title, url: doc.url }
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::TempSource;
+
+    #[test]
+    fn the_same_seed_draws_the_same_documents() {
+        let source = TempSource::new("sample", "log", "apple pie\npear tart\nplum jam\nfig roll\n");
+        let path = source.to_str().unwrap();
+        let mut indexer = RayonIndexer::new(IndexOptions { format: Format::Lines, ..IndexOptions::default() });
+        indexer.build_streaming(path).unwrap();
+        SerializedIndex::write_index_to_path(path, &indexer, SyncPolicy::Never).unwrap();
+
+        let drawn = |count: usize, seed: u64| -> Vec<(i32, String, String)> {
+            random_documents(path, count, seed).unwrap().into_iter().map(|d| (d.id, d.url, d.snippet)).collect()
+        };
+        let two = drawn(2, 7);
+        assert_eq!(two.len(), 2);
+        assert!(two[0].0 < two[1].0);
+        assert_eq!(two, drawn(2, 7));
+        let all = drawn(10, 7);
+        assert_eq!(all.len(), 4);
+        assert_eq!(all[2], (2, format!("{}:3", path), String::from("plum jam")));
+    }
+}
@@ -0,0 +1,288 @@
+use crate::indexers::*;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic;
+
+// Reading /proc costs a syscall or three, so threads only look every this many documents
+const CHECK_INTERVAL: usize = 1000;
+// Spilling a near-empty index frees nothing and the allocator may not hand freed memory back to the
+// OS anyway, so a thread waits until it has built up this many terms again
+const MIN_SPILL_TERMS: usize = 10_000;
+
+// Resident set size of this process, None where /proc isn't available
+fn resident_bytes() -> Option<usize> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<usize>().ok()?;
+    Some(kb * 1024)
+}
+
+// A term's positions in each document it occurs in
+type TermDocPositions = Vec<(i32, TermPositions)>;
+
+// One term of a segment file: the term, its ids delta encoded as in the .idx, and its positions
+type SpilledTerm = (String, Vec<u8>, TermDocPositions);
+
+fn invalid(e: bincode::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("spilled segment: {}", e))
+}
+
+// Moves thread-local indexes out to temporary segment files once the process gets within 10% of
+// IndexOptions::memory_limit. A segment file is a u64 term count and then its SpilledTerms in term
+// order, so once every thread has finished they're read back merged, a term at a time, see finish.
+pub(super) struct SegmentSpill {
+    limit: Option<usize>,
+    next_segment: atomic::AtomicUsize,
+    segments: Mutex<Vec<PathBuf>>
+}
+
+impl SegmentSpill {
+    pub(super) fn new(limit: Option<usize>) -> Self {
+        SegmentSpill {
+            limit,
+            next_segment: atomic::AtomicUsize::new(0),
+            segments: Mutex::new(Vec::new())
+        }
+    }
+
+    // `docs_seen` is how many documents the calling thread has indexed so far
+    pub(super) fn maybe_spill(&self, docs_seen: usize, index: &mut TermIdIndex, analyzer: &mut ThreadAnalyzer, terms: &TermIds) {
+        let limit = match self.limit {
+            _ if disk::writes_forbidden() => return,
+            Some(limit) if docs_seen.is_multiple_of(CHECK_INTERVAL) && index.len() >= MIN_SPILL_TERMS => limit,
+            _ => return
+        };
+        match resident_bytes() {
+            Some(resident) if resident >= limit / 10 * 9 => {},
+            _ => return
+        }
+        let mut positions = analyzer.take_positions();
+        match self.write_segment(index, &mut positions, terms) {
+            Ok(()) => *index = new_term_id_index(0),
+            // Keep going in memory, the build may still fit
+            Err(e) => {
+                println!("Failed to spill index segment: {}", e);
+                merge_positions(&mut analyzer.positions, positions);
+            }
+        }
+    }
+
+    // Writes `index` and the positions of its terms, taken out of `positions`, as the next segment
+    fn write_segment(&self, index: &TermIdIndex, positions: &mut PositionIndex, terms: &TermIds) -> Result<(), io::Error> {
+        let path = std::env::temp_dir().join(format!("fulltext-{}-{}.seg", std::process::id(), self.next_segment.fetch_add(1, atomic::Ordering::SeqCst)));
+        let written = (|| {
+            let mut w = BufWriter::new(File::create(&path)?);
+            bincode::serialize_into(&mut w, &(index.len() as u64)).map_err(invalid)?;
+            let mut ids: Vec<i32> = Vec::new();
+            let mut encoded: Vec<u8> = Vec::new();
+            for (term, id) in terms.terms_of(index) {
+                ids.clear();
+                ids.extend(index[&id].iter().copied());
+                ids.sort_unstable();
+                encoded.clear();
+                postings::encode(&ids, &mut encoded);
+                let term_positions: TermDocPositions = positions.remove(&term).map(|docs| docs.into_iter().collect()).unwrap_or_default();
+                bincode::serialize_into(&mut w, &(&term, &encoded, &term_positions)).map_err(invalid)?;
+            }
+            w.flush()
+        })();
+        match written {
+            Ok(()) => {
+                self.segments.lock().unwrap().push(path);
+                Ok(())
+            },
+            Err(e) => {
+                let _ = fs::remove_file(&path);
+                Err(io::Error::new(e.kind(), format!("{:?}: {}", path, e)))
+            }
+        }
+    }
+
+    // The build's postings from `index` and `positions`, what the threads had left in memory: those
+    // alone when nothing was spilled, otherwise spilled as one more segment, so that nothing of
+    // them stays in memory and every term is read back from the segments in order
+    pub(super) fn finish(self, index: TermIdIndex, mut positions: PositionIndex, terms: TermIds) -> Result<BuiltPostings, io::Error> {
+        if self.segments.lock().unwrap().is_empty() {
+            return Ok(BuiltPostings::Memory(terms.resolve(index), positions));
+        }
+        self.write_segment(&index, &mut positions, &terms)?;
+        drop((index, positions, terms));
+        let segments = std::mem::take(&mut *self.segments.lock().unwrap());
+        println!("Merging {} spilled index segments", segments.len());
+        Ok(BuiltPostings::Spilled(SpilledPostings { segments }))
+    }
+}
+
+impl Drop for SegmentSpill {
+    // Don't leave segments behind in the temp dir if the build bails out before merging
+    fn drop(&mut self) {
+        for path in self.segments.lock().unwrap().drain(..) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// What a build's postings came to, see SegmentSpill::finish
+pub(super) enum BuiltPostings {
+    Memory(HashMapInvertedIndex, PositionIndex),
+    Spilled(SpilledPostings)
+}
+
+impl BuiltPostings {
+    // As the index the in-memory backends search, filled from spilled segments a term at a time
+    pub(super) fn into_memory(self) -> Result<(HashMapInvertedIndex, PositionIndex), io::Error> {
+        let spilled = match self {
+            BuiltPostings::Memory(index, positions) => return Ok((index, positions)),
+            BuiltPostings::Spilled(spilled) => spilled
+        };
+        let mut index = HashMapInvertedIndex::with_hasher(BuildHasherDefault::<FxHasher>::default());
+        let mut positions = new_position_index();
+        spilled.merge(|term, ids, term_positions| {
+            if !term_positions.is_empty() {
+                positions.insert(term.clone(), term_positions.into_iter().collect());
+            }
+            index.insert(term, ids.into_iter().collect());
+            Ok(())
+        })?;
+        Ok((index, positions))
+    }
+}
+
+// The segment files of a build that spilled, every one of its postings among them. Dropping it
+// removes them.
+pub(super) struct SpilledPostings {
+    segments: Vec<PathBuf>
+}
+
+// The next term of one segment file
+struct SegmentReader {
+    reader: BufReader<File>,
+    remaining: u64
+}
+
+impl SegmentReader {
+    fn open(path: &Path) -> Result<SegmentReader, io::Error> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let remaining: u64 = bincode::deserialize_from(&mut reader).map_err(invalid)?;
+        Ok(SegmentReader { reader, remaining })
+    }
+
+    fn next(&mut self) -> Result<Option<SpilledTerm>, io::Error> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        bincode::deserialize_from(&mut self.reader).map(Some).map_err(invalid)
+    }
+}
+
+impl SpilledPostings {
+    // Calls `each` with every term in order, its sorted ids and its positions, merging the terms the
+    // segments share. Only one term of each segment is read in at a time, so this can be done again
+    // for as long as the files are there, as when laying out a file in two passes.
+    pub(super) fn merge<F>(&self, mut each: F) -> Result<(), io::Error>
+        where F: FnMut(String, Vec<i32>, TermDocPositions) -> Result<(), io::Error> {
+        let mut readers: Vec<SegmentReader> = self.segments.iter().map(|path| SegmentReader::open(path)).collect::<Result<_, _>>()?;
+        // Each reader's current term, the smallest on top, and the rest of what it read
+        let mut heap: BinaryHeap<Reverse<(String, usize)>> = BinaryHeap::with_capacity(readers.len());
+        let mut current: Vec<(Vec<u8>, TermDocPositions)> = vec![(Vec::new(), Vec::new()); readers.len()];
+        for (i, reader) in readers.iter_mut().enumerate() {
+            if let Some((term, encoded, term_positions)) = reader.next()? {
+                current[i] = (encoded, term_positions);
+                heap.push(Reverse((term, i)));
+            }
+        }
+        while let Some(Reverse((term, i))) = heap.pop() {
+            let mut ids: Vec<i32> = Vec::new();
+            let mut term_positions: TermDocPositions = Vec::new();
+            let mut take = |i: usize, heap: &mut BinaryHeap<Reverse<(String, usize)>>| -> Result<(), io::Error> {
+                let (encoded, positions) = std::mem::take(&mut current[i]);
+                postings::decode(&encoded, &mut ids)?;
+                term_positions.extend(positions);
+                if let Some((next, encoded, positions)) = readers[i].next()? {
+                    current[i] = (encoded, positions);
+                    heap.push(Reverse((next, i)));
+                }
+                Ok(())
+            };
+            take(i, &mut heap)?;
+            while let Some(Reverse((_, i))) = heap.peek().filter(|Reverse((next, _))| *next == term).cloned() {
+                heap.pop();
+                take(i, &mut heap)?;
+            }
+            // Each segment's documents are indexed by one thread, so segments never share an id
+            ids.sort_unstable();
+            each(term, ids, term_positions)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SpilledPostings {
+    fn drop(&mut self) {
+        for path in &self.segments {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn term_ids(index: &[(&str, &[i32])], terms: &TermIds) -> TermIdIndex {
+        index.iter().map(|(term, ids)| (terms.id_of(String::from(*term)), ids.iter().copied().collect())).collect()
+    }
+
+    #[test]
+    fn spilled_segments_merge_back_in_term_order() {
+        let terms = TermIds::new();
+        let spill = SegmentSpill::new(Some(1));
+        let mut positions = new_position_index();
+        positions.entry(String::from("pear")).or_default().insert(1, TermPositions { positions: vec![3], ..TermPositions::default() });
+        spill.write_segment(&term_ids(&[("pear", &[1, 0]), ("apple", &[0])], &terms), &mut positions, &terms).unwrap();
+        let remainder = term_ids(&[("pear", &[5]), ("fig", &[4])], &terms);
+        let spilled = match spill.finish(remainder, new_position_index(), terms).unwrap() {
+            BuiltPostings::Spilled(spilled) => spilled,
+            BuiltPostings::Memory(..) => panic!("nothing spilled")
+        };
+        let mut merged: Vec<(String, Vec<i32>, usize)> = Vec::new();
+        spilled.merge(|term, ids, term_positions| {
+            merged.push((term, ids, term_positions.len()));
+            Ok(())
+        }).unwrap();
+        assert_eq!(merged, vec![
+            (String::from("apple"), vec![0], 0),
+            (String::from("fig"), vec![4], 0),
+            (String::from("pear"), vec![0, 1, 5], 1)
+        ]);
+        let paths = spilled.segments.clone();
+        let (index, positions) = BuiltPostings::Spilled(spilled).into_memory().unwrap();
+        assert_eq!(index["pear"].len(), 3);
+        assert_eq!(positions["pear"][&1].positions, vec![3]);
+        assert!(paths.iter().all(|path| !path.exists()));
+    }
+
+    #[test]
+    fn spilled_layout_matches_the_in_memory_one() {
+        let terms = TermIds::new();
+        let spill = SegmentSpill::new(Some(1));
+        spill.write_segment(&term_ids(&[("b", &[2]), ("a", &[0, 2])], &terms), &mut new_position_index(), &terms).unwrap();
+        let remainder = term_ids(&[("c", &[1]), ("a", &[1])], &terms);
+        let documents: Vec<DocumentRaw> = (0..3).map(|id| DocumentRaw { id, ..DocumentRaw::default() }).collect();
+        let mut spilled_bytes: Vec<u8> = Vec::new();
+        match spill.finish(remainder, new_position_index(), terms).unwrap() {
+            BuiltPostings::Spilled(spilled) => mapped::write_spilled_layout(&mut spilled_bytes, &spilled, &documents).unwrap(),
+            BuiltPostings::Memory(..) => panic!("nothing spilled")
+        }
+        let index: HashMapInvertedIndex = [("a", vec![0, 1, 2]), ("b", vec![2]), ("c", vec![1])].iter()
+            .map(|(term, ids)| (String::from(*term), ids.iter().copied().collect()))
+            .collect();
+        let mut bytes: Vec<u8> = Vec::new();
+        mapped::write_layout(&mut bytes, &index, &documents).unwrap();
+        assert!(spilled_bytes == bytes);
+    }
+}
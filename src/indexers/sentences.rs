@@ -0,0 +1,120 @@
+// A simple sentence segmenter for snippets. A sentence ends at '.', '!', '?' or '…', and any closing
+// quotes or brackets after it, when whitespace and then something that isn't a lowercase letter
+// follow: "e.g. the" doesn't end one. The CJK full stops end one with or without the whitespace.
+// What else doesn't end one depends on the language, one of LANGUAGES:
+//
+//   abbreviations   "Dr. Smith" or "z. B. Berlin", the word before the '.' lowercased
+//   initials        "J. R. R. Tolkien", a single letter before the '.', in every language
+//   ordinals        "am 3. Oktober", a number before the '.', in languages that write them so
+//
+// Greek also ends questions with ';', and Arabic with '؟'.
+
+const TERMINATORS: [char; 5] = ['.', '!', '?', '…', '؟'];
+// End a sentence whatever follows
+const CJK_TERMINATORS: [char; 3] = ['。', '！', '？'];
+const CLOSING: [char; 9] = ['"', '\'', '”', '’', '»', '«', ')', ']', '」'];
+const OPENING: [char; 9] = ['"', '\'', '“', '‘', '«', '»', '(', '[', '¿'];
+
+struct Rules {
+    abbreviations: &'static [&'static str],
+    ordinals: bool,
+    semicolon_questions: bool
+}
+
+fn rules(language: &str) -> Rules {
+    let abbreviations: &'static [&'static str] = match language {
+        "en" => &["mr", "mrs", "ms", "dr", "prof", "st", "jr", "sr", "vs", "etc", "e.g", "i.e", "cf", "fig", "al", "no", "vol", "inc", "ltd", "co", "approx"],
+        "de" => &["z.b", "d.h", "u.a", "usw", "bzw", "ca", "dr", "prof", "hr", "fr", "nr", "vgl", "str", "evtl", "ggf"],
+        "fr" => &["m", "mme", "mlle", "dr", "etc", "cf", "p.ex", "env", "av", "bd"],
+        "es" => &["sr", "sra", "srta", "dr", "dra", "ud", "uds", "etc", "p.ej", "pág", "núm"],
+        "it" => &["sig", "sig.ra", "dott", "prof", "ecc", "ca", "pag"],
+        "nl" => &["dhr", "mevr", "dr", "bijv", "enz", "o.a", "d.w.z", "nr"],
+        "pt" => &["sr", "sra", "dr", "dra", "etc", "ex", "pág", "nº"],
+        "ru" => &["г", "гг", "т.е", "т.д", "т.п", "др", "см", "стр", "им"],
+        _ => &[]
+    };
+    Rules {
+        abbreviations,
+        ordinals: ["da", "de", "fi", "hu", "no", "tr"].contains(&language),
+        semicolon_questions: language == "el"
+    }
+}
+
+impl Rules {
+    fn is_terminator(&self, c: char) -> bool {
+        TERMINATORS.contains(&c) || (self.semicolon_questions && c == ';')
+    }
+
+    // Whether the '.' ending `word` shortens it rather than ending the sentence
+    fn shortens(&self, word: &str) -> bool {
+        let word = word.trim_start_matches(|c| OPENING.contains(&c));
+        let mut chars = word.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_alphabetic() => true,
+            _ if self.ordinals && !word.is_empty() && word.chars().all(|c| c.is_ascii_digit()) => true,
+            _ => self.abbreviations.contains(&word.to_lowercase().as_str())
+        }
+    }
+
+    // Where the sentence after a terminator `c` at `at` starts, None when it doesn't end one
+    fn next_start(&self, text: &str, at: usize, c: char) -> Option<usize> {
+        let cjk = CJK_TERMINATORS.contains(&c);
+        if !cjk && !self.is_terminator(c) {
+            return None;
+        }
+        let rest = &text[at + c.len_utf8()..];
+        let after_closing = rest.trim_start_matches(|c| CLOSING.contains(&c));
+        let start = after_closing.trim_start();
+        let next = start.chars().next()?;
+        if cjk {
+            return Some(text.len() - start.len());
+        }
+        if self.is_terminator(next) || start.len() == after_closing.len() || next.is_lowercase() {
+            return None;
+        }
+        if c == '.' && after_closing.len() == rest.len() {
+            let word_start = text[..at].rfind(char::is_whitespace).map_or(0, |i| i + 1);
+            if self.shortens(&text[word_start..at]) {
+                return None;
+            }
+        }
+        Some(text.len() - start.len())
+    }
+}
+
+// Byte offsets in `text` where each sentence after the first starts, in order, lazily so a caller
+// only after the sentences near the start doesn't segment the rest
+pub(super) fn sentence_starts<'a>(text: &'a str, language: &str) -> impl Iterator<Item = usize> + 'a {
+    let rules = rules(language);
+    text.char_indices().filter_map(move |(at, c)| rules.next_start(text, at, c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sentences<'a>(text: &'a str, language: &str) -> Vec<&'a str> {
+        let mut starts: Vec<usize> = vec![0];
+        starts.extend(sentence_starts(text, language));
+        starts.push(text.len());
+        starts.windows(2).map(|w| text[w[0]..w[1]].trim_end()).collect()
+    }
+
+    #[test]
+    fn abbreviations_initials_and_numbers_dont_end_sentences() {
+        assert_eq!(sentences("Dr. J. Smith arrived at 3.15 p.m. today. \"Is it late?\" she asked. Yes!", "en"),
+            vec!["Dr. J. Smith arrived at 3.15 p.m. today.", "\"Is it late?\" she asked.", "Yes!"]);
+        assert_eq!(sentences("Wait... What?! Fine. e.g. this", "en"), vec!["Wait...", "What?!", "Fine. e.g. this"]);
+        assert_eq!(sentences("Er kam am 3. Oktober, z. B. mit Dr. Weber. Dann ging er.", "de"),
+            vec!["Er kam am 3. Oktober, z. B. mit Dr. Weber.", "Dann ging er."]);
+        // English doesn't write ordinals with a '.'
+        assert_eq!(sentences("It was 3. Then 4.", "en"), vec!["It was 3.", "Then 4."]);
+    }
+
+    #[test]
+    fn other_scripts_end_sentences_their_own_way() {
+        assert_eq!(sentences("今日は晴れ。明日は雨？たぶん。", "en"), vec!["今日は晴れ。", "明日は雨？", "たぶん。"]);
+        assert_eq!(sentences("Τι ώρα είναι; Είναι αργά.", "el"), vec!["Τι ώρα είναι;", "Είναι αργά."]);
+        assert_eq!(sentences("¿Qué hora es? Es tarde, Sr. Pérez.", "es"), vec!["¿Qué hora es?", "Es tarde, Sr. Pérez."]);
+    }
+}
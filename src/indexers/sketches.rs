@@ -0,0 +1,170 @@
+use crate::indexers::*;
+use std::collections::BTreeMap;
+use std::hash::Hasher;
+
+// Register index bits: 4096 one byte registers, about 1.6% standard error
+const PRECISION: u32 = 12;
+const REGISTERS: usize = 1 << PRECISION;
+
+// HyperLogLog estimate of how many distinct terms were inserted, in a fixed few KB however many
+// there were. Sketches of parts of a build merge into the sketch of the whole.
+#[derive(Clone)]
+pub(super) struct Sketch {
+    registers: Vec<u8>
+}
+
+// Fx is fast but its high bits are poor, and the register comes from those, so mix them in
+fn hash_term(term: &str) -> u64 {
+    let mut hasher = FxHasher::default();
+    hasher.write(term.as_bytes());
+    let mut h = hasher.finish();
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+impl Sketch {
+    pub(super) fn new() -> Sketch {
+        Sketch { registers: vec![0; REGISTERS] }
+    }
+
+    pub(super) fn insert(&mut self, term: &str) {
+        let h = hash_term(term);
+        let register = (h >> (64 - PRECISION)) as usize;
+        let rank = ((h << PRECISION) | (1 << (PRECISION - 1))).leading_zeros() as u8 + 1;
+        self.registers[register] = cmp::max(self.registers[register], rank);
+    }
+
+    pub(super) fn merge(&mut self, other: &Sketch) {
+        for (register, other) in self.registers.iter_mut().zip(&other.registers) {
+            *register = cmp::max(*register, *other);
+        }
+    }
+
+    // Linear counting while registers are still empty, where the raw estimate is biased high
+    pub(super) fn estimate(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let sum: f64 = self.registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let raw = 0.7213 / (1.0 + 1.079 / m) * m * m / sum;
+        let empty = self.registers.iter().filter(|r| **r == 0).count();
+        match empty {
+            0 => raw.round() as u64,
+            _ if raw <= 2.5 * m => (m * (m / empty as f64).ln()).round() as u64,
+            _ => raw.round() as u64
+        }
+    }
+}
+
+// Estimated distinct terms of a build, saved with its BuildInfo
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DistinctTerms {
+    // By field: keyword terms are "field:value", every other term is in "text"
+    pub fields: BTreeMap<String, u64>,
+    // By source file, only for builds of several
+    pub sources: BTreeMap<String, u64>
+}
+
+// The sketches one indexing thread keeps while analyzing its documents, merged when the threads'
+// postings are, see RayonIndexer::index_documents
+pub(super) struct TermSketches {
+    fields: HashMap<String, Sketch>,
+    // Start offset of each source file, none for a single file build
+    starts: Vec<usize>,
+    sources: Vec<Sketch>,
+    source: usize
+}
+
+impl TermSketches {
+    pub(super) fn new(starts: &[usize]) -> TermSketches {
+        let starts: Vec<usize> = if starts.len() > 1 { starts.to_vec() } else { Vec::new() };
+        TermSketches { fields: HashMap::new(), sources: vec![Sketch::new(); starts.len()], starts, source: 0 }
+    }
+
+    // Terms inserted from here on are from `doc`
+    pub(super) fn start_document(&mut self, doc: &DocumentRaw) {
+        if !self.starts.is_empty() {
+            self.source = self.starts.partition_point(|start| *start <= doc.text.start).saturating_sub(1);
+        }
+    }
+
+    pub(super) fn insert(&mut self, term: &str) {
+        let field = match term.split_once(':') {
+            Some((field, _)) if !field.is_empty() => field,
+            _ => TEXT_FIELD
+        };
+        match self.fields.get_mut(field) {
+            Some(sketch) => sketch.insert(term),
+            None => {
+                let mut sketch = Sketch::new();
+                sketch.insert(term);
+                self.fields.insert(String::from(field), sketch);
+            }
+        }
+        if let Some(sketch) = self.sources.get_mut(self.source) {
+            sketch.insert(term);
+        }
+    }
+
+    pub(super) fn merge(mut self, other: TermSketches) -> TermSketches {
+        for (field, sketch) in other.fields {
+            match self.fields.get_mut(&field) {
+                Some(mine) => mine.merge(&sketch),
+                None => {
+                    self.fields.insert(field, sketch);
+                }
+            }
+        }
+        for (mine, theirs) in self.sources.iter_mut().zip(&other.sources) {
+            mine.merge(theirs);
+        }
+        self
+    }
+
+    pub(super) fn estimates(&self, sources: &SourceFiles) -> DistinctTerms {
+        DistinctTerms {
+            fields: self.fields.iter().map(|(field, sketch)| (field.clone(), sketch.estimate())).collect(),
+            sources: sources.paths().zip(&self.sources).map(|(path, sketch)| (String::from(path), sketch.estimate())).collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn estimate_of(terms: impl Iterator<Item = String>) -> u64 {
+        let mut sketch = Sketch::new();
+        for term in terms {
+            sketch.insert(&term);
+        }
+        sketch.estimate()
+    }
+
+    #[test]
+    fn estimates_are_close_small_and_large() {
+        assert_eq!(estimate_of(std::iter::empty()), 0);
+        assert_eq!(estimate_of((0..10).map(|i| format!("term{}", i))), 10);
+        let estimate = estimate_of((0..100_000).map(|i| format!("term{}", i))) as f64;
+        assert!((estimate - 100_000.0).abs() < 100_000.0 * 0.05, "{}", estimate);
+        // Repeats don't count
+        assert_eq!(estimate_of((0..1000).map(|i| format!("term{}", i % 10))), 10);
+    }
+
+    #[test]
+    fn merged_sketches_estimate_the_union() {
+        let mut a = TermSketches::new(&[]);
+        let mut b = TermSketches::new(&[]);
+        for i in 0..500 {
+            a.insert(&format!("term{}", i));
+            b.insert(&format!("term{}", i + 250));
+            b.insert(&format!("subject:value{}", i % 20));
+        }
+        let merged = a.merge(b).estimates(&SourceFiles::default());
+        let text = merged.fields[TEXT_FIELD] as f64;
+        assert!((text - 750.0).abs() < 750.0 * 0.05, "{}", text);
+        assert_eq!(merged.fields["subject"], 20);
+        assert!(merged.sources.is_empty());
+    }
+}
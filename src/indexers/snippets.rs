@@ -0,0 +1,267 @@
+use crate::indexers::*;
+
+// Most characters of text a snippet shows by default, give or take the words cut at its ends when it
+// can't show whole sentences
+pub const SNIPPET_CHARS: usize = 200;
+
+// How long snippets are and how many a document gets, --snippet-len and --max-snippets-per-doc.
+// Past the first, a snippet is only made for matches the ones before didn't show, so long texts
+// with their matches spread through them get one per stretch of matches.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SnippetOptions {
+    pub chars: usize,
+    pub max_per_doc: usize
+}
+
+impl Default for SnippetOptions {
+    fn default() -> Self {
+        SnippetOptions { chars: SNIPPET_CHARS, max_per_doc: 1 }
+    }
+}
+
+// How matched terms are marked in a snippet
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Highlight {
+    // **term**
+    Markdown,
+    // Bold red on a terminal
+    Ansi
+}
+
+impl Highlight {
+    pub fn parse(name: &str) -> Option<Highlight> {
+        match name {
+            "markdown" => Some(Highlight::Markdown),
+            "ansi" => Some(Highlight::Ansi),
+            _ => None
+        }
+    }
+
+    fn marks(&self) -> (&'static str, &'static str) {
+        match self {
+            Highlight::Markdown => ("**", "**"),
+            Highlight::Ansi => ("\x1b[1;31m", "\x1b[0m")
+        }
+    }
+}
+
+// `label` without the "title:" of its title terms, whose words are highlighted where the text has them
+// rather than the word "title"
+fn without_title_fields(label: &str) -> String {
+    let field = format!("{}:", TITLE_FIELD);
+    let words: Vec<String> = label.split(' ').map(|word| {
+        let at = word.len() - word.trim_start_matches(['+', '-', '(']).len();
+        match word[at..].strip_prefix(field.as_str()) {
+            Some(rest) => format!("{}{}", &word[..at], rest),
+            None => String::from(word)
+        }
+    }).collect();
+    words.join(" ")
+}
+
+// Byte ranges in `doc.text` of the terms a hit's label stands for, sorted, with overlapping
+// ranges merged. The label may be an analyzed term, a quoted phrase or a whole boolean query, whose
+// operators analyze away as stopwords. A prefix only highlights words that analyze to the prefix.
+fn match_offsets(indexer: &dyn DocumentIndexer, label: &str, doc: &Document) -> Vec<(u32, u32)> {
+    let mut offsets: Vec<(u32, u32)> = indexer.analyze_text(&without_title_fields(label)).iter()
+        .flat_map(|term| indexer.term_offsets(term, doc))
+        .collect();
+    offsets.sort_unstable();
+    let mut merged: Vec<(u32, u32)> = Vec::with_capacity(offsets.len());
+    for (start, end) in offsets {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = cmp::max(last.1, end),
+            _ => merged.push((start, end))
+        }
+    }
+    merged
+}
+
+// Runs of whitespace become one space, so a snippet stays on one line
+fn push_flat(snippet: &mut String, text: &str) {
+    for c in text.chars() {
+        match c.is_whitespace() {
+            true if snippet.is_empty() || snippet.ends_with(' ') => {},
+            true => snippet.push(' '),
+            false => snippet.push(c)
+        }
+    }
+}
+
+// As many whole sentences as fit in `chars`, from the one `first` is in, see sentences.rs. None when
+// that one alone doesn't fit.
+fn sentence_window(text: &str, first: usize, language: &str, chars: usize) -> Option<(usize, usize)> {
+    let mut starts = sentences::sentence_starts(text, language);
+    let (mut start, mut next) = (0, starts.next());
+    while let Some(next_start) = next.filter(|next_start| *next_start <= first) {
+        start = next_start;
+        next = starts.next();
+    }
+    let mut end = start;
+    loop {
+        let sentence_end = start + text[start..next.unwrap_or(text.len())].trim_end().len();
+        if text[start..sentence_end].chars().nth(chars).is_some() {
+            break;
+        }
+        end = sentence_end;
+        match next {
+            Some(_) => next = starts.next(),
+            None => break
+        }
+    }
+    (end > start).then_some((start, end))
+}
+
+// About `chars` from a little before the first of `offsets`, cut at whitespace where there is some.
+// A quarter of the window goes before the match.
+fn char_window(text: &str, offsets: &[(u32, u32)], first: usize, chars: usize) -> (usize, usize) {
+    let lead = cmp::max(chars / 4, 1);
+    let mut start = text[..first].char_indices().rev().nth(lead - 1).map_or(0, |(i, _)| i);
+    if start > 0 {
+        if let Some(space) = text[start..first].find(char::is_whitespace) {
+            start += space + 1;
+        }
+    }
+    let mut end = text[start..].char_indices().nth(chars).map_or(text.len(), |(i, _)| start + i);
+    if end < text.len() {
+        let first_end = offsets.first().map_or(start, |(_, end)| *end as usize);
+        if let Some(space) = text[first_end.min(end)..end].rfind(char::is_whitespace) {
+            end = first_end.min(end) + space;
+        }
+    }
+    (start, end)
+}
+
+// The sentences of `text` around the first of `offsets`, in `language` (one of LANGUAGES), or when
+// that sentence is too long about SNIPPET_CHARS around the match, with every range in the window
+// highlighted. From the start of the text when there are no offsets, as for keyword terms.
+pub fn snippet(text: &str, offsets: &[(u32, u32)], highlight: Highlight, language: &str) -> String {
+    let first = offsets.first().map_or(0, |(start, _)| *start as usize);
+    let (start, end) = window(text, offsets, first, language, SNIPPET_CHARS);
+    highlighted(text, offsets, start, end, highlight)
+}
+
+// snippet for each run of `offsets` far enough from the one before not to show in its snippet, up to
+// options.max_per_doc of them, in the order they're in the text
+pub fn snippets(text: &str, offsets: &[(u32, u32)], highlight: Highlight, language: &str, options: SnippetOptions) -> Vec<String> {
+    let mut shown: Vec<String> = Vec::new();
+    let mut rest = offsets;
+    while shown.len() < cmp::max(options.max_per_doc, 1) {
+        let first = rest.first().map_or(0, |(start, _)| *start as usize);
+        let (start, end) = window(text, rest, first, language, options.chars);
+        shown.push(highlighted(text, offsets, start, end, highlight));
+        match rest.iter().position(|(start, _)| *start as usize >= cmp::max(end, first + 1)) {
+            Some(next) => rest = &rest[next..],
+            None => break
+        }
+    }
+    shown
+}
+
+fn window(text: &str, offsets: &[(u32, u32)], first: usize, language: &str, chars: usize) -> (usize, usize) {
+    sentence_window(text, first, language, chars).unwrap_or_else(|| char_window(text, offsets, first, chars))
+}
+
+// text[start..end] on one line with the ranges of `offsets` in it marked, and an ellipsis for each
+// end that cuts the text
+fn highlighted(text: &str, offsets: &[(u32, u32)], start: usize, end: usize, highlight: Highlight) -> String {
+    let (open, close) = highlight.marks();
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    let mut at = start;
+    for &(range_start, range_end) in offsets {
+        let (range_start, range_end) = (cmp::max(range_start as usize, at), cmp::min(range_end as usize, end));
+        if range_start >= range_end {
+            continue;
+        }
+        push_flat(&mut snippet, &text[at..range_start]);
+        snippet.push_str(open);
+        push_flat(&mut snippet, &text[range_start..range_end]);
+        snippet.push_str(close);
+        at = range_end;
+    }
+    push_flat(&mut snippet, &text[at..end]);
+    if !text[end..].trim_start().is_empty() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+// The snippet for `doc` as a match of `term`, a hit's label
+pub fn match_snippet(indexer: &dyn DocumentIndexer, term: &str, doc: &Document, highlight: Highlight) -> String {
+    snippet(&doc.text, &match_offsets(indexer, term, doc), highlight, indexer.language())
+}
+
+// match_snippet as `options` has it, each stretch of matches in the text with its own snippet
+pub fn match_snippets(indexer: &dyn DocumentIndexer, term: &str, doc: &Document, highlight: Highlight, options: SnippetOptions) -> Vec<String> {
+    snippets(&doc.text, &match_offsets(indexer, term, doc), highlight, indexer.language(), options)
+}
+
+impl SearchResults {
+    // One snippet per hit, in order, with every term it matched highlighted, made as they're iterated
+    // since each may analyze the hit's text again
+    pub fn snippets<'a>(&'a self, indexer: &'a dyn DocumentIndexer, highlight: Highlight) -> impl Iterator<Item = String> + 'a {
+        self.hits.iter().map(move |hit| match_snippet(indexer, &hit.label(), &hit.doc, highlight))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_text_is_whole_with_every_match_highlighted() {
+        let text = "the cat sat\non the  cat mat";
+        assert_eq!(snippet(text, &[(4, 7), (20, 23)], Highlight::Markdown, "en"), "the **cat** sat on the **cat** mat");
+        assert_eq!(snippet(text, &[], Highlight::Markdown, "en"), "the cat sat on the cat mat");
+    }
+
+    #[test]
+    fn title_terms_highlight_their_words() {
+        assert_eq!(without_title_fields("title:web +title:server (title:api OR rest) from:bob"), "web +server (api OR rest) from:bob");
+    }
+
+    #[test]
+    fn long_text_is_cut_at_words_around_the_first_match() {
+        let text = format!("{} needle {}", "word ".repeat(100), "word ".repeat(100));
+        let at = text.find("needle").unwrap() as u32;
+        let snippet = snippet(&text, &[(at, at + 6)], Highlight::Ansi, "en");
+        assert!(snippet.starts_with("…word "));
+        assert!(snippet.ends_with(" word…"));
+        assert!(snippet.contains("\x1b[1;31mneedle\x1b[0m"));
+        assert!(snippet.chars().count() <= SNIPPET_CHARS + 2 + "\x1b[1;31m\x1b[0m".len());
+    }
+
+    #[test]
+    fn long_text_shows_the_whole_sentences_that_fit() {
+        let text = format!("An opening line. The needle is here, said Dr. Watson. A short one. {}", "Then a long one. ".repeat(20));
+        let at = text.find("needle").unwrap() as u32;
+        let shown = snippet(&text, &[(at, at + 6)], Highlight::Markdown, "en");
+        assert!(shown.starts_with("…The **needle** is here, said Dr. Watson. A short one. Then a long one. "));
+        assert!(shown.ends_with(" Then a long one.…"));
+        assert!(shown.chars().count() <= SNIPPET_CHARS + 2 + "****".len());
+        // A sentence too long to show whole is cut around the match instead
+        let text = format!("Intro. {} needle {}.", "word ".repeat(100), "word ".repeat(100));
+        let at = text.find("needle").unwrap() as u32;
+        assert!(snippet(&text, &[(at, at + 6)], Highlight::Markdown, "en").starts_with("…word "));
+    }
+
+    #[test]
+    fn spread_matches_get_a_snippet_each() {
+        let text = format!("{} needle {} needle pin {}", "word ".repeat(100), "word ".repeat(100), "word ".repeat(100));
+        let mut offsets: Vec<(u32, u32)> = text.match_indices("needle").chain(text.match_indices("pin"))
+            .map(|(at, found)| (at as u32, (at + found.len()) as u32))
+            .collect();
+        offsets.sort_unstable();
+        let options = SnippetOptions { chars: 40, max_per_doc: 5 };
+        let shown = snippets(&text, &offsets, Highlight::Markdown, "en", options);
+        assert_eq!(shown.len(), 2);
+        assert!(shown[0].contains("**needle**") && !shown[0].contains("pin"));
+        assert!(shown[1].contains("**needle** **pin**"));
+        assert!(shown.iter().all(|shown| shown.chars().count() <= 40 + 2 + "********".len()));
+        assert_eq!(snippets(&text, &offsets, Highlight::Markdown, "en", SnippetOptions { max_per_doc: 1, ..options }), shown[..1].to_vec());
+        assert_eq!(snippets(&text, &offsets, Highlight::Markdown, "en", SnippetOptions::default()), vec![snippet(&text, &offsets, Highlight::Markdown, "en")]);
+    }
+}
@@ -0,0 +1,21 @@
+// The index's terms in sorted order, next to the hash map the postings live in, so the terms starting
+// with a prefix are a single range found by binary search. Prefix queries like "comput*" and
+// terms_with_prefix use it instead of scanning every term. Backends build it on first use after a
+// build, load or add.
+pub(super) struct SortedTerms {
+    terms: Vec<String>
+}
+
+impl SortedTerms {
+    pub(super) fn new(terms: impl Iterator<Item = String>) -> Self {
+        let mut terms: Vec<String> = terms.collect();
+        terms.sort_unstable();
+        SortedTerms { terms }
+    }
+
+    pub(super) fn with_prefix(&self, prefix: &str) -> &[String] {
+        let start = self.terms.partition_point(|term| term.as_str() < prefix);
+        let len = self.terms[start..].partition_point(|term| term.starts_with(prefix));
+        &self.terms[start..start + len]
+    }
+}
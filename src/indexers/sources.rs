@@ -0,0 +1,175 @@
+use crate::indexers::*;
+use crate::indexers::archive::EntryFilter;
+
+// The files an index was built from, when several are indexed as one: their contents are joined in
+// order into one buffer, which the document ranges point into as usual. Each file's byte offset in it
+// tells which file a document came from, so documents carry nothing extra. A single file build has
+// one, at offset 0.
+#[derive(Clone, Debug, Default)]
+pub struct SourceFiles {
+    files: Vec<SourceFile>
+}
+
+#[derive(Clone, Debug)]
+struct SourceFile {
+    path: String,
+    offset: usize,
+    // Lines before the file in the joined contents, for Format::Lines urls
+    first_line: usize
+}
+
+impl Format {
+    // What a file's contents must end with so the next file's first document doesn't run into its
+    // last one. Record buffers and WARC records always end with their own separator.
+    fn file_terminator(&self) -> Option<char> {
+        match self {
+            Format::Xml | Format::Lines | Format::Mbox | Format::Rss => Some('\n'),
+            Format::Sql | Format::Parquet | Format::Archive | Format::Warc | Format::Jsonl | Format::Records => None
+        }
+    }
+}
+
+impl SourceFiles {
+    pub fn single(path: &str) -> SourceFiles {
+        SourceFiles { files: vec![SourceFile { path: String::from(path), offset: 0, first_line: 0 }] }
+    }
+
+    // Reads each of `paths` with `read`, like read_source, and joins the contents in order. A file
+    // that can't be read fails them all unless `keep_going`, which skips it, as archive entries are,
+    // and lists it at the end; Err still when none of them could be read.
+    pub fn read<F>(paths: &[PathBuf], format: Format, keep_going: bool, read: F) -> Result<(SourceFiles, String), io::Error>
+        where F: Fn(&str) -> Result<String, io::Error> {
+        let mut files: Vec<SourceFile> = Vec::with_capacity(paths.len());
+        let mut contents = String::new();
+        let mut lines = 0;
+        let mut filter = EntryFilter::new(&[], keep_going);
+        for path in paths {
+            let path = path.to_string_lossy();
+            let file_contents = match read(&path) {
+                Ok(file_contents) => file_contents,
+                Err(e) => {
+                    filter.skip_failed(&path, e).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+                    continue;
+                }
+            };
+            files.push(SourceFile { path: path.into_owned(), offset: contents.len(), first_line: lines });
+            contents.push_str(&file_contents);
+            if let Some(terminator) = format.file_terminator() {
+                if !contents.is_empty() && !contents.ends_with(terminator) {
+                    contents.push(terminator);
+                }
+            }
+            if format == Format::Lines {
+                lines += file_contents.split_terminator('\n').count();
+            }
+        }
+        filter.report("the files to index");
+        if files.is_empty() && !paths.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "none of the files to index could be read"));
+        }
+        Ok((SourceFiles { files }, contents))
+    }
+
+    // `paths` with each directory replaced by the files directly in it, sorted by name. Hidden files
+    // and cache files a single file build left next to its source are skipped.
+    pub fn list<P: Into<PathBuf>>(paths: impl Iterator<Item = P>) -> Result<Vec<PathBuf>, io::Error> {
+        let mut listed: Vec<PathBuf> = Vec::new();
+        for path in paths.map(Into::into) {
+            if !path.is_dir() {
+                listed.push(path);
+                continue;
+            }
+            let mut entries: Vec<PathBuf> = fs::read_dir(&path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<PathBuf>, io::Error>>()?;
+            entries.retain(|entry| {
+                let hidden = entry.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'));
+                let cache = entry.extension().and_then(|ext| ext.to_str()).is_some_and(|ext| is_cache_extension(ext) || ext == "tmp");
+                entry.is_file() && !hidden && !cache
+            });
+            entries.sort();
+            listed.extend(entries);
+        }
+        Ok(listed)
+    }
+
+    // The only file, None for a multi file build
+    pub fn single_path(&self) -> Option<&str> {
+        match self.files.as_slice() {
+            [file] => Some(&file.path),
+            _ => None
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.files.iter().map(|file| file.path.as_str())
+    }
+
+    // Where each file starts in the joined contents
+    pub(super) fn starts(&self) -> Vec<usize> {
+        self.files.iter().map(|file| file.offset).collect()
+    }
+
+    fn at_offset(&self, offset: usize) -> Option<&SourceFile> {
+        self.locate(offset).map(|(i, _)| &self.files[i])
+    }
+
+    // The number of the file holding `offset` in the joined contents, and where that file starts
+    pub(super) fn locate(&self, offset: usize) -> Option<(usize, usize)> {
+        let i = self.files.partition_point(|file| file.offset <= offset).checked_sub(1)?;
+        Some((i, self.files[i].offset))
+    }
+
+    // The path of the file the document starting at `offset` is from
+    pub fn path_at(&self, offset: usize) -> Option<&str> {
+        self.at_offset(offset).map(|file| file.path.as_str())
+    }
+
+    // "path:line" for the Format::Lines document with `id` that starts at `offset`
+    pub(super) fn line_url(&self, offset: usize, id: i32) -> String {
+        match self.at_offset(offset) {
+            Some(file) => format!("{}:{}", file.path, id as usize - file.first_line + 1),
+            None => format!("{}", id + 1)
+        }
+    }
+}
+
+impl std::fmt::Display for SourceFiles {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.files.as_slice() {
+            [file] => write!(f, "{}", file.path),
+            files => write!(f, "{} files", files.len())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(path: &str) -> Result<String, io::Error> {
+        match path {
+            "bad" => Err(io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8")),
+            path => Ok(format!("{} line\n", path))
+        }
+    }
+
+    #[test]
+    fn unreadable_files_are_skipped_with_keep_going() {
+        let paths: Vec<PathBuf> = ["a", "bad", "b"].iter().map(PathBuf::from).collect();
+        assert!(SourceFiles::read(&paths, Format::Lines, false, read).is_err());
+        let (sources, contents) = SourceFiles::read(&paths, Format::Lines, true, read).unwrap();
+        assert_eq!(sources.paths().collect::<Vec<&str>>(), vec!["a", "b"]);
+        assert_eq!(contents, "a line\nb line\n");
+        assert_eq!(sources.line_url(contents.find("b line").unwrap(), 1), "b:1");
+        assert!(SourceFiles::read(&[PathBuf::from("bad")], Format::Lines, true, read).is_err());
+    }
+}
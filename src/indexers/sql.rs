@@ -0,0 +1,64 @@
+use crate::indexers::*;
+use crate::indexers::records::ColumnMapping;
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+use crate::indexers::records::{RecordWriter, to_io_error};
+
+
+#[cfg(feature = "sqlite")]
+fn query_sqlite(path: &str, query: &str, columns: &ColumnMapping, records: &mut RecordWriter) -> Result<(), io::Error> {
+    use rusqlite::types::ValueRef;
+    let connection = rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(to_io_error)?;
+    let mut statement = connection.prepare(query).map_err(to_io_error)?;
+    let mut rows = statement.query([]).map_err(to_io_error)?;
+    let column_text = |row: &rusqlite::Row, name: &str| -> Result<String, io::Error> {
+        Ok(match row.get_ref(name).map_err(to_io_error)? {
+            ValueRef::Null | ValueRef::Blob(_) => String::new(),
+            ValueRef::Integer(i) => i.to_string(),
+            ValueRef::Real(f) => f.to_string(),
+            ValueRef::Text(t) => String::from_utf8_lossy(t).into_owned()
+        })
+    };
+    while let Some(row) = rows.next().map_err(to_io_error)? {
+        records.push(&column_text(row, &columns.title)?, &column_text(row, &columns.url)?, &column_text(row, &columns.text)?);
+    }
+    Ok(())
+}
+
+// Only text-typed columns are supported, cast anything else in the query itself
+#[cfg(feature = "postgres")]
+fn query_postgres(url: &str, query: &str, columns: &ColumnMapping, records: &mut RecordWriter) -> Result<(), io::Error> {
+    let mut client = postgres::Client::connect(url, postgres::NoTls).map_err(to_io_error)?;
+    for row in client.query(query, &[]).map_err(to_io_error)? {
+        let column_text = |name: &str| -> Result<String, io::Error> {
+            Ok(row.try_get::<_, Option<String>>(name).map_err(to_io_error)?.unwrap_or_default())
+        };
+        records.push(&column_text(&columns.title)?, &column_text(&columns.url)?, &column_text(&columns.text)?);
+    }
+    Ok(())
+}
+
+// `connection` is either sqlite:<path> or a postgres://... connection url
+#[allow(unused_variables)]
+pub fn query_records(connection: &str, query: &str, columns: &ColumnMapping) -> Result<String, io::Error> {
+    if let Some(path) = connection.strip_prefix("sqlite:") {
+        #[cfg(feature = "sqlite")]
+        {
+            let mut records = RecordWriter::new();
+            query_sqlite(path.trim_start_matches("//"), query, columns, &mut records)?;
+            return Ok(records.into_contents());
+        }
+        #[cfg(not(feature = "sqlite"))]
+        return Err(io::Error::other("built without the 'sqlite' feature"));
+    }
+    if connection.starts_with("postgres://") || connection.starts_with("postgresql://") {
+        #[cfg(feature = "postgres")]
+        {
+            let mut records = RecordWriter::new();
+            query_postgres(connection, query, columns, &mut records)?;
+            return Ok(records.into_contents());
+        }
+        #[cfg(not(feature = "postgres"))]
+        return Err(io::Error::other("built without the 'postgres' feature"));
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported connection '{}', expected sqlite:<path> or postgres://", connection)))
+}
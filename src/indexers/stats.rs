@@ -0,0 +1,127 @@
+use crate::indexers::*;
+
+// How many values are in min..=max. Buckets double: 0, then 1, 2-3, 4-7 and so on up to the largest.
+#[derive(Serialize)]
+pub struct Bucket {
+    pub min: usize,
+    pub max: usize,
+    pub count: usize
+}
+
+fn histogram(values: impl Iterator<Item = usize>) -> Vec<Bucket> {
+    let mut counts: Vec<usize> = Vec::new();
+    for value in values {
+        let bucket = (usize::BITS - value.leading_zeros()) as usize;
+        if counts.len() <= bucket {
+            counts.resize(bucket + 1, 0);
+        }
+        counts[bucket] += 1;
+    }
+    counts.into_iter().enumerate().map(|(bucket, count)| match bucket {
+        0 => Bucket { min: 0, max: 0, count },
+        _ => Bucket { min: 1 << (bucket - 1), max: (1 << bucket) - 1, count }
+    }).collect()
+}
+
+// The document frequency of the term at `rank`, 1 being the most frequent
+#[derive(Serialize)]
+pub struct RankedTerm {
+    pub rank: usize,
+    pub term: String,
+    pub documents: usize
+}
+
+// Ranks 1, 2, 4, 8 and so on, and the last, which plotted on log scales shows how closely the corpus
+// follows Zipf's law. A term's frequency here is the number of documents it's in: counts within
+// documents are only kept with --term-frequencies, where they weigh scores, see Hit::score.
+fn ranked_terms(document_frequencies: &HashMap<String, usize>) -> Vec<RankedTerm> {
+    let mut terms: Vec<(&String, &usize)> = document_frequencies.iter().collect();
+    terms.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+    let mut ranks: Vec<usize> = std::iter::successors(Some(1usize), |rank| rank.checked_mul(2)).take_while(|rank| *rank <= terms.len()).collect();
+    if ranks.last().is_some_and(|rank| *rank < terms.len()) {
+        ranks.push(terms.len());
+    }
+    ranks.into_iter().map(|rank| RankedTerm { rank, term: terms[rank - 1].0.clone(), documents: *terms[rank - 1].1 }).collect()
+}
+
+#[derive(Serialize)]
+pub struct Histograms {
+    pub term_frequency: Vec<RankedTerm>,
+    // Bytes of each document's text
+    pub document_length: Vec<Bucket>,
+    // Ids in each term's postings
+    pub postings_length: Vec<Bucket>
+}
+
+// The shape of an indexed corpus, read from its cache without loading the index, for deciding on
+// limits and filters before tuning. Documents added since the build aren't counted.
+#[derive(Serialize)]
+pub struct CorpusStats {
+    pub documents: usize,
+    pub terms: usize,
+    pub mean_document_bytes: f64,
+    pub median_document_bytes: usize,
+    pub mean_postings_length: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub histograms: Option<Histograms>
+}
+
+impl CorpusStats {
+    pub fn load(file_to_index_path: &str, histograms: bool) -> Result<CorpusStats, io::Error> {
+        let base_path = Path::new(file_to_index_path);
+        let index = compare::IndexStats::load(base_path)?;
+        let documents = migrate::read_documents(&fs::read(base_path.with_extension("dcm"))?)?;
+        let mut lengths: Vec<usize> = documents.iter().map(|d| d.text.len()).collect();
+        lengths.sort_unstable();
+        let mean = |total: usize, count: usize| if count == 0 { 0.0 } else { total as f64 / count as f64 };
+        Ok(CorpusStats {
+            documents: index.num_documents,
+            terms: index.document_frequencies.len(),
+            mean_document_bytes: mean(lengths.iter().sum(), lengths.len()),
+            median_document_bytes: lengths.get(lengths.len() / 2).copied().unwrap_or(0),
+            mean_postings_length: mean(index.document_frequencies.values().sum(), index.document_frequencies.len()),
+            histograms: histograms.then(|| Histograms {
+                term_frequency: ranked_terms(&index.document_frequencies),
+                document_length: histogram(lengths.iter().copied()),
+                postings_length: histogram(index.document_frequencies.values().copied())
+            })
+        })
+    }
+
+    pub fn print(&self) {
+        println!("Documents: {}", self.documents);
+        println!("Terms: {}", self.terms);
+        println!("Document bytes: mean {:.1}, median {}", self.mean_document_bytes, self.median_document_bytes);
+        println!("Postings length: mean {:.1}", self.mean_postings_length);
+        let histograms = match &self.histograms {
+            Some(histograms) => histograms,
+            None => return
+        };
+        println!("Term frequency by rank:");
+        for ranked in &histograms.term_frequency {
+            println!("  {}\t{}\t{}", ranked.rank, ranked.documents, ranked.term);
+        }
+        for (name, buckets) in [("Document length (bytes)", &histograms.document_length), ("Postings length (documents)", &histograms.postings_length)] {
+            println!("{}:", name);
+            for bucket in buckets {
+                println!("  {}-{}\t{}", bucket.min, bucket.max, bucket.count);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_double_and_ranks_are_log_spaced() {
+        let buckets: Vec<(usize, usize, usize)> = histogram(vec![0, 1, 2, 3, 4, 9].into_iter()).into_iter().map(|b| (b.min, b.max, b.count)).collect();
+        assert_eq!(buckets, vec![(0, 0, 1), (1, 1, 1), (2, 3, 2), (4, 7, 1), (8, 15, 1)]);
+        assert!(histogram(std::iter::empty()).is_empty());
+
+        let frequencies: HashMap<String, usize> = (1..=5).map(|df| (format!("t{}", df), df)).collect();
+        let ranked: Vec<(usize, usize)> = ranked_terms(&frequencies).into_iter().map(|r| (r.rank, r.documents)).collect();
+        assert_eq!(ranked, vec![(1, 5), (2, 4), (4, 2), (5, 1)]);
+    }
+}
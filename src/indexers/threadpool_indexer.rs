@@ -2,17 +2,18 @@ use crate::indexers::*;
 
 use std::sync::atomic;
 use core::ops::Range;
-use crossbeam;
 use crossbeam::crossbeam_channel;
-use dashmap;
+use rayon::prelude::*;
 
 pub type DashMapInvertedIndex = dashmap::DashMap<String, dashmap::DashSet<i32>>;
 pub type DocumentIndex = Vec<DocumentRaw>;
 
 type DocumentSender = crossbeam_channel::Sender<Vec<DocumentRaw>>;
 type DocumentReceiver = crossbeam_channel::Receiver<Vec<DocumentRaw>>;
-type IndexSender = crossbeam_channel::Sender<HashMapInvertedIndex>;
-type IndexReceiver = crossbeam_channel::Receiver<HashMapInvertedIndex>;
+type IndexSender = crossbeam_channel::Sender<(TermIdIndex, PositionIndex)>;
+type IndexReceiver = crossbeam_channel::Receiver<(TermIdIndex, PositionIndex)>;
+type PositionSender = crossbeam_channel::Sender<PositionIndex>;
+type PositionReceiver = crossbeam_channel::Receiver<PositionIndex>;
 type AllDocSender = crossbeam_channel::Sender<DocumentIndex>;
 type AllDocReceiver = crossbeam_channel::Receiver<DocumentIndex>;
 
@@ -23,16 +24,24 @@ enum IndexType {
 
 pub struct ThreadPoolIndexer {
     index: IndexType, 
+    positions: PositionIndex,
     documents: DocumentIndex,
+    titles: OnceLock<titles::TitleIndex>,
+    sorted_terms: OnceLock<sorted_terms::SortedTerms>,
     analyzer: Analyzer,
+    query_analyzer: Option<Analyzer>,
     cur_id: atomic::AtomicI32,
     pool: rayon::ThreadPool,
     parse_threads: usize,
     index_threads: usize,
-    full_contents: BoxedBytes
+    full_contents: BoxedBytes,
+    options: IndexOptions,
+    sources: SourceFiles,
+    // Only checked by searches, builds run to the end
+    cancel: CancelToken
 }
 
-fn parse_task(contents: &ContentsSplit, tx_doc: DocumentSender, tx_alldocs: AllDocSender, cur_id: &atomic::AtomicI32) {
+fn parse_task(contents: &ContentsSplit, options: &IndexOptions, tx_doc: DocumentSender, tx_alldocs: AllDocSender, cur_id: &atomic::AtomicI32) {
     let base_offset = contents.base_offset;
     let mut cur_doc = DocumentRaw::default();
     let mut cur_tag: &str = "";
@@ -63,6 +72,7 @@ fn parse_task(contents: &ContentsSplit, tx_doc: DocumentSender, tx_alldocs: AllD
                     cur_tag = "";
                     if n.as_str() == "doc" {
                         cur_doc.id = cur_id.fetch_add(1, atomic::Ordering::SeqCst);
+                        enforce_max_doc_bytes(&mut cur_doc, contents.data, base_offset, options);
                         chunk.push(cur_doc.clone());
                         all_docs.push(cur_doc);
                     
@@ -84,217 +94,306 @@ fn parse_task(contents: &ContentsSplit, tx_doc: DocumentSender, tx_alldocs: AllD
     tx_alldocs.send(all_docs).unwrap();
 }
 
-fn parse_documents<'b, 'a: 'b>(file_contents: Vec<ContentsSplit<'a>>, cur_id: &'b atomic::AtomicI32, scope: &rayon::Scope<'b>, tx_doc: DocumentSender) -> AllDocReceiver {
+fn send_parsed_task(mut all_docs: DocumentIndex, contents: &ContentsSplit, options: &IndexOptions, tx_doc: DocumentSender, tx_alldocs: AllDocSender) {
+    for d in all_docs.iter_mut() {
+        enforce_max_doc_bytes(d, contents.data, contents.base_offset, options);
+    }
+    for chunk in all_docs.chunks(100) {
+        tx_doc.send(chunk.to_vec()).unwrap();
+    }
+    println!("Parse task complete");
+    tx_alldocs.send(all_docs).unwrap();
+}
+
+fn parse_documents<'b, 'a: 'b>(file_contents: Vec<ContentsSplit<'a>>, options: &'b IndexOptions, cur_id: &'b atomic::AtomicI32, scope: &rayon::Scope<'b>, tx_doc: DocumentSender) -> AllDocReceiver {
     let (tx_alldocs, rx_alldocs): (AllDocSender, AllDocReceiver) = crossbeam_channel::unbounded();
-    for contents in file_contents {
+    let first_doc_ids = first_ids(&file_contents, options.format);
+    for (contents, first_id) in file_contents.into_iter().zip(first_doc_ids) {
         let tx_doc = tx_doc.clone();
         let tx_alldocs = tx_alldocs.clone();
         scope.spawn(move |_| {
-            parse_task(&contents, tx_doc, tx_alldocs, cur_id)
+            match options.format {
+                Format::Xml => parse_task(&contents, options, tx_doc, tx_alldocs, cur_id),
+                Format::Lines => send_parsed_task(parse_lines(&contents, first_id), &contents, options, tx_doc, tx_alldocs),
+                Format::Mbox => send_parsed_task(mbox::parse_mbox(&contents, cur_id), &contents, options, tx_doc, tx_alldocs),
+                Format::Rss => send_parsed_task(feed::parse_feed(&contents, cur_id), &contents, options, tx_doc, tx_alldocs),
+                Format::Sql | Format::Parquet | Format::Archive | Format::Jsonl | Format::Records => send_parsed_task(records::parse_records(&contents, first_id), &contents, options, tx_doc, tx_alldocs),
+                Format::Warc => send_parsed_task(warc::parse_warc(&contents, cur_id), &contents, options, tx_doc, tx_alldocs)
+            }
         });    
     }
     rx_alldocs
 }
 
-fn index_task(rx_doc: DocumentReceiver, tx_index: IndexSender, analyzer: &Analyzer, full_contents: &str) {
-    let mut inverted_index: HashMapInvertedIndex = HashMapInvertedIndex::with_capacity_and_hasher(500_000, BuildHasherDefault::<FxHasher>::default());
+fn index_task(rx_doc: DocumentReceiver, tx_index: IndexSender, analyzer: &Analyzer, schema: Schema, terms: &TermIds, spill: &segments::SegmentSpill, full_contents: &str) {
+    let mut analyzer = ThreadAnalyzer::new(analyzer, schema);
+    let mut inverted_index = new_term_id_index(500_000);
+    let mut docs_seen = 0;
     for chunk in rx_doc {
         for d in chunk {
-            for token in analyzer.analyze(&full_contents[d.text.clone()]) {
-                match inverted_index.get_mut(&token) {
+            spill.maybe_spill(docs_seen, &mut inverted_index, &mut analyzer, terms);
+            docs_seen += 1;
+            for token in analyzer.analyze_document(full_contents, &d) {
+                let term_id = terms.id_of(token);
+                match inverted_index.get_mut(&term_id) {
                     Some(set) => {
-                        set.insert(d.id as i32);
+                        set.insert(d.id);
                     }, 
                     None => {
                         let mut set = HashSet::with_hasher(BuildHasherDefault::<FxHasher>::default());
-                        set.insert(d.id as i32);
-                        inverted_index.insert(token, set);
+                        set.insert(d.id);
+                        inverted_index.insert(term_id, set);
                     }
                 }
             }
         }
     }
-    tx_index.send(inverted_index).unwrap();
+    tx_index.send((inverted_index, analyzer.take_positions())).unwrap();
 }
 
-fn dashmap_index_task(rx_doc: DocumentReceiver, inverted_index: &DashMapInvertedIndex, analyzer: &Analyzer, full_contents: &str) {
+fn dashmap_index_task(rx_doc: DocumentReceiver, inverted_index: &DashMapInvertedIndex, tx_positions: PositionSender, analyzer: &Analyzer, schema: Schema, full_contents: &str) {
+    let mut analyzer = ThreadAnalyzer::new(analyzer, schema);
     for chunk in rx_doc {
         for d in chunk {
-            for token in analyzer.analyze(&full_contents[d.text.clone()]) {
-                match inverted_index.get_mut(&token) {
-                    Some(set) => {
-                        set.insert(d.id as i32);
-                    }, 
-                    None => {
-                        let set = dashmap::DashSet::new();
-                        set.insert(d.id as i32);
-                        inverted_index.insert(token, set);
-                    }
-                }
+            for token in analyzer.analyze_document(full_contents, &d) {
+                // Through the entry, so two threads meeting a new term don't each insert a set
+                inverted_index.entry(token).or_default().insert(d.id);
             }
         }
     }
+    tx_positions.send(analyzer.take_positions()).unwrap();
 }
 
-fn spawn_index_tasks<'a>(num_threads: usize, scope: &rayon::Scope<'a>, analyzer: &'a Analyzer, full_contents: &'a str) -> (DocumentSender, IndexReceiver) {
+fn spawn_index_tasks<'a>(num_threads: usize, scope: &rayon::Scope<'a>, analyzer: &'a Analyzer, schema: Schema, terms: &'a TermIds, spill: &'a segments::SegmentSpill, full_contents: &'a str) -> (DocumentSender, IndexReceiver) {
     let (tx_doc, rx_doc): (DocumentSender, DocumentReceiver) = crossbeam_channel::unbounded();
     let (tx_index, rx_index) = crossbeam_channel::unbounded();
     for _ in 0..num_threads {
         let rx_doc = rx_doc.clone();
         let tx_index = tx_index.clone();
         scope.spawn(move |_| {
-            index_task(rx_doc, tx_index, analyzer, full_contents)
+            index_task(rx_doc, tx_index, analyzer, schema, terms, spill, full_contents)
         });
     }
     (tx_doc, rx_index)
 }
 
-fn spawn_dashmap_index_tasks<'a>(num_threads: usize, inverted_index: &'a DashMapInvertedIndex, scope: &rayon::Scope<'a>, analyzer: &'a Analyzer, full_contents: &'a str) -> DocumentSender {
+fn spawn_dashmap_index_tasks<'a>(num_threads: usize, inverted_index: &'a DashMapInvertedIndex, scope: &rayon::Scope<'a>, analyzer: &'a Analyzer, schema: Schema, full_contents: &'a str) -> (DocumentSender, PositionReceiver) {
     let (tx_doc, rx_doc): (DocumentSender, DocumentReceiver) = crossbeam_channel::unbounded();
+    let (tx_positions, rx_positions): (PositionSender, PositionReceiver) = crossbeam_channel::unbounded();
     for _ in 0..num_threads {
         let rx_doc = rx_doc.clone();
+        let tx_positions = tx_positions.clone();
         scope.spawn(move |_| {
-            dashmap_index_task(rx_doc, inverted_index, analyzer, full_contents);
+            dashmap_index_task(rx_doc, inverted_index, tx_positions, analyzer, schema, full_contents);
         });
     }
 
-    tx_doc
+    (tx_doc, rx_positions)
+}
+
+// Index tasks hold a worker each for the whole build and the build's scope holds one more, so the
+// parse tasks need parse_threads on top of those to all run at once. Merging, sorting and searches
+// use the same pool once the index tasks are done, and get at least a thread per cpu.
+fn pool_size(parse_threads: usize, index_threads: usize) -> usize {
+    cmp::max(parse_threads + index_threads + 1, num_cpus::get())
+}
+
+fn new_pool(parse_threads: usize, index_threads: usize) -> rayon::ThreadPool {
+    rayon::ThreadPoolBuilder::new().num_threads(pool_size(parse_threads, index_threads)).build().unwrap()
 }
 
 impl ThreadPoolIndexer {
-    pub fn new_hashmap(parse_threads: usize, index_threads: usize) -> Self {
+    fn sorted_terms(&self) -> &sorted_terms::SortedTerms {
+        self.sorted_terms.get_or_init(|| match &self.index {
+            IndexType::SingleThread(idx) => sorted_terms::SortedTerms::new(idx.keys().cloned()),
+            IndexType::MultiThread(idx) => sorted_terms::SortedTerms::new(idx.iter().map(|e| e.key().clone()))
+        })
+    }
+
+    pub fn new_hashmap(options: IndexOptions, parse_threads: usize, index_threads: usize) -> Self {
+        let (analyzer, query_analyzer) = Analyzer::from_options(&options);
         ThreadPoolIndexer { 
             index: IndexType::SingleThread(HashMapInvertedIndex::with_hasher(BuildHasherDefault::<FxHasher>::default())), 
+            positions: new_position_index(),
             documents: DocumentIndex::new(), 
-            analyzer: Analyzer::new_english(),
+            titles: OnceLock::new(),
+            sorted_terms: OnceLock::new(),
+            analyzer,
+            query_analyzer,
             cur_id: atomic::AtomicI32::new(0),
-            pool: rayon::ThreadPoolBuilder::new().num_threads(parse_threads + index_threads + 1).build().unwrap(),
-            parse_threads: parse_threads,
-            index_threads: index_threads,
+            pool: new_pool(parse_threads, index_threads),
+            parse_threads,
+            index_threads,
             full_contents: Box::new(String::new()),
+            options,
+            sources: SourceFiles::default(),
+            cancel: CancelToken::new()
         }
     }
     
-    pub fn new_dashmap(parse_threads: usize, index_threads: usize) -> Self {
+    pub fn new_dashmap(options: IndexOptions, parse_threads: usize, index_threads: usize) -> Self {
+        let (analyzer, query_analyzer) = Analyzer::from_options(&options);
         ThreadPoolIndexer { 
             index: IndexType::MultiThread(DashMapInvertedIndex::new()), 
+            positions: new_position_index(),
             documents: DocumentIndex::new(), 
-            analyzer: Analyzer::new_english(),
+            titles: OnceLock::new(),
+            sorted_terms: OnceLock::new(),
+            analyzer,
+            query_analyzer,
             cur_id: atomic::AtomicI32::new(0),
-            pool: rayon::ThreadPoolBuilder::new().num_threads(parse_threads + index_threads + 1).build().unwrap(),
-            parse_threads: parse_threads,
-            index_threads: index_threads,
+            pool: new_pool(parse_threads, index_threads),
+            parse_threads,
+            index_threads,
             full_contents: Box::new(String::new()),
+            options,
+            sources: SourceFiles::default(),
+            cancel: CancelToken::new()
         }
     }
 
-    fn build_hashmap(&self, contents_split: Vec<ContentsSplit>, full_contents: &str) -> (HashMapInvertedIndex, DocumentIndex) {
+    fn build_hashmap(&self, contents_split: Vec<ContentsSplit>, full_contents: &str) -> Result<(HashMapInvertedIndex, PositionIndex, DocumentIndex), io::Error> {
         let pool = &self.pool;
         let analyzer = &self.analyzer;
         let cur_id = &self.cur_id;
-        let (inverted_index, documents) = pool.scope(|s| {
-            let (tx_doc, rx_index) = spawn_index_tasks(self.index_threads, s, &analyzer, full_contents);
+        let terms = TermIds::new();
+        let spill = segments::SegmentSpill::new(self.options.memory_limit);
+        let (inverted_index, positions, documents) = pool.scope(|s| {
+            let (tx_doc, rx_index) = spawn_index_tasks(self.index_threads, s, analyzer, self.options.schema, &terms, &spill, full_contents);
 
             // Async parse documents and push to indexing threads
-            let rx_alldocs = parse_documents(contents_split, &cur_id, s, tx_doc);
+            let rx_alldocs = parse_documents(contents_split, &self.options, cur_id, s, tx_doc);
     
-            // Read off indexing threads and merge
-            let mut rx_index_iter = rx_index.into_iter();
-            let mut joined_index = rx_index_iter.next().unwrap();
-            for mut thread_index in rx_index_iter {
-                for (thread_k, thread_set) in thread_index.drain() {
-                    match joined_index.get_mut(&thread_k) {
-                        Some(joined_set) => {
-                            joined_set.extend(thread_set);
-                        }, 
-                        None => {
-                            joined_index.insert(thread_k, thread_set);
-                        }                 
-                    }
+            // Read off indexing threads, whose workers are free again once they've all sent, and merge
+            // pairwise across the pool
+            let thread_indexes: Vec<(TermIdIndex, PositionIndex)> = rx_index.into_iter().collect();
+            let (joined_index, joined_positions) = thread_indexes.into_par_iter().reduce(
+                || (new_term_id_index(0), new_position_index()),
+                |(mut a, mut a_positions), (b, b_positions)| {
+                    merge_positions(&mut a_positions, b_positions);
+                    merge_postings(&mut a, b);
+                    (a, a_positions)
                 }
-            }
-    
+            );
+
             let mut all_docs_iter = rx_alldocs.into_iter();
             let mut documents: DocumentIndex = all_docs_iter.next().unwrap();
             for docs in all_docs_iter {
                 documents.extend(docs);
             }
-            (joined_index, documents)
+            (joined_index, joined_positions, documents)
         });
-
-        (inverted_index, documents)
+        let (inverted_index, positions) = spill.finish(inverted_index, positions, terms)?.into_memory()?;
+        Ok((inverted_index, positions, documents))
     }
 
-    fn build_dashmap(&self, contents_split: Vec<ContentsSplit>, full_contents: &str) -> (DashMapInvertedIndex, DocumentIndex) {
+    fn build_dashmap(&self, contents_split: Vec<ContentsSplit>, full_contents: &str) -> (DashMapInvertedIndex, PositionIndex, DocumentIndex) {
         let pool = &self.pool;
         let analyzer = &self.analyzer;
         let cur_id = &self.cur_id;
         let inverted_index = DashMapInvertedIndex::with_capacity(2_000_000);
-        let documents = pool.scope(|s| {
-            let tx_doc = spawn_dashmap_index_tasks(self.index_threads, &inverted_index, s, &analyzer, full_contents);
+        let (positions, documents) = pool.scope(|s| {
+            let (tx_doc, rx_positions) = spawn_dashmap_index_tasks(self.index_threads, &inverted_index, s, analyzer, self.options.schema, full_contents);
 
             // Async parse documents and push to indexing threads
-            let rx_alldocs = parse_documents(contents_split, &cur_id, s, tx_doc);
+            let rx_alldocs = parse_documents(contents_split, &self.options, cur_id, s, tx_doc);
     
             let mut all_docs_iter = rx_alldocs.into_iter();
             let mut documents: DocumentIndex = all_docs_iter.next().unwrap();
             for docs in all_docs_iter {
                 documents.extend(docs);
             }
-            documents
+
+            let thread_positions: Vec<PositionIndex> = rx_positions.into_iter().collect();
+            let positions = thread_positions.into_par_iter().reduce(new_position_index, |mut a, b| {
+                merge_positions(&mut a, b);
+                a
+            });
+            (positions, documents)
         });
 
-        (inverted_index, documents)
+        (inverted_index, positions, documents)
     }
 }
 
 macro_rules! search {
-    ($s:expr, $idx:expr, $all_terms:expr ,$results:expr) => {
-        for search_term in $all_terms {
-            for term in $s.analyzer.analyze(search_term) {
-                if let Some(ids) = $idx.get(&term) {
-                    let mut matched_docs: Vec<Document> = Vec::new();
-                    for id in ids.iter() {
-                        matched_docs.push($s.documents[*id as usize].to_document($s.full_contents.as_ref()));
-                    }
-                    $results.push(SearchResults{term: term, matches: matched_docs});
-                }
-            }
-        }
-    };
+    ($s:expr, $idx:expr, $all_terms:expr, $page:expr) => {{
+        let analyzer = $s.query_analyzer.as_ref().unwrap_or(&$s.analyzer);
+        run_query(analyzer, $all_terms, PhraseSource::new(&$s.analyzer, &$s.positions, $s.options.schema),
+            |term, ids: &mut Vec<i32>| $idx.get(term).map(|postings| ids.extend(postings.iter().map(|id| *id))).is_some(),
+            |prefix| $s.sorted_terms().with_prefix(prefix).iter().map(String::as_str).collect(),
+            |id| $s.documents.get(id as usize).map(|d| d.to_document($s.full_contents.as_ref(), $s.options.format, &$s.sources)),
+            |term, id| $s.term_frequency(term, id),
+            &$s.cancel,
+            &$s.options, $page)
+    }};
 }
 
 impl DocumentIndexer for ThreadPoolIndexer {
-    fn build_from_file_contents(&mut self, file_contents: String) {
+    fn build_from_files(&mut self, files: SourceFiles, file_contents: String) -> Result<(), io::Error> {
+        self.cur_id.store(0, atomic::Ordering::SeqCst);
         //self.file_contents = file_contents;
         let mut contents_split: Vec<ContentsSplit> = Vec::new();
         println!("NUM CPUS: {}", num_cpus::get());
-        for contents in split_contents(&file_contents, "</doc>", self.parse_threads) {
+        for contents in split_contents(&file_contents, self.options.format.split_on(), self.parse_threads) {
             contents_split.push(contents);
         }
 
         if let IndexType::SingleThread(_) = self.index {
-            let (index, documents) = self.build_hashmap(contents_split, &file_contents);
+            let (index, positions, documents) = self.build_hashmap(contents_split, &file_contents)?;
             self.index = IndexType::SingleThread(index);
+            self.positions = positions;
             self.documents = documents;
         } else {
-            let (index, documents) = self.build_dashmap(contents_split, &file_contents);
+            let (index, positions, documents) = self.build_dashmap(contents_split, &file_contents);
             self.index = IndexType::MultiThread(index);
+            self.positions = positions;
             self.documents = documents;
         }
-        self.documents.sort();
+        let documents = &mut self.documents;
+        self.pool.install(|| documents.par_sort());
         self.full_contents = Box::new(file_contents);
+        self.sources = files;
+        self.titles = OnceLock::new();
+        self.sorted_terms = OnceLock::new();
+        Ok(())
     }
     
-    fn search(&self, all_terms: Vec<&str>) -> Vec<SearchResults> {
-        let mut results: Vec<SearchResults> = Vec::new();
-        match &self.index {
-            IndexType::SingleThread(idx) => search!(self, idx, all_terms, results),
-            IndexType::MultiThread(idx) => search!(self, idx, all_terms, results)
+    // On the pool, so large result sets are built by its threads instead of rayon's global pool
+    // Kept for searches only, so false
+    fn set_cancel_token(&mut self, token: CancelToken) -> bool {
+        self.cancel = token;
+        false
+    }
+    // The pool is only replaced when its size changes
+    fn set_threads(&mut self, parse_threads: usize, index_threads: usize) -> bool {
+        if pool_size(parse_threads, index_threads) != self.pool.current_num_threads() {
+            self.pool = new_pool(parse_threads, index_threads);
         }
-        results
+        self.parse_threads = parse_threads;
+        self.index_threads = index_threads;
+        true
+    }
+    fn search_page(&self, all_terms: Vec<&str>, page: Page) -> Result<SearchResults, QueryError> {
+        self.pool.install(|| match &self.index {
+            IndexType::SingleThread(idx) => search!(self, idx, all_terms, page),
+            IndexType::MultiThread(idx) => search!(self, idx, all_terms, page)
+        })
     }
 
+    fn search_batch(&self, queries: &[Vec<&str>]) -> Vec<Result<SearchResults, QueryError>> {
+        self.pool.install(|| search_batch_with(queries, |all_terms| self.search(all_terms)))
+    }
+
+    fn document(&self, id: i32) -> Option<Document> {
+        self.documents.get(id as usize).map(|d| d.to_document(self.full_contents.as_ref(), self.options.format, &self.sources))
+    }
+    fn term_offsets(&self, term: &str, doc: &Document) -> Vec<(u32, u32)> {
+        find_term_offsets(&self.analyzer, &self.positions, self.options.schema, term, doc)
+    }
+    fn term_frequency(&self, term: &str, id: i32) -> Option<u32> {
+        find_term_frequency(&self.positions, self.options.schema, term, id)
+    }
     fn num_tokens(&self) -> usize {
         match &self.index {
             IndexType::SingleThread(idx) => idx.len(),
@@ -304,4 +403,67 @@ impl DocumentIndexer for ThreadPoolIndexer {
     fn num_documents(&self) -> usize {
         self.documents.len()
     }
-}
\ No newline at end of file
+    fn num_oversized(&self) -> usize {
+        self.documents.iter().filter(|d| d.oversized).count()
+    }
+    fn schema(&self) -> Schema {
+        self.options.schema
+    }
+    fn num_positioned(&self) -> usize {
+        self.positions.values().map(|docs| docs.len()).sum()
+    }
+    fn language(&self) -> &str {
+        self.options.query_language.as_deref().unwrap_or("en")
+    }
+    fn analyze_text(&self, text: &str) -> Vec<String> {
+        self.analyzer.analyze(text)
+    }
+    fn document_frequency(&self, term: &str) -> usize {
+        match &self.index {
+            IndexType::SingleThread(idx) => idx.get(term).map(|ids| ids.len()).unwrap_or(0),
+            IndexType::MultiThread(idx) => idx.get(term).map(|ids| ids.len()).unwrap_or(0)
+        }
+    }
+    fn terms_with_prefix(&self, prefix: &str, limit: usize) -> Vec<(String, usize)> {
+        match &self.index {
+            IndexType::SingleThread(idx) => most_frequent(self.sorted_terms().with_prefix(prefix).iter().map(|term| (term.clone(), idx[term].len())), limit),
+            IndexType::MultiThread(idx) => most_frequent(self.sorted_terms().with_prefix(prefix).iter().map(|term| (term.clone(), idx.get(term).unwrap().len())), limit)
+        }
+    }
+    fn suggest_titles(&self, prefix: &str, limit: usize) -> Vec<(String, i32)> {
+        let title = |d: &DocumentRaw| self.full_contents.from_utf8_unchecked(d.title.clone());
+        let titles = self.titles.get_or_init(|| titles::TitleIndex::new(self.documents.iter().map(|d| (title(d), d.id))));
+        titles.complete(prefix, limit).into_iter().map(|id| (String::from(title(&self.documents[id as usize])), id)).collect()
+    }
+    fn cooccurring_terms(&self, term: &str, limit: usize) -> Vec<(String, usize)> {
+        let idx = match &self.index {
+            IndexType::SingleThread(idx) => return cooccurring_in(idx, term, limit),
+            IndexType::MultiThread(idx) => idx
+        };
+        let ids = match idx.get(term) {
+            Some(ids) => ids,
+            None => return Vec::new()
+        };
+        let shared = idx.iter()
+            .filter(|e| e.key().as_str() != term)
+            .map(|e| (e.key().clone(), ids.iter().filter(|id| e.value().contains(id.key())).count()))
+            .filter(|(_, count)| *count > 0);
+        most_frequent(shared, limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_threads_resizes_the_pool_builds_and_searches_run_on() {
+        let mut indexer = ThreadPoolIndexer::new_hashmap(IndexOptions { format: Format::Lines, ..IndexOptions::default() }, 1, 1);
+        let parse_threads = num_cpus::get() + 4;
+        assert!(indexer.set_threads(parse_threads, 2));
+        assert_eq!(indexer.pool.current_num_threads(), parse_threads + 3);
+        indexer.build_from_file_contents("lines", String::from("red apple\ngreen apple\n")).unwrap();
+        let results = indexer.search(vec!["apple"]).unwrap();
+        assert_eq!(results.ids().collect::<Vec<i32>>(), vec![0, 1]);
+    }
+}
@@ -0,0 +1,40 @@
+// Document titles for completion, kept apart from the term index so "operating sys" completes to
+// whole titles rather than to terms. Titles are compared lowercased with runs of whitespace
+// collapsed and kept sorted, so the ones starting with a prefix are a single range found by binary
+// search. Backends build it on the first suggest_titles after a build or load.
+pub(super) struct TitleIndex {
+    // Normalized title and the id of the first document with it
+    entries: Vec<(String, i32)>
+}
+
+// A trailing space is kept so "new " only completes titles with more words after "new"
+fn normalize(title: &str) -> String {
+    let mut normalized = title.split_whitespace().collect::<Vec<&str>>().join(" ").to_lowercase();
+    if !normalized.is_empty() && title.ends_with(char::is_whitespace) {
+        normalized.push(' ');
+    }
+    normalized
+}
+
+impl TitleIndex {
+    pub(super) fn new<'a>(titles: impl Iterator<Item = (&'a str, i32)>) -> Self {
+        let mut entries: Vec<(String, i32)> = titles
+            .map(|(title, id)| (normalize(title.trim()), id))
+            .filter(|(title, _)| !title.is_empty())
+            .collect();
+        entries.sort_unstable();
+        entries.dedup_by(|a, b| a.0 == b.0);
+        TitleIndex { entries }
+    }
+
+    // Ids of up to `limit` documents whose titles start with `prefix`, in title order
+    pub(super) fn complete(&self, prefix: &str, limit: usize) -> Vec<i32> {
+        let prefix = normalize(prefix.trim_start());
+        let start = self.entries.partition_point(|(title, _)| title.as_str() < prefix.as_str());
+        self.entries[start..].iter()
+            .take_while(|(title, _)| title.starts_with(&prefix))
+            .take(limit)
+            .map(|(_, id)| *id)
+            .collect()
+    }
+}
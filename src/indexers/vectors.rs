@@ -0,0 +1,287 @@
+use crate::indexers::*;
+use crate::indexers::records::{RECORD_SEPARATOR, UNIT_SEPARATOR};
+use std::collections::BinaryHeap;
+
+// Neighbors kept per node on the upper layers, the bottom layer keeps twice as many
+const M: usize = 16;
+const EF_CONSTRUCTION: usize = 100;
+const EF_SEARCH: usize = 64;
+// Damps the weight of the top ranks in reciprocal rank fusion, 60 is the usual choice
+const RRF_K: f32 = 60.0;
+// The file next to a source the graph is saved to, see VectorIndex::read_cached
+pub const VECTORS_EXTENSION: &str = "hnsw";
+
+#[derive(Clone, Copy, PartialEq)]
+struct Candidate {
+    distance: f32,
+    node: u32
+}
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.distance.total_cmp(&other.distance).then_with(|| self.node.cmp(&other.node))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn normalized(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        for x in vector.iter_mut() {
+            *x /= norm;
+        }
+    }
+    vector
+}
+
+// Cosine distance, both vectors are normalized on the way in
+fn distance(a: &[f32], b: &[f32]) -> f32 {
+    1.0 - a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>()
+}
+
+// Approximate nearest neighbor search over one vector per document, as a hierarchical navigable
+// small world graph. Built in memory from the records, which takes a distance computation per
+// neighbor considered for every insert, and saved next to the source so later runs over the same
+// source only read it back.
+#[derive(Serialize, Deserialize)]
+pub struct VectorIndex {
+    ids: Vec<i32>,
+    vectors: Vec<Vec<f32>>,
+    // neighbors[node][layer], a node is on every layer up to the one it was drawn for
+    neighbors: Vec<Vec<Vec<u32>>>,
+    entry: Option<u32>,
+    top_layer: usize,
+    dimensions: usize,
+    // xorshift state for drawing layers, seeded the same every build so results are repeatable
+    rng: u64
+}
+
+impl VectorIndex {
+    pub fn new() -> Self {
+        VectorIndex {
+            ids: Vec::new(),
+            vectors: Vec::new(),
+            neighbors: Vec::new(),
+            entry: None,
+            top_layer: 0,
+            dimensions: 0,
+            rng: 0x2545_f491_4f6c_dd1d
+        }
+    }
+
+    // Reads the vector field of each record in a records buffer, the record number being the
+    // document id. Vectors whose dimensions differ from the first one are left out with a warning.
+    pub fn from_records(contents: &str) -> Self {
+        let mut index = VectorIndex::new();
+        let mut skipped = 0;
+        for (id, record) in contents.split_terminator(RECORD_SEPARATOR).enumerate() {
+            let vector = match record.splitn(4, UNIT_SEPARATOR).nth(3) {
+                Some(field) => field.split_whitespace().map(|x| x.parse::<f32>()).collect::<Result<Vec<f32>, _>>(),
+                None => continue
+            };
+            match vector {
+                Ok(vector) if !vector.is_empty() && (index.is_empty() || vector.len() == index.dimensions) => index.insert(id as i32, vector),
+                _ => skipped += 1
+            }
+        }
+        if skipped > 0 {
+            println!("Skipped {} vectors that were empty or didn't have {} dimensions", skipped, index.dimensions);
+        }
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    fn random_layer(&mut self) -> usize {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let uniform = ((self.rng >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        (-uniform.ln() / (M as f64).ln()) as usize
+    }
+
+    // The `ef` nodes closest to `query` reachable on `layer` from `entry`, closest first
+    fn search_layer(&self, query: &[f32], entry: u32, ef: usize, layer: usize) -> Vec<Candidate> {
+        let start = Candidate { distance: distance(query, &self.vectors[entry as usize]), node: entry };
+        let mut visited: HashSet<u32> = HashSet::new();
+        visited.insert(entry);
+        let mut candidates: BinaryHeap<cmp::Reverse<Candidate>> = BinaryHeap::new();
+        candidates.push(cmp::Reverse(start));
+        let mut found: BinaryHeap<Candidate> = BinaryHeap::new();
+        found.push(start);
+        while let Some(cmp::Reverse(closest)) = candidates.pop() {
+            if found.len() >= ef && closest.distance > found.peek().unwrap().distance {
+                break;
+            }
+            for &node in &self.neighbors[closest.node as usize][layer] {
+                if !visited.insert(node) {
+                    continue;
+                }
+                let candidate = Candidate { distance: distance(query, &self.vectors[node as usize]), node };
+                if found.len() < ef || candidate.distance < found.peek().unwrap().distance {
+                    candidates.push(cmp::Reverse(candidate));
+                    found.push(candidate);
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+        found.into_sorted_vec()
+    }
+
+    // Walks down from the top layer to just above `layer`, one closest node at a time
+    fn descend(&self, query: &[f32], layer: usize) -> Option<u32> {
+        let mut entry = self.entry?;
+        for upper in (layer + 1..=self.top_layer).rev() {
+            entry = self.search_layer(query, entry, 1, upper)[0].node;
+        }
+        Some(entry)
+    }
+
+    fn insert(&mut self, id: i32, vector: Vec<f32>) {
+        let vector = normalized(vector);
+        let layer = self.random_layer();
+        let node = self.ids.len() as u32;
+        let mut chosen: Vec<Vec<u32>> = vec![Vec::new(); layer + 1];
+        if let Some(mut entry) = self.descend(&vector, layer) {
+            for l in (0..=cmp::min(layer, self.top_layer)).rev() {
+                let found = self.search_layer(&vector, entry, EF_CONSTRUCTION, l);
+                entry = found[0].node;
+                chosen[l] = found.into_iter().take(M).map(|c| c.node).collect();
+            }
+        } else {
+            self.dimensions = vector.len();
+        }
+        self.ids.push(id);
+        self.vectors.push(vector);
+        self.neighbors.push(chosen.clone());
+        for (l, nodes) in chosen.into_iter().enumerate() {
+            let max_neighbors = if l == 0 { 2 * M } else { M };
+            for neighbor in nodes {
+                self.neighbors[neighbor as usize][l].push(node);
+                if self.neighbors[neighbor as usize][l].len() > max_neighbors {
+                    self.prune(neighbor, l, max_neighbors);
+                }
+            }
+        }
+        if self.entry.is_none() || layer > self.top_layer {
+            self.entry = Some(node);
+            self.top_layer = layer;
+        }
+    }
+
+    // Keeps the closest `max_neighbors` of a node's links on `layer`
+    fn prune(&mut self, node: u32, layer: usize, max_neighbors: usize) {
+        let vector = &self.vectors[node as usize];
+        let mut links: Vec<Candidate> = self.neighbors[node as usize][layer].iter()
+            .map(|&n| Candidate { distance: distance(vector, &self.vectors[n as usize]), node: n })
+            .collect();
+        links.sort();
+        self.neighbors[node as usize][layer] = links.into_iter().take(max_neighbors).map(|c| c.node).collect();
+    }
+
+    // Up to `k` (cosine similarity, document id) pairs, most similar first. `query` must have
+    // dimensions() entries.
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<(f32, i32)> {
+        let query = normalized(query.to_vec());
+        let entry = match self.descend(&query, 0) {
+            Some(entry) => entry,
+            None => return Vec::new()
+        };
+        self.search_layer(&query, entry, cmp::max(EF_SEARCH, k), 0).into_iter()
+            .take(k)
+            .map(|c| (1.0 - c.distance, self.ids[c.node as usize]))
+            .collect()
+    }
+}
+
+impl VectorIndex {
+    // The graph write_cached saved next to `source`, None when there's none or the source changed
+    // since, or it was written by another version
+    pub fn read_cached(source: &Path) -> Result<Option<VectorIndex>, io::Error> {
+        let data = match fs::read(source.with_extension(VECTORS_EXTENSION)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e)
+        };
+        let (version, built, index): (u32, SourceFingerprint, VectorIndex) = bincode::deserialize(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", VECTORS_EXTENSION, e)))?;
+        let current = version == CACHE_FORMAT_VERSION && SourceFingerprint::of(source)?.same_contents(&built);
+        Ok(current.then_some(index))
+    }
+
+    // Saves the graph built from `source` next to it, written to a temp file and renamed into place
+    pub fn write_cached(&self, source: &Path, sync: SyncPolicy) -> Result<(), io::Error> {
+        let data = bincode::serialize(&(CACHE_FORMAT_VERSION, SourceFingerprint::of(source)?, self))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let tmp_path = source.with_extension(format!("{}.tmp", VECTORS_EXTENSION));
+        disk::write_checked(&tmp_path, &disk::CacheFile::bytes(data), sync)?;
+        fs::rename(&tmp_path, source.with_extension(VECTORS_EXTENSION))
+    }
+}
+
+impl Default for VectorIndex {
+    fn default() -> Self {
+        VectorIndex::new()
+    }
+}
+
+// `knn:0.1,0.2,...` searches the vector index for the documents nearest that vector. None when the
+// term isn't a knn term.
+pub fn parse_knn_term(term: &str) -> Option<Result<Vec<f32>, io::Error>> {
+    let vector = term.strip_prefix("knn:")?;
+    Some(vector.split(',').map(|x| x.trim().parse::<f32>()).collect::<Result<Vec<f32>, _>>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("bad knn vector '{}': {}", vector, e))))
+}
+
+// Reciprocal rank fusion: each document scores the sum of 1 / (RRF_K + rank) over the rankings it
+// appears in, so documents ranked well by both the text and vector searches come first
+pub fn fuse_rankings(rankings: &[Vec<i32>]) -> Vec<(f32, i32)> {
+    let mut scores: HashMap<i32, f32> = HashMap::new();
+    for ranking in rankings {
+        for (rank, id) in ranking.iter().enumerate() {
+            *scores.entry(*id).or_insert(0.0) += 1.0 / (RRF_K + rank as f32 + 1.0);
+        }
+    }
+    let mut fused: Vec<(f32, i32)> = scores.into_iter().map(|(id, score)| (score, id)).collect();
+    fused.sort_by(|a, b| b.0.total_cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+    fused
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::TempSource;
+
+    #[test]
+    fn a_saved_graph_is_read_back_until_the_source_changes() {
+        let path = TempSource::new("vectors", "jsonl", "{}");
+        let mut index = VectorIndex::new();
+        for id in 0..50 {
+            index.insert(id, vec![id as f32, 1.0, (id % 7) as f32]);
+        }
+        index.write_cached(&path, SyncPolicy::Never).unwrap();
+        let cached = VectorIndex::read_cached(&path).unwrap().unwrap();
+        assert_eq!(cached.search(&[3.0, 1.0, 3.0], 5), index.search(&[3.0, 1.0, 3.0], 5));
+        fs::write(&path, "{\"changed\": true}").unwrap();
+        assert!(VectorIndex::read_cached(&path).unwrap().is_none());
+    }
+}
@@ -0,0 +1,63 @@
+use crate::indexers::*;
+use crate::indexers::mbox::{parse_header_block, find_header};
+use std::sync::atomic;
+
+pub fn read_gzipped(path: &str) -> Result<String, io::Error> {
+    let mut contents = String::new();
+    // Common Crawl files are a series of gzip members, one per record
+    flate2::read::MultiGzDecoder::new(io::BufReader::new(File::open(path)?)).read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+fn is_text_record(record_type: &str, content_type: &str) -> bool {
+    match record_type {
+        "conversion" => true,
+        "resource" | "response" => content_type.starts_with("text/plain"),
+        _ => false
+    }
+}
+
+// Keeps WET "conversion" records and plain text WARC resources; the first line of the text, which is
+// the page title in Common Crawl extracts, becomes the title
+pub(super) fn parse_warc(contents: &ContentsSplit, cur_id: &atomic::AtomicI32) -> Vec<DocumentRaw> {
+    let data = contents.data;
+    let base_offset = contents.base_offset;
+    let mut docs: Vec<DocumentRaw> = Vec::new();
+    let mut pos = match data.find("WARC/") {
+        Some(p) => p,
+        None => return docs
+    };
+    while pos < data.len() {
+        let record = &data[pos..];
+        let (headers, body_start) = parse_header_block(record);
+        let header = |name: &str| find_header(record, &headers, name);
+        let content_length = header("content-length").and_then(|(v, _)| v.parse::<usize>().ok()).unwrap_or(0);
+        let mut body_end = cmp::min(body_start + content_length, record.len());
+        while !record.is_char_boundary(body_end) {
+            body_end += 1;
+        }
+
+        let record_type = header("warc-type").map(|(v, _)| v.to_lowercase()).unwrap_or_default();
+        let content_type = header("content-type").map(|(v, _)| v.to_lowercase()).unwrap_or_default();
+        if is_text_record(&record_type, &content_type) {
+            let body = &record[body_start..body_end];
+            let title = body.trim_start().lines().next().unwrap_or("");
+            let title_start = body_start + (body.len() - body.trim_start().len());
+            let absolute = |start: usize, end: usize| Range{start: base_offset + pos + start, end: base_offset + pos + end};
+            let url = header("warc-target-uri").map(|(_, url)| url).unwrap_or_default();
+            docs.push(DocumentRaw {
+                title: absolute(title_start, title_start + title.trim_end().len()),
+                url: absolute(url.start, url.end),
+                text: absolute(body_start, body_end),
+                id: cur_id.fetch_add(1, atomic::Ordering::SeqCst),
+                ..Default::default()
+            });
+        }
+
+        pos = match data[pos + body_end..].find("WARC/") {
+            Some(next) => pos + body_end + next,
+            None => data.len()
+        };
+    }
+    docs
+}
@@ -0,0 +1,132 @@
+use crate::indexers::*;
+use crate::indexers::records::DocumentSource;
+use std::sync::atomic;
+
+// Each <doc> of a Wikipedia abstract dump, with its <title>, <url> and <abstract>
+pub(super) fn parse_xml(file_contents: &ContentsSplit, cur_id: &atomic::AtomicI32) -> Vec<DocumentRaw> {
+    let base_offset = file_contents.base_offset;
+    let mut cur_doc = DocumentRaw::default();
+    let mut cur_tag: &str = "";
+    let mut docs: Vec<DocumentRaw> = Vec::with_capacity(500_000);
+    //println!("len contents: {}", file_contents.len());
+    for token in xmlparser::Tokenizer::from_fragment(file_contents.data, 0..file_contents.data.len()) {
+        //println!("token: {:?}", token);
+        match token {
+            Ok(xmlparser::Token::ElementStart{local, ..}) => {
+                cur_tag = local.as_str();
+            },
+            Ok(xmlparser::Token::Text{text}) => {
+                let range_start = text.range().start;
+                let range_end = text.range().end;
+                let absolute_range = Range{start: base_offset+range_start, end: base_offset+range_end};
+                match cur_tag {
+                    "title" => cur_doc.title = absolute_range,
+                    "abstract" => {
+                        //println!("RANGE: {}\n{:?}\n{}\n{}\n", base_offset, absolute_range.clone(),
+                        //    &file_contents.data[text.range()], text.as_str());
+                        cur_doc.text = absolute_range
+                    },
+                    "url" => cur_doc.url = absolute_range,
+                    _ => {}
+                }
+            },
+            Ok(xmlparser::Token::ElementEnd{end, ..}) => {
+                if let xmlparser::ElementEnd::Close(_, n) = end {
+                    cur_tag = "";
+                    if n.as_str() == "doc" {
+                        cur_doc.id = cur_id.fetch_add(1, atomic::Ordering::SeqCst);
+                        docs.push(cur_doc);
+                        cur_doc = DocumentRaw::default();
+                    }
+                }
+            },
+
+            Ok(_) => {},
+            Err(e) => {println!("Error! {}, contents_start: {}", e, &file_contents.data[0..1024])}
+        }
+    }
+    docs
+}
+
+// How much of the source XmlSource reads for each batch, before cutting it after its last </doc>
+const BATCH_BYTES: usize = 4 << 20;
+
+// The XML parser as a DocumentSource, reading the source a batch at a time rather than all at once
+pub struct XmlSource<R> {
+    reader: R,
+    // What's been read past the last batch
+    buffer: Vec<u8>,
+    at_end: bool
+}
+
+impl XmlSource<File> {
+    pub fn open(path: &str) -> Result<Self, io::Error> {
+        Ok(XmlSource::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> XmlSource<R> {
+    pub fn new(reader: R) -> Self {
+        XmlSource { reader, buffer: Vec::new(), at_end: false }
+    }
+}
+
+impl<R: Read> DocumentSource for XmlSource<R> {
+    fn next_batch(&mut self, contents: &mut String) -> Result<Option<Vec<DocumentRaw>>, io::Error> {
+        let (split_on, split_after) = Format::Xml.split_on();
+        let end = loop {
+            if self.at_end {
+                break self.buffer.len();
+            }
+            let read = (&mut self.reader).take(BATCH_BYTES as u64).read_to_end(&mut self.buffer)?;
+            self.at_end = read < BATCH_BYTES;
+            if let Some(at) = self.buffer.windows(split_on.len()).rposition(|window| window == split_on.as_bytes()) {
+                break at + split_after;
+            }
+            // A document longer than a batch, read on until it ends
+        };
+        if end == 0 {
+            return Ok(None);
+        }
+        let batch = std::str::from_utf8(&self.buffer[..end])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let base_offset = contents.len();
+        contents.push_str(batch);
+        self.buffer.drain(..end);
+        let documents = parse_xml(&ContentsSplit { base_offset, data: &contents[base_offset..] }, &atomic::AtomicI32::new(0));
+        Ok(Some(documents))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xml_batches_are_cut_between_documents() {
+        let docs: String = (0..3).map(|i| format!("<doc><title>Doc {}</title><url>u{}</url><abstract>{}</abstract></doc>\n", i, i, "word ".repeat(BATCH_BYTES / 8))).collect();
+        let xml = format!("<feed>\n{}</feed>\n", docs);
+        let mut source = XmlSource::new(xml.as_bytes());
+        let mut contents = String::new();
+        let mut titles: Vec<String> = Vec::new();
+        let mut batches = 0;
+        while let Some(batch) = source.next_batch(&mut contents).unwrap() {
+            titles.extend(batch.iter().map(|d| String::from(&contents[d.title.clone()])));
+            batches += 1;
+        }
+        assert_eq!(titles, vec!["Doc 0", "Doc 1", "Doc 2"]);
+        assert!(batches > 1);
+        assert_eq!(contents, xml);
+    }
+
+    #[test]
+    fn xml_sources_index_as_parsed() {
+        let xml = "<feed>\n<doc><title>Apple</title><url>a</url><abstract>A red fruit</abstract></doc>\n\
+                   <doc><title>Pear</title><url>p</url><abstract>A green fruit</abstract></doc>\n</feed>\n";
+        let mut indexer = RayonIndexer::new(IndexOptions { format: Format::Records, ..IndexOptions::default() });
+        indexer.build_from_source("abstracts", &mut XmlSource::new(xml.as_bytes())).unwrap();
+        assert_eq!(indexer.num_documents(), 2);
+        let found: Vec<(i32, String)> = indexer.search(vec!["green"]).unwrap().documents().map(|doc| (doc.id, doc.url.clone())).collect();
+        assert_eq!(found, vec![(1, String::from("p"))]);
+    }
+}
@@ -0,0 +1,58 @@
+use super::*;
+
+// Slowest searches listed in the summary
+const SLOWEST: usize = 5;
+
+// How long each search of a REPL session took, summarized when the session ends so runs with
+// different backends or options can be compared. Only the search is timed, not printing its results,
+// and knn: searches, which print as they go, aren't counted.
+#[derive(Default)]
+pub struct Latencies {
+    searches: Vec<(time::Duration, String)>
+}
+
+impl Latencies {
+    pub fn new() -> Self {
+        Latencies::default()
+    }
+
+    pub fn record(&mut self, query: &str, took: time::Duration) {
+        self.searches.push((took, String::from(query.trim())));
+    }
+
+    // Nearest rank: the smallest latency at least `percent` of the searches took no longer than
+    pub fn percentile(sorted: &[time::Duration], percent: usize) -> time::Duration {
+        sorted[(sorted.len() * percent).div_ceil(100).max(1) - 1]
+    }
+
+    pub fn print_summary(&self) {
+        if self.searches.is_empty() {
+            return;
+        }
+        // Off the line of the prompt that got end of input
+        println!();
+        self.print_latencies();
+    }
+
+    // The summary so far, for :stats
+    pub fn print_latencies(&self) {
+        if self.searches.is_empty() {
+            println!("No searches yet");
+            return;
+        }
+        let mut sorted: Vec<time::Duration> = self.searches.iter().map(|(took, _)| *took).collect();
+        sorted.sort_unstable();
+        println!("Session: {} searches, p50 {} us, p95 {} us, p99 {} us, max {} us",
+            sorted.len(),
+            Latencies::percentile(&sorted, 50).as_micros(),
+            Latencies::percentile(&sorted, 95).as_micros(),
+            Latencies::percentile(&sorted, 99).as_micros(),
+            sorted[sorted.len() - 1].as_micros());
+        let mut slowest: Vec<&(time::Duration, String)> = self.searches.iter().collect();
+        slowest.sort_by_key(|(took, _)| std::cmp::Reverse(*took));
+        println!("Slowest:");
+        for (took, query) in slowest.into_iter().take(SLOWEST) {
+            println!("{:>10} us  {}", took.as_micros(), query);
+        }
+    }
+}
@@ -0,0 +1,21 @@
+// The search engine behind the fulltext CLI, for embedding in other programs. Index is the entry
+// point: build one from a source file, from InputDocs you already have, or from documents of your own
+// through a DocumentSource, or open the cache the CLI wrote for it, then search it with the same query
+// syntax as the command line. The indexers module has the backends and the pieces the CLI is built
+// from. Programs with several indexes can keep them in a Registry, whose results carry DocKeys saying
+// which index each document is from. To search while documents are added or the index is rebuilt,
+// give it to an IndexWriter and search through the Searchers its IndexReader hands out. --stdio
+// searches its indexes that way too, publishing a snapshot of each after it's changed and saved.
+pub mod indexers;
+mod index;
+mod registry;
+mod writer;
+#[cfg(test)]
+mod fixture;
+
+pub use index::Index;
+pub use registry::{DocKey, IndexId, Registry, RegistryResults};
+pub use writer::{IndexReader, IndexWriter, Searcher};
+pub use indexers::{Analyzer, CancelToken, Document, DocumentBatches, DocumentSource, FieldBoosts, FilterArgs, Format, Highlight, Hit, IndexOptions, InputDoc, Mode, Page, ParquetSource, QueryError, QueryLimits, Schema, SearchResults, TokenFilter, XmlSource, register_token_filter};
+#[cfg(feature = "legacy-results")]
+pub use indexers::TermResults;
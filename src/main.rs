@@ -1,45 +1,1085 @@
-use clap;
 use std::fs;
 use std::time::{self};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
 use std::io::{self, Write};
-mod indexers;
-use indexers::*;
+use std::cell::{Cell, RefCell};
+use std::path::{Path, PathBuf};
+mod stdio;
+mod sets;
+mod latency;
+mod prompt;
+mod bench;
+mod querylog;
+#[cfg(test)]
+mod fixture;
+use fulltext::indexers::{self, *};
+use sets::ResultSets;
+use latency::Latencies;
+use prompt::{LineEditor, ReadLine};
+use querylog::QueryLog;
+use bench::{BENCH_BACKENDS, run_bench};
 
-macro_rules! print_flush {
-    ($($arg:tt),*) => {
-        print!($($arg)*);
-        io::stdout().flush().unwrap();
+fn print_match(format: Format, term: &str, doc: &Document) {
+    match format {
+        Format::Xml | Format::Mbox | Format::Rss | Format::Sql | Format::Parquet | Format::Archive | Format::Warc | Format::Jsonl | Format::Records => println!("Found \"{}\" in {} {}", term, doc.title, doc.url),
+        Format::Lines => println!("{}: {}", doc.url, doc.text)
     }
 }
 
-fn try_build_from_cache(matches: &clap::ArgMatches, word_index: &mut dyn DocumentIndexer, index_filename: &str) -> bool {
-    if matches.is_present("no-cache-read") {
+fn column_mapping(matches: &clap::ArgMatches) -> ColumnMapping {
+    let mut columns = ColumnMapping::default();
+    if let Some(mapping) = matches.value_of("columns") {
+        for pair in mapping.split(',') {
+            let mut kv = pair.splitn(2, '=');
+            match (kv.next().map(|k| k.trim()), kv.next()) {
+                (Some("title"), Some(column)) => columns.title = String::from(column.trim()),
+                (Some("url"), Some(column)) => columns.url = String::from(column.trim()),
+                (Some("text"), Some(column)) => columns.text = String::from(column.trim()),
+                (Some("vector"), Some(column)) => columns.vector = String::from(column.trim()),
+                _ => panic!("bad --columns entry '{}', expected title=COL, url=COL, text=COL or vector=COL", pair)
+            }
+        }
+    }
+    columns
+}
+
+fn read_source(matches: &clap::ArgMatches, format: Format, index_filename: &str) -> Result<String, io::Error> {
+    let source = SourceOptions {
+        columns: column_mapping(matches),
+        sql_query: matches.value_of("sql-query").map(String::from),
+        force_extensions: match matches.value_of("force-index-ext") {
+            Some(exts) => exts.split(',').map(|e| String::from(e.trim().trim_start_matches('.'))).collect(),
+            None => Vec::new()
+        },
+        keep_going: matches.is_present("keep-going")
+    };
+    indexers::read_source(index_filename, format, &source)
+}
+
+// --token-filters as IndexOptions::token_filters has them
+fn token_filter_names(matches: &clap::ArgMatches) -> Option<Vec<String>> {
+    matches.value_of("token-filters").map(|names| names.split(',').map(str::trim).filter(|name| !name.is_empty()).map(String::from).collect())
+}
+
+fn read_sources(matches: &clap::ArgMatches, format: Format, paths: &[PathBuf]) -> Result<(SourceFiles, String), io::Error> {
+    SourceFiles::read(paths, format, matches.is_present("keep-going"), |path| read_source(matches, format, path))
+}
+
+fn parse_format(name: &str) -> Option<Format> {
+    match name {
+        "xml" => Some(Format::Xml),
+        "lines" => Some(Format::Lines),
+        "mbox" => Some(Format::Mbox),
+        "rss" => Some(Format::Rss),
+        "sql" => Some(Format::Sql),
+        "parquet" => Some(Format::Parquet),
+        "archive" => Some(Format::Archive),
+        "warc" => Some(Format::Warc),
+        "jsonl" => Some(Format::Jsonl),
+        _ => None
+    }
+}
+
+// "1%" or "0.5%", the share of documents `estimate` indexes, as a fraction in (0, 1]
+fn parse_sample(value: &str) -> Option<f64> {
+    let percent = value.trim().trim_end_matches('%').parse::<f64>().ok()?;
+    if percent > 0.0 && percent <= 100.0 { Some(percent / 100.0) } else { None }
+}
+
+// Feeds, queries and columnar files are turned into documents fresh every run, the cache can only
+// point back into a source file that it can mmap as is. It also doesn't record how its terms were
+// analyzed, so only text mode indexes with units left whole, the default token filters and no title
+// terms go in it.
+fn uses_cache(format: Format, options: &IndexOptions, index_filename: &str) -> bool {
+    options.mode == Mode::Text && !options.split_units && options.token_filters.is_none() && !options.search_titles && match format {
+        Format::Rss | Format::Sql | Format::Parquet | Format::Archive | Format::Jsonl | Format::Records => false,
+        Format::Warc => !index_filename.ends_with(".gz"),
+        _ => true
+    }
+}
+
+// Indexes handed between the background build and the REPL
+type SharedIndex = Box<dyn DocumentIndexer + Send>;
+// A snapshot searched on one thread while its index goes on changing on another
+type PublishedIndex = Arc<dyn DocumentIndexer + Send + Sync>;
+
+fn new_indexer(backend: &str, options: IndexOptions, num_parse_threads: usize, num_index_threads: usize) -> SharedIndex {
+    match backend {
+        "rayon" => Box::new(RayonIndexer::new(options)),
+        "threadpool" => Box::new(ThreadPoolIndexer::new_hashmap(options, num_parse_threads, num_index_threads)),
+        "threadpool_dashmap" => Box::new(ThreadPoolIndexer::new_dashmap(options, num_parse_threads, num_index_threads)),
+        "mmap" => Box::new(MmapIndexer::new(options)),
+        "roaring" => Box::new(RoaringIndexer::new(options)),
+        _ => panic!("unknown backend")
+    }
+}
+
+struct OutputOptions {
+    format: Format,
+    // One path:line:column:text line per occurrence instead of one line per document
+    grep: bool,
+    // Group matches into up to this many clusters
+    cluster: Option<usize>,
+    // Keep only the best hit per normalized title
+    dedupe_titles: bool,
+    // Embedding command to re-rank the first rerank_top matches with
+    rerank_command: Option<String>,
+    rerank_top: usize,
+    // Nearest neighbors each knn: term contributes
+    knn_k: usize,
+    rewrites: Option<Rewrites>,
+    // Print each rewrite rule that fired
+    show_rewrites: bool,
+    // Look every query term up in titles, see indexers::titles_only
+    titles_only: bool,
+    // Where --output json writes results, see print_json
+    json: Option<RefCell<Box<dyn Write>>>,
+    // Print each match's snippets with their terms marked this way
+    snippets: Option<Highlight>,
+    // How long they are and how many each match gets, --snippet-len and --max-snippets-per-doc
+    snippet_options: SnippetOptions,
+    // Which of each search's ranked matches to print, --limit and --offset, changed by :limit
+    page: Cell<Page>
+}
+
+// One JSON array per search, for scripts. Each hit has the terms it matched, also joined as "term",
+// and its score: the number of terms matched, or with --rerank-command or knn: terms the re-ranked or
+// fused one. With --snippets each hit also has one, and all of them as "snippets" with
+// --max-snippets-per-doc above 1, and with --term-frequencies "frequencies", each term's count in the
+// document, see term_frequencies.
+fn print_json<'a>(word_index: &dyn DocumentIndexer, output: &OutputOptions, json: &RefCell<Box<dyn Write>>,
+                  hits: impl Iterator<Item = (&'a [String], &'a Document, f32)>) {
+    let hits: Vec<serde_json::Value> = hits
+        .map(|(terms, doc, score)| {
+            let term = terms.join(" ");
+            let mut hit = serde_json::json!({"term": term, "terms": terms, "id": doc.id, "title": doc.title, "url": doc.url, "score": score});
+            if let Some(highlight) = output.snippets {
+                let snippets = match_snippets(word_index, &term, doc, highlight, output.snippet_options);
+                hit["snippet"] = serde_json::Value::from(snippets[0].clone());
+                if output.snippet_options.max_per_doc > 1 {
+                    hit["snippets"] = serde_json::Value::from(snippets);
+                }
+            }
+            if let Some(frequencies) = term_frequencies(word_index, terms, doc.id) {
+                hit["frequencies"] = serde_json::Value::from(frequencies);
+            }
+            hit
+        })
+        .collect();
+    let mut json = json.borrow_mut();
+    if let Err(e) = writeln!(json, "{}", serde_json::Value::Array(hits)).and_then(|_| json.flush()) {
+        println!("Failed to write results: {}", e);
+    }
+}
+
+// Best matches :explain lists the term frequencies of
+const EXPLAIN_HITS: usize = 5;
+
+// The count of each of a hit's terms in its document, null for labels that aren't one indexed term
+// like phrases and prefixes, None unless the index records frequencies
+fn term_frequencies(word_index: &dyn DocumentIndexer, terms: &[String], id: i32) -> Option<Vec<Option<u32>>> {
+    match word_index.schema().frequencies {
+        true => Some(terms.iter().map(|term| word_index.term_frequency(term, id)).collect()),
+        false => None
+    }
+}
+
+fn print_scored_match(word_index: &dyn DocumentIndexer, output: &OutputOptions, term: &str, doc: &Document, score: Option<f32>) {
+    if interrupted() {
+        return;
+    }
+    if let Some(score) = score {
+        print!("{:.3} ", score);
+    }
+    print_match(output.format, term, doc);
+    if let Some(highlight) = output.snippets {
+        for snippet in match_snippets(word_index, term, doc, highlight, output.snippet_options) {
+            println!("    {}", snippet);
+        }
+    }
+}
+
+// The format grep -n --column and editors' quickfix lists use, one line per occurrence of the term.
+// Lines and columns are 1-based, columns count bytes. With --format lines the url already ends in the
+// line number. Keyword terms have no place in the text and point at its first line.
+fn print_grep_match(word_index: &dyn DocumentIndexer, format: Format, term: &str, doc: &Document) {
+    if interrupted() {
+        return;
+    }
+    let mut offsets = word_index.term_offsets(term, doc);
+    if offsets.is_empty() {
+        offsets.push((0, 0));
+    }
+    for (start, _) in offsets {
+        let start = start as usize;
+        let line_start = doc.text[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = doc.text[start..].find('\n').map(|i| start + i).unwrap_or(doc.text.len());
+        let text = doc.text[line_start..line_end].trim_end_matches('\r');
+        let column = start - line_start + 1;
+        match format {
+            Format::Lines => println!("{}:{}:{}", doc.url, column, text),
+            _ => println!("{}:{}:{}:{}", doc.url, doc.text[..start].matches('\n').count() + 1, column, text)
+        }
+    }
+}
+
+// Case and runs of whitespace don't make titles different
+fn normalize_title(title: &str) -> String {
+    title.split_whitespace().collect::<Vec<&str>>().join(" ").to_lowercase()
+}
+
+// Keeps the first of each run of documents with the same normalized title, callers order the best
+// hits first
+fn dedupe_titles<T, F>(hits: Vec<T>, doc: F) -> Vec<T> where F: Fn(&T) -> &Document {
+    let mut seen: std::collections::HashSet<String> = std::collections::HashSet::new();
+    hits.into_iter().filter(|hit| seen.insert(normalize_title(&doc(hit).title))).collect()
+}
+
+// Hits are printed best first, each with the terms it matched. Clustering, re-ranking and title
+// deduplication reorder or drop them first.
+fn print_results(word_index: &dyn DocumentIndexer, query: &str, results: SearchResults, output: &OutputOptions) {
+    let format = output.format;
+    if output.grep {
+        for hit in results.hits {
+            print_grep_match(word_index, format, &hit.label(), &hit.doc);
+        }
+        return;
+    }
+    if output.cluster.is_none() && output.rerank_command.is_none() && !output.dedupe_titles {
+        if let Some(json) = &output.json {
+            print_json(word_index, output, json, results.hits.iter().map(|hit| (hit.terms.as_slice(), &hit.doc, hit.score)));
+            return;
+        }
+        for hit in results.hits {
+            print_scored_match(word_index, output, &hit.label(), &hit.doc, None);
+        }
+        return;
+    }
+    // Each hit with its re-ranked score, if it was
+    let mut matches: Vec<(Hit, Option<f32>)> = results.hits.into_iter().map(|hit| (hit, None)).collect();
+    if let Some(command) = &output.rerank_command {
+        let docs: Vec<Document> = matches.iter().map(|(hit, _)| hit.doc.clone()).collect();
+        match CommandEmbedder::new(command).and_then(|mut embedder| rerank(&mut embedder, query, &docs, output.rerank_top)) {
+            Ok(ranked) => {
+                let rest = matches.split_off(ranked.len());
+                let mut top: Vec<Option<(Hit, Option<f32>)>> = matches.into_iter().map(Some).collect();
+                matches = ranked.into_iter()
+                    .map(|(score, i)| (top[i].take().unwrap().0, Some(score)))
+                    .chain(rest)
+                    .collect();
+            },
+            Err(e) => println!("Re-ranking failed, keeping match order: {}", e)
+        }
+    }
+    if output.dedupe_titles {
+        matches = dedupe_titles(matches, |(hit, _)| &hit.doc);
+    }
+    if let Some(json) = &output.json {
+        print_json(word_index, output, json, matches.iter().map(|(hit, score)| (hit.terms.as_slice(), &hit.doc, score.unwrap_or(hit.score))));
+        return;
+    }
+    let k = match output.cluster {
+        Some(k) => k,
+        None => {
+            for (hit, score) in &matches {
+                print_scored_match(word_index, output, &hit.label(), &hit.doc, *score);
+            }
+            return;
+        }
+    };
+    let docs: Vec<Document> = matches.iter().map(|(hit, _)| hit.doc.clone()).collect();
+    for cluster in cluster_documents(word_index, &docs, k) {
+        println!("== {} ({} documents)", cluster.label.join(", "), cluster.members.len());
+        for member in cluster.members {
+            let (hit, score) = &matches[member];
+            print_scored_match(word_index, output, &hit.label(), &hit.doc, *score);
+        }
+    }
+    if matches.len() > CLUSTER_TOP_N {
+        println!("== not clustered ({} documents)", matches.len() - CLUSTER_TOP_N);
+        for (hit, score) in &matches[CLUSTER_TOP_N..] {
+            print_scored_match(word_index, output, &hit.label(), &hit.doc, *score);
+        }
+    }
+}
+
+// Fuses the vector search for each knn: term with the text matches of the other terms, printing one
+// line per document with its fused score. Returns the ids printed.
+fn print_hybrid_results(word_index: &dyn DocumentIndexer, vectors: Option<&VectorIndex>, terms: Vec<&str>, output: &OutputOptions) -> Vec<i32> {
+    let vectors = match vectors {
+        Some(vectors) if !vectors.is_empty() => vectors,
+        _ => {
+            println!("knn: terms need a jsonl source with vectors");
+            return Vec::new();
+        }
+    };
+    let mut rankings: Vec<Vec<i32>> = Vec::new();
+    let mut text_terms: Vec<&str> = Vec::new();
+    for term in terms {
+        match parse_knn_term(term) {
+            Some(Ok(query)) if query.len() == vectors.dimensions() => {
+                rankings.push(vectors.search(&query, output.knn_k).into_iter().map(|(_, id)| id).collect());
+            },
+            Some(Ok(query)) => println!("knn vector has {} dimensions, the index has {}", query.len(), vectors.dimensions()),
+            Some(Err(e)) => println!("{}", e),
+            None => text_terms.push(term)
+        }
+    }
+    let mut matched_terms: std::collections::HashMap<i32, Vec<String>> = std::collections::HashMap::new();
+    if !text_terms.is_empty() {
+        let mut text_ranking: Vec<i32> = Vec::new();
+        let results = match word_index.search(text_terms) {
+            Ok(results) => results,
+            Err(e) => {
+                println!("Search refused: {}", e);
+                return Vec::new();
+            }
+        };
+        for hit in results.hits {
+            matched_terms.insert(hit.doc.id, hit.terms);
+            text_ranking.push(hit.doc.id);
+        }
+        rankings.push(text_ranking);
+    }
+    let fused = fuse_rankings(&rankings);
+    let page = output.page.get();
+    let mut hits: Vec<(f32, Document)> = fused.into_iter()
+        .skip(page.offset)
+        .take(page.limit.unwrap_or(usize::MAX))
+        .filter_map(|(score, id)| word_index.document(id).map(|doc| (score, doc)))
+        .collect();
+    if output.dedupe_titles {
+        hits = dedupe_titles(hits, |(_, doc)| doc);
+    }
+    let knn = vec![String::from("knn")];
+    let terms = |doc: &Document| matched_terms.get(&doc.id).unwrap_or(&knn).as_slice();
+    match &output.json {
+        Some(json) => print_json(word_index, output, json, hits.iter().map(|(score, doc)| (terms(doc), doc, *score))),
+        None => {
+            println!("Search found {} results", hits.len());
+            for (score, doc) in hits.iter() {
+                print_scored_match(word_index, output, &terms(doc).join(" "), doc, Some(*score));
+            }
+        }
+    }
+    hits.into_iter().map(|(_, doc)| doc.id).collect()
+}
+
+// The query terms after the --rewrites rules, then looked up in titles with --titles-only
+fn rewrite_query(output: &OutputOptions, show_rewrites: bool, terms: Vec<&str>) -> Vec<String> {
+    let terms = match &output.rewrites {
+        Some(rewrites) => {
+            let (terms, applied) = rewrites.apply(&terms);
+            if show_rewrites {
+                for rewrite in applied {
+                    println!("Rewrote '{}' => '{}'", rewrite.from, rewrite.to);
+                }
+            }
+            terms
+        },
+        None => terms.into_iter().map(String::from).collect()
+    };
+    match output.titles_only {
+        true => titles_only(&terms.iter().map(String::as_str).collect::<Vec<&str>>()),
+        false => terms
+    }
+}
+
+// Writes each of the documents once, in order, as a JSON object with its stored fields. The field
+// names are the --columns defaults, so the file indexes as is with --format jsonl.
+fn export_hits(word_index: &dyn DocumentIndexer, ids: &[i32], path: &str) -> Result<usize, io::Error> {
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+    let mut seen: std::collections::HashSet<i32> = std::collections::HashSet::new();
+    for id in ids.iter().filter(|id| seen.insert(**id)) {
+        if let Some(doc) = word_index.document(*id) {
+            writeln!(file, "{}", serde_json::json!({"id": doc.id, "title": doc.title, "url": doc.url, "text": doc.text}))?;
+        }
+    }
+    file.flush()?;
+    Ok(seen.len())
+}
+
+// Which matches are printed, when not all of them
+fn report_page(results: &SearchResults, page: Page) {
+    if !page.is_all() {
+        println!("Showing {} from match {}", results.len(), page.offset + 1);
+    }
+}
+
+const HELP: &str = "\
+Type a query to search, or a command:
+  :limit N [OFFSET]  print only N matches of each search, from match OFFSET + 1
+  :limit off         print every match again
+  :stats             index counts and this session's search latencies
+  :explain QUERY     how QUERY is searched, and its best matches' term counts
+  :save NAME         keep the last result as NAME
+  :and A B           documents in both saved sets A and B
+  :or A B            documents in either
+  :diff A B          documents in A but not B
+  :sets              the saved sets
+  :help              this list
+On a terminal, Up/Down browse earlier queries, Ctrl-C stops a search or drops the line being typed,
+and Ctrl-D on an empty line quits.";
+
+// `:help` prints the commands and keys. False, doing nothing, for other input.
+fn run_help_command(input: &str) -> bool {
+    if input.trim() != ":help" {
         return false;
     }
+    println!("{}", HELP);
+    true
+}
+
+// `:limit N [OFFSET]` prints only that page of each later search's matches, `:limit off` all of them
+// again. False, doing nothing, for other input.
+fn run_limit_command(output: &OutputOptions, input: &str) -> bool {
+    let words: Vec<&str> = input.split_whitespace().collect();
+    let page = match words.as_slice() {
+        [":limit", "off"] => Page::default(),
+        [":limit", limit] | [":limit", limit, _] => match (limit.parse::<usize>(), words.get(2).map_or(Ok(0), |offset| offset.parse::<usize>())) {
+            (Ok(limit), Ok(offset)) => Page { offset, limit: Some(limit) },
+            _ => {
+                println!("usage: :limit N [OFFSET] or :limit off");
+                return true;
+            }
+        },
+        [":limit", ..] => {
+            println!("usage: :limit N [OFFSET] or :limit off");
+            return true;
+        },
+        _ => return false
+    };
+    output.page.set(page);
+    match page.limit {
+        Some(limit) => println!("Showing {} matches per search from match {}", limit, page.offset + 1),
+        None => println!("Showing every match")
+    }
+    true
+}
+
+// `:stats` prints the index's counts and the session's search latencies so far, `:explain QUERY` how
+// QUERY is searched and how many matches it has. False, doing nothing, for other input.
+fn run_index_command(word_index: &dyn DocumentIndexer, input: &str, output: &OutputOptions, latencies: &Latencies) -> bool {
+    let input = input.trim();
+    if input == ":stats" {
+        println!("Documents: {}, terms: {}", word_index.num_documents(), word_index.num_tokens());
+        if word_index.num_positioned() > 0 {
+            println!("Term/document pairs with positions: {}", word_index.num_positioned());
+        }
+        if word_index.num_oversized() > 0 {
+            println!("Documents over max doc bytes: {}", word_index.num_oversized());
+        }
+        latencies.print_latencies();
+        return true;
+    }
+    let query = match input.strip_prefix(":explain") {
+        Some(query) if query.is_empty() || query.starts_with(char::is_whitespace) => query,
+        _ => return false
+    };
+    let terms = rewrite_query(output, true, split_query(query));
+    let terms: Vec<&str> = terms.iter().map(String::as_str).collect();
+    for line in explain_query(word_index, &terms) {
+        println!("{}", line);
+    }
+    let before = time::Instant::now();
+    let results = match word_index.search_page(terms, Page { offset: 0, limit: Some(EXPLAIN_HITS) }) {
+        Ok(results) => results,
+        Err(e) => {
+            println!("Search refused: {}", e);
+            return true;
+        }
+    };
+    println!("Matches: {}, searched in {} us", results.total, before.elapsed().as_micros());
+    for hit in &results.hits {
+        if let Some(frequencies) = term_frequencies(word_index, &hit.terms, hit.doc.id) {
+            let counts: Vec<String> = hit.terms.iter().zip(frequencies)
+                .map(|(term, frequency)| format!("{} x{}", term, frequency.map(|f| f.to_string()).unwrap_or_else(|| String::from("?"))))
+                .collect();
+            println!("  {} {}: {}", hit.doc.id, hit.doc.title, counts.join(", "));
+        }
+    }
+    true
+}
+
+// Matches left out because their documents are missing, see SearchResults::missing
+fn report_missing(results: &SearchResults) {
+    if results.missing > 0 {
+        println!("Skipped {} matches whose documents are missing from the index", results.missing);
+    }
+}
+
+// Returns the ids of the documents found, for the REPL's result sets
+fn search_and_print(word_index: &dyn DocumentIndexer, vectors: Option<&VectorIndex>, input: &str, output: &OutputOptions, latencies: &mut Latencies) -> Vec<i32> {
+    let terms = rewrite_query(output, output.show_rewrites, split_query(input));
+    let terms: Vec<&str> = terms.iter().map(String::as_str).collect();
+    if terms.iter().any(|t| parse_knn_term(t).is_some()) {
+        return print_hybrid_results(word_index, vectors, terms, output);
+    }
+    let before = time::Instant::now();
+    let results = word_index.search_page(terms, output.page.get());
+    let duration = time::Instant::now() - before;
+    latencies.record(input, duration);
+    let results = match results {
+        Ok(results) => results,
+        // report_interrupted says so
+        Err(QueryError::Cancelled) => return Vec::new(),
+        Err(e) => {
+            println!("Search refused: {}", e);
+            return Vec::new();
+        }
+    };
+    println!("Search found {} results, completed in {} us", results.total, duration.as_micros());
+    report_page(&results, output.page.get());
+    report_missing(&results);
+    let ids: Vec<i32> = results.ids().collect();
+    print_results(word_index, input.trim(), results, output);
+    ids
+}
+
+// Searches every non-blank line of `queries` at once, then prints the results of each in order.
+// knn: terms need the vector index and a query of their own, so queries with them are skipped.
+fn run_batch(word_index: &dyn DocumentIndexer, queries: &str, output: &OutputOptions) {
+    let queries: Vec<&str> = queries.lines().map(str::trim).filter(|query| !query.is_empty()).collect();
+    let rewritten: Vec<Vec<String>> = queries.iter()
+        .map(|query| rewrite_query(output, output.show_rewrites, split_query(query)))
+        .collect();
+    let mut batch: Vec<(&str, Vec<&str>)> = Vec::with_capacity(queries.len());
+    for (query, terms) in queries.iter().zip(&rewritten) {
+        let terms: Vec<&str> = terms.iter().map(String::as_str).collect();
+        match terms.iter().any(|t| parse_knn_term(t).is_some()) {
+            true => println!("Skipping '{}': knn: queries can't be batched", query),
+            false => batch.push((query, terms))
+        }
+    }
+    let all_terms: Vec<Vec<&str>> = batch.iter().map(|(_, terms)| terms.clone()).collect();
+    let before = time::Instant::now();
+    let results = word_index.search_batch(&all_terms);
+    let duration = time::Instant::now() - before;
+    println!("Batch of {} queries completed in {} us", batch.len(), duration.as_micros());
+    for ((query, _), results) in batch.into_iter().zip(results) {
+        match results {
+            Ok(results) => {
+                let results = results.into_page(output.page.get());
+                report_missing(&results);
+                print_results(word_index, query, results, output);
+            },
+            Err(e) => println!("Search '{}' refused: {}", query, e)
+        }
+    }
+}
+
+// One line per co-occurring term: shared documents, the share of the term's documents that is, and
+// the term
+fn print_cooccurrences(word_index: &dyn DocumentIndexer, input: &str, limit: usize) {
+    for term in word_index.analyze_text(input) {
+        let frequency = word_index.document_frequency(&term);
+        println!("\"{}\" is in {} documents", term, frequency);
+        for (other, shared) in word_index.cooccurring_terms(&term, limit) {
+            println!("{}\t{:.3}\t{}", shared, shared as f32 / frequency as f32, other);
+        }
+    }
+}
+
+// Fractions of the source (1/n) indexed and published before the full build
+const PARTIAL_STAGES: [usize; 2] = [16, 4];
+
+// Builds the index on a background thread while the REPL searches what's been indexed so far.
+// Backends that can share a snapshot of themselves index the start of the source, then add the
+// documents up to each later stage and the rest to that same index, publishing a snapshot after each
+// stage; the others are searchable once done. Returns the complete index with the first query after
+// it's done, or None if stdin closes first.
+#[allow(clippy::too_many_arguments)]
+fn search_while_indexing<F>(new_index: F, options: IndexOptions, sources: SourceFiles, contents: String, vectors: Option<&VectorIndex>, output: &OutputOptions,
+                            editor: &mut LineEditor, sets: &mut ResultSets, latencies: &mut Latencies) -> Option<SharedIndex>
+    where F: Fn() -> SharedIndex + Send + 'static {
+    // A snapshot of the latest stage, and the index once complete
+    let latest: Arc<Mutex<(Option<PublishedIndex>, Option<SharedIndex>)>> = Arc::new(Mutex::new((None, None)));
+    let builder_latest = Arc::clone(&latest);
+    catch_interrupts(editor);
+    thread::spawn(move || {
+        let mut word_index = new_index();
+        let built = match word_index.snapshot() {
+            Some(_) => build_in_stages(word_index.as_mut(), options, sources, contents, |snapshot| builder_latest.lock().unwrap().0 = Some(snapshot)),
+            None => word_index.build_from_files(sources, contents)
+        };
+        if let Err(e) = built {
+            println!("Build failed: {}, searching what was indexed so far", e);
+            return;
+        }
+        // Only once built, so Ctrl-C during a search doesn't cancel the build
+        cancel_searches(word_index.as_mut());
+        println!("Full index ready: {} documents", word_index.num_documents());
+        *builder_latest.lock().unwrap() = (None, Some(word_index));
+    });
+
+    loop {
+        match editor.read_line("Search: ") {
+            Ok(ReadLine::Eof) => return None,
+            Ok(ReadLine::Interrupted) => {},
+            Ok(ReadLine::Line(input)) => {
+                // Taken out of the lock, so the builder can publish while this searches
+                let (partial, complete) = {
+                    let mut latest = latest.lock().unwrap();
+                    (latest.0.clone(), latest.1.take())
+                };
+                let word_index = match (&complete, &partial) {
+                    (Some(word_index), _) => Some(word_index.as_ref() as &dyn DocumentIndexer),
+                    (None, Some(word_index)) => Some(word_index.as_ref() as &dyn DocumentIndexer),
+                    (None, None) => None
+                };
+                match word_index {
+                    _ if run_help_command(&input) || run_limit_command(output, &input) => {},
+                    None => println!("No documents indexed yet, try again shortly"),
+                    Some(word_index) if run_index_command(word_index, &input, output, latencies) => {},
+                    Some(word_index) if ResultSets::is_command(&input) => {
+                        sets.run(word_index, &input, output.format);
+                        if complete.is_none() {
+                            println!("(partial results, {} documents indexed so far)", word_index.num_documents());
+                        }
+                    },
+                    Some(word_index) => {
+                        sets.set_last(search_and_print(word_index, vectors, &input, output, latencies));
+                        if complete.is_none() {
+                            println!("(partial results, {} documents indexed so far)", word_index.num_documents());
+                        }
+                    }
+                }
+                report_interrupted();
+                if complete.is_some() {
+                    return complete;
+                }
+            }
+            Err(error) => println!("error: {}", error),
+        }
+    }
+}
+
+// Indexes the first of PARTIAL_STAGES' prefixes of `contents`, then adds the documents up to each
+// later one and the rest, handing a snapshot of `word_index` to `publish` after each stage
+fn build_in_stages(word_index: &mut dyn DocumentIndexer, options: IndexOptions, sources: SourceFiles, contents: String,
+                   mut publish: impl FnMut(PublishedIndex)) -> Result<(), io::Error> {
+    let format = options.format;
+    let parser = RayonIndexer::new(options);
+    let mut end = 0;
+    for stage in PARTIAL_STAGES.iter() {
+        let stage_end = document_prefix(&contents, format, contents.len() / stage).len();
+        match end {
+            0 => word_index.build_from_files(sources.clone(), contents[..stage_end].to_string())?,
+            _ => word_index.add_documents(parser.parse_documents(&contents, end..stage_end, word_index.num_documents() as i32, &sources))
+        }
+        end = stage_end;
+        if let Some(mut snapshot) = word_index.snapshot() {
+            cancel_searches(snapshot.as_mut());
+            println!("Partial index ready: {} documents", snapshot.num_documents());
+            publish(Arc::from(snapshot));
+        }
+    }
+    word_index.add_documents(parser.parse_documents(&contents, end..contents.len(), word_index.num_documents() as i32, &sources));
+    Ok(())
+}
+
+// The token Ctrl-C cancels while build_interruptible runs, and in a REPL on a terminal while a
+// search runs or prints
+static INTERRUPT: OnceLock<CancelToken> = OnceLock::new();
+
+// Whether Ctrl-C cut the current search short, the matches not printed yet are then skipped
+fn interrupted() -> bool {
+    INTERRUPT.get().is_some_and(|token| token.is_cancelled())
+}
+
+// Says so after a search Ctrl-C cut short, and readies the token for the next one
+fn report_interrupted() {
+    if let Some(token) = INTERRUPT.get().filter(|token| token.is_cancelled()) {
+        println!("Cancelled");
+        token.reset();
+    }
+}
+
+// From here on Ctrl-C cancels the search rather than exiting, on a terminal where the line editor
+// takes Ctrl-C at the prompt as a key and Ctrl-D quits. Piped input keeps the usual Ctrl-C.
+#[cfg(unix)]
+fn catch_interrupts(editor: &LineEditor) {
+    if !editor.is_terminal() {
+        return;
+    }
+    INTERRUPT.get_or_init(CancelToken::new).reset();
+    let handler: extern "C" fn(libc::c_int) = cancel_build;
+    unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+}
+
+#[cfg(not(unix))]
+fn catch_interrupts(_editor: &LineEditor) {}
+
+// Has `word_index`'s searches stop at Ctrl-C once catch_interrupts took it over
+fn cancel_searches(word_index: &mut dyn DocumentIndexer) {
+    if let Some(token) = INTERRUPT.get() {
+        word_index.set_cancel_token(token.clone());
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn cancel_build(_: libc::c_int) {
+    if let Some(token) = INTERRUPT.get() {
+        token.cancel();
+    }
+}
+
+// Builds with Ctrl-C cancelling the build instead of exiting, for backends that can stop early.
+// Returns false if it was cancelled, leaving `word_index` empty.
+#[cfg(unix)]
+fn build_interruptible(word_index: &mut dyn DocumentIndexer, sources: SourceFiles, contents: String) -> Result<bool, io::Error> {
+    let token = INTERRUPT.get_or_init(CancelToken::new);
+    token.reset();
+    if !word_index.set_cancel_token(token.clone()) {
+        return word_index.build_from_files(sources, contents).map(|_| true);
+    }
+    let handler: extern "C" fn(libc::c_int) = cancel_build;
+    let previous = unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+    let built = word_index.build_from_files(sources, contents);
+    unsafe { libc::signal(libc::SIGINT, previous) };
+    built.map(|_| !token.is_cancelled())
+}
+
+#[cfg(not(unix))]
+fn build_interruptible(word_index: &mut dyn DocumentIndexer, sources: SourceFiles, contents: String) -> Result<bool, io::Error> {
+    word_index.build_from_files(sources, contents).map(|_| true)
+}
+
+// The graph saved next to a single source when it's still current, otherwise built and saved there
+fn build_vectors(matches: &clap::ArgMatches, sources: &SourceFiles, contents: &str) -> VectorIndex {
+    let before = time::Instant::now();
+    let source = sources.single_path().map(Path::new);
+    let cached = match source.filter(|_| reads_cache(matches)).map(VectorIndex::read_cached) {
+        Some(Ok(cached)) => cached,
+        Some(Err(e)) => {
+            println!("Not using the saved vector index, which failed to read: {}", e);
+            None
+        },
+        None => None
+    };
+    let vectors = match cached {
+        Some(vectors) => vectors,
+        None => {
+            let vectors = VectorIndex::from_records(contents);
+            if let Some(source) = source.filter(|_| !vectors.is_empty() && writes_cache(matches)) {
+                if let Err(e) = vectors.write_cached(source, cache_sync_policy(matches)) {
+                    println!("Failed to save the vector index: {}", e);
+                }
+            }
+            vectors
+        }
+    };
+    if !vectors.is_empty() {
+        println!("Vector index: {} vectors of {} dimensions, elapsed {} ms", vectors.len(), vectors.dimensions(), before.elapsed().as_millis());
+    }
+    vectors
+}
+
+fn cache_sync_policy(matches: &clap::ArgMatches) -> SyncPolicy {
+    SyncPolicy::parse(matches.value_of("cache-fsync").unwrap()).unwrap()
+}
+
+// Whether an existing cache is loaded rather than built again, when uses_cache allows one at all
+fn reads_cache(matches: &clap::ArgMatches) -> bool {
+    !matches.is_present("no-cache-read") && !matches.is_present("force-rebuild")
+}
+
+// Whether a build is written to the cache afterwards, when uses_cache allows one at all
+fn writes_cache(matches: &clap::ArgMatches) -> bool {
+    !matches.is_present("no-cache-write") && !matches.is_present("read-only")
+}
+
+// Offsets into a source that changed since would point at the wrong bytes, so a stale cache is refused
+fn show_document(index_filename: &str, id: i32, raw: bool) -> Result<(), io::Error> {
+    if let Some(reason) = SerializedIndex::stale_reason(index_filename) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("the cache is stale: {}", reason)));
+    }
+    let offsets = match RawOffsets::read(index_filename) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(io::Error::new(e.kind(), "the cache has no raw offsets, rebuild it with --raw-offsets --force-rebuild")),
+        result => result?
+    };
+    if !raw {
+        let (path, range) = offsets.get(id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such document in the build"))?;
+        println!("{}\t{}\t{}", path, range.start, range.end);
+        return Ok(());
+    }
+    let fragment = offsets.fragment(id)?;
+    let mut stdout = io::stdout();
+    stdout.write_all(&fragment)?;
+    stdout.write_all(b"\n")
+}
+
+fn try_build_from_cache(word_index: &mut dyn DocumentIndexer, index_filename: &str, schema: Schema) -> Result<(), CacheError> {
+    SerializedIndex::check_current(index_filename)?;
+    // Documents have the query language's stems too, see Analyzer::with_query_stems
+    if let Some(build) = SerializedIndex::metadata(index_filename).ok().and_then(|metadata| metadata.build) {
+        let built = build.query_language.as_deref().unwrap_or("en");
+        if built != word_index.language() {
+            return Err(CacheError::Stale(format!("the cache was built for queries in '{}', not '{}'", built, word_index.language())));
+        }
+    }
+    if word_index.open_cache(index_filename)? {
+        return Ok(());
+    }
 
     let before = time::Instant::now();
     println!("Reading index files...");
     let load_result = SerializedIndex::load_from_path(index_filename);
     let duration = time::Instant::now() - before;
     println!("Reading complete. {} elapsed ms", duration.as_millis());
-    match load_result {
-        Ok(s) => {
-            word_index.build_from_serialized(s);
-            true
-        }
-        Err(_) => false
+    let serialized = load_result?;
+    let cached = serialized.schema()?;
+    if cached != schema {
+        return Err(CacheError::SchemaMismatch { cached, requested: schema });
     }
+    word_index.build_from_serialized(serialized)?;
+    Ok(())
+}
+
+// Put in front of a "-term" argument so that clap, which only lets a positional start with '-'
+// when it follows another value of it, takes it as a TERM rather than an unknown flag. There are
+// no short flags to mistake it for, and no argument can contain the mark itself.
+const HYPHEN_TERM_MARK: char = '\u{0}';
+
+fn mark_hyphen_terms(args: impl Iterator<Item = std::ffi::OsString>) -> Vec<std::ffi::OsString> {
+    let mut trailing = false;
+    args.enumerate().map(|(i, arg)| {
+        trailing |= arg == "--";
+        match arg.to_str() {
+            Some(term) if i > 0 && !trailing && term.len() > 1 && term.starts_with('-') && !term.starts_with("--") => {
+                std::ffi::OsString::from(format!("{}{}", HYPHEN_TERM_MARK, term))
+            },
+            _ => arg
+        }
+    }).collect()
+}
+
+// The TERM values with mark_hyphen_terms undone
+fn terms<'a>(matches: &'a clap::ArgMatches) -> Option<Vec<&'a str>> {
+    matches.values_of("TERM").map(|terms| terms.map(|term| term.trim_start_matches(HYPHEN_TERM_MARK)).collect())
 }
 
 fn main() {
     let matches = clap::App::new("fulltext")
                     .about("Dumb fulltext searcher")
+                    .setting(clap::AppSettings::SubcommandsNegateReqs)
+                    // Otherwise a search for the term "dump" would run the subcommand
+                    .setting(clap::AppSettings::ArgsNegateSubcommands)
+                    .subcommand(clap::SubCommand::with_name("dump")
+                        .about("print the structure of the cache files for an index")
+                        .arg(clap::Arg::with_name("index")
+                            .long("index")
+                            .value_name("FILE")
+                            .number_of_values(1)
+                            .takes_value(true)
+                            .required(true))
+                        .arg(clap::Arg::with_name("raw")
+                            .long("raw")
+                            .help("one line per term and document with its byte offset")))
+                    .subcommand(clap::SubCommand::with_name("compare")
+                        .about("compare the cache files of two indexes: unique terms, document counts and document frequency shifts")
+                        .arg(clap::Arg::with_name("A")
+                            .required(true)
+                            .help("first index, its .idx or the file it indexes"))
+                        .arg(clap::Arg::with_name("B")
+                            .required(true)
+                            .help("second index"))
+                        .arg(clap::Arg::with_name("top")
+                            .long("top")
+                            .value_name("N")
+                            .number_of_values(1)
+                            .default_value("20")
+                            .takes_value(true)
+                            .help("terms to list per section")))
+                    .subcommand(clap::SubCommand::with_name("estimate")
+                        .about("index a random sample of the documents and extrapolate the full index's size, term count and build time")
+                        .arg(clap::Arg::with_name("index")
+                            .long("index")
+                            .value_name("FILE")
+                            .number_of_values(1)
+                            .takes_value(true)
+                            .required(true))
+                        .arg(clap::Arg::with_name("format")
+                            .long("format")
+                            .value_name("FORMAT")
+                            .number_of_values(1)
+                            .default_value("xml")
+                            .possible_values(&["xml", "lines", "mbox", "warc", "jsonl", "archive"])
+                            .takes_value(true))
+                        .arg(clap::Arg::with_name("sample")
+                            .long("sample")
+                            .value_name("PERCENT")
+                            .number_of_values(1)
+                            .default_value("1%")
+                            .takes_value(true)
+                            .help("share of the documents to index, e.g. 1% or 0.1%")))
+                    .subcommand(clap::SubCommand::with_name("analyze")
+                        .about("print the tokens indexing some text would produce: each word, the filters applied, the final term, \
+                                position and byte offsets")
+                        .arg(clap::Arg::with_name("TEXT")
+                            .required(true))
+                        .arg(clap::Arg::with_name("language")
+                            .long("language")
+                            .value_name("LANG")
+                            .number_of_values(1)
+                            .takes_value(true)
+                            .possible_values(&LANGUAGES)
+                            .help("stem and drop stopwords for this language instead of English"))
+                        .arg(clap::Arg::with_name("no-stem")
+                            .long("no-stem")
+                            .help("leave words unstemmed"))
+                        .arg(clap::Arg::with_name("split-units")
+                            .long("split-units")
+                            .help("split numbers from the units after them as --split-units does when indexing"))
+                        .arg(clap::Arg::with_name("token-filters")
+                            .long("token-filters")
+                            .value_name("NAMES")
+                            .number_of_values(1)
+                            .takes_value(true)
+                            .help("put words through these token filters as --token-filters does when indexing"))
+                        .arg(clap::Arg::with_name("mode")
+                            .long("mode")
+                            .value_name("MODE")
+                            .number_of_values(1)
+                            .possible_values(&["text", "code"])
+                            .conflicts_with("language")
+                            .takes_value(true)
+                            .help("analyze as --mode does when indexing, text by default")))
+                    .subcommand(clap::SubCommand::with_name("info")
+                        .about("print how the cache for an index was built and what it holds, without loading it")
+                        .arg(clap::Arg::with_name("index")
+                            .long("index")
+                            .value_name("FILE")
+                            .number_of_values(1)
+                            .takes_value(true)
+                            .required(true)))
+                    .subcommand(clap::SubCommand::with_name("stats")
+                        .about("print the shape of the corpus an index was built from: document lengths, and how many documents \
+                                its terms are in")
+                        .arg(clap::Arg::with_name("index")
+                            .long("index")
+                            .value_name("FILE")
+                            .number_of_values(1)
+                            .takes_value(true)
+                            .required(true))
+                        .arg(clap::Arg::with_name("histograms")
+                            .long("histograms")
+                            .help("also print the term frequency by rank, and document and postings length histograms"))
+                        .arg(clap::Arg::with_name("output")
+                            .long("output")
+                            .value_name("STYLE")
+                            .number_of_values(1)
+                            .possible_values(&["text", "json"])
+                            .default_value("text")
+                            .takes_value(true)
+                            .help("'json' prints one JSON object, for plotting")))
+                    .subcommand(clap::SubCommand::with_name("bench")
+                        .about("replay a query log against an index loaded from its cache, reporting throughput and latency \
+                                percentiles")
+                        .arg(clap::Arg::with_name("index")
+                            .long("index")
+                            .value_name("FILE")
+                            .number_of_values(1)
+                            .takes_value(true)
+                            .required(true))
+                        .arg(clap::Arg::with_name("replay")
+                            .long("replay")
+                            .value_name("FILE")
+                            .number_of_values(1)
+                            .takes_value(true)
+                            .required(true)
+                            .help("a --query-log file, or one query per line"))
+                        .arg(clap::Arg::with_name("concurrency")
+                            .long("concurrency")
+                            .value_name("N")
+                            .number_of_values(1)
+                            .default_value("1")
+                            .takes_value(true)
+                            .help("searches run at once, each on its own thread"))
+                        .arg(clap::Arg::with_name("backend")
+                            .long("backend")
+                            .value_name("BACKEND")
+                            .number_of_values(1)
+                            .default_value("rayon")
+                            .possible_values(&BENCH_BACKENDS)
+                            .takes_value(true))
+                        .arg(clap::Arg::with_name("limit")
+                            .long("limit")
+                            .value_name("N")
+                            .number_of_values(1)
+                            .default_value("10")
+                            .takes_value(true)
+                            .help("hits each search fetches, as for one page of results")))
+                    .subcommand(clap::SubCommand::with_name("random")
+                        .about("print documents of an index drawn at random, with the start of their text, for checking a corpus \
+                                or picking documents to label")
+                        .arg(clap::Arg::with_name("index")
+                            .long("index")
+                            .value_name("FILE")
+                            .number_of_values(1)
+                            .takes_value(true)
+                            .required(true))
+                        .arg(clap::Arg::with_name("count")
+                            .long("count")
+                            .value_name("N")
+                            .number_of_values(1)
+                            .default_value("10")
+                            .takes_value(true))
+                        .arg(clap::Arg::with_name("seed")
+                            .long("seed")
+                            .value_name("SEED")
+                            .number_of_values(1)
+                            .takes_value(true)
+                            .help("draw the same documents as another run with this seed did, a new draw each run otherwise"))
+                        .arg(clap::Arg::with_name("output")
+                            .long("output")
+                            .value_name("STYLE")
+                            .number_of_values(1)
+                            .possible_values(&["text", "json"])
+                            .default_value("text")
+                            .takes_value(true)
+                            .help("'json' prints one JSON array")))
+                    .subcommand(clap::SubCommand::with_name("show")
+                        .about("print where a document of an index is in its source file, from the cache written with --raw-offsets")
+                        .arg(clap::Arg::with_name("index")
+                            .long("index")
+                            .value_name("FILE")
+                            .number_of_values(1)
+                            .takes_value(true)
+                            .required(true))
+                        .arg(clap::Arg::with_name("raw")
+                            .long("raw")
+                            .help("print the document's bytes as they are in the source instead"))
+                        .arg(clap::Arg::with_name("ID")
+                            .required(true)
+                            .validator(|id| id.parse::<i32>().map(|_| ()).map_err(|_| format!("'{}' isn't a document id", id)))
+                            .help("document id, as --output json prints them")))
+                    .subcommand(clap::SubCommand::with_name("migrate")
+                        .about("upgrade the cache files for an index written by an older version in place")
+                        .arg(clap::Arg::with_name("index")
+                            .long("index")
+                            .value_name("FILE")
+                            .number_of_values(1)
+                            .takes_value(true)
+                            .required(true)))
                     .arg(clap::Arg::with_name("index")
                         .long("index")
                         .value_name("FILE")
                         .number_of_values(1)
+                        .multiple(true)
+                        .takes_value(true)
+                        .required_unless("index-dir")
+                        .help("file to index. Repeat it or name a directory to build one index over several files, \
+                               which isn't cached"))
+                    .arg(clap::Arg::with_name("index-dir")
+                        .long("index-dir")
+                        .value_name("DIR")
+                        .number_of_values(1)
                         .takes_value(true)
-                        .required(true))
+                        .conflicts_with("index")
+                        .help("index every text file under DIR, at any depth, as one document titled with its file name \
+                               and with its path as the url, like the entries of an archive"))
                     .arg(clap::Arg::with_name("index-threads")
                         .long("index-threads")
                         .value_name("NUM_THREADS")
@@ -55,19 +1095,450 @@ fn main() {
                         .value_name("BACKEND")
                         .number_of_values(1)
                         .default_value("rayon")
-                        .possible_values(&["rayon", "threadpool", "threadpool_dashmap"])
+                        .possible_values(&["rayon", "threadpool", "threadpool_dashmap", "mmap", "roaring"])
+                        .help("'mmap' searches its cache file in place instead of loading it, so opening a large index is quick; \
+                               'roaring' keeps postings as compressed bitmaps, smaller for common terms and quicker for AND, OR \
+                               and NOT queries; both build like 'rayon' but without --positions or --offsets")
                         .takes_value(true))
+                    .arg(clap::Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .number_of_values(1)
+                        .default_value("xml")
+                        .possible_values(&["xml", "lines", "mbox", "rss", "sql", "parquet", "archive", "warc", "jsonl"])
+                        .help("input format; 'lines' treats every line of the file as a document, 'rss' fetches --index as a feed URL, \
+                               'sql' runs --sql-query against --index (sqlite:<path> or postgres://...)")
+                        .takes_value(true))
+                    .arg(clap::Arg::with_name("sql-query")
+                        .long("sql-query")
+                        .value_name("QUERY")
+                        .number_of_values(1)
+                        .takes_value(true)
+                        .help("query whose rows become documents (sql only)"))
+                    .arg(clap::Arg::with_name("columns")
+                        .long("columns")
+                        .value_name("MAPPING")
+                        .number_of_values(1)
+                        .takes_value(true)
+                        .help("columns to use as fields, e.g. title=name,url=slug,text=body (sql, parquet and jsonl only); \
+                               jsonl also takes vector=FIELD for the array searched by knn: terms"))
+                    .arg(clap::Arg::with_name("refresh")
+                        .long("refresh")
+                        .value_name("SECONDS")
+                        .number_of_values(1)
+                        .takes_value(true)
+                        .help("re-fetch and rebuild an rss index before searching once this many seconds have passed"))
+                    .arg(clap::Arg::with_name("force-index-ext")
+                        .long("force-index-ext")
+                        .value_name("EXTENSIONS")
+                        .number_of_values(1)
+                        .takes_value(true)
+                        .help("comma separated file extensions to index even when they look binary (archive and --index-dir only)"))
+                    .arg(clap::Arg::with_name("keep-going")
+                        .long("keep-going")
+                        .help("skip archive entries, files under --index-dir or files of several --index that can't be read, \
+                               listing them at the end, instead of failing the build"))
+                    .arg(clap::Arg::with_name("max-doc-bytes")
+                        .long("max-doc-bytes")
+                        .value_name("BYTES")
+                        .number_of_values(1)
+                        .takes_value(true)
+                        .help("limit on the text indexed per document, see --oversize-policy"))
+                    .arg(clap::Arg::with_name("oversize-policy")
+                        .long("oversize-policy")
+                        .value_name("POLICY")
+                        .number_of_values(1)
+                        .default_value("truncate")
+                        .possible_values(&["truncate", "skip", "warn"])
+                        .takes_value(true)
+                        .help("what to do with documents over --max-doc-bytes"))
+                    .arg(clap::Arg::with_name("index-headers")
+                        .long("index-headers")
+                        .value_name("HEADERS")
+                        .number_of_values(1)
+                        .takes_value(true)
+                        .help("comma separated mail headers to index as keyword fields, searchable as header:value (mbox only)"))
+                    .arg(clap::Arg::with_name("output")
+                        .long("output")
+                        .value_name("STYLE")
+                        .number_of_values(1)
+                        .possible_values(&["text", "grep", "json"])
+                        .conflicts_with_all(&["cluster", "stdio"])
+                        .takes_value(true)
+                        .help("'grep' prints every occurrence as url:line:column:text for editors to jump to, 'json' prints \
+                               each search's matches best first as one JSON array of {term, terms, id, title, url, score} \
+                               on stdout and everything else on stderr"))
+                    .arg(clap::Arg::with_name("limit")
+                        .long("limit")
+                        .value_name("N")
+                        .number_of_values(1)
+                        .takes_value(true)
+                        .help("print only the best N matches of each search, fetching no more documents than that \
+                               (':limit N [OFFSET]' in the REPL)"))
+                    .arg(clap::Arg::with_name("offset")
+                        .long("offset")
+                        .value_name("M")
+                        .number_of_values(1)
+                        .takes_value(true)
+                        .help("skip the best M matches of each search, for the pages after --limit's"))
+                    .arg(clap::Arg::with_name("snippets")
+                        .long("snippets")
+                        .help("print the sentences of each match's text from the one with its first match, up to about 200 \
+                               characters, with the matched terms marked (a 'snippet' field with --output json)"))
+                    .arg(clap::Arg::with_name("highlight")
+                        .long("highlight")
+                        .value_name("STYLE")
+                        .number_of_values(1)
+                        .possible_values(&["markdown", "ansi"])
+                        .requires("snippets")
+                        .takes_value(true)
+                        .help("mark matched terms in snippets as **term** (markdown, the default) or in color (ansi)"))
+                    .arg(clap::Arg::with_name("snippet-len")
+                        .long("snippet-len")
+                        .value_name("CHARS")
+                        .number_of_values(1)
+                        .requires("snippets")
+                        .takes_value(true)
+                        .help("show up to about CHARS characters of text per snippet instead of 200"))
+                    .arg(clap::Arg::with_name("max-snippets-per-doc")
+                        .long("max-snippets-per-doc")
+                        .value_name("N")
+                        .number_of_values(1)
+                        .requires("snippets")
+                        .takes_value(true)
+                        .help("show up to N snippets per match, one more for each stretch of its text with matches the \
+                               ones before didn't show, as in long articles (a 'snippets' field with --output json)"))
+                    .arg(clap::Arg::with_name("cooccur")
+                        .long("cooccur")
+                        .value_name("N")
+                        .number_of_values(1)
+                        .takes_value(true)
+                        .requires("TERM")
+                        .help("instead of searching, print the N terms that share the most documents with each TERM"))
+                    .arg(clap::Arg::with_name("export-hits")
+                        .long("export-hits")
+                        .value_name("FILE")
+                        .number_of_values(1)
+                        .takes_value(true)
+                        .requires("TERM")
+                        .conflicts_with("cooccur")
+                        .help("also write the matching documents to FILE as JSON lines with all stored fields, \
+                               which --format jsonl can index again"))
+                    .arg(clap::Arg::with_name("dedupe-titles")
+                        .long("dedupe-titles")
+                        .help("keep only the best hit per title, ignoring case and whitespace (the first match, or the \
+                               highest scoring one when re-ranking)"))
+                    .arg(clap::Arg::with_name("cluster")
+                        .long("cluster")
+                        .value_name("K")
+                        .number_of_values(1)
+                        .takes_value(true)
+                        .help("group the first matches into up to K clusters labelled by their top terms"))
+                    .arg(clap::Arg::with_name("rerank-command")
+                        .long("rerank-command")
+                        .value_name("COMMAND")
+                        .number_of_values(1)
+                        .takes_value(true)
+                        .help("re-rank matches by cosine similarity to the query using this embedding command, which reads one \
+                               text per line and prints one line of floats per text (the query comes first)"))
+                    .arg(clap::Arg::with_name("rerank-top")
+                        .long("rerank-top")
+                        .value_name("K")
+                        .number_of_values(1)
+                        .default_value("50")
+                        .takes_value(true)
+                        .help("how many of the first matches --rerank-command re-ranks"))
+                    .arg(clap::Arg::with_name("knn-k")
+                        .long("knn-k")
+                        .value_name("K")
+                        .number_of_values(1)
+                        .default_value("10")
+                        .takes_value(true)
+                        .help("nearest neighbors each knn:0.1,0.2,... search term fuses into the results (jsonl only)"))
+                    .arg(clap::Arg::with_name("mode")
+                        .long("mode")
+                        .value_name("MODE")
+                        .number_of_values(1)
+                        .default_value("text")
+                        .possible_values(&["text", "code"])
+                        .takes_value(true)
+                        .help("'code' indexes identifiers unstemmed and split on camelCase/snake_case, searchable verbatim as \
+                               exact:Ident, plus file paths as path:... and ext:... keyword fields"))
+                    .arg(clap::Arg::with_name("split-units")
+                        .long("split-units")
+                        .help("index a number followed by letters, like 10km, as the number and the unit, so it's found by '10 km' \
+                               (text mode, not cached)"))
+                    .arg(clap::Arg::with_name("token-filters")
+                        .long("token-filters")
+                        .value_name("NAMES")
+                        .number_of_values(1)
+                        .conflicts_with("mode")
+                        .takes_value(true)
+                        .help("comma separated token filters each word goes through, in order, instead of \
+                               lowercase,stopword,stemmer; ngram:N or ngram:MIN-MAX indexes character n-grams (text mode, not cached)"))
+                    .arg(clap::Arg::with_name("search-titles")
+                        .long("search-titles")
+                        .conflicts_with("mode")
+                        .help("index the words of titles too, as themselves and as title:word, so searches find documents by their \
+                               titles and rank title matches higher (text mode, not cached)"))
+                    .arg(clap::Arg::with_name("titles-only")
+                        .long("titles-only")
+                        .requires("search-titles")
+                        .help("look every query term up in titles only, as if written title:term"))
+                    .arg(clap::Arg::with_name("boost")
+                        .long("boost")
+                        .value_name("FIELD=WEIGHT,...")
+                        .number_of_values(1)
+                        .takes_value(true)
+                        .help("what a match in each field adds to a document's score, like title=3.0,text=1.0 with --search-titles; \
+                               text is every term without a field, others weigh 1. Stored with a cache when it's built and used when \
+                               none are given, which leaves title out as --search-titles builds aren't cached"))
+                    .arg(clap::Arg::with_name("query-language")
+                        .long("query-language")
+                        .value_name("LANGUAGE")
+                        .number_of_values(1)
+                        .possible_values(&LANGUAGES)
+                        .conflicts_with("mode")
+                        .takes_value(true)
+                        .help("stem search terms for this language instead of English; documents are indexed with its stems \
+                               as well as the English ones, so a cache built without it is rebuilt"))
+                    .arg(clap::Arg::with_name("memory-limit")
+                        .long("memory-limit")
+                        .value_name("MB")
+                        .number_of_values(1)
+                        .takes_value(true)
+                        .help("spill partial indexes to temp files when resident memory nears this limit during a build \
+                               (rayon, mmap, roaring and threadpool backends); the mmap backend lays the .mix file out \
+                               straight from them"))
+                    .arg(clap::Arg::with_name("max-expansions")
+                        .long("max-expansions")
+                        .value_name("TERMS")
+                        .number_of_values(1)
+                        .takes_value(true)
+                        .help("refuse prefix searches like 'comp*' that match more indexed terms than this"))
+                    .arg(clap::Arg::with_name("max-candidates")
+                        .long("max-candidates")
+                        .value_name("DOCS")
+                        .number_of_values(1)
+                        .takes_value(true)
+                        .help("refuse searches whose terms match more documents than this between them, \
+                               a document counted once for each term"))
+                    .arg(clap::Arg::with_name("max-query-memory")
+                        .long("max-query-memory")
+                        .value_name("MB")
+                        .number_of_values(1)
+                        .takes_value(true)
+                        .help("refuse searches that need more than this for matching ids and the documents they return"))
+                    .arg(clap::Arg::with_name("positions")
+                        .long("positions")
+                        .help("record token positions in the index, costs memory and cache size"))
+                    .arg(clap::Arg::with_name("offsets")
+                        .long("offsets")
+                        .help("record token byte offsets in the index, costs memory and cache size"))
+                    .arg(clap::Arg::with_name("term-frequencies")
+                        .long("term-frequencies")
+                        .help("record how many times each term occurs in each document, which weighs scores and is reported with \
+                               --output json hits and :explain, costs memory and cache size"))
+                    .arg(clap::Arg::with_name("search-while-indexing")
+                        .long("search-while-indexing")
+                        .help("answer searches from what's been indexed of the source while the build runs, growing it in \
+                               stages (partial results from the rayon backend only; interactive only)"))
+                    .arg(clap::Arg::with_name("stream")
+                        .long("stream")
+                        .conflicts_with_all(&["search-while-indexing", "refresh", "index-dir"])
+                        .help("build by reading the source a chunk at a time, so it needn't fit in memory \
+                               (rayon, mmap and roaring backends; xml, lines, mbox and uncompressed warc files)"))
+                    .arg(clap::Arg::with_name("stdio")
+                        .long("stdio")
+                        .conflicts_with_all(&["TERM", "search-while-indexing"])
+                        .help("serve JSON-RPC 2.0 on stdin/stdout, one message per line, for editor plugins \
+                               (methods: open-index, search, suggest, cooccur)"))
+                    .arg(clap::Arg::with_name("query-log")
+                        .long("query-log")
+                        .value_name("FILE")
+                        .number_of_values(1)
+                        .takes_value(true)
+                        .requires("stdio")
+                        .help("append every search's query to FILE and complete queries from the ones logged with the \
+                               suggest-queries method (--stdio only)"))
+                    .arg(clap::Arg::with_name("merge-rate")
+                        .long("merge-rate")
+                        .value_name("MB")
+                        .number_of_values(1)
+                        .takes_value(true)
+                        .requires("stdio")
+                        .help("write background merges of added documents into the cache at most this many MB a second \
+                               (--stdio only, unthrottled by default)"))
+                    .arg(clap::Arg::with_name("batch-queries")
+                        .long("batch-queries")
+                        .value_name("FILE")
+                        .number_of_values(1)
+                        .conflicts_with_all(&["TERM", "search-while-indexing", "stdio", "dry-run", "cooccur"])
+                        .help("run every query in FILE, one per line, in parallel and print each one's results in order"))
+                    .arg(clap::Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .conflicts_with_all(&["TERM", "search-while-indexing", "stdio"])
+                        .help("parse and analyze the source, then report documents, tokens and the projected index and \
+                               cache sizes, without building the index or touching the cache"))
+                    .arg(clap::Arg::with_name("rewrites")
+                        .long("rewrites")
+                        .value_name("FILE")
+                        .number_of_values(1)
+                        .takes_value(true)
+                        .help("rewrite query terms before analysis with the rules in FILE, one 'pattern => replacement' per line, \
+                               e.g. 'nyc => new york city'"))
+                    .arg(clap::Arg::with_name("show-rewrites")
+                        .long("show-rewrites")
+                        .requires("rewrites")
+                        .help("print each rewrite rule that fired"))
                     .arg(clap::Arg::with_name("no-cache-read")
                         .long("no-cache-read")
                         .help("don't use any on-disk cache, even if present"))
+                    .arg(clap::Arg::with_name("raw-offsets")
+                        .long("raw-offsets")
+                        .help("also cache where each document is in its source file, for 'show --raw' to print the \
+                               original fragment of a hit. For xml, lines, mbox and uncompressed warc sources"))
+                    .arg(clap::Arg::with_name("force-rebuild")
+                        .long("force-rebuild")
+                        .conflicts_with_all(&["no-cache-read", "no-cache-write", "read-only"])
+                        .help("rebuild the index and rewrite its cache even if the cache is current. A cache whose source \
+                               changed since it was written is rebuilt without it"))
+                    .arg(clap::Arg::with_name("require-cache")
+                        .long("require-cache")
+                        .conflicts_with_all(&["no-cache-read", "force-rebuild", "stdio"])
+                        .help("exit with an error, saying why, instead of rebuilding when the cache is missing, stale, of \
+                               another version or schema, or corrupt"))
                     .arg(clap::Arg::with_name("no-cache-write")
                         .long("no-cache-write")
                         .help("don't write on-disk cache files after parsing"))
+                    .arg(clap::Arg::with_name("read-only")
+                        .long("read-only")
+                        .conflicts_with_all(&["memory-limit", "export-hits"])
+                        .help("never write anything to disk: no cache files, no spilled segments, no exports. For sources \
+                               on read-only mounts and network shares; an existing cache is still read"))
+                    .arg(clap::Arg::with_name("cache-fsync")
+                        .long("cache-fsync")
+                        .takes_value(true)
+                        .possible_values(&["never", "files", "all"])
+                        .default_value("never")
+                        .help("when writing the cache, fsync each file before renaming it into place (files) and the directory after (all)"))
                     .arg(clap::Arg::with_name("TERM")
                         .required(false)
+                        // So "-term" reaches the query as an exclusion, see query.rs and mark_hyphen_terms
+                        .allow_hyphen_values(true)
                         .multiple(true))
-                    .get_matches();
+                    .get_matches_from(mark_hyphen_terms(std::env::args_os()));
+
+    match matches.subcommand() {
+        ("dump", Some(dump_matches)) => {
+            if let Err(e) = dump_index(dump_matches.value_of("index").unwrap(), dump_matches.is_present("raw")) {
+                println!("Failed to dump index: {}", e);
+            }
+            return;
+        },
+        ("compare", Some(compare_matches)) => {
+            let top = compare_matches.value_of("top").unwrap().parse::<usize>().unwrap();
+            if let Err(e) = compare_indexes(compare_matches.value_of("A").unwrap(), compare_matches.value_of("B").unwrap(), top) {
+                println!("Failed to compare indexes: {}", e);
+            }
+            return;
+        },
+        ("estimate", Some(estimate_matches)) => {
+            let index_filename = estimate_matches.value_of("index").unwrap();
+            let format = match estimate_matches.value_of("format").unwrap() {
+                "xml" if estimate_matches.occurrences_of("format") == 0 && is_archive(index_filename) => Format::Archive,
+                name => parse_format(name).unwrap()
+            };
+            let fraction = match parse_sample(estimate_matches.value_of("sample").unwrap()) {
+                Some(fraction) => fraction,
+                None => {
+                    println!("--sample must be a percentage over 0 and at most 100");
+                    return;
+                }
+            };
+            match read_source(estimate_matches, format, index_filename) {
+                Ok(file_content) => {
+                    let options = IndexOptions { format, ..IndexOptions::default() };
+                    let schema = options.schema;
+                    RayonIndexer::new(options).estimate_from_sample(&file_content, fraction).print(schema);
+                },
+                Err(e) => println!("Failed to read {}: {}", index_filename, e)
+            }
+            return;
+        },
+        ("analyze", Some(analyze_matches)) => {
+            let mode = if analyze_matches.value_of("mode") == Some("code") { Mode::Code } else { Mode::Text };
+            let token_filters = token_filter_names(analyze_matches);
+            if let Err(e) = print_analysis(analyze_matches.value_of("TEXT").unwrap(), analyze_matches.value_of("language"), mode,
+                !analyze_matches.is_present("no-stem"), analyze_matches.is_present("split-units"), token_filters.as_deref()) {
+                println!("Bad --token-filters: {}", e);
+            }
+            return;
+        },
+        ("info", Some(info_matches)) => {
+            match SerializedIndex::metadata(info_matches.value_of("index").unwrap()) {
+                Ok(metadata) => metadata.print(),
+                Err(e) => println!("Failed to read index info: {}", e)
+            }
+            return;
+        },
+        ("stats", Some(stats_matches)) => {
+            match CorpusStats::load(stats_matches.value_of("index").unwrap(), stats_matches.is_present("histograms")) {
+                Ok(stats) if stats_matches.value_of("output") == Some("json") => println!("{}", serde_json::to_string_pretty(&stats).unwrap()),
+                Ok(stats) => stats.print(),
+                Err(e) => println!("Failed to read index stats: {}", e)
+            }
+            return;
+        },
+        ("bench", Some(bench_matches)) => {
+            let concurrency = bench_matches.value_of("concurrency").unwrap().parse::<usize>().unwrap();
+            let limit = bench_matches.value_of("limit").unwrap().parse::<usize>().unwrap();
+            if let Err(e) = run_bench(bench_matches.value_of("index").unwrap(), bench_matches.value_of("backend").unwrap(),
+                                      bench_matches.value_of("replay").unwrap(), concurrency, limit) {
+                println!("Bench failed: {}", e);
+            }
+            return;
+        },
+        ("random", Some(random_matches)) => {
+            let count = random_matches.value_of("count").unwrap().parse::<usize>().unwrap();
+            let seed = match random_matches.value_of("seed") {
+                Some(seed) => seed.parse::<u64>().unwrap(),
+                None => time::SystemTime::now().duration_since(time::UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+            };
+            match random_documents(random_matches.value_of("index").unwrap(), count, seed) {
+                Ok(documents) if random_matches.value_of("output") == Some("json") => println!("{}", serde_json::to_string_pretty(&documents).unwrap()),
+                Ok(documents) => {
+                    println!("Drawn with --seed {}", seed);
+                    for doc in documents {
+                        println!("{}\t{}\t{}", doc.id, doc.title, doc.url);
+                        println!("    {}", doc.snippet);
+                    }
+                },
+                Err(e) => println!("Failed to draw documents: {}", e)
+            }
+            return;
+        },
+        ("show", Some(show_matches)) => {
+            // Checked by the ID validator
+            let id = show_matches.value_of("ID").unwrap().parse::<i32>().unwrap();
+            if let Err(e) = show_document(show_matches.value_of("index").unwrap(), id, show_matches.is_present("raw")) {
+                println!("Failed to show document {}: {}", id, e);
+            }
+            return;
+        },
+        ("migrate", Some(migrate_matches)) => {
+            if let Err(e) = migrate_index(migrate_matches.value_of("index").unwrap()) {
+                println!("Failed to migrate index: {}", e);
+            }
+            return;
+        },
+        _ => {}
+    }
     
+    if matches.is_present("read-only") {
+        forbid_writes();
+    }
+
     let num_index_threads = match matches.value_of("index-threads") {
         Some(t) => t.parse::<usize>().unwrap(),
         None => num_cpus::get()
@@ -79,72 +1550,354 @@ fn main() {
     };
 
     let backend = matches.value_of("backend").unwrap();
+    let index_files = match (matches.value_of("index-dir"), matches.values_of("index")) {
+        // One source whose files are the documents, unlike directories given to --index
+        (Some(dir), _) => vec![PathBuf::from(dir)],
+        (None, paths) => match SourceFiles::list(paths.unwrap()) {
+            Ok(files) if !files.is_empty() => files,
+            Ok(_) => {
+                println!("No files to index");
+                return;
+            },
+            Err(e) => {
+                println!("Failed to list files to index: {}", e);
+                return;
+            }
+        }
+    };
+    // The cache and the --stdio server only deal with one source file
+    let index_filename = &*index_files[0].to_string_lossy();
+    if index_files.len() > 1 && matches.is_present("stdio") {
+        clap::Error::with_description("--stdio takes a single --index file", clap::ErrorKind::ArgumentConflict).exit();
+    }
+    if (backend == "mmap" || backend == "roaring") && (matches.is_present("positions") || matches.is_present("offsets") || matches.is_present("term-frequencies")) {
+        clap::Error::with_description(&format!("the {} backend doesn't record positions, offsets or term frequencies", backend), clap::ErrorKind::ArgumentConflict).exit();
+    }
+    if index_files.len() > 1 && matches.is_present("stream") {
+        clap::Error::with_description("--stream takes a single --index file", clap::ErrorKind::ArgumentConflict).exit();
+    }
+    if matches.is_present("index-dir") && matches.occurrences_of("format") > 0 {
+        clap::Error::with_description("--index-dir can't be used with --format", clap::ErrorKind::ArgumentConflict).exit();
+    }
+    let format = match matches.value_of("format").unwrap() {
+        _ if matches.is_present("index-dir") => Format::Archive,
+        // Archives are recognized by extension unless a format was given explicitly
+        "xml" if matches.occurrences_of("format") == 0 && is_archive(index_filename) => Format::Archive,
+        name => parse_format(name).expect("unknown format")
+    };
+    let mode = match matches.value_of("mode").unwrap() {
+        "text" => Mode::Text,
+        "code" => Mode::Code,
+        _ => panic!("unknown mode")
+    };
+    if matches.value_of("output") == Some("grep") && (matches.is_present("rerank-command") || matches.is_present("dedupe-titles") || matches.is_present("snippets")) {
+        clap::Error::with_description("--output grep can't be used with --rerank-command, --dedupe-titles or --snippets", clap::ErrorKind::ArgumentConflict).exit();
+    }
+    let json = match matches.value_of("output") {
+        Some("json") => match stdio::take_stdout() {
+            Ok(json) => Some(RefCell::new(json)),
+            Err(e) => {
+                println!("Failed to set up JSON output: {}", e);
+                return;
+            }
+        },
+        _ => None
+    };
+    let output = OutputOptions {
+        format,
+        grep: matches.value_of("output") == Some("grep"),
+        dedupe_titles: matches.is_present("dedupe-titles"),
+        cluster: matches.value_of("cluster").map(|k| k.parse::<usize>().unwrap()),
+        rerank_command: matches.value_of("rerank-command").map(String::from),
+        rerank_top: matches.value_of("rerank-top").unwrap().parse::<usize>().unwrap(),
+        knn_k: matches.value_of("knn-k").unwrap().parse::<usize>().unwrap(),
+        rewrites: match matches.value_of("rewrites").map(Rewrites::load) {
+            Some(Ok(rewrites)) => Some(rewrites),
+            Some(Err(e)) => {
+                println!("Failed to load rewrite rules: {}", e);
+                return;
+            },
+            None => None
+        },
+        show_rewrites: matches.is_present("show-rewrites"),
+        titles_only: matches.is_present("titles-only"),
+        json,
+        snippets: match matches.is_present("snippets") {
+            true => Some(matches.value_of("highlight").and_then(Highlight::parse).unwrap_or(Highlight::Markdown)),
+            false => None
+        },
+        snippet_options: SnippetOptions {
+            chars: matches.value_of("snippet-len").map(|n| n.parse::<usize>().unwrap().max(1)).unwrap_or(SNIPPET_CHARS),
+            max_per_doc: matches.value_of("max-snippets-per-doc").map(|n| n.parse::<usize>().unwrap().max(1)).unwrap_or(1)
+        },
+        page: Cell::new(Page {
+            offset: matches.value_of("offset").map(|m| m.parse::<usize>().unwrap()).unwrap_or(0),
+            limit: matches.value_of("limit").map(|n| n.parse::<usize>().unwrap())
+        })
+    };
+    let refresh_interval = matches.value_of("refresh").map(|t| time::Duration::from_secs(t.parse::<u64>().unwrap()));
+    let boosts = match FieldBoosts::parse(matches.value_of("boost").unwrap_or("")) {
+        Ok(boosts) => boosts,
+        Err(e) => {
+            println!("Bad --boost: {}", e);
+            return;
+        }
+    };
+    let options = IndexOptions {
+        format,
+        schema: Schema {
+            positions: matches.is_present("positions"),
+            offsets: matches.is_present("offsets"),
+            frequencies: matches.is_present("term-frequencies")
+        },
+        keyword_headers: match matches.value_of("index-headers") {
+            Some(h) => h.split(',').map(|x| x.trim().to_lowercase()).filter(|x| !x.is_empty()).collect(),
+            None => Vec::new()
+        },
+        max_doc_bytes: matches.value_of("max-doc-bytes").map(|b| b.parse::<usize>().unwrap()),
+        oversize_policy: match matches.value_of("oversize-policy").unwrap() {
+            "truncate" => OversizePolicy::Truncate,
+            "skip" => OversizePolicy::Skip,
+            "warn" => OversizePolicy::Warn,
+            _ => panic!("unknown oversize policy")
+        },
+        memory_limit: matches.value_of("memory-limit").map(|m| m.parse::<usize>().unwrap() * 1024 * 1024),
+        query_language: matches.value_of("query-language").map(String::from),
+        mode,
+        split_units: matches.is_present("split-units"),
+        token_filters: token_filter_names(&matches),
+        search_titles: matches.is_present("search-titles"),
+        boosts,
+        limits: QueryLimits {
+            max_expanded_terms: matches.value_of("max-expansions").map(|t| t.parse::<usize>().unwrap()),
+            max_candidates: matches.value_of("max-candidates").map(|d| d.parse::<usize>().unwrap()),
+            max_memory: matches.value_of("max-query-memory").map(|m| m.parse::<usize>().unwrap() * 1024 * 1024)
+        },
+        raw_offsets: matches.is_present("raw-offsets")
+    };
+    if let Err(e) = options.check_token_filters() {
+        println!("Bad --token-filters: {}", e);
+        return;
+    }
+    let use_cache = index_files.len() == 1 && uses_cache(format, &options, index_filename);
+
+    if matches.is_present("stdio") {
+        if let Err(e) = stdio::serve(&matches, &output, backend, options, num_parse_threads, num_index_threads) {
+            eprintln!("stdio server failed: {}", e);
+        }
+        return;
+    }
+
+    // Always counted with the rayon backend's parsing, which every backend shares the output of
+    if matches.is_present("dry-run") {
+        let before_estimate = time::Instant::now();
+        let file_content = match read_sources(&matches, format, &index_files) {
+            Ok((_, file_content)) => file_content,
+            Err(e) => {
+                println!("Reading failed: {}", e);
+                std::process::exit(1);
+            }
+        };
+        let schema = options.schema;
+        let estimate = RayonIndexer::new(options).dry_run(&file_content);
+        estimate.print(schema);
+        println!("Dry run elapsed: {} ms", (time::Instant::now() - before_estimate).as_millis());
+        return;
+    }
 
     let before_all = time::Instant::now();
-    let index_filename = matches.value_of("index").unwrap();
 
     let before_parse = time::Instant::now();
-    let mut word_index: Box<dyn DocumentIndexer> = match backend {
-        "rayon" => Box::new(RayonIndexer::new()),
-        "threadpool" => Box::new(ThreadPoolIndexer::new_hashmap(num_parse_threads, num_index_threads)),
-        "threadpool_dashmap" => Box::new(ThreadPoolIndexer::new_dashmap(num_parse_threads, num_index_threads)),
-        _ => panic!("unknown backend")
-    };
+    let schema = options.schema;
+    let mut word_index = new_indexer(backend, options.clone(), num_parse_threads, num_index_threads);
+    let mut vectors: Option<VectorIndex> = None;
+    let mut sets = ResultSets::new();
+    let mut latencies = Latencies::new();
+    // Appending to the history is a write like any other
+    let mut editor = LineEditor::new(LineEditor::default_history_path().filter(|_| !matches.is_present("read-only")));
 
     println!("Attempting to build from cache");
-    let build_result = try_build_from_cache(&matches, word_index.as_mut(), index_filename);
+    let build_result = match use_cache && reads_cache(&matches) {
+        true => match try_build_from_cache(word_index.as_mut(), index_filename, schema) {
+            Ok(()) => true,
+            Err(e) if matches.is_present("require-cache") => {
+                println!("Not rebuilding {} without its cache, which is {}", index_filename, e);
+                std::process::exit(1);
+            },
+            Err(e) => {
+                println!("Not using the cache, which is {}", e);
+                false
+            }
+        },
+        false if matches.is_present("require-cache") => {
+            println!("Not rebuilding {}: its format and options keep it out of the cache", index_filename);
+            std::process::exit(1);
+        },
+        false => false
+    };
+    // Held from the build until its cache is written, so a second process building the same index
+    // stops here rather than writing over the first one's files
+    let cache_lock = match use_cache && !build_result && writes_cache(&matches) {
+        true => match CacheLock::acquire(Path::new(index_filename)) {
+            Ok(lock) => Some(lock),
+            Err(e) => {
+                println!("Not building {}: {}", index_filename, e);
+                return;
+            }
+        },
+        false => None
+    };
     if build_result {
         println!("Build from cache successful!");
     } else {
         println!("Could not load from cache. Building index using '{}' backend...", backend);
-        let file_content: String = fs::read_to_string(index_filename).unwrap();
-        let duration_read = time::Instant::now() - before_all;
-        println!("Reading done. Elapsed: {} ms", duration_read.as_millis());
-        word_index.build_from_file_contents(file_content);
+        if matches.is_present("stream") {
+            if let Err(e) = word_index.build_streaming(index_filename) {
+                println!("Streaming build of {} failed: {}", index_filename, e);
+                return;
+            }
+        } else {
+            let (sources, file_content) = match read_sources(&matches, format, &index_files) {
+                Ok(read) => read,
+                Err(e) => {
+                    println!("Reading failed: {}", e);
+                    drop(cache_lock);
+                    std::process::exit(1);
+                }
+            };
+            let duration_read = time::Instant::now() - before_all;
+            println!("Reading done. Elapsed: {} ms", duration_read.as_millis());
+            if format == Format::Jsonl {
+                vectors = Some(build_vectors(&matches, &sources, &file_content));
+            }
+            if matches.is_present("search-while-indexing") && terms(&matches).is_none() {
+                let backend = String::from(backend);
+                let options = options.clone();
+                let stage_options = options.clone();
+                let new_index = move || new_indexer(&backend, options.clone(), num_parse_threads, num_index_threads);
+                word_index = match search_while_indexing(new_index, stage_options, sources, file_content, vectors.as_ref(), &output, &mut editor, &mut sets, &mut latencies) {
+                    Some(word_index) => word_index,
+                    None => {
+                        latencies.print_summary();
+                        return;
+                    }
+                };
+            } else if let Err(e) = word_index.build_from_files(sources, file_content) {
+                println!("Building {} failed: {}", index_filename, e);
+                return;
+            }
+        }
         let duration_parse = time::Instant::now() - before_parse;
         println!("Parsing and indexing elapsed: {} ms, Index size: {}, Num documents indexed: {}",
             duration_parse.as_millis(), word_index.num_tokens(), word_index.num_documents());
     }
+    if word_index.num_positioned() > 0 {
+        println!("Schema: {:?}, term/document pairs with positions: {}", word_index.schema(), word_index.num_positioned());
+    }
+    if word_index.num_oversized() > 0 {
+        println!("Documents over max doc bytes: {}", word_index.num_oversized());
+    }
     let duration_all = time::Instant::now() - before_all;
     println!("Total elapsed: {} ms", duration_all.as_millis());
 
-    if !build_result && !matches.is_present("no-cache-write") {
+    if use_cache && !build_result && writes_cache(&matches) {
         let before_write = time::Instant::now();
-        let result = SerializedIndex::write_index_to_path(index_filename, word_index.as_ref());
+        let result = SerializedIndex::write_index_to_path(index_filename, word_index.as_ref(), cache_sync_policy(&matches));
         if result.is_err() {
             println!("Failed to write index: {:?}", result)
         }
         let duration_write = time::Instant::now() - before_write;
         println!("Duration write: {}", duration_write.as_millis());
     }
+    drop(cache_lock);
 
-    if let Some(terms) = matches.values_of("TERM") {
-        let terms = terms.collect();
-        let results = word_index.search(terms);
-        for result in results {
-            for doc in result.matches {
-                println!("Found \"{}\" in {} {}", result.term, doc.title, doc.url);
+    if let Some(limit) = matches.value_of("cooccur") {
+        let limit = limit.parse::<usize>().unwrap();
+        for term in terms(&matches).unwrap() {
+            print_cooccurrences(word_index.as_ref(), term, limit);
+        }
+        return;
+    }
+    if let Some(path) = matches.value_of("batch-queries") {
+        match fs::read_to_string(path) {
+            Ok(queries) => run_batch(word_index.as_ref(), &queries, &output),
+            Err(e) => println!("Failed to read queries from {}: {}", path, e)
+        }
+        return;
+    }
+    if let Some(terms) = terms(&matches) {
+        let terms = rewrite_query(&output, output.show_rewrites, terms);
+        let terms: Vec<&str> = terms.iter().map(String::as_str).collect();
+        let ids = if terms.iter().any(|t| parse_knn_term(t).is_some()) {
+            print_hybrid_results(word_index.as_ref(), vectors.as_ref(), terms, &output)
+        } else {
+            let query = terms.join(" ");
+            match word_index.search_page(terms, output.page.get()) {
+                Ok(results) => {
+                    report_missing(&results);
+                    let ids: Vec<i32> = results.ids().collect();
+                    print_results(word_index.as_ref(), &query, results, &output);
+                    ids
+                },
+                Err(e) => {
+                    println!("Search refused: {}", e);
+                    Vec::new()
+                }
+            }
+        };
+        if let Some(path) = matches.value_of("export-hits") {
+            match export_hits(word_index.as_ref(), &ids, path) {
+                Ok(exported) => println!("Exported {} documents to {}", exported, path),
+                Err(e) => println!("Failed to export hits to {}: {}", path, e)
             }
         }
     } else {
+        let mut last_refresh = time::Instant::now();
+        catch_interrupts(&editor);
+        cancel_searches(word_index.as_mut());
         loop {
-            let mut input = String::new();
-            print_flush!("Search: "); 
-            match io::stdin().read_line(&mut input) {
-                Ok(_) => {
-                    let terms = input.split(' ').collect();
-                    let before = time::Instant::now();
-                    let results = word_index.search(terms);
-                    let duration = time::Instant::now() - before;
-                    println!("Search found {} results, completed in {} us", results.iter().map(|m| m.matches.len()).sum::<usize>(), duration.as_micros());
-                    for result in results {
-                        for doc in result.matches {
-                            println!("Found \"{}\" in {} {}", result.term, doc.title, doc.url);
+            match editor.read_line("Search: ") {
+                Ok(ReadLine::Eof) => break,
+                Ok(ReadLine::Interrupted) => {},
+                Ok(ReadLine::Line(input)) => {
+                    if let Some(interval) = refresh_interval {
+                        if last_refresh.elapsed() >= interval {
+                            // Into a new index, so one cancelled with Ctrl-C leaves the current one
+                            match read_sources(&matches, format, &index_files) {
+                                Ok((sources, contents)) => {
+                                    let refreshed_vectors = if format == Format::Jsonl { Some(build_vectors(&matches, &sources, &contents)) } else { None };
+                                    let mut refreshed = new_indexer(backend, options.clone(), num_parse_threads, num_index_threads);
+                                    let label = sources.to_string();
+                                    match build_interruptible(refreshed.as_mut(), sources, contents) {
+                                        Ok(true) => {
+                                            word_index = refreshed;
+                                            if refreshed_vectors.is_some() {
+                                                vectors = refreshed_vectors;
+                                            }
+                                            println!("Refreshed {}, Num documents indexed: {}", label, word_index.num_documents());
+                                        },
+                                        Ok(false) => println!("Refresh cancelled, still searching the previous index"),
+                                        Err(error) => println!("Refresh failed: {}, still searching the previous index", error)
+                                    }
+                                }
+                                Err(error) => println!("Refresh failed: {}", error)
+                            }
+                            last_refresh = time::Instant::now();
                         }
-                    }              
+                    }
+                    if run_help_command(&input) || run_limit_command(&output, &input) || run_index_command(word_index.as_ref(), &input, &output, &latencies) {
+                        continue;
+                    }
+                    if ResultSets::is_command(&input) {
+                        sets.run(word_index.as_ref(), &input, format);
+                    } else {
+                        sets.set_last(search_and_print(word_index.as_ref(), vectors.as_ref(), &input, &output, &mut latencies));
+                    }
+                    report_interrupted();
                 }
                 Err(error) => println!("error: {}", error),
             }
         }
+        latencies.print_summary();
     }
 }
\ No newline at end of file
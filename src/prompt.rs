@@ -0,0 +1,455 @@
+use super::*;
+use std::io::{BufRead, IsTerminal, Read};
+
+// Lines kept in the history file, the oldest are dropped past this
+const HISTORY_LINES: usize = 1000;
+
+// What reading a line at the prompt came to
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReadLine {
+    Line(String),
+    // Ctrl-C, the line is dropped
+    Interrupted,
+    // Ctrl-D on an empty line, or the end of piped input
+    Eof
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    // Ctrl-U
+    KillLine,
+    // Ctrl-C
+    Interrupt,
+    // Ctrl-D
+    Eof,
+    // Anything not bound, ignored
+    Other
+}
+
+fn read_byte(input: &mut dyn Read) -> io::Result<Option<u8>> {
+    let mut byte = [0u8];
+    loop {
+        match input.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+// The next key typed, None at the end of input. Escape sequences are the VT100 ones xterm and
+// friends send, "\x1b[A" for up or "\x1b[3~" for delete; those not bound are read whole and ignored.
+fn read_key(input: &mut dyn Read) -> io::Result<Option<Key>> {
+    let first = match read_byte(input)? {
+        Some(byte) => byte,
+        None => return Ok(None)
+    };
+    let key = match first {
+        b'\r' | b'\n' => Key::Enter,
+        0x7f | 0x08 => Key::Backspace,
+        0x01 => Key::Home,
+        0x05 => Key::End,
+        0x02 => Key::Left,
+        0x06 => Key::Right,
+        0x10 => Key::Up,
+        0x0e => Key::Down,
+        0x15 => Key::KillLine,
+        0x03 => Key::Interrupt,
+        0x04 => Key::Eof,
+        0x1b => match read_byte(input)? {
+            Some(b'[') | Some(b'O') => {
+                let mut parameters: Vec<u8> = Vec::new();
+                let last = loop {
+                    match read_byte(input)? {
+                        Some(byte) if (0x40..=0x7e).contains(&byte) => break byte,
+                        Some(byte) => parameters.push(byte),
+                        None => return Ok(None)
+                    }
+                };
+                match (parameters.as_slice(), last) {
+                    (_, b'A') => Key::Up,
+                    (_, b'B') => Key::Down,
+                    (_, b'C') => Key::Right,
+                    (_, b'D') => Key::Left,
+                    (_, b'H') | (b"1", b'~') | (b"7", b'~') => Key::Home,
+                    (_, b'F') | (b"4", b'~') | (b"8", b'~') => Key::End,
+                    (b"3", b'~') => Key::Delete,
+                    _ => Key::Other
+                }
+            },
+            _ => Key::Other
+        },
+        byte if byte < 0x20 => Key::Other,
+        byte => {
+            // The rest of a UTF-8 character, which the lead byte counts
+            let mut bytes = vec![byte];
+            let length = match byte {
+                0xf0..=0xff => 4,
+                0xe0..=0xef => 3,
+                0xc0..=0xdf => 2,
+                _ => 1
+            };
+            while bytes.len() < length {
+                match read_byte(input)? {
+                    Some(byte) => bytes.push(byte),
+                    None => return Ok(None)
+                }
+            }
+            match std::str::from_utf8(&bytes).ok().and_then(|s| s.chars().next()) {
+                Some(c) => Key::Char(c),
+                None => Key::Other
+            }
+        }
+    };
+    Ok(Some(key))
+}
+
+// Columns a character takes on a terminal: none for combining marks and zero width spaces, two for
+// East Asian wide and fullwidth characters and most emoji, one for the rest
+fn char_width(c: char) -> usize {
+    match c as u32 {
+        0x0300..=0x036f | 0x1ab0..=0x1aff | 0x1dc0..=0x1dff | 0x200b..=0x200f | 0x20d0..=0x20ff | 0xfe00..=0xfe0f | 0xfe20..=0xfe2f => 0,
+        0x1100..=0x115f | 0x2e80..=0x303e | 0x3041..=0x33ff | 0x3400..=0x4dbf | 0x4e00..=0x9fff | 0xa000..=0xa4cf
+        | 0xac00..=0xd7a3 | 0xf900..=0xfaff | 0xfe30..=0xfe4f | 0xff00..=0xff60 | 0xffe0..=0xffe6
+        | 0x1f300..=0x1f64f | 0x1f900..=0x1f9ff | 0x20000..=0x2fffd | 0x30000..=0x3fffd => 2,
+        _ => 1
+    }
+}
+
+// The row and column, counted from where the prompt starts, that `chars` end at after the prompt on
+// a terminal `width` columns wide. A wide character that doesn't fit at the end of a row goes
+// whole to the next, as terminals wrap it.
+fn end_of(prompt: &str, chars: &[char], width: usize) -> (usize, usize) {
+    let mut at = prompt.chars().map(char_width).sum::<usize>();
+    for c in chars {
+        let c_width = char_width(*c);
+        if at % width + c_width > width {
+            at += width - at % width;
+        }
+        at += c_width;
+    }
+    (at / width, at % width)
+}
+
+// The line being edited and where in the history it is
+struct Editing {
+    chars: Vec<char>,
+    cursor: usize,
+    // Index of the history line shown, history.len() for the line being typed
+    browsing: usize,
+    // The line being typed, kept while browsing the history
+    draft: Vec<char>,
+    // How many rows below the prompt's the terminal's cursor is, once the line wraps
+    row: usize
+}
+
+impl Editing {
+    fn new(browsing: usize) -> Self {
+        Editing { chars: Vec::new(), cursor: 0, browsing, draft: Vec::new(), row: 0 }
+    }
+
+    // What shows the line again after `prompt` and puts the terminal's cursor at `cursor`: up to
+    // the prompt's row, the line over what was there, the rest of the screen cleared, and back
+    // to the cursor
+    fn redraw(&mut self, prompt: &str, width: usize) -> String {
+        let mut out = String::new();
+        if self.row > 0 {
+            out.push_str(&format!("\x1b[{}A", self.row));
+        }
+        out.push('\r');
+        out.push_str(prompt);
+        out.extend(&self.chars);
+        out.push_str("\x1b[J");
+        let (end_row, end_column) = end_of(prompt, &self.chars, width);
+        // A line that fills its last row leaves the terminal's cursor on that row until more is
+        // written, so it's moved down to where the next character goes
+        if end_column == 0 && end_row > 0 {
+            out.push_str("\r\n");
+        }
+        let (row, column) = end_of(prompt, &self.chars[..self.cursor], width);
+        if end_row > row {
+            out.push_str(&format!("\x1b[{}A", end_row - row));
+        }
+        out.push('\r');
+        if column > 0 {
+            out.push_str(&format!("\x1b[{}C", column));
+        }
+        self.row = row;
+        out
+    }
+
+    // Moves the terminal's cursor below the line, for whatever's printed after it
+    fn leave(&self, prompt: &str, width: usize) -> String {
+        let (end_row, _) = end_of(prompt, &self.chars, width);
+        match end_row > self.row {
+            true => format!("\x1b[{}B\r\n", end_row - self.row),
+            false => String::from("\r\n")
+        }
+    }
+}
+
+// Reads REPL input with line editing and history when stdin is a terminal, and plainly otherwise:
+//
+//   Left/Right, Ctrl-B/Ctrl-F   move the cursor
+//   Home/End, Ctrl-A/Ctrl-E     to the start or end of the line
+//   Up/Down, Ctrl-P/Ctrl-N      earlier or later lines of the history
+//   Backspace, Delete           delete before or under the cursor
+//   Ctrl-U                      clear the line
+//   Ctrl-C                      drop the line
+//   Ctrl-D                      end input on an empty line, delete under the cursor otherwise
+//
+// The history is read from and appended to a file when there is one, so it lasts across sessions.
+pub struct LineEditor {
+    history: Vec<String>,
+    history_path: Option<PathBuf>,
+    terminal: bool
+}
+
+fn show(text: &str) {
+    let mut stdout = io::stdout();
+    let _ = stdout.write_all(text.as_bytes()).and_then(|_| stdout.flush());
+}
+
+impl LineEditor {
+    pub fn new(history_path: Option<PathBuf>) -> Self {
+        let mut history: Vec<String> = history_path.as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|text| text.lines().map(String::from).collect())
+            .unwrap_or_default();
+        if history.len() > HISTORY_LINES {
+            history.drain(..history.len() - HISTORY_LINES);
+            if let Some(path) = &history_path {
+                let _ = fs::write(path, history.iter().map(|line| format!("{}\n", line)).collect::<String>());
+            }
+        }
+        LineEditor { history, history_path, terminal: io::stdin().is_terminal() && io::stdout().is_terminal() }
+    }
+
+    // `$HOME/.fulltext_history`, None without a home directory
+    pub fn default_history_path() -> Option<PathBuf> {
+        std::env::var_os("HOME").map(|home| Path::new(&home).join(".fulltext_history"))
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        self.terminal
+    }
+
+    pub fn read_line(&mut self, prompt: &str) -> io::Result<ReadLine> {
+        show(prompt);
+        let read = match self.terminal {
+            true => self.read_edited(prompt),
+            false => {
+                let mut input = String::new();
+                io::stdin().lock().read_line(&mut input).map(|read| match read {
+                    0 => ReadLine::Eof,
+                    _ => ReadLine::Line(input)
+                })
+            }
+        };
+        // Piped queries are a script's, not history
+        if let (Ok(ReadLine::Line(line)), true) = (&read, self.terminal) {
+            self.remember(line.trim_end_matches(['\r', '\n']));
+        }
+        read
+    }
+
+    fn remember(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.last().is_some_and(|last| last == line) {
+            return;
+        }
+        self.history.push(String::from(line));
+        if let Some(path) = &self.history_path {
+            let appended = fs::OpenOptions::new().create(true).append(true).open(path).and_then(|mut file| writeln!(file, "{}", line));
+            if let Err(e) = appended {
+                println!("Not keeping history in {:?}: {}", path, e);
+                self.history_path = None;
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn read_edited(&self, prompt: &str) -> io::Result<ReadLine> {
+        let _raw = RawMode::enable()?;
+        let stdin = io::stdin();
+        let mut input = stdin.lock();
+        let mut editing = Editing::new(self.history.len());
+        loop {
+            let key = match read_key(&mut input)? {
+                Some(key) => key,
+                None => return Ok(ReadLine::Eof)
+            };
+            // Read each time, so a resized terminal is redrawn to its new width
+            let width = terminal_width();
+            if let Some(read) = self.edit(&mut editing, key) {
+                show(&editing.leave(prompt, width));
+                return Ok(read);
+            }
+            show(&editing.redraw(prompt, width));
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn read_edited(&self, _prompt: &str) -> io::Result<ReadLine> {
+        let mut input = String::new();
+        io::stdin().lock().read_line(&mut input).map(|read| match read {
+            0 => ReadLine::Eof,
+            _ => ReadLine::Line(input)
+        })
+    }
+
+    // Applies `key` to the line, Some once the line is done
+    fn edit(&self, editing: &mut Editing, key: Key) -> Option<ReadLine> {
+        let Editing { chars, cursor, browsing, draft, .. } = editing;
+        match key {
+            Key::Char(c) => {
+                chars.insert(*cursor, c);
+                *cursor += 1;
+            },
+            Key::Enter => return Some(ReadLine::Line(chars.iter().collect::<String>() + "\n")),
+            Key::Interrupt => return Some(ReadLine::Interrupted),
+            Key::Eof if chars.is_empty() => return Some(ReadLine::Eof),
+            Key::Eof | Key::Delete => if *cursor < chars.len() {
+                chars.remove(*cursor);
+            },
+            Key::Backspace => if *cursor > 0 {
+                *cursor -= 1;
+                chars.remove(*cursor);
+            },
+            Key::Left => *cursor = cursor.saturating_sub(1),
+            Key::Right => *cursor = (*cursor + 1).min(chars.len()),
+            Key::Home => *cursor = 0,
+            Key::End => *cursor = chars.len(),
+            Key::KillLine => {
+                chars.clear();
+                *cursor = 0;
+            },
+            Key::Up | Key::Down => {
+                let to = match key {
+                    Key::Up if *browsing > 0 => *browsing - 1,
+                    Key::Down if *browsing < self.history.len() => *browsing + 1,
+                    _ => return None
+                };
+                if *browsing == self.history.len() {
+                    *draft = chars.clone();
+                }
+                *chars = match self.history.get(to) {
+                    Some(line) => line.chars().collect(),
+                    None => draft.clone()
+                };
+                *browsing = to;
+                *cursor = chars.len();
+            },
+            Key::Other => {}
+        }
+        None
+    }
+}
+
+// The terminal without line buffering, echo or signals, so keys reach the editor as typed and
+// Ctrl-C is a key. Output processing stays on, so "\n" still starts a new line. The settings before
+// are restored when dropped.
+#[cfg(unix)]
+struct RawMode {
+    previous: libc::termios
+}
+
+#[cfg(unix)]
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let previous = termios;
+        termios.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+        termios.c_iflag &= !(libc::IXON | libc::ICRNL);
+        termios.c_cc[libc::VMIN] = 1;
+        termios.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(RawMode { previous })
+    }
+}
+
+// Columns of the terminal on stdout, 80 when it won't say
+#[cfg(unix)]
+fn terminal_width() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    match unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } {
+        0 if size.ws_col > 0 => size.ws_col as usize,
+        _ => 80
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &self.previous) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(mut input: &[u8]) -> Vec<Key> {
+        std::iter::from_fn(|| read_key(&mut input).unwrap()).collect()
+    }
+
+    #[test]
+    fn keys_edit_the_line_and_browse_the_history() {
+        assert_eq!(keys(b"a\xc3\xa9\x1b[D\x1b[3~\x1b[1~\x03\x7f\r"),
+            vec![Key::Char('a'), Key::Char('é'), Key::Left, Key::Delete, Key::Home, Key::Interrupt, Key::Backspace, Key::Enter]);
+        assert_eq!(keys(b"\x1b[1;5C\x1bOA\x1b[200~"), vec![Key::Right, Key::Up, Key::Other]);
+
+        let editor = LineEditor { history: vec![String::from("first"), String::from("second")], history_path: None, terminal: false };
+        let mut editing = Editing::new(2);
+        let mut type_keys = |keys: &[Key]| keys.iter().filter_map(|key| editor.edit(&mut editing, *key)).next();
+        assert_eq!(type_keys(&[Key::Char('b'), Key::Char('d'), Key::Left, Key::Char('c'), Key::Home, Key::Char('a'), Key::Up, Key::Up, Key::Up]), None);
+        assert_eq!(type_keys(&[Key::Backspace, Key::Down, Key::Down, Key::Eof, Key::Enter]), Some(ReadLine::Line(String::from("abcd\n"))));
+        assert_eq!(type_keys(&[Key::KillLine, Key::Eof]), Some(ReadLine::Eof));
+    }
+
+    #[test]
+    fn wide_and_wrapped_lines_are_redrawn_from_the_prompts_row() {
+        assert_eq!(end_of("> ", &"ab".chars().collect::<Vec<char>>(), 10), (0, 4));
+        // Two columns each: four fill a row of 10 after the prompt, and in a row of 9 the fourth
+        // doesn't fit after the third and goes whole to the next
+        let line: Vec<char> = "東京都庁舎".chars().collect();
+        assert_eq!(end_of("> ", &line[..4], 10), (1, 0));
+        assert_eq!(end_of("> ", &line, 9), (1, 4));
+        assert_eq!(end_of("", &"e\u{301}".chars().collect::<Vec<char>>(), 10), (0, 1));
+
+        let mut editing = Editing::new(0);
+        editing.chars = line;
+        editing.cursor = 1;
+        assert_eq!(editing.redraw("> ", 9), "\r> 東京都庁舎\x1b[J\x1b[1A\r\x1b[4C");
+        assert_eq!(editing.row, 0);
+        editing.cursor = 5;
+        assert_eq!(editing.redraw("> ", 9), "\r> 東京都庁舎\x1b[J\r\x1b[4C");
+        assert_eq!(editing.row, 1);
+        // Up from the row the cursor was left on first
+        editing.cursor = 0;
+        assert_eq!(editing.redraw("> ", 9), "\x1b[1A\r> 東京都庁舎\x1b[J\x1b[1A\r\x1b[2C");
+        assert_eq!(editing.leave("> ", 9), "\x1b[1B\r\n");
+
+        // Filling the row exactly moves the cursor down onto the next
+        editing.chars = "abcdefg".chars().collect();
+        editing.cursor = 7;
+        editing.row = 0;
+        assert_eq!(editing.redraw("> ", 9), "\r> abcdefg\x1b[J\r\n\r");
+        assert_eq!(editing.row, 1);
+    }
+}
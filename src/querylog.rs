@@ -0,0 +1,80 @@
+use super::*;
+use std::collections::BTreeMap;
+
+// The searches a --stdio server was asked for, appended to the --query-log file one per line as
+// "MATCHES\tQUERY", and mined for query completions. Queries are counted lowercased with their
+// whitespace collapsed, and only those that matched something are suggested, most asked first. The
+// file is read back when the server starts, so the counts carry over between runs.
+pub struct QueryLog {
+    file: fs::File,
+    // Times each query that matched something was asked
+    counts: BTreeMap<String, usize>
+}
+
+fn normalize(query: &str) -> String {
+    query.split_whitespace().collect::<Vec<&str>>().join(" ").to_lowercase()
+}
+
+impl QueryLog {
+    pub fn open(path: &str) -> Result<QueryLog, io::Error> {
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        match fs::read_to_string(path) {
+            Ok(logged) => for line in logged.lines() {
+                // Lines that aren't the log's own are skipped rather than refusing the log
+                if let Some((matches, query)) = line.split_once('\t') {
+                    if matches.parse::<usize>().is_ok_and(|matches| matches > 0) {
+                        *counts.entry(normalize(query)).or_default() += 1;
+                    }
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e)
+        }
+        let file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(QueryLog { file, counts })
+    }
+
+    // Newlines go with the rest of the whitespace, so a log line is always one search
+    pub fn record(&mut self, query: &str, matches: usize) -> Result<(), io::Error> {
+        let query = normalize(query);
+        if query.is_empty() {
+            return Ok(());
+        }
+        writeln!(self.file, "{}\t{}", matches, query)?;
+        if matches > 0 {
+            *self.counts.entry(query).or_default() += 1;
+        }
+        Ok(())
+    }
+
+    // Up to `limit` logged queries starting with `prefix`, with how many times each was asked
+    pub fn suggest(&self, prefix: &str, limit: usize) -> Vec<(String, usize)> {
+        let prefix = normalize(prefix);
+        let mut found: Vec<(&String, &usize)> = self.counts.range(prefix.clone()..)
+            .take_while(|(query, _)| query.starts_with(&prefix))
+            .collect();
+        found.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        found.into_iter().take(limit).map(|(query, count)| (query.clone(), *count)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::TempSource;
+
+    #[test]
+    fn popular_queries_that_matched_come_first_and_persist() {
+        let source = TempSource::new("queries", "log", "");
+        let path = source.to_str().unwrap();
+        let mut log = QueryLog::open(path).unwrap();
+        for (query, matches) in [("apple pie", 3), ("Apple   tart", 1), ("apple tart", 2), ("apple zzz", 0), ("pear", 4)] {
+            log.record(query, matches).unwrap();
+        }
+        let expected = vec![(String::from("apple tart"), 2), (String::from("apple pie"), 1)];
+        assert_eq!(log.suggest("APPLE ", 10), expected);
+        assert_eq!(log.suggest("apple", 1), expected[..1].to_vec());
+        drop(log);
+        assert_eq!(QueryLog::open(path).unwrap().suggest("apple", 10), expected);
+    }
+}
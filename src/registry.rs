@@ -0,0 +1,130 @@
+use crate::index::Index;
+use crate::indexers::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+// An index's id in its Registry. Ids aren't reused, so a DocKey kept from before its index was removed
+// or replaced finds nothing rather than another index's document.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct IndexId(u32);
+
+// A document in one of several indexes: document ids are only unique within an index
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DocKey {
+    pub index: IndexId,
+    pub doc: i32
+}
+
+// "index:doc", which FromStr reads back, for keys handed out in urls or JSON
+impl fmt::Display for DocKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.index.0, self.doc)
+    }
+}
+
+impl FromStr for DocKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DocKey, String> {
+        let (index, doc) = s.split_once(':').ok_or_else(|| format!("'{}' isn't a document key, expected index:doc", s))?;
+        match (index.parse::<u32>(), doc.parse::<i32>()) {
+            (Ok(index), Ok(doc)) => Ok(DocKey { index: IndexId(index), doc }),
+            _ => Err(format!("'{}' isn't a document key, expected index:doc", s))
+        }
+    }
+}
+
+// SearchResults from one of a Registry's indexes
+pub struct RegistryResults {
+    pub index: IndexId,
+    pub results: SearchResults
+}
+
+impl RegistryResults {
+    pub fn keys(&self) -> impl Iterator<Item = DocKey> + '_ {
+        self.results.ids().map(move |doc| DocKey { index: self.index, doc })
+    }
+}
+
+// Several indexes in one process, each under a name, whose results carry keys that lead back to the
+// index they came from
+#[derive(Default)]
+pub struct Registry {
+    indexes: HashMap<IndexId, (String, Index)>,
+    by_name: HashMap<String, IndexId>,
+    next_id: u32
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    // Adds `index` under `name` with a new id, dropping any index that had the name before
+    pub fn insert(&mut self, name: &str, index: Index) -> IndexId {
+        self.remove_named(name);
+        let id = IndexId(self.next_id);
+        self.next_id += 1;
+        self.indexes.insert(id, (String::from(name), index));
+        self.by_name.insert(String::from(name), id);
+        id
+    }
+
+    pub fn remove(&mut self, id: IndexId) -> Option<Index> {
+        let (name, index) = self.indexes.remove(&id)?;
+        self.by_name.remove(&name);
+        Some(index)
+    }
+
+    pub fn remove_named(&mut self, name: &str) -> Option<Index> {
+        let id = self.by_name.get(name).copied()?;
+        self.remove(id)
+    }
+
+    pub fn id(&self, name: &str) -> Option<IndexId> {
+        self.by_name.get(name).copied()
+    }
+
+    pub fn name(&self, id: IndexId) -> Option<&str> {
+        self.indexes.get(&id).map(|(name, _)| name.as_str())
+    }
+
+    pub fn get(&self, id: IndexId) -> Option<&Index> {
+        self.indexes.get(&id).map(|(_, index)| index)
+    }
+
+    pub fn get_mut(&mut self, id: IndexId) -> Option<&mut Index> {
+        self.indexes.get_mut(&id).map(|(_, index)| index)
+    }
+
+    // Ids and names of every index, in the order they were added
+    pub fn indexes(&self) -> Vec<(IndexId, &str)> {
+        let mut indexes: Vec<(IndexId, &str)> = self.indexes.iter().map(|(id, (name, _))| (*id, name.as_str())).collect();
+        indexes.sort();
+        indexes
+    }
+
+    // None when the key's index has been removed, as well as for ids it doesn't have
+    pub fn document(&self, key: DocKey) -> Option<Document> {
+        self.get(key.index)?.document(key.doc)
+    }
+
+    // Searches the index with `id`, empty if there's none
+    pub fn search(&self, id: IndexId, query: &str) -> Result<RegistryResults, QueryError> {
+        let results = match self.get(id) {
+            Some(index) => index.search(query)?,
+            None => SearchResults::default()
+        };
+        Ok(RegistryResults { index: id, results })
+    }
+
+    // Searches every index, in the order they were added, refused if any index refuses it
+    pub fn search_all(&self, query: &str) -> Result<Vec<RegistryResults>, QueryError> {
+        let mut all: Vec<RegistryResults> = Vec::new();
+        for (id, _) in self.indexes() {
+            all.push(self.search(id, query)?);
+        }
+        Ok(all)
+    }
+}
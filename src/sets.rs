@@ -0,0 +1,90 @@
+use super::*;
+use std::collections::BTreeMap;
+
+const USAGE: &str = "commands: :save NAME, :and A B, :or A B, :diff A B, :sets, :limit N [OFFSET], :stats, :explain QUERY, :help";
+
+// Result sets kept between REPL searches, as sorted document ids. Every search and every combination
+// becomes the last result, only the page printed after a :limit, which `:save NAME` keeps under a name:
+//
+//   :save A     saves the last result as A
+//   :and A B    documents in both A and B
+//   :or A B     documents in either
+//   :diff A B   documents in A but not B
+//   :sets       lists the saved sets
+//
+// Ids stay valid across the partial and full builds of --search-while-indexing, which number the
+// documents of a prefix the same, and across --refresh as long as the source is only appended to.
+pub struct ResultSets {
+    last: Vec<i32>,
+    saved: BTreeMap<String, Vec<i32>>
+}
+
+impl ResultSets {
+    pub fn new() -> Self {
+        ResultSets { last: Vec::new(), saved: BTreeMap::new() }
+    }
+
+    pub fn is_command(input: &str) -> bool {
+        input.trim_start().starts_with(':')
+    }
+
+    pub fn set_last(&mut self, mut ids: Vec<i32>) {
+        ids.sort_unstable();
+        ids.dedup();
+        self.last = ids;
+    }
+
+    fn get(&self, name: &str) -> Option<&Vec<i32>> {
+        let set = self.saved.get(name);
+        if set.is_none() {
+            println!("No saved set '{}'", name);
+        }
+        set
+    }
+
+    pub fn run(&mut self, word_index: &dyn DocumentIndexer, input: &str, format: Format) {
+        let words: Vec<&str> = input.split_whitespace().collect();
+        let combine: fn(&Vec<i32>, &Vec<i32>) -> Vec<i32> = match words.as_slice() {
+            [":save", name] => {
+                self.saved.insert(String::from(*name), self.last.clone());
+                println!("Saved {} documents as {}", self.last.len(), name);
+                return;
+            },
+            [":sets"] => {
+                if self.saved.is_empty() {
+                    println!("No saved sets");
+                }
+                for (name, ids) in self.saved.iter() {
+                    println!("{}\t{} documents", name, ids.len());
+                }
+                return;
+            },
+            [":and", _, _] => IdSet::and,
+            [":or", _, _] => IdSet::or,
+            [":diff", _, _] => IdSet::and_not,
+            _ => {
+                println!("{}", USAGE);
+                return;
+            }
+        };
+        let (a, b) = match (self.get(words[1]), self.get(words[2])) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return
+        };
+        let ids = combine(a, b);
+        let label = words.join(" ");
+        println!("{} documents", ids.len());
+        for id in ids.iter() {
+            if let Some(doc) = word_index.document(*id) {
+                print_match(format, &label, &doc);
+            }
+        }
+        self.last = ids;
+    }
+}
+
+impl Default for ResultSets {
+    fn default() -> Self {
+        ResultSets::new()
+    }
+}
@@ -0,0 +1,583 @@
+use super::*;
+use std::collections::{HashMap, HashSet};
+use std::io::BufRead;
+use serde_json::{json, Value};
+use fulltext::{IndexWriter, Searcher};
+
+// JSON-RPC 2.0 on stdin/stdout, one message per line, for editor plugins that keep the engine
+// running as a subprocess. Indexes stay loaded between requests, keyed by path.
+//
+//   open-index {"index": PATH, "format"?: FORMAT}                -> {"documents": N, "terms": N}
+//   search     {"query": QUERY, "index"?: PATH, "limit"?: N, "offset"?: N}
+//                                                                -> [{"term", "terms", "score", "id", "title", "url", "frequencies"?}]
+//   suggest    {"prefix": PREFIX, "index"?: PATH, "limit"?: N}   -> [{"term", "documents"}]
+//   suggest-titles {"prefix": PREFIX, "index"?: PATH, "limit"?: N} -> [{"title", "id"}]
+//   suggest-queries {"prefix": PREFIX, "limit"?: N}             -> [{"query", "count"}]
+//   cooccur    {"term": TERM, "index"?: PATH, "limit"?: N}       -> [{"term", "documents"}]
+//   add-documents {"documents": [{"title", "url", "text"}], "index"?: PATH} -> {"documents": N, "pending": N}
+//   delete-documents {"ids": [N], "index"?: PATH}                 -> {"deleted": N, "documents": N}
+//   compact    {"index"?: PATH}                                  -> {"documents": N, "compacted": N}
+//   merge-policy {"max_segments"?: N, "merge_factor"?: N, "max_bytes_per_sec"?: N}
+//                                                                -> {"max_segments": N, "merge_factor": N, "max_bytes_per_sec": N}
+//   threads    {"parse_threads"?: N, "index_threads"?: N}        -> {"parse_threads": N, "index_threads": N, "resized": N}
+//   metrics    {}                                                -> {"merge": {"index", "documents", "written", "total", "seconds"} | null,
+//                                                                    "merges": N, "failed_merges": N, "max_bytes_per_sec": N,
+//                                                                    "pending": {PATH: N}}
+//   feed       {"query": QUERY, "index"?: PATH, "since"?: N, "format"?: "json" | "rss"}
+//                                                                -> {"items": [{"id", "title", "url"}], "next": N}
+//                                                                   or {"rss": XML, "next": N}
+//
+// "index" defaults to the index opened last, which starts out as --index.
+//
+// search and feed go through a Searcher over the snapshot of their index published after it was
+// opened and after each add-documents or delete-documents, once saved, so they see what its cache
+// has: the last full write and the segment files since. The snapshot is dropped before the index is
+// changed again, so nothing it shares has to be copied for the change. Backends without snapshots
+// can't be changed here and are searched as they are.
+//
+// With --query-log every search's query is appended to the log, and suggest-queries completes whole
+// queries from the ones asked before that matched something, most asked first, rather than the
+// last word from the index's terms as suggest does. See QueryLog.
+//
+// threads sets the --parse-threads and --index-threads of indexes opened from then on, and resizes
+// the thread pools of the open ones that have their own, counted in "resized", see
+// DocumentIndexer::set_threads. The threadpool backends build, merge and search on theirs.
+//
+// A feed is a saved search that is polled: documents get increasing ids as they are added, so each
+// poll returns the matches with ids from "since" on, and "next" is where the following poll starts.
+// Without "since" the server remembers "next" per index and query, so the first poll returns every
+// match and later ones only what add-documents brought in since. Reopening an index forgets them.
+//
+// Each add-documents call is saved to a cached index straight away as a segment file of its own,
+// and deleted ids to its .del file, without rewriting anything already on disk. Segments are only
+// folded into the rest of the cache, which takes as long to write as after a build, when the merge
+// policy calls for it or on compact. "pending" counts the documents waiting for that. Raising the
+// policy's limits at busy times and calling compact later keeps the heavy writes off-peak; see
+// MergePolicy. Deleted documents keep their ids, and "documents" still counts them.
+//
+// When the policy calls for it, the write runs as a BackgroundMerge between requests rather than
+// holding up add-documents: it's paused while each request is handled, and otherwise writes at most
+// "max_bytes_per_sec", from --merge-rate, so searches don't wait on the disk. One runs at a time.
+// Documents added to or deleted from its index meanwhile are saved to segment files as usual, the
+// deletions again once it's done, since its .del file replaces theirs. compact waits for it, then
+// writes straight away whatever is still pending, unthrottled. "metrics" shows how far along
+// it is.
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+const DEFAULT_LIMIT: usize = 10;
+const FEED_TITLE_PREFIX: &str = "fulltext: ";
+
+struct RpcError {
+    code: i64,
+    message: String
+}
+
+impl RpcError {
+    fn new(code: i64, message: String) -> Self {
+        RpcError { code, message }
+    }
+}
+
+fn string_param<'a>(params: &'a Value, name: &str) -> Result<&'a str, RpcError> {
+    params.get(name).and_then(Value::as_str)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("missing string param '{}'", name)))
+}
+
+fn limit_param(params: &Value) -> usize {
+    params.get("limit").and_then(Value::as_u64).map(|l| l as usize).unwrap_or(DEFAULT_LIMIT)
+}
+
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+// RSS 2.0 with the document id as a guid that isn't a link, since urls can be empty or shared
+fn rss_feed(query: &str, docs: &[Document]) -> String {
+    let mut rss = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<rss version=\"2.0\"><channel><title>{}</title><description>{}</description>",
+                          xml_escape(&format!("{}{}", FEED_TITLE_PREFIX, query)), xml_escape(query));
+    for doc in docs {
+        rss.push_str(&format!("<item><title>{}</title><link>{}</link><guid isPermaLink=\"false\">{}</guid></item>",
+                              xml_escape(&doc.title), xml_escape(&doc.url), doc.id));
+    }
+    rss.push_str("</channel></rss>");
+    rss
+}
+
+fn term_counts(terms: Vec<(String, usize)>) -> Value {
+    terms.into_iter().map(|(term, documents)| json!({"term": term, "documents": documents})).collect()
+}
+
+struct Server<'a> {
+    matches: &'a clap::ArgMatches<'a>,
+    // Only the query rewrites and --titles-only apply, results go out as JSON
+    output: &'a OutputOptions,
+    backend: &'a str,
+    options: IndexOptions,
+    num_parse_threads: usize,
+    num_index_threads: usize,
+    indexes: HashMap<String, SharedIndex>,
+    // What search and feed go through, see publish
+    published: HashMap<String, IndexWriter>,
+    // Indexes whose cache is rewritten after add-documents, locked against other writers while open
+    cached: HashSet<String>,
+    locks: HashMap<String, CacheLock>,
+    // Where the next poll of each (index, query) feed starts
+    feeds: HashMap<(String, String), i32>,
+    merge_policy: MergePolicy,
+    throttle: MergeThrottle,
+    merge: Option<BackgroundMerge>,
+    merges: usize,
+    failed_merges: usize,
+    query_log: Option<QueryLog>,
+    current: String
+}
+
+impl Server<'_> {
+    // Same as a command line run over `path`: the cache when it's usable, otherwise a fresh build
+    // that is then written to the cache
+    fn open_index(&mut self, path: &str, format: Format) -> Result<SharedIndex, io::Error> {
+        let mut options = self.options.clone();
+        options.format = format;
+        let schema = options.schema;
+        let use_cache = uses_cache(format, &options, path);
+        let write_cache = use_cache && writes_cache(self.matches);
+        self.feeds.retain(|(index, _), _| index != path);
+        if write_cache {
+            if !self.locks.contains_key(path) {
+                self.locks.insert(String::from(path), CacheLock::acquire(Path::new(path))?);
+            }
+            self.cached.insert(String::from(path));
+        } else {
+            self.locks.remove(path);
+            self.cached.remove(path);
+        }
+        let mut word_index = new_indexer(self.backend, options, self.num_parse_threads, self.num_index_threads);
+        if use_cache && reads_cache(self.matches) {
+            match try_build_from_cache(word_index.as_mut(), path, schema) {
+                Ok(()) => return Ok(word_index),
+                Err(e) => println!("Not using the cache of {}, which is {}", path, e)
+            }
+        }
+        word_index.build_from_file_contents(path, read_source(self.matches, format, path)?)?;
+        if write_cache {
+            if let Err(e) = SerializedIndex::write_index_to_path(path, word_index.as_ref(), cache_sync_policy(self.matches)) {
+                println!("Failed to write index: {:?}", e);
+            }
+        }
+        Ok(word_index)
+    }
+
+    // Takes in the background merge once it's done, or waits for it when `wait`, and saves again what
+    // was deleted from its index meanwhile, which the merge's .del file left out
+    fn finish_merge(&mut self, wait: bool) {
+        if !self.merge.as_ref().is_some_and(|merge| wait || merge.is_finished()) {
+            return;
+        }
+        let merge = self.merge.take().unwrap();
+        let path = String::from(merge.path());
+        let word_index = match self.indexes.get_mut(&path) {
+            Some(word_index) => word_index,
+            None => return
+        };
+        match merge.finish(word_index.as_mut()) {
+            Ok(()) => self.merges += 1,
+            Err(e) => {
+                self.failed_merges += 1;
+                println!("Failed to merge {}: {}", path, e);
+            }
+        }
+        if word_index.pending_additions().1 > 0 || word_index.num_deleted() > 0 {
+            if let Err(e) = SerializedIndex::write_additions_to_path(&path, word_index.as_ref(), cache_sync_policy(self.matches)) {
+                println!("Failed to write index: {:?}", e);
+            }
+        }
+    }
+
+    // Takes a snapshot of the index at `path` as it is now for searches to go through
+    fn publish(&mut self, path: &str) {
+        let snapshot = match self.indexes.get(path).and_then(|word_index| word_index.snapshot()) {
+            Some(snapshot) => snapshot,
+            None => return
+        };
+        let options = self.options.clone();
+        self.published.entry(String::from(path)).or_insert_with(|| IndexWriter::new(options)).publish(snapshot);
+    }
+
+    // Before the index at `path` is changed, so that changing it doesn't copy what its snapshot shares
+    fn unpublish(&mut self, path: &str) {
+        self.published.remove(path);
+    }
+
+    // None for backends without snapshots, which are searched as they are
+    fn searcher(&self, params: &Value) -> Option<Searcher> {
+        self.published.get(self.index_path(params)).map(|writer| writer.reader().searcher())
+    }
+
+    fn merging(&self, path: &str) -> bool {
+        self.merge.as_ref().is_some_and(|merge| merge.path() == path)
+    }
+
+    fn index_path<'p>(&'p self, params: &'p Value) -> &'p str {
+        params.get("index").and_then(Value::as_str).unwrap_or(&self.current)
+    }
+
+    fn index(&self, params: &Value) -> Result<&dyn DocumentIndexer, RpcError> {
+        let path = self.index_path(params);
+        self.indexes.get(path).map(|index| index.as_ref() as &dyn DocumentIndexer)
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("index '{}' is not open", path)))
+    }
+
+    fn open(&mut self, params: &Value) -> Result<Value, RpcError> {
+        let path = string_param(params, "index")?;
+        let format = match params.get("format").and_then(Value::as_str) {
+            Some(name) => parse_format(name).ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("unknown format '{}'", name)))?,
+            None if is_archive(path) => Format::Archive,
+            None => parse_format(self.matches.value_of("format").unwrap()).unwrap()
+        };
+        if self.merging(path) {
+            self.finish_merge(true);
+        }
+        let word_index = self.open_index(path, format).map_err(|e| RpcError::new(SERVER_ERROR, format!("failed to open {}: {}", path, e)))?;
+        let result = json!({"documents": word_index.num_documents(), "terms": word_index.num_tokens()});
+        self.unpublish(path);
+        self.indexes.insert(String::from(path), word_index);
+        self.publish(path);
+        self.current = String::from(path);
+        Ok(result)
+    }
+
+    fn search(&mut self, params: &Value) -> Result<Value, RpcError> {
+        let query = string_param(params, "query")?;
+        let terms = rewrite_query(self.output, self.output.show_rewrites, split_query(query));
+        let page = Page {
+            offset: params.get("offset").and_then(Value::as_u64).unwrap_or(0) as usize,
+            limit: params.get("limit").and_then(Value::as_u64).map(|limit| limit as usize)
+        };
+        let word_index = self.index(params)?;
+        let searcher = self.searcher(params);
+        let terms: Vec<&str> = terms.iter().map(String::as_str).collect();
+        let results = match &searcher {
+            Some(searcher) => searcher.search_terms(terms, page),
+            None => word_index.search_page(terms, page)
+        }.map_err(|e| RpcError::new(SERVER_ERROR, format!("search refused: {}", e)))?;
+        let total = results.total;
+        let hits: Value = results.hits.into_iter()
+            .map(|hit| {
+                let mut value = json!({"term": hit.label(), "terms": hit.terms, "score": hit.score, "id": hit.doc.id, "title": hit.doc.title, "url": hit.doc.url});
+                let (segment, id) = searcher.as_ref().and_then(|searcher| searcher.segment_of(hit.doc.id)).unwrap_or((word_index, hit.doc.id));
+                if let Some(frequencies) = term_frequencies(segment, &hit.terms, id) {
+                    value["frequencies"] = Value::from(frequencies);
+                }
+                value
+            })
+            .collect();
+        if let Some(log) = &mut self.query_log {
+            if let Err(e) = log.record(query, total) {
+                println!("Failed to log query: {}", e);
+            }
+        }
+        Ok(hits)
+    }
+
+    // Completes the last word being typed from the index's own terms, which are lowercased and, in
+    // text mode, stemmed
+    fn suggest(&self, params: &Value) -> Result<Value, RpcError> {
+        let prefix = string_param(params, "prefix")?.to_lowercase();
+        let terms = self.index(params)?.terms_with_prefix(&prefix, limit_param(params));
+        Ok(term_counts(terms))
+    }
+
+    // Completes whole document titles, as typed into a "go to page" box
+    fn suggest_titles(&self, params: &Value) -> Result<Value, RpcError> {
+        let titles = self.index(params)?.suggest_titles(string_param(params, "prefix")?, limit_param(params));
+        Ok(titles.into_iter().map(|(title, id)| json!({"title": title, "id": id})).collect())
+    }
+
+    // Completes a whole query from the ones logged, as typed into a search box
+    fn suggest_queries(&self, params: &Value) -> Result<Value, RpcError> {
+        let log = self.query_log.as_ref().ok_or_else(|| RpcError::new(SERVER_ERROR, String::from("queries aren't logged, start the server with --query-log")))?;
+        let queries = log.suggest(string_param(params, "prefix")?, limit_param(params));
+        Ok(queries.into_iter().map(|(query, count)| json!({"query": query, "count": count})).collect())
+    }
+
+    // Terms sharing the most documents with any of the analyzed terms of "term"; "documents" is how
+    // many they share
+    fn cooccur(&self, params: &Value) -> Result<Value, RpcError> {
+        let word_index = self.index(params)?;
+        let mut terms: Vec<(String, usize)> = Vec::new();
+        for term in word_index.analyze_text(string_param(params, "term")?) {
+            terms.extend(word_index.cooccurring_terms(&term, limit_param(params)));
+        }
+        Ok(term_counts(terms))
+    }
+
+    // Only the rayon backend can add to a built index
+    fn add_documents(&mut self, params: &Value) -> Result<Value, RpcError> {
+        if self.backend != "rayon" {
+            return Err(RpcError::new(SERVER_ERROR, format!("the {} backend can't add documents", self.backend)));
+        }
+        let docs = params.get("documents").and_then(Value::as_array)
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, String::from("missing array param 'documents'")))?;
+        let docs = docs.iter().map(|doc| Ok(Document {
+            title: String::from(string_param(doc, "title")?),
+            url: String::from(doc.get("url").and_then(Value::as_str).unwrap_or("")),
+            text: String::from(string_param(doc, "text")?),
+            id: 0
+        })).collect::<Result<Vec<Document>, RpcError>>()?;
+        let path = String::from(self.index_path(params));
+        self.unpublish(&path);
+        let word_index = self.indexes.get_mut(&path)
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("index '{}' is not open", path)))?;
+        word_index.add_documents(docs);
+        let mut written = Ok(());
+        if self.cached.contains(&path) {
+            let (sync, merge) = (cache_sync_policy(self.matches), &mut self.merge);
+            written = match merge.is_none() && self.merge_policy.should_compact(word_index.as_ref()) {
+                true => BackgroundMerge::start(&path, word_index.as_ref(), sync, &self.throttle).map(|started| *merge = Some(started)),
+                false => SerializedIndex::write_additions_to_path(&path, word_index.as_ref(), sync)
+            };
+        }
+        let result = json!({"documents": word_index.num_documents(), "pending": word_index.pending_additions().1});
+        self.publish(&path);
+        written.map_err(|e| RpcError::new(SERVER_ERROR, format!("added, but failed to write index: {}", e)))?;
+        Ok(result)
+    }
+
+    // Like add-documents, only the rayon backend can delete. Ids that aren't there are ignored.
+    fn delete_documents(&mut self, params: &Value) -> Result<Value, RpcError> {
+        if self.backend != "rayon" {
+            return Err(RpcError::new(SERVER_ERROR, format!("the {} backend can't delete documents", self.backend)));
+        }
+        let ids = params.get("ids").and_then(Value::as_array)
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, String::from("missing array param 'ids'")))?;
+        let ids = ids.iter().map(|id| id.as_i64().map(|id| id as i32)
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("bad document id {}", id))))
+            .collect::<Result<Vec<i32>, RpcError>>()?;
+        let path = String::from(self.index_path(params));
+        self.unpublish(&path);
+        let word_index = self.indexes.get_mut(&path)
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("index '{}' is not open", path)))?;
+        let deleted = word_index.delete_documents(&ids);
+        let written = match deleted > 0 && self.cached.contains(&path) {
+            true => SerializedIndex::write_additions_to_path(&path, word_index.as_ref(), cache_sync_policy(self.matches)),
+            false => Ok(())
+        };
+        let result = json!({"deleted": deleted, "documents": word_index.num_documents()});
+        self.publish(&path);
+        written.map_err(|e| RpcError::new(SERVER_ERROR, format!("deleted, but failed to write index: {}", e)))?;
+        Ok(result)
+    }
+
+    // Folds the pending additions into the cache now, whatever the merge policy
+    fn compact(&mut self, params: &Value) -> Result<Value, RpcError> {
+        let path = String::from(self.index_path(params));
+        if self.merging(&path) {
+            self.finish_merge(true);
+        }
+        let word_index = self.indexes.get_mut(&path)
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("index '{}' is not open", path)))?;
+        if !self.cached.contains(&path) {
+            return Err(RpcError::new(INVALID_PARAMS, format!("index '{}' isn't cached", path)));
+        }
+        let pending = word_index.pending_additions();
+        if pending.1 > 0 {
+            SerializedIndex::write_index_to_path(&path, word_index.as_ref(), cache_sync_policy(self.matches))
+                .map_err(|e| RpcError::new(SERVER_ERROR, format!("failed to write index: {}", e)))?;
+            word_index.mark_compacted(pending);
+        }
+        Ok(json!({"documents": word_index.num_documents(), "compacted": pending.1}))
+    }
+
+    // Applies to every open index from the next add-documents on, and the rate to a merge already running
+    fn set_merge_policy(&mut self, params: &Value) -> Result<Value, RpcError> {
+        let param = |name: &str| match params.get(name) {
+            None => Ok(None),
+            Some(value) => value.as_u64().map(|n| Some(n as usize))
+                .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("'{}' must be a non-negative integer", name)))
+        };
+        let (max_segments, merge_factor, rate) = (param("max_segments")?, param("merge_factor")?, param("max_bytes_per_sec")?);
+        self.merge_policy.max_segments = max_segments.unwrap_or(self.merge_policy.max_segments);
+        self.merge_policy.merge_factor = merge_factor.unwrap_or(self.merge_policy.merge_factor);
+        if let Some(rate) = rate {
+            self.throttle.set_rate(rate as u64);
+        }
+        Ok(json!({"max_segments": self.merge_policy.max_segments, "merge_factor": self.merge_policy.merge_factor, "max_bytes_per_sec": self.throttle.rate()}))
+    }
+
+    fn set_threads(&mut self, params: &Value) -> Result<Value, RpcError> {
+        let param = |name: &str, current: usize| match params.get(name) {
+            None => Ok(current),
+            Some(value) => value.as_u64().filter(|n| *n > 0).map(|n| n as usize)
+                .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("'{}' must be a positive integer", name)))
+        };
+        let (parse_threads, index_threads) = (param("parse_threads", self.num_parse_threads)?, param("index_threads", self.num_index_threads)?);
+        self.num_parse_threads = parse_threads;
+        self.num_index_threads = index_threads;
+        let resized = self.indexes.values_mut().map(|word_index| word_index.set_threads(parse_threads, index_threads)).filter(|resized| *resized).count();
+        Ok(json!({"parse_threads": parse_threads, "index_threads": index_threads, "resized": resized}))
+    }
+
+    // The background merge's progress in bytes, and the documents each cached index has pending
+    fn metrics(&self) -> Result<Value, RpcError> {
+        let merge = self.merge.as_ref().map(|merge| {
+            let (written, total) = merge.progress();
+            json!({"index": merge.path(), "documents": merge.pending().1, "written": written, "total": total, "seconds": merge.elapsed().as_secs_f64()})
+        });
+        let pending: serde_json::Map<String, Value> = self.indexes.iter()
+            .filter(|(path, _)| self.cached.contains(*path))
+            .map(|(path, word_index)| (path.clone(), json!(word_index.pending_additions().1)))
+            .collect();
+        Ok(json!({"merge": merge, "merges": self.merges, "failed_merges": self.failed_merges, "max_bytes_per_sec": self.throttle.rate(), "pending": pending}))
+    }
+
+    // Matches of "query" among the documents from the feed's cursor on, oldest first, once each
+    // however many terms they match
+    fn feed(&mut self, params: &Value) -> Result<Value, RpcError> {
+        let query = string_param(params, "query")?;
+        let rss = match params.get("format").and_then(Value::as_str) {
+            None | Some("json") => false,
+            Some("rss") => true,
+            Some(name) => return Err(RpcError::new(INVALID_PARAMS, format!("unknown feed format '{}'", name)))
+        };
+        let key = (String::from(self.index_path(params)), String::from(query));
+        let since = match params.get("since").and_then(Value::as_i64) {
+            Some(since) => since as i32,
+            None => self.feeds.get(&key).copied().unwrap_or(0)
+        };
+        let word_index = self.index(params)?;
+        let terms = rewrite_query(self.output, self.output.show_rewrites, split_query(query));
+        let terms: Vec<&str> = terms.iter().map(String::as_str).collect();
+        let results = match self.searcher(params) {
+            Some(searcher) => searcher.search_terms(terms, Page::default()),
+            None => word_index.search(terms)
+        };
+        let mut docs: Vec<Document> = results
+            .map_err(|e| RpcError::new(SERVER_ERROR, format!("search refused: {}", e)))?
+            .into_documents()
+            .filter(|doc| doc.id >= since)
+            .collect();
+        docs.sort_by_key(|doc| doc.id);
+        docs.dedup_by_key(|doc| doc.id);
+        let next = since.max(word_index.num_documents() as i32);
+        self.feeds.insert(key, next);
+        Ok(if rss {
+            json!({"rss": rss_feed(query, &docs), "next": next})
+        } else {
+            let items: Vec<Value> = docs.iter().map(|doc| json!({"id": doc.id, "title": doc.title, "url": doc.url})).collect();
+            json!({"items": items, "next": next})
+        })
+    }
+
+    // None for notifications, which get no response
+    fn handle(&mut self, line: &str) -> Option<Value> {
+        let request: Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => return Some(json!({"jsonrpc": "2.0", "id": null, "error": {"code": PARSE_ERROR, "message": e.to_string()}}))
+        };
+        let id = request.get("id").cloned();
+        let params = request.get("params").cloned().unwrap_or_else(|| json!({}));
+        self.finish_merge(false);
+        self.throttle.pause();
+        let result = match request.get("method").and_then(Value::as_str) {
+            Some("open-index") => self.open(&params),
+            Some("search") => self.search(&params),
+            Some("suggest") => self.suggest(&params),
+            Some("suggest-titles") => self.suggest_titles(&params),
+            Some("suggest-queries") => self.suggest_queries(&params),
+            Some("cooccur") => self.cooccur(&params),
+            Some("add-documents") => self.add_documents(&params),
+            Some("delete-documents") => self.delete_documents(&params),
+            Some("feed") => self.feed(&params),
+            Some("compact") => self.compact(&params),
+            Some("merge-policy") => self.set_merge_policy(&params),
+            Some("threads") => self.set_threads(&params),
+            Some("metrics") => self.metrics(),
+            Some(method) => Err(RpcError::new(METHOD_NOT_FOUND, format!("unknown method '{}'", method))),
+            None => Err(RpcError::new(INVALID_REQUEST, String::from("missing method")))
+        };
+        self.throttle.resume();
+        let id = id?;
+        Some(match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err(e) => json!({"jsonrpc": "2.0", "id": id, "error": {"code": e.code, "message": e.message}})
+        })
+    }
+}
+
+// Indexers report progress with println!, so while serving, or printing --output json, stdout is
+// pointed at stderr and responses go to a duplicate of the original stdout
+#[cfg(unix)]
+pub fn take_stdout() -> Result<Box<dyn Write>, io::Error> {
+    use std::os::unix::io::FromRawFd;
+    io::stdout().flush()?;
+    unsafe {
+        let responses = libc::dup(1);
+        if responses < 0 || libc::dup2(2, 1) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Box::new(fs::File::from_raw_fd(responses)))
+    }
+}
+
+#[cfg(not(unix))]
+pub fn take_stdout() -> Result<Box<dyn Write>, io::Error> {
+    Ok(Box::new(io::stdout()))
+}
+
+pub fn serve(matches: &clap::ArgMatches, output: &OutputOptions, backend: &str, options: IndexOptions, num_parse_threads: usize, num_index_threads: usize) -> Result<(), io::Error> {
+    let mut responses = take_stdout()?;
+    let mut server = Server {
+        matches,
+        output,
+        backend,
+        options,
+        num_parse_threads,
+        num_index_threads,
+        indexes: HashMap::new(),
+        published: HashMap::new(),
+        cached: HashSet::new(),
+        locks: HashMap::new(),
+        feeds: HashMap::new(),
+        merge_policy: MergePolicy::default(),
+        throttle: MergeThrottle::new(matches.value_of("merge-rate").map(|mb| mb.parse::<u64>().unwrap() << 20).unwrap_or(0)),
+        merge: None,
+        merges: 0,
+        failed_merges: 0,
+        query_log: matches.value_of("query-log").map(QueryLog::open).transpose()?,
+        current: String::new()
+    };
+    let index_filename = matches.value_of("index").or_else(|| matches.value_of("index-dir")).unwrap();
+    let format = server.options.format;
+    let word_index = server.open_index(index_filename, format)?;
+    server.indexes.insert(String::from(index_filename), word_index);
+    server.publish(index_filename);
+    server.current = String::from(index_filename);
+
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = server.handle(&line) {
+            writeln!(responses, "{}", response)?;
+            responses.flush()?;
+        }
+    }
+    server.finish_merge(true);
+    Ok(())
+}
@@ -0,0 +1,261 @@
+use crate::index::Index;
+use crate::indexers::*;
+use std::io;
+use std::sync::{Arc, RwLock};
+
+// A segment of a snapshot, never changed once published: an Index of the writer's own, or what the
+// --stdio server published of an index it keeps changing, see DocumentIndexer::snapshot
+type Segment = Arc<dyn DocumentIndexer + Send + Sync>;
+
+// An index as a list of immutable segments searched as one. Document ids run on across the segments
+// in order.
+struct Snapshot {
+    // Each segment with the id its first document has in the snapshot
+    segments: Vec<(i32, Segment)>,
+    generation: u64
+}
+
+impl Snapshot {
+    fn new(segments: &[Segment], generation: u64) -> Snapshot {
+        let mut base: i32 = 0;
+        let segments = segments.iter().map(|segment| {
+            let start = base;
+            base += segment.num_documents() as i32;
+            (start, Arc::clone(segment))
+        }).collect();
+        Snapshot { segments, generation }
+    }
+
+    // The segment with the document that has `id` in the snapshot, and its id there
+    fn locate(&self, id: i32) -> Option<(&dyn DocumentIndexer, i32)> {
+        let i = self.segments.partition_point(|(base, _)| *base <= id).checked_sub(1)?;
+        let (base, segment) = &self.segments[i];
+        Some((segment.as_ref() as &dyn DocumentIndexer, id - base))
+    }
+}
+
+// Builds and replaces the segments readers search, on whichever thread it's moved to. Nothing it
+// does is seen by readers until commit, which swaps in a new snapshot in one step; searches already
+// running finish on the one they started with. There's one writer per index, it isn't Clone. Its
+// segments are only kept in memory; --stdio saves its indexes to their caches itself and publishes a
+// snapshot of each once saved, see publish.
+pub struct IndexWriter {
+    options: IndexOptions,
+    // What the next commit publishes
+    segments: Vec<Segment>,
+    published: Arc<RwLock<Arc<Snapshot>>>
+}
+
+impl IndexWriter {
+    // An index with no documents yet. `options` are what add_documents builds segments with.
+    pub fn new(options: IndexOptions) -> IndexWriter {
+        IndexWriter { options, segments: Vec::new(), published: Arc::new(RwLock::new(Arc::new(Snapshot::new(&[], 0)))) }
+    }
+
+    // Starts from `index`, committed, which should have been built with `options` so the segments
+    // added after analyze as it did
+    pub fn from_index(index: Index, options: IndexOptions) -> IndexWriter {
+        let mut writer = IndexWriter::new(options);
+        writer.replace(index);
+        writer.commit();
+        writer
+    }
+
+    // A reader of what this writer commits, for as many threads as need one
+    pub fn reader(&self) -> IndexReader {
+        IndexReader { published: Arc::clone(&self.published) }
+    }
+
+    // Indexes `docs` as a new segment, numbered after the documents of the segments before it. Err,
+    // adding nothing, when the segment's build fails, see Index::from_contents.
+    pub fn add_documents(&mut self, docs: impl IntoIterator<Item = InputDoc>) -> Result<(), io::Error> {
+        let segment = Index::build_from_documents("segment", docs, self.options.clone())?;
+        if segment.num_documents() > 0 {
+            self.segments.push(Arc::new(segment.into_indexer()));
+        }
+        Ok(())
+    }
+
+    // Drops every segment for `index`, as after a full rebuild built alongside, renumbering the
+    // documents from 0
+    pub fn replace(&mut self, index: Index) {
+        self.segments = vec![Arc::new(index.into_indexer())];
+    }
+
+    // Commits `snapshot` as the only segment, for an index changed in place elsewhere that can share
+    // what it has built
+    pub fn publish(&mut self, snapshot: Box<dyn DocumentIndexer + Send + Sync>) -> u64 {
+        self.segments = vec![Arc::from(snapshot)];
+        self.commit()
+    }
+
+    // Publishes the segments as they are now to every reader, and returns the new generation
+    pub fn commit(&mut self) -> u64 {
+        let mut published = self.published.write().unwrap();
+        let generation = published.generation + 1;
+        *published = Arc::new(Snapshot::new(&self.segments, generation));
+        generation
+    }
+
+    // Drops what was added or replaced since the last commit
+    pub fn rollback(&mut self) {
+        self.segments = self.published.read().unwrap().segments.iter().map(|(_, segment)| Arc::clone(segment)).collect();
+    }
+}
+
+// Hands out Searchers over the writer's last commit. Clones share it, and it outlives the writer.
+#[derive(Clone)]
+pub struct IndexReader {
+    published: Arc<RwLock<Arc<Snapshot>>>
+}
+
+impl IndexReader {
+    // The lock is only held to clone the snapshot's Arc, so searches never wait on a build
+    pub fn searcher(&self) -> Searcher {
+        Searcher { snapshot: Arc::clone(&self.published.read().unwrap()) }
+    }
+}
+
+// One commit of an index, unchanged however long it's kept; take a new one from the reader to see
+// later commits. Searches go through every segment and merge their hits, best first, ties in
+// segment order.
+pub struct Searcher {
+    snapshot: Arc<Snapshot>
+}
+
+impl Searcher {
+    // 0 before the first commit
+    pub fn generation(&self) -> u64 {
+        self.snapshot.generation
+    }
+
+    pub fn num_segments(&self) -> usize {
+        self.snapshot.segments.len()
+    }
+
+    // Err when the query goes over the limits in any segment
+    pub fn search(&self, query: &str) -> Result<SearchResults, QueryError> {
+        self.search_page(query, Page::default())
+    }
+
+    pub fn search_page(&self, query: &str, page: Page) -> Result<SearchResults, QueryError> {
+        self.search_terms(split_query(query), page)
+    }
+
+    // search_page for a query already split into terms. Each segment returns its best offset + limit
+    // hits, of which the page is taken.
+    pub fn search_terms(&self, terms: Vec<&str>, page: Page) -> Result<SearchResults, QueryError> {
+        let segment_page = Page { offset: 0, limit: page.limit.map(|limit| page.offset.saturating_add(limit)) };
+        let mut merged = SearchResults::default();
+        for (base, segment) in &self.snapshot.segments {
+            let results = segment.search_page(terms.clone(), segment_page)?;
+            merged.total += results.total;
+            merged.missing += results.missing;
+            for term in results.terms {
+                if !merged.terms.contains(&term) {
+                    merged.terms.push(term);
+                }
+            }
+            merged.hits.extend(results.hits.into_iter().map(|mut hit| {
+                hit.doc.id += base;
+                hit
+            }));
+        }
+        merged.hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+        Ok(merged.into_page(page))
+    }
+
+    pub fn document(&self, id: i32) -> Option<Document> {
+        let (segment, local) = self.snapshot.locate(id)?;
+        segment.document(local).map(|doc| Document { id, ..doc })
+    }
+
+    // Text around where `hit`, one of this searcher's, matched its terms
+    pub fn snippet(&self, hit: &Hit, highlight: Highlight) -> String {
+        match self.snapshot.locate(hit.doc.id) {
+            Some((segment, local)) => match_snippet(segment, &hit.label(), &Document { id: local, ..hit.doc.clone() }, highlight),
+            None => String::new()
+        }
+    }
+
+    // The segment with the document that has `id` here, and its id there
+    pub fn segment_of(&self, id: i32) -> Option<(&dyn DocumentIndexer, i32)> {
+        self.snapshot.locate(id)
+    }
+
+    pub fn num_documents(&self) -> usize {
+        self.snapshot.segments.iter().map(|(_, segment)| segment.num_documents()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn docs(texts: &[&str]) -> Vec<InputDoc> {
+        texts.iter().map(|text| InputDoc { title: String::from(*text), url: String::new(), text: String::from(*text) }).collect()
+    }
+
+    #[test]
+    fn searchers_keep_their_commit_while_the_writer_goes_on() {
+        let mut writer = IndexWriter::new(IndexOptions::default());
+        let reader = writer.reader();
+        writer.add_documents(docs(&["apple pie", "pear tart"])).unwrap();
+        assert_eq!(reader.searcher().num_documents(), 0);
+        assert_eq!(writer.commit(), 1);
+
+        let before = reader.searcher();
+        let handle = std::thread::spawn(move || {
+            writer.add_documents(docs(&["apple jam", "apple tart"])).unwrap();
+            writer.commit();
+            writer
+        });
+        let mut writer = handle.join().unwrap();
+        assert_eq!(before.search("apple").unwrap().total, 1);
+
+        let after = reader.searcher();
+        assert_eq!((after.generation(), after.num_segments()), (2, 2));
+        let results = after.search("apple tart").unwrap();
+        let ids: Vec<i32> = results.ids().collect();
+        // "apple tart" matches both terms and ranks first, then the rest in segment order
+        assert_eq!(ids, vec![3, 0, 1, 2]);
+        assert_eq!(after.document(3).unwrap().text, "apple tart");
+        assert_eq!(after.search_page("apple tart", Page { offset: 1, limit: Some(2) }).unwrap().ids().collect::<Vec<i32>>(), vec![0, 1]);
+
+        writer.replace(Index::build_from_documents("rebuilt", docs(&["plum"]), IndexOptions::default()).unwrap());
+        writer.rollback();
+        writer.commit();
+        assert_eq!(reader.searcher().num_documents(), 4);
+        writer.replace(Index::build_from_documents("rebuilt", docs(&["plum"]), IndexOptions::default()).unwrap());
+        writer.commit();
+        assert_eq!((reader.searcher().num_documents(), after.num_documents()), (1, 4));
+    }
+
+    #[test]
+    fn published_snapshots_keep_their_documents_while_the_index_changes() {
+        let mut live = Index::build_from_documents("live", docs(&["apple pie", "pear tart"]), IndexOptions::default()).unwrap().into_indexer();
+        let mut writer = IndexWriter::new(IndexOptions::default());
+        let reader = writer.reader();
+        assert_eq!(writer.publish(live.snapshot().unwrap()), 1);
+        let before = reader.searcher();
+
+        live.add_documents(vec![Document { title: String::from("apple jam"), text: String::from("apple jam"), ..Document::default() }]);
+        live.delete_documents(&[0]);
+        assert_eq!(before.search("apple").unwrap().ids().collect::<Vec<i32>>(), vec![0]);
+
+        writer.publish(live.snapshot().unwrap());
+        let after = reader.searcher();
+        assert_eq!(after.search("apple").unwrap().ids().collect::<Vec<i32>>(), vec![2]);
+        let (segment, id) = after.segment_of(2).unwrap();
+        assert_eq!((segment.num_documents(), id), (3, 2));
+    }
+
+    #[test]
+    fn unknown_token_filters_fail_the_add() {
+        let options = IndexOptions { token_filters: Some(vec![String::from("lowercase"), String::from("reverse")]), ..IndexOptions::default() };
+        let mut writer = IndexWriter::new(options);
+        let e = writer.add_documents(docs(&["apple pie"])).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(e.to_string().contains("reverse"));
+    }
+}